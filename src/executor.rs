@@ -1,30 +1,36 @@
 use std::cell::RefCell;
 use std::fmt::{Debug, Formatter};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::mpsc;
 use std::thread;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 use log::error;
-use crate::executor::communication::TinyConnection;
+use crate::executor::communication::{SendError, TinyConnection};
+use crate::executor::sched_job::{SchedJobCtl, SchedJobError, SchedJobFnBox, SchedJobHandle, SchedJobReq};
 use crate::executor::sched_msg::{AsyncTaskFnBox, SchedMsg};
 use crate::executor::sched_param::SchedParams;
+use crate::executor::sched_trace::SchedTraceMode;
 use crate::executor::scheduler::Scheduler;
 use crate::executor::sleep_async::SleepAsyncNode;
 use crate::executor::runtime::Runtime;
 
 mod scheduler;
-mod communication;
+pub mod communication;
 mod task;
 mod taskmng;
 mod runqueue;
 mod sched_param;
 mod sched_wake;
 mod sched_context;
+pub mod sched_job;
+pub mod sched_scope;
 pub mod sched_msg;
 mod sched_sleep_ring;
+pub mod sched_trace;
 mod sleep_async;
 pub mod runtime;
 mod tsc_time_clock;
@@ -42,12 +48,9 @@ impl SubThread {
     }
 
     fn stop(&self) {
-        // send the info first
-        loop {
-            match self.conn.try_send(SchedMsg::new(String::from("q"), None)) {
-                Ok(_) => break,
-                Err(_) => thread::sleep(Duration::from_millis(100)),
-            }
+        // send the info first, waiting for room if the thread is busy
+        if let Err(e) = self.conn.send_blocking(SchedMsg::new(String::from("q"), None)) {
+            println!("SubThread::stop(): thread {} not reachable: {:?}", self.id, e);
         }
     }
     
@@ -60,10 +63,16 @@ impl SubThread {
 
 }
 
+/// Default depth of the executor <-> scheduler message channels
+const DEFAULT_CHANNEL_CAPACITY: usize = 10;
+
 pub struct Executor {
     id: AtomicUsize,
     name: String,
     subs: RefCell<Vec<SubThread>>,
+    job_id: AtomicU64,
+    req_capacity: usize,
+    rsp_capacity: usize,
     // conn: Option<TinyConnection<String>>,
     // handles: Vec<thread::JoinHandle<()>>,
 }
@@ -76,10 +85,19 @@ impl Debug for Executor {
 
 impl Executor {
     pub fn new() -> Self {
+        Self::with_channel_capacity(DEFAULT_CHANNEL_CAPACITY, DEFAULT_CHANNEL_CAPACITY)
+    }
+
+    /// Create an executor whose per-thread request (executor -> scheduler) and
+    /// response (scheduler -> executor) channels hold the given number of messages.
+    pub fn with_channel_capacity(req_capacity: usize, rsp_capacity: usize) -> Self {
         Executor {
             id: AtomicUsize::new(0),
             name: String::from("default"),
             subs: RefCell::new(vec![]),
+            job_id: AtomicU64::new(1),
+            req_capacity: req_capacity.max(1),
+            rsp_capacity: rsp_capacity.max(1),
         }
     }
 
@@ -88,18 +106,26 @@ impl Executor {
     }
 
     pub fn start_thread(&self) -> usize {
+        self.start_traced_thread(SchedTraceMode::Off, None)
+    }
+
+    /// Start a scheduler thread that records or replays its scheduling
+    /// decisions. With `shuffle_seed` the run queue is drained in a seeded
+    /// random order instead of FIFO, to explore task interleavings.
+    pub fn start_traced_thread(&self, trace: SchedTraceMode, shuffle_seed: Option<u64>) -> usize {
         let new_id = self.id.fetch_add(1, Relaxed);
 
         // create communication tunnel
-        let (req_tx, req_rx) = flume::bounded::<SchedMsg>(10);
-        let (rsp_tx, rsp_rx) = flume::bounded::<SchedMsg>(10);
+        let (req_tx, req_rx) = flume::bounded::<SchedMsg>(self.req_capacity);
+        let (rsp_tx, rsp_rx) = flume::bounded::<SchedMsg>(self.rsp_capacity);
         let exe_end = TinyConnection::new(rsp_rx, req_tx);
         let thread_end = TinyConnection::new(req_rx, rsp_tx);
 
         // create a new thread
         let handle = thread::spawn(move || {
             println!("thread spawned {}", new_id);
-            let params = SchedParams::new(new_id, String::from("tmp"));
+            let params = SchedParams::new(new_id, String::from("tmp"))
+                .with_trace(trace, shuffle_seed);
 
             let sched = Rc::new(Scheduler::new(new_id.to_string()));
             println!("sched is {:?}", sched);
@@ -138,15 +164,58 @@ impl Executor {
     }
 
 
-    pub fn try_send(&self, sub_id: usize, sched_msg: SchedMsg) -> Result<(), ()> {
-        let subs = self.subs.borrow();
-        
-        for sub in subs.iter() {
-            if sub.id == sub_id {
-                return sub.conn.try_send(sched_msg);
+    fn sub_conn(&self, sub_id: usize) -> Option<TinyConnection<SchedMsg>> {
+        self.subs
+            .borrow()
+            .iter()
+            .find(|sub| sub.id == sub_id)
+            .map(|sub| sub.conn.clone())
+    }
+
+    pub fn try_send(&self, sub_id: usize, sched_msg: SchedMsg) -> Result<(), SendError<SchedMsg>> {
+        match self.sub_conn(sub_id) {
+            Some(conn) => conn.try_send(sched_msg),
+            None => Err(SendError::UnknownThread(sched_msg)),
+        }
+    }
+
+    /// Send, waiting for capacity. Only usable from inside a scheduler task.
+    pub async fn send(&self, sub_id: usize, sched_msg: SchedMsg) -> Result<(), SendError<SchedMsg>> {
+        match self.sub_conn(sub_id) {
+            Some(conn) => conn.send(sched_msg).await,
+            None => Err(SendError::UnknownThread(sched_msg)),
+        }
+    }
+
+    /// Send, blocking the calling thread until there is capacity.
+    pub fn send_blocking(&self, sub_id: usize, sched_msg: SchedMsg) -> Result<(), SendError<SchedMsg>> {
+        match self.sub_conn(sub_id) {
+            Some(conn) => conn.send_blocking(sched_msg),
+            None => Err(SendError::UnknownThread(sched_msg)),
+        }
+    }
+
+    /// Run `job` on thread `sub_id` once after `delay`, then every `period`
+    /// if one is given. Blocks while the thread's channel is full.
+    pub fn schedule_job(&self, sub_id: usize, delay: Duration, period: Option<Duration>,
+                        job: SchedJobFnBox) -> Result<SchedJobHandle, SchedJobError> {
+        let Some(conn) = self.sub_conn(sub_id) else {
+            return Err(SchedJobError::UnknownThread(job));
+        };
+        let ctl = Arc::new(SchedJobCtl::new(self.job_id.fetch_add(1, Relaxed), delay, period));
+        let msg = SchedMsg::new_job(String::from("new_job"), SchedJobReq::Add(ctl.clone(), job));
+        if let Err(e) = conn.send_blocking(msg) {
+            match e.into_inner().get_job_req() {
+                Some(SchedJobReq::Add(_, job)) => return Err(SchedJobError::Disconnected(job)),
+                _ => unreachable!("new_job message lost its job"),
             }
         }
-        Err(())
+        Ok(SchedJobHandle::new(ctl, conn))
+    }
+
+    /// Number of messages queued towards thread `sub_id`, if it exists.
+    pub fn pending(&self, sub_id: usize) -> Option<usize> {
+        self.sub_conn(sub_id).map(|conn| conn.pending())
     }
 
     // pub fn sleep(dur: Duration) -> SleepRet {
@@ -1,5 +1,41 @@
-
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
 use flume::{Receiver, Sender};
+use crate::executor::runtime::Runtime;
+
+/// Back-off used by the async `send` while the peer channel is full.
+const SEND_RETRY_INTERVAL: Duration = Duration::from_micros(200);
+
+/// Why a message could not be delivered. The message is handed back so the
+/// caller can retry or drop it explicitly.
+pub enum SendError<T> {
+    /// the channel is at capacity
+    Full(T),
+    /// the other end has been dropped (thread exited)
+    Disconnected(T),
+    /// no thread with the requested id is registered
+    UnknownThread(T),
+}
+
+impl<T> SendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::Full(t) => t,
+            SendError::Disconnected(t) => t,
+            SendError::UnknownThread(t) => t,
+        }
+    }
+}
+
+impl<T> Debug for SendError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Full(_) => write!(f, "SendError::Full"),
+            SendError::Disconnected(_) => write!(f, "SendError::Disconnected"),
+            SendError::UnknownThread(_) => write!(f, "SendError::UnknownThread"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct TinyConnection<T> {
@@ -7,19 +43,46 @@ pub(crate) struct TinyConnection<T> {
     tx: Sender<T>
 }
 
+impl<T> Clone for TinyConnection<T> {
+    fn clone(&self) -> Self {
+        Self { rx: self.rx.clone(), tx: self.tx.clone() }
+    }
+}
+
 impl<T> TinyConnection<T> {
     pub(crate) fn new(rx: Receiver<T>, tx: Sender<T>) -> Self {
         Self { rx, tx }
     }
 
-    pub(crate) fn try_send(&self, t: T) -> Result<(), ()> {
-        let tx_res = self.tx.try_send(t);
-        match tx_res {
+    pub(crate) fn try_send(&self, t: T) -> Result<(), SendError<T>> {
+        match self.tx.try_send(t) {
             Ok(_) => Ok(()),
-            Err(_) => Err(()),
+            Err(flume::TrySendError::Full(t)) => Err(SendError::Full(t)),
+            Err(flume::TrySendError::Disconnected(t)) => Err(SendError::Disconnected(t)),
+        }
+    }
+
+    /// Wait for capacity and send. Must be awaited from a scheduler task:
+    /// while the channel is full the task yields through `Runtime::sleep`.
+    pub(crate) async fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut t = t;
+        loop {
+            match self.try_send(t) {
+                Err(SendError::Full(back)) => {
+                    t = back;
+                    Runtime::sleep(SEND_RETRY_INTERVAL).await;
+                }
+                res => return res,
+            }
         }
     }
 
+    /// Block the calling OS thread until there is capacity.
+    pub(crate) fn send_blocking(&self, t: T) -> Result<(), SendError<T>> {
+        self.tx.send(t)
+            .map_err(|e| SendError::Disconnected(e.into_inner()))
+    }
+
     pub(crate) fn try_recv(&self) -> Result<T, ()> {
         let rx_res = self.rx.try_recv();
         match rx_res {
@@ -27,4 +90,8 @@ impl<T> TinyConnection<T> {
             Err(_) => Err(()),
         }
     }
-}
\ No newline at end of file
+
+    pub(crate) fn pending(&self) -> usize {
+        self.tx.len()
+    }
+}
@@ -23,4 +23,20 @@ impl RunQueue {
             .borrow_mut()
             .push_back(st);
     }
+
+    pub(crate) fn take_task_at(&self, idx: usize) -> Option<Rc<SchedTask>> {
+        self.task_vec
+            .borrow_mut()
+            .remove(idx)
+    }
+
+    pub(crate) fn take_task_by_id(&self, task_id: usize) -> Option<Rc<SchedTask>> {
+        let mut tasks = self.task_vec.borrow_mut();
+        let idx = tasks.iter().position(|t| t.get_id() == task_id)?;
+        tasks.remove(idx)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.task_vec.borrow().len()
+    }
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::time::Duration;
+use crate::executor::sched_scope::{ScopeError, TaskScope};
 use crate::executor::scheduler::Scheduler;
 use crate::executor::sleep_async::SleepAsyncNode;
 use crate::executor::task::SchedTask;
@@ -32,6 +33,28 @@ impl Runtime {
         }
     }
     
+    /// Create a scope bound to the current scheduler. Useful for long-lived
+    /// groups (e.g. everything owned by one interface) that are cancelled
+    /// and joined on teardown.
+    pub fn new_scope() -> TaskScope {
+        match Self::get_scheduler() {
+            Some(sched) => TaskScope::new(sched),
+            None => panic!("Scheduler not running"),
+        }
+    }
+
+    /// Run `f` to spawn children into a fresh scope, then wait for all of
+    /// them. A failing child cancels its siblings and makes this return
+    /// `ScopeError::ChildFailed`.
+    pub async fn scope<F>(f: F) -> Result<(), ScopeError>
+    where
+        F: FnOnce(&TaskScope),
+    {
+        let scope = Self::new_scope();
+        f(&scope);
+        scope.join().await
+    }
+
     pub(crate) fn get_time_usec() -> u64 {
         CURR_TIME_USEC.get()
     }
@@ -0,0 +1,260 @@
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicU8};
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use crate::executor::communication::TinyConnection;
+use crate::executor::runtime::Runtime;
+use crate::executor::sched_context::get_sched_context_from_waker;
+use crate::executor::sched_msg::SchedMsg;
+
+/// Body of a delayed or periodic job, called once per run on the scheduler thread.
+pub type SchedJobFnBox = Box<dyn FnMut() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+const JOB_ACTIVE: u8 = 0;
+const JOB_PAUSED: u8 = 1;
+const JOB_CANCELLED: u8 = 2;
+const JOB_DONE: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedJobState {
+    Active,
+    Paused,
+    Cancelled,
+    /// a one-shot job that has run
+    Done,
+}
+
+/// Job settings shared between the handle and the runner task.
+pub(crate) struct SchedJobCtl {
+    id: u64,
+    state: AtomicU8,
+    delay_usec: AtomicU64,
+    // 0 for one-shot jobs
+    period_usec: AtomicU64,
+    // bumped on every reschedule so the runner restarts its timing
+    generation: AtomicU64,
+    runs: AtomicU64,
+}
+
+impl SchedJobCtl {
+    pub(crate) fn new(id: u64, delay: Duration, period: Option<Duration>) -> Self {
+        SchedJobCtl {
+            id,
+            state: AtomicU8::new(JOB_ACTIVE),
+            delay_usec: AtomicU64::new(delay.as_micros() as u64),
+            period_usec: AtomicU64::new(period.map_or(0, |p| (p.as_micros() as u64).max(1))),
+            generation: AtomicU64::new(0),
+            runs: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn state(&self) -> SchedJobState {
+        match self.state.load(Acquire) {
+            JOB_ACTIVE => SchedJobState::Active,
+            JOB_PAUSED => SchedJobState::Paused,
+            JOB_CANCELLED => SchedJobState::Cancelled,
+            _ => SchedJobState::Done,
+        }
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Acquire)
+    }
+}
+
+/// One job message: register a new job, or wake a job's runner after its
+/// settings changed.
+pub(crate) enum SchedJobReq {
+    Add(Arc<SchedJobCtl>, SchedJobFnBox),
+    Wake(u64),
+}
+
+/// Why `Executor::schedule_job` failed. The job body is handed back.
+pub enum SchedJobError {
+    /// no thread with the requested id is registered
+    UnknownThread(SchedJobFnBox),
+    /// the thread's channel is closed (thread exited)
+    Disconnected(SchedJobFnBox),
+}
+
+impl SchedJobError {
+    pub fn into_inner(self) -> SchedJobFnBox {
+        match self {
+            SchedJobError::UnknownThread(job) => job,
+            SchedJobError::Disconnected(job) => job,
+        }
+    }
+}
+
+impl Debug for SchedJobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedJobError::UnknownThread(_) => write!(f, "SchedJobError::UnknownThread"),
+            SchedJobError::Disconnected(_) => write!(f, "SchedJobError::Disconnected"),
+        }
+    }
+}
+
+/// Wait until the job is due, or until its settings change.
+struct SchedJobWait {
+    ctl: Arc<SchedJobCtl>,
+    due_usec: u64,
+    generation: u64,
+    // the sleep-ring node stays queued until `due_usec`, add it only once
+    registered: bool,
+}
+
+impl Future for SchedJobWait {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.ctl.generation() != self.generation {
+            return Poll::Ready(());
+        }
+        match self.ctl.state() {
+            SchedJobState::Cancelled | SchedJobState::Done => return Poll::Ready(()),
+            // parked off the sleep ring, `resume` wakes it up
+            SchedJobState::Paused => return Poll::Pending,
+            SchedJobState::Active => {}
+        }
+        if Runtime::get_time_usec() >= self.due_usec {
+            return Poll::Ready(());
+        }
+
+        if !self.registered {
+            let sched_ctx = get_sched_context_from_waker(cx.waker());
+            match sched_ctx.get_curr_task() {
+                None => panic!("job wait logic error"),
+                Some(t) => sched_ctx.get_curr_scheduler().add_to_sleep_ring(self.due_usec, t),
+            }
+            self.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+/// The task driving one job on its scheduler thread.
+pub(crate) async fn sched_job_runner(ctl: Arc<SchedJobCtl>, mut job: SchedJobFnBox) {
+    let mut generation = ctl.generation();
+    let mut due_usec = Runtime::get_time_usec() + ctl.delay_usec.load(Relaxed);
+    loop {
+        SchedJobWait { ctl: ctl.clone(), due_usec, generation, registered: false }.await;
+
+        let now = Runtime::get_time_usec();
+        if ctl.state() == SchedJobState::Cancelled {
+            break;
+        }
+        if ctl.generation() != generation {
+            // rescheduled, start over from now
+            generation = ctl.generation();
+            due_usec = now + ctl.delay_usec.load(Relaxed);
+            continue;
+        }
+
+        job().await;
+        ctl.runs.fetch_add(1, Relaxed);
+
+        let period = ctl.period_usec.load(Relaxed);
+        if period == 0 {
+            // keep a concurrent cancel visible
+            _ = ctl.state.compare_exchange(JOB_ACTIVE, JOB_DONE, Release, Relaxed);
+            break;
+        }
+        due_usec += period;
+        if due_usec < now {
+            // fell behind, skip the missed runs
+            due_usec = now + period;
+        }
+    }
+    println!("job {} finished, runs: {}", ctl.id, ctl.runs.load(Relaxed));
+}
+
+/// Handle to a job registered with `Executor::schedule_job`.
+///
+/// Changes take effect immediately for the job's bookkeeping; the runner is
+/// woken through the thread's message channel, which is best effort. If the
+/// channel is full the change is picked up at the job's next wakeup.
+pub struct SchedJobHandle {
+    ctl: Arc<SchedJobCtl>,
+    conn: TinyConnection<SchedMsg>,
+}
+
+impl SchedJobHandle {
+    pub(crate) fn new(ctl: Arc<SchedJobCtl>, conn: TinyConnection<SchedMsg>) -> Self {
+        SchedJobHandle { ctl, conn }
+    }
+
+    fn wake(&self) {
+        let msg = SchedMsg::new_job(String::from("wake_job"), SchedJobReq::Wake(self.ctl.id));
+        if let Err(e) = self.conn.try_send(msg) {
+            println!("SchedJobHandle::wake(): job {} not woken: {:?}", self.ctl.id, e);
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.ctl.id
+    }
+
+    pub fn state(&self) -> SchedJobState {
+        self.ctl.state()
+    }
+
+    /// Number of completed runs.
+    pub fn runs(&self) -> u64 {
+        self.ctl.runs.load(Relaxed)
+    }
+
+    /// Stop running the job until `resume`. A run already in progress completes.
+    pub fn pause(&self) -> Result<(), ()> {
+        self.ctl.state
+            .compare_exchange(JOB_ACTIVE, JOB_PAUSED, Release, Relaxed)
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Resume a paused job. If it became due while paused it runs right away.
+    pub fn resume(&self) -> Result<(), ()> {
+        self.ctl.state
+            .compare_exchange(JOB_PAUSED, JOB_ACTIVE, Release, Relaxed)
+            .map_err(|_| ())?;
+        self.wake();
+        Ok(())
+    }
+
+    /// Restart the job's timing from now with a new delay and period.
+    pub fn reschedule(&self, delay: Duration, period: Option<Duration>) -> Result<(), ()> {
+        match self.ctl.state() {
+            SchedJobState::Cancelled | SchedJobState::Done => return Err(()),
+            _ => {}
+        }
+        self.ctl.delay_usec.store(delay.as_micros() as u64, Relaxed);
+        self.ctl.period_usec.store(period.map_or(0, |p| (p.as_micros() as u64).max(1)), Relaxed);
+        self.ctl.generation.fetch_add(1, Release);
+        self.wake();
+        Ok(())
+    }
+
+    /// Cancel the job; it will not run again.
+    pub fn cancel(&self) {
+        let res = self.ctl.state.fetch_update(Release, Relaxed, |s| match s {
+            JOB_CANCELLED | JOB_DONE => None,
+            _ => Some(JOB_CANCELLED),
+        });
+        if res.is_ok() {
+            self.wake();
+        }
+    }
+}
+
+impl Debug for SchedJobHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SchedJob(id:{}, state:{:?}, runs:{})", self.ctl.id, self.ctl.state(), self.runs())
+    }
+}
@@ -3,18 +3,24 @@ use std::pin::Pin;
 
 use std::future::Future;
 use std::rc::Rc;
+use crate::executor::sched_job::SchedJobReq;
 use crate::executor::scheduler::Scheduler;
 
 pub type AsyncTaskFnBox = Box<dyn FnOnce(String) -> Pin<Box<dyn Future<Output = ()>>> + Send + Sync>;
 pub struct SchedMsg {
     cmd: String,
     // task_func: Option<Box<dyn Fn(String) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>>,
-    task_func: Option<AsyncTaskFnBox>
+    task_func: Option<AsyncTaskFnBox>,
+    job_req: Option<SchedJobReq>,
 }
 
 impl SchedMsg {
     pub fn new(cmd: String, task_func: Option<AsyncTaskFnBox>) -> Self {
-        Self { cmd, task_func }
+        Self { cmd, task_func, job_req: None }
+    }
+
+    pub(crate) fn new_job(cmd: String, job_req: SchedJobReq) -> Self {
+        Self { cmd, task_func: None, job_req: Some(job_req) }
     }
 
     pub(crate) fn get_cmd(&self) -> &str {
@@ -24,6 +30,10 @@ impl SchedMsg {
     pub(crate) fn get_task_func(&mut self) -> Option<AsyncTaskFnBox> {
         self.task_func.take()
     }
+
+    pub(crate) fn get_job_req(&mut self) -> Option<SchedJobReq> {
+        self.job_req.take()
+    }
 }
 
 impl Debug for SchedMsg {
@@ -1,16 +1,32 @@
-use crate::executor::scheduler::Scheduler;
+use crate::executor::sched_trace::SchedTraceMode;
 
 pub(crate) struct SchedParams {
     id: usize,
     name: String,
+    trace: SchedTraceMode,
+    shuffle_seed: Option<u64>,
 }
 
 impl SchedParams {
     pub fn new(id: usize, name: String) -> Self {
-        Self { id, name }
+        Self { id, name, trace: SchedTraceMode::Off, shuffle_seed: None }
+    }
+
+    pub fn with_trace(mut self, trace: SchedTraceMode, shuffle_seed: Option<u64>) -> Self {
+        self.trace = trace;
+        self.shuffle_seed = shuffle_seed;
+        self
     }
     
     pub fn get_id(&self) -> usize {
         self.id
     }
+
+    pub fn get_trace(&self) -> &SchedTraceMode {
+        &self.trace
+    }
+
+    pub fn get_shuffle_seed(&self) -> Option<u64> {
+        self.shuffle_seed
+    }
 }
\ No newline at end of file
@@ -0,0 +1,260 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use crate::executor::sched_context::get_sched_context_from_waker;
+use crate::executor::scheduler::Scheduler;
+use crate::executor::task::SchedTask;
+
+/// Why a scope refused a child or its join failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeError {
+    /// the scope was cancelled, it takes no new children
+    Cancelled,
+    /// the scheduler did not take the task
+    SpawnFailed,
+    /// the child with this task id returned an error, its siblings were
+    /// cancelled
+    ChildFailed(usize),
+}
+
+/// Shared state between a scope, its children and the task joining it.
+struct ScopeInner {
+    sched: Rc<Scheduler>,
+    children: RefCell<Vec<Rc<SchedTask>>>,
+    live: Cell<usize>,
+    // task id of the first child that failed
+    failed: Cell<Option<usize>>,
+    cancelled: Cell<bool>,
+    waiter: RefCell<Option<Rc<SchedTask>>>,
+}
+
+impl ScopeInner {
+    fn cancel_children(&self) {
+        self.cancelled.set(true);
+        // take the list out, dropping children touches it again
+        let children: Vec<Rc<SchedTask>> = self.children.borrow().clone();
+        for child in children {
+            child.cancel();
+            // let the scheduler drop it now instead of at its next wakeup
+            self.sched.wake_task(child);
+        }
+    }
+
+    fn child_done(&self, task_id: usize) {
+        self.children.borrow_mut().retain(|t| t.get_id() != task_id);
+        self.live.set(self.live.get() - 1);
+        if self.live.get() == 0
+            && let Some(waiter) = self.waiter.borrow_mut().take()
+        {
+            self.sched.wake_task(waiter);
+        }
+    }
+}
+
+/// Lives inside every child future; runs on completion and on cancellation.
+struct ScopeChildGuard {
+    inner: Rc<ScopeInner>,
+    task_id: Rc<Cell<usize>>,
+}
+
+impl Drop for ScopeChildGuard {
+    fn drop(&mut self) {
+        self.inner.child_done(self.task_id.get());
+    }
+}
+
+/// A group of tasks that cannot outlive it.
+///
+/// Children are spawned on the current scheduler. If any child returns an
+/// error the remaining ones are cancelled. Dropping the scope cancels
+/// whatever is still running, so `join` it before letting it go.
+pub struct TaskScope {
+    inner: Rc<ScopeInner>,
+}
+
+impl TaskScope {
+    pub(crate) fn new(sched: Rc<Scheduler>) -> Self {
+        TaskScope {
+            inner: Rc::new(ScopeInner {
+                sched,
+                children: RefCell::new(Vec::new()),
+                live: Cell::new(0),
+                failed: Cell::new(None),
+                cancelled: Cell::new(false),
+                waiter: RefCell::new(None),
+            }),
+        }
+    }
+
+    /// Spawn a child task, returns its task id.
+    pub fn spawn<F, E>(&self, name: &str, fut: F) -> Result<usize, ScopeError>
+    where
+        F: Future<Output = Result<(), E>> + 'static,
+        E: Debug,
+    {
+        if self.inner.cancelled.get() {
+            return Err(ScopeError::Cancelled);
+        }
+
+        let task_id = Rc::new(Cell::new(0));
+        let guard = ScopeChildGuard { inner: self.inner.clone(), task_id: task_id.clone() };
+        let wrapped = async move {
+            let guard = guard;
+            if let Err(e) = fut.await {
+                println!("TaskScope: task {} failed: {:?}", guard.task_id.get(), e);
+                if guard.inner.failed.get().is_none() {
+                    guard.inner.failed.set(Some(guard.task_id.get()));
+                    guard.inner.cancel_children();
+                }
+            }
+        };
+
+        self.inner.live.set(self.inner.live.get() + 1);
+        match self.inner.sched.spawn_task(String::from(name), wrapped) {
+            Ok(task) => {
+                task_id.set(task.get_id());
+                let id = task.get_id();
+                self.inner.children.borrow_mut().push(task);
+                Ok(id)
+            }
+            // the wrapper (and so the guard) was dropped, live is back in balance
+            Err(_) => Err(ScopeError::SpawnFailed),
+        }
+    }
+
+    /// Cancel every child that is still running.
+    pub fn cancel(&self) {
+        self.inner.cancel_children();
+    }
+
+    /// Number of children that have not finished yet.
+    pub fn live(&self) -> usize {
+        self.inner.live.get()
+    }
+
+    /// Wait for all children. `ScopeError::ChildFailed` if one of them failed.
+    pub fn join(&self) -> ScopeJoin {
+        ScopeJoin { inner: self.inner.clone() }
+    }
+}
+
+impl Drop for TaskScope {
+    fn drop(&mut self) {
+        if self.inner.live.get() > 0 {
+            self.inner.cancel_children();
+        }
+    }
+}
+
+impl Debug for TaskScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TaskScope(live:{}, failed:{:?})", self.inner.live.get(), self.inner.failed.get())
+    }
+}
+
+pub struct ScopeJoin {
+    inner: Rc<ScopeInner>,
+}
+
+impl Future for ScopeJoin {
+    type Output = Result<(), ScopeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.inner.live.get() == 0 {
+            return match self.inner.failed.get() {
+                Some(task_id) => Poll::Ready(Err(ScopeError::ChildFailed(task_id))),
+                None => Poll::Ready(Ok(())),
+            };
+        }
+
+        // park the joining task until the last child is gone
+        let sched_ctx = get_sched_context_from_waker(cx.waker());
+        match sched_ctx.get_curr_task() {
+            None => panic!("scope join logic error"),
+            Some(t) => {
+                self.inner.waiter.replace(Some(t));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use crate::executor::Executor;
+    use crate::executor::runtime::Runtime;
+    use crate::executor::sched_msg::{AsyncTaskFnBox, SchedMsg};
+
+    /// Run `f` as a task on a scheduler thread of its own, its result once
+    /// it is done.
+    fn run_task<T, F>(f: impl FnOnce() -> F + Send + Sync + 'static) -> T
+    where
+        T: Send + 'static,
+        F: Future<Output = T> + 'static,
+    {
+        let e = Executor::new();
+        let thread_id = e.start_thread();
+        let (tx, rx) = mpsc::channel();
+        let task: AsyncTaskFnBox = Box::new(move |_| Box::pin(async move {
+            _ = tx.send(f().await);
+        }));
+        e.send_blocking(thread_id, SchedMsg::new(String::from("new_task"), Some(task))).unwrap();
+        let res = rx.recv_timeout(Duration::from_secs(5)).expect("task did not finish");
+        e.exit();
+        res
+    }
+
+    #[test]
+    fn failing_child_cancels_siblings() {
+        let (res, failed_id, slow_done, spawn_after, elapsed) = run_task(|| async {
+            let start = Instant::now();
+            let slow_done = Rc::new(Cell::new(false));
+            let scope = Runtime::new_scope();
+            let done = slow_done.clone();
+            scope.spawn("slow", async move {
+                Runtime::sleep(Duration::from_secs(2)).await;
+                done.set(true);
+                Ok::<(), &str>(())
+            }).unwrap();
+            scope.spawn("quick", async { Ok::<(), &str>(()) }).unwrap();
+            let failed_id = scope.spawn("fail", async {
+                Runtime::sleep(Duration::from_millis(5)).await;
+                Err("boom")
+            }).unwrap();
+            let res = scope.join().await;
+            let spawn_after = scope.spawn("late", async { Ok::<(), &str>(()) });
+            (res, failed_id, slow_done.get(), spawn_after, start.elapsed())
+        });
+        assert_eq!(res, Err(ScopeError::ChildFailed(failed_id)));
+        assert!(!slow_done);
+        assert!(elapsed < Duration::from_secs(1), "waited for the cancelled child: {:?}", elapsed);
+        assert_eq!(spawn_after, Err(ScopeError::Cancelled));
+    }
+
+    #[test]
+    fn join_wakes_after_last_child() {
+        let (res, finished) = run_task(|| async {
+            let finished = Rc::new(RefCell::new(Vec::new()));
+            let res = Runtime::scope(|scope| {
+                for (name, ms) in [("a", 10), ("b", 2), ("c", 5)] {
+                    let finished = finished.clone();
+                    scope.spawn(name, async move {
+                        Runtime::sleep(Duration::from_millis(ms)).await;
+                        finished.borrow_mut().push(name);
+                        Ok::<(), ()>(())
+                    }).unwrap();
+                }
+            }).await;
+            let finished = finished.borrow().clone();
+            (res, finished)
+        });
+        assert_eq!(res, Ok(()));
+        assert_eq!(finished.len(), 3);
+        assert_eq!(finished.last(), Some(&"a"));
+    }
+}
@@ -0,0 +1,350 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// How a scheduler thread treats its scheduling decisions.
+#[derive(Debug, Clone, Default)]
+pub enum SchedTraceMode {
+    #[default]
+    Off,
+    /// write every clock reading, message, poll and wakeup to the file
+    Record(PathBuf),
+    /// take clock readings and the poll order from a recorded file
+    Replay(PathBuf),
+}
+
+/// One scheduling decision, one line in the trace file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SchedEvent {
+    Seed(u64),
+    Clock(u64),
+    Recv(String),
+    Poll(usize),
+    Wake(usize),
+}
+
+impl Display for SchedEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedEvent::Seed(s) => write!(f, "S {}", s),
+            SchedEvent::Clock(t) => write!(f, "T {}", t),
+            SchedEvent::Recv(cmd) => write!(f, "R {}", cmd),
+            SchedEvent::Poll(id) => write!(f, "P {}", id),
+            SchedEvent::Wake(id) => write!(f, "W {}", id),
+        }
+    }
+}
+
+impl FromStr for SchedEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tag, val) = s.split_once(' ')
+            .ok_or_else(|| format!("Invalid trace line: {}", s))?;
+        let num = || val.parse::<u64>().map_err(|_| format!("Invalid trace value: {}", s));
+        match tag {
+            "S" => Ok(SchedEvent::Seed(num()?)),
+            "T" => Ok(SchedEvent::Clock(num()?)),
+            "R" => Ok(SchedEvent::Recv(String::from(val))),
+            "P" => Ok(SchedEvent::Poll(num()? as usize)),
+            "W" => Ok(SchedEvent::Wake(num()? as usize)),
+            _ => Err(format!("Invalid trace tag: {}", s)),
+        }
+    }
+}
+
+/// Stands in for a zero shuffle seed.
+const SHUFFLE_ZERO_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+enum TraceState {
+    Live,
+    Recording(BufWriter<File>),
+    Replaying(VecDeque<SchedEvent>, usize),
+}
+
+/// Records or replays the scheduling decisions of one `Scheduler`, and picks
+/// the next runnable task at random when a shuffle seed is set.
+pub(crate) struct SchedTrace {
+    state: RefCell<TraceState>,
+    rng: Cell<Option<u64>>,
+}
+
+impl SchedTrace {
+    pub(crate) fn new() -> Self {
+        SchedTrace {
+            state: RefCell::new(TraceState::Live),
+            rng: Cell::new(None),
+        }
+    }
+
+    pub(crate) fn setup(&self, mode: &SchedTraceMode, shuffle_seed: Option<u64>) {
+        // xorshift state must not be zero, other seeds are taken as they are
+        self.rng.set(shuffle_seed.map(|s| if s == 0 { SHUFFLE_ZERO_SEED } else { s }));
+        let state = match mode {
+            SchedTraceMode::Off => TraceState::Live,
+            SchedTraceMode::Record(path) => match File::create(path) {
+                Ok(f) => TraceState::Recording(BufWriter::new(f)),
+                Err(e) => {
+                    println!("SchedTrace: cannot create {:?}: {}", path, e);
+                    TraceState::Live
+                }
+            },
+            SchedTraceMode::Replay(path) => match Self::load(path) {
+                Ok(events) => TraceState::Replaying(events, 0),
+                Err(e) => {
+                    println!("SchedTrace: cannot load {:?}: {}", path, e);
+                    TraceState::Live
+                }
+            },
+        };
+        self.state.replace(state);
+        if let Some(seed) = shuffle_seed {
+            self.log(SchedEvent::Seed(seed));
+        }
+        // a recorded seed is informational only, the poll order is in the file
+        self.skip_seed();
+    }
+
+    fn load(path: &PathBuf) -> Result<VecDeque<SchedEvent>, String> {
+        let f = File::open(path).map_err(|e| e.to_string())?;
+        let mut events = VecDeque::new();
+        for line in BufReader::new(f).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.is_empty() {
+                continue;
+            }
+            events.push_back(SchedEvent::from_str(&line)?);
+        }
+        Ok(events)
+    }
+
+    pub(crate) fn is_replaying(&self) -> bool {
+        matches!(*self.state.borrow(), TraceState::Replaying(..))
+    }
+
+    fn log(&self, ev: SchedEvent) {
+        if let TraceState::Recording(w) = &mut *self.state.borrow_mut()
+            && let Err(e) = writeln!(w, "{}", ev)
+        {
+            println!("SchedTrace: write failed: {}", e);
+        }
+    }
+
+    fn peek(&self) -> Option<SchedEvent> {
+        match &*self.state.borrow() {
+            TraceState::Replaying(events, _) => events.front().cloned(),
+            _ => None,
+        }
+    }
+
+    /// Consume the next replayed event, which must equal `ev`.
+    fn expect(&self, ev: SchedEvent) {
+        let mut state = self.state.borrow_mut();
+        let TraceState::Replaying(events, pos) = &mut *state else {
+            return;
+        };
+        match events.pop_front() {
+            Some(rec) if rec == ev => *pos += 1,
+            rec => panic!("SchedTrace: replay diverged at event {}: recorded {:?}, running {:?}", pos, rec, ev),
+        }
+        if events.is_empty() {
+            println!("SchedTrace: replay finished after {} events", pos);
+            *state = TraceState::Live;
+        }
+    }
+
+    fn skip_seed(&self) {
+        if let Some(SchedEvent::Seed(_)) = self.peek() {
+            let mut state = self.state.borrow_mut();
+            if let TraceState::Replaying(events, pos) = &mut *state {
+                events.pop_front();
+                *pos += 1;
+            }
+        }
+    }
+
+    /// Current time: the live reading when running, the recorded one on replay.
+    pub(crate) fn clock(&self, live: impl FnOnce() -> u64) -> u64 {
+        if let Some(SchedEvent::Clock(t)) = self.peek() {
+            self.expect(SchedEvent::Clock(t));
+            return t;
+        }
+        if self.is_replaying() {
+            panic!("SchedTrace: replay diverged, expected a clock reading, next is {:?}", self.peek());
+        }
+        let t = live();
+        self.log(SchedEvent::Clock(t));
+        t
+    }
+
+    /// On replay: whether the recorded run received a message at this point.
+    /// `None` when not replaying.
+    pub(crate) fn replay_expects_recv(&self) -> Option<bool> {
+        if !self.is_replaying() {
+            return None;
+        }
+        Some(matches!(self.peek(), Some(SchedEvent::Recv(_))))
+    }
+
+    pub(crate) fn recv(&self, cmd: &str) {
+        if self.is_replaying() {
+            self.expect(SchedEvent::Recv(String::from(cmd)));
+        } else {
+            self.log(SchedEvent::Recv(String::from(cmd)));
+        }
+    }
+
+    /// On replay: `Some(Some(id))` for the task to poll next, `Some(None)` if
+    /// the recorded run stopped draining the run queue here.
+    pub(crate) fn replay_next_poll(&self) -> Option<Option<usize>> {
+        if !self.is_replaying() {
+            return None;
+        }
+        match self.peek() {
+            Some(SchedEvent::Poll(id)) => Some(Some(id)),
+            _ => Some(None),
+        }
+    }
+
+    pub(crate) fn poll(&self, task_id: usize) {
+        if self.is_replaying() {
+            self.expect(SchedEvent::Poll(task_id));
+        } else {
+            self.log(SchedEvent::Poll(task_id));
+        }
+    }
+
+    pub(crate) fn wake(&self, task_id: usize) {
+        if self.is_replaying() {
+            self.expect(SchedEvent::Wake(task_id));
+        } else {
+            self.log(SchedEvent::Wake(task_id));
+        }
+    }
+
+    /// Index into a run queue of length `len` to take next, `None` for FIFO.
+    pub(crate) fn shuffle_pick(&self, len: usize) -> Option<usize> {
+        let mut x = self.rng.get()?;
+        if len == 0 {
+            return None;
+        }
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(Some(x));
+        Some((x % len as u64) as usize)
+    }
+
+    pub(crate) fn finish(&self) {
+        if let TraceState::Recording(w) = &mut *self.state.borrow_mut()
+            && let Err(e) = w.flush()
+        {
+            println!("SchedTrace: flush failed: {}", e);
+        }
+        self.state.replace(TraceState::Live);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::executor::Executor;
+    use crate::executor::runtime::Runtime;
+    use crate::executor::sched_msg::{AsyncTaskFnBox, SchedMsg};
+
+    const NAMES: [&str; 6] = ["a", "b", "c", "d", "e", "f"];
+
+    /// Spawn a child per name into a scope, each logging `rounds` steps with
+    /// a short sleep in between, on a thread of its own; the order the steps
+    /// ran in.
+    fn run_children(trace: SchedTraceMode, shuffle_seed: Option<u64>, rounds: u32) -> Vec<(&'static str, u32)> {
+        let e = Executor::new();
+        let thread_id = e.start_traced_thread(trace, shuffle_seed);
+        let (tx, rx) = mpsc::channel();
+        let task: AsyncTaskFnBox = Box::new(move |_| Box::pin(async move {
+            let order = Rc::new(RefCell::new(Vec::new()));
+            let res = Runtime::scope(|scope| {
+                for name in NAMES {
+                    let order = order.clone();
+                    scope.spawn(name, async move {
+                        for i in 0..rounds {
+                            order.borrow_mut().push((name, i));
+                            if i + 1 < rounds {
+                                Runtime::sleep(Duration::from_millis(1)).await;
+                            }
+                        }
+                        Ok::<(), ()>(())
+                    }).unwrap();
+                }
+            }).await;
+            assert_eq!(res, Ok(()));
+            _ = tx.send(order.borrow().clone());
+        }));
+        e.send_blocking(thread_id, SchedMsg::new(String::from("new_task"), Some(task))).unwrap();
+        let order = rx.recv_timeout(Duration::from_secs(5)).expect("task did not finish");
+        e.exit();
+        order
+    }
+
+    fn trace_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("remu-sched-trace-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn event_lines_round_trip() {
+        let events = [
+            SchedEvent::Seed(7),
+            SchedEvent::Clock(123456),
+            SchedEvent::Recv(String::from("new task")),
+            SchedEvent::Poll(3),
+            SchedEvent::Wake(4),
+        ];
+        for ev in events {
+            assert_eq!(SchedEvent::from_str(&ev.to_string()), Ok(ev));
+        }
+        assert!(SchedEvent::from_str("P").is_err());
+        assert!(SchedEvent::from_str("P x").is_err());
+        assert!(SchedEvent::from_str("X 1").is_err());
+    }
+
+    #[test]
+    fn shuffle_is_seeded() {
+        let picks = |seed: Option<u64>| {
+            let t = SchedTrace::new();
+            t.setup(&SchedTraceMode::Off, seed);
+            (1..50).map(|len| t.shuffle_pick(len)).collect::<Vec<_>>()
+        };
+        assert_eq!(picks(Some(42)), picks(Some(42)));
+        assert_ne!(picks(Some(42)), picks(Some(43)));
+        assert_ne!(picks(Some(0)), picks(Some(1)));
+        assert!(picks(Some(42)).iter().enumerate().all(|(i, p)| p.is_some_and(|p| p < i + 1)));
+        assert!(picks(None).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn shuffled_run_is_repeatable() {
+        let fifo = run_children(SchedTraceMode::Off, None, 1);
+        assert_eq!(fifo, NAMES.iter().map(|n| (*n, 0)).collect::<Vec<_>>());
+        let shuffled = run_children(SchedTraceMode::Off, Some(5), 1);
+        assert_ne!(shuffled, fifo);
+        assert_eq!(shuffled, run_children(SchedTraceMode::Off, Some(5), 1));
+    }
+
+    #[test]
+    fn replay_repeats_recorded_order() {
+        let path = trace_path("replay");
+        let recorded = run_children(SchedTraceMode::Record(path.clone()), Some(11), 3);
+        assert_eq!(recorded.len(), NAMES.len() * 3);
+        let replayed = run_children(SchedTraceMode::Replay(path.clone()), None, 3);
+        _ = std::fs::remove_file(&path);
+        assert_eq!(replayed, recorded);
+    }
+}
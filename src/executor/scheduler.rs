@@ -1,4 +1,5 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::rc::Rc;
 use std::task::{Context, Poll};
@@ -9,8 +10,10 @@ use crate::executor::sched_msg::SchedMsg;
 use crate::executor::runqueue::RunQueue;
 use crate::executor::runtime::Runtime;
 use crate::executor::sched_context::SchedContext;
+use crate::executor::sched_job::{sched_job_runner, SchedJobReq};
 use crate::executor::sched_param::SchedParams;
 use crate::executor::sched_sleep_ring::SchedSleepRing;
+use crate::executor::sched_trace::SchedTrace;
 use crate::executor::sched_wake::sched_waker_create;
 use crate::executor::sleep_async::SleepAsyncNode;
 use crate::executor::task::SchedTask;
@@ -23,12 +26,15 @@ pub(crate) struct Scheduler {
     task_mng: SchedTaskMng,
     task_run_queue: RunQueue,
     task_sleep_ring: SchedSleepRing,
+    trace: SchedTrace,
+    // runner tasks of delayed/periodic jobs, by job id
+    jobs: RefCell<HashMap<u64, Rc<SchedTask>>>,
     curr_running_task: Option<Rc<SchedTask>>,
 }
 
 impl Debug for Scheduler {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Scheduler {{ name: {} }}", self.name)
+        write!(f, "Scheduler {{ name: {}, tasks: {} }}", self.name, self.task_mng.len())
     }
 }
 
@@ -40,6 +46,8 @@ impl Scheduler {
             task_mng: SchedTaskMng::new(), 
             task_run_queue: RunQueue::new(),
             task_sleep_ring: SchedSleepRing::new(10000),
+            trace: SchedTrace::new(),
+            jobs: RefCell::new(HashMap::new()),
             curr_running_task: None,
         }
     }
@@ -69,15 +77,73 @@ impl Scheduler {
     pub(crate) fn add_to_sleep_ring(&self, delay_to: u64, task: Rc<SchedTask>) {
         self.task_sleep_ring.add_task_node(delay_to, task);
     }
+
+    /// Register a new task and queue it for its first poll.
+    pub(crate) fn spawn_task<F>(&self, name: String, fut: F) -> Result<Rc<SchedTask>, ()>
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        let new_sched_task = SchedTask::new(name, fut);
+
+        // put it into the hashmap
+        self.task_mng.add_task(new_sched_task.clone())?;
+        println!("new task added, {:?}", new_sched_task);
+        // push it to the run queuee
+        self.task_run_queue.push_one_task(new_sched_task.clone());
+        Ok(new_sched_task)
+    }
+
+    /// Put a pending task back on the run queue so it gets polled again.
+    pub(crate) fn wake_task(&self, task: Rc<SchedTask>) {
+        if !task.is_finished() {
+            self.task_run_queue.push_one_task(task);
+        }
+    }
+
+    fn handle_job_req(&self, req: SchedJobReq) {
+        match req {
+            SchedJobReq::Add(ctl, job) => {
+                let job_id = ctl.get_id();
+                let runner = sched_job_runner(ctl, job);
+                if let Ok(task) = self.spawn_task(format!("{}-job-{}", self.name, job_id), runner) {
+                    let mut jobs = self.jobs.borrow_mut();
+                    jobs.retain(|_, t| !t.is_finished());
+                    jobs.insert(job_id, task);
+                }
+            }
+            SchedJobReq::Wake(job_id) => {
+                let task = self.jobs.borrow().get(&job_id).cloned();
+                match task {
+                    Some(t) if t.is_finished() => {
+                        self.jobs.borrow_mut().remove(&job_id);
+                    }
+                    Some(t) => self.wake_task(t),
+                    None => println!("Scheduler: wake for unknown job {}", job_id),
+                }
+            }
+        }
+    }
+
+    /// Drop a cancelled task without polling it again.
+    fn discard_task(&self, task: &Rc<SchedTask>) {
+        if task.is_finished() {
+            return;
+        }
+        println!("task cancelled: {:?}", task);
+        task.drop_fut();
+        // the task may already be gone if it was queued more than once
+        _ = self.task_mng.remove_task(task.get_id());
+    }
     
     pub fn run(self: Rc<Self>, param: SchedParams) {
         // create a context
         let sched_ctx = Rc::new(SchedContext::new(0, 0, self.clone()));
         let sched_waker = sched_waker_create(sched_ctx.clone());
         let mut ctx = Context::from_waker(&sched_waker);
+        self.trace.setup(param.get_trace(), param.get_shuffle_seed());
         
         loop {
-            if let Ok(mut val) = self.try_recv() {
+            if let Some(mut val) = self.next_msg() {
                 println!("thread {} recved: {:?}", param.get_id(), val);
                 if val.get_cmd() == "q" {
                     break;
@@ -85,14 +151,11 @@ impl Scheduler {
                     if let Some(task_func) = val.get_task_func() {
                         // create a new wrapper async task, that call this function
                         let new_task = task_func(String::from("xxx"));
-                        let new_sched_task = Rc::new(SchedTask::new(self.name.clone() + "-task", new_task));
-
-                        // put it into the hashmap
-                        if let Ok(_) =self.task_mng.add_task(new_sched_task.clone()) {
-                            println!("new task added, {:?}", new_sched_task);
-                            // push it to the run queuee
-                            self.task_run_queue.push_one_task(new_sched_task);
-                        }
+                        _ = self.spawn_task(self.name.clone() + "-task", new_task);
+                    }
+                } else if val.get_cmd() == "new_job" || val.get_cmd() == "wake_job" {
+                    if let Some(req) = val.get_job_req() {
+                        self.handle_job_req(req);
                     }
                 } else if val.get_cmd() == "add mac" {
                     
@@ -100,38 +163,41 @@ impl Scheduler {
             }
 
             // schedule all the tasks in the run-queue
-            while let Some(task) = self.task_run_queue.take_one_task() {
+            while let Some(task) = self.next_task() {
+                if task.is_cancelled() {
+                    self.discard_task(&task);
+                    continue;
+                }
                 sched_ctx.set_curr_task(Some(task.clone()));
-                Runtime::set_time_usec(TscClock::rdtsc_usec());
-                match task.get_task_fut() {
-                    Some(task_fut) => {
-                        match task_fut.borrow_mut().as_mut().poll(&mut ctx) {
-                            Poll::Pending => {
-                                println!("task future pending");
+                Runtime::set_time_usec(self.trace.clock(TscClock::rdtsc_usec));
+                match task.poll_fut(&mut ctx) {
+                    Some(Poll::Pending) => {
+                        println!("task future pending");
+                    }
+                    Some(Poll::Ready(_)) => {
+                        println!("task future ready: {:?}", task);
+                        task.drop_fut();
+                        match self.task_mng.remove_task(task.get_id()) {
+                            Ok(t) => {
+                                println!("task future removed: {:?}", t);
                             }
-                            Poll::Ready(_) => {
-                                println!("task future ready: {:?}", task);
-                                match self.task_mng.remove_task(task.get_id()) {
-                                    Ok(t) => {
-                                        println!("task future removed: {:?}", t);
-                                    }
-                                    Err(_) => {
-                                        println!("task future failed to remove: {:?}", task);
-                                        panic!("task future failed to remove: {:?}", task);
-                                    }
-                                }
+                            Err(_) => {
+                                println!("task future failed to remove: {:?}", task);
+                                panic!("task future failed to remove: {:?}", task);
                             }
                         }
                     }
+                    // stale entry of a task that already finished
                     None => {}
                 }
                 sched_ctx.set_curr_task(None);
             }
 
-            Runtime::set_time_usec(TscClock::rdtsc_usec());
+            Runtime::set_time_usec(self.trace.clock(TscClock::rdtsc_usec));
             let mut sleep_tasks = self.task_sleep_ring.get_tasks();
             // println!("Scheduler::run() processing sleep nodes: {}", sleep_tasks.len());
             for task in sleep_tasks.drain(..) {
+                self.trace.wake(task.get_id());
                 self.task_run_queue.push_one_task(task);
             }
 
@@ -139,6 +205,41 @@ impl Scheduler {
             // println!("Scheduler::run(): cur usec: {}", cur_usec);
             thread::sleep(Duration::from_millis(1));
         }
+        self.trace.finish();
+    }
+
+    /// Next control message. On replay only receive where the recorded run
+    /// did, waiting for the message if it has not arrived yet.
+    fn next_msg(&self) -> Option<SchedMsg> {
+        let val = match self.trace.replay_expects_recv() {
+            None => self.try_recv().ok()?,
+            Some(false) => return None,
+            Some(true) => loop {
+                if let Ok(val) = self.try_recv() {
+                    break val;
+                }
+                thread::sleep(Duration::from_millis(1));
+            },
+        };
+        self.trace.recv(val.get_cmd());
+        Some(val)
+    }
+
+    /// Next task to poll: FIFO, random with a shuffle seed, or the recorded one on replay.
+    fn next_task(&self) -> Option<Rc<SchedTask>> {
+        let task = match self.trace.replay_next_poll() {
+            Some(Some(task_id)) => match self.task_run_queue.take_task_by_id(task_id) {
+                Some(t) => Some(t),
+                None => panic!("SchedTrace: replay diverged, task {} not runnable", task_id),
+            },
+            Some(None) => None,
+            None => match self.trace.shuffle_pick(self.task_run_queue.len()) {
+                Some(idx) => self.task_run_queue.take_task_at(idx),
+                None => self.task_run_queue.take_one_task(),
+            },
+        }?;
+        self.trace.poll(task.get_id());
+        Some(task)
     }
     
     pub(crate) fn sched_sleep(&self, dur_usec: u64) -> SleepAsyncNode {
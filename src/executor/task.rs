@@ -1,17 +1,54 @@
 use std::cell::{Cell, RefCell};
 use std::fmt::{Debug, Formatter};
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
-pub(crate) struct SchedTask {
+/// Type-erased access to the future stored inline in a `SchedTask`.
+pub(crate) trait SchedTaskFut {
+    /// `None` once the future is gone (completed or cancelled).
+    fn poll_fut(&mut self, cx: &mut Context) -> Option<Poll<()>>;
+    fn drop_fut(&mut self);
+}
+
+/// Keeps the future in the task allocation and lets it be dropped in place.
+pub(crate) struct TaskFutSlot<F> {
+    fut: Option<F>,
+}
+
+impl<F: Future<Output = ()>> SchedTaskFut for TaskFutSlot<F> {
+    fn poll_fut(&mut self, cx: &mut Context) -> Option<Poll<()>> {
+        let f = self.fut.as_mut()?;
+        // the slot lives inside the task's Rc allocation and is never moved
+        Some(unsafe { Pin::new_unchecked(f) }.poll(cx))
+    }
+
+    fn drop_fut(&mut self) {
+        self.fut = None;
+    }
+}
+
+/// Task header and future in one allocation; handled as `Rc<SchedTask>`.
+pub(crate) struct SchedTask<F: ?Sized = dyn SchedTaskFut> {
     id: Cell<usize>,
     name: String,
-    exe_block: Option<RefCell<Pin<Box<dyn Future<Output = ()>>>>>,
+    cancelled: Cell<bool>,
+    finished: Cell<bool>,
+    exe_block: RefCell<F>,
 }
 
 impl SchedTask {
-    pub(crate) fn new(name: String, exe: Pin<Box<dyn Future<Output = ()>>>) -> SchedTask {
-        SchedTask { id: Cell::new(0), name, exe_block: Some(RefCell::new(exe)) }
+    pub(crate) fn new<F>(name: String, exe: F) -> Rc<SchedTask>
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        Rc::new(SchedTask {
+            id: Cell::new(0),
+            name,
+            cancelled: Cell::new(false),
+            finished: Cell::new(false),
+            exe_block: RefCell::new(TaskFutSlot { fut: Some(exe) }),
+        })
     }
     
     pub(crate) fn set_id(&self, id: usize) {
@@ -21,12 +58,31 @@ impl SchedTask {
     pub(crate) fn get_id(&self) -> usize {
         self.id.get()
     }
-    
-    pub(crate) fn get_task_fut(&self) -> Option<&RefCell<Pin<Box<dyn Future<Output = ()>>>>> {
-        match &self.exe_block {
-            None => None,
-            Some(f) => Some(f),
-        }
+
+    /// Poll the task future once. `None` means the future is already gone
+    /// (completed or cancelled), e.g. for a stale run-queue entry.
+    pub(crate) fn poll_fut(&self, cx: &mut Context) -> Option<Poll<()>> {
+        self.exe_block.borrow_mut().poll_fut(cx)
+    }
+
+    /// Drop the future, running the destructors of everything it holds.
+    pub(crate) fn drop_fut(&self) {
+        // mark first, the destructors may ask the scheduler about this task
+        self.finished.set(true);
+        self.exe_block.borrow_mut().drop_fut();
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished.get()
+    }
+
+    /// Mark the task as cancelled; the scheduler drops it instead of polling.
+    pub(crate) fn cancel(&self) {
+        self.cancelled.set(true);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
     }
 }
 
@@ -1,41 +1,87 @@
 use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
 use std::rc::Rc;
 use crate::executor::task::SchedTask;
 
+// task id = generation << INDEX_BITS | (slot index + 1), 0 is never a valid id
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+enum TaskSlot {
+    Occupied(usize, Rc<SchedTask>),
+    // generation for the next occupant, next free slot
+    Vacant(usize, Option<usize>),
+}
+
+/// Slab of live tasks. Ids carry the slot generation, so a stale id of a
+/// finished task never resolves to the task that reused its slot.
 pub(crate) struct SchedTaskMng {
-    id_generator: Cell<usize>,
-    task_map: RefCell<HashMap<usize, Rc<SchedTask>>>,
+    slots: RefCell<Vec<TaskSlot>>,
+    free_head: Cell<Option<usize>>,
+    live: Cell<usize>,
 }
 
 impl SchedTaskMng {
     pub(crate) fn new() -> Self {
         SchedTaskMng {
-            id_generator: Cell::new(1),
-            task_map: RefCell::new(HashMap::new()),
+            slots: RefCell::new(Vec::new()),
+            free_head: Cell::new(None),
+            live: Cell::new(0),
         }
     }
 
+    fn make_id(idx: usize, generation: usize) -> usize {
+        (generation << INDEX_BITS) | (idx + 1)
+    }
+
+    fn split_id(task_id: usize) -> Option<(usize, usize)> {
+        let idx = (task_id & INDEX_MASK).checked_sub(1)?;
+        Some((idx, task_id >> INDEX_BITS))
+    }
+
     pub(crate) fn add_task(&self, t: Rc<SchedTask>) -> Result<(), ()> {
-        let mut map = self.task_map.borrow_mut();
-        loop {
-            let task_id = self.id_generator.get();
-            self.id_generator.set(task_id + 1);
-            t.set_id(task_id);
-
-            if map.contains_key(&task_id) {
-                continue;
-            } else {
-                map.insert(task_id, t);
-                return Ok(());
+        let mut slots = self.slots.borrow_mut();
+        let (idx, generation) = match self.free_head.get() {
+            Some(idx) => {
+                let TaskSlot::Vacant(generation, next) = slots[idx] else {
+                    panic!("SchedTaskMng: free list points at a live slot {}", idx);
+                };
+                self.free_head.set(next);
+                (idx, generation)
             }
-        }
+            None => {
+                if slots.len() >= INDEX_MASK {
+                    return Err(());
+                }
+                slots.push(TaskSlot::Vacant(0, None));
+                (slots.len() - 1, 0)
+            }
+        };
+
+        t.set_id(Self::make_id(idx, generation));
+        slots[idx] = TaskSlot::Occupied(generation, t);
+        self.live.set(self.live.get() + 1);
+        Ok(())
     }
 
     pub(crate) fn remove_task(&self, task_id: usize) -> Result<Rc<SchedTask>, ()> {
-        self.task_map
-            .borrow_mut()
-            .remove(&task_id)
-            .ok_or(())
+        let (idx, generation) = Self::split_id(task_id).ok_or(())?;
+        let mut slots = self.slots.borrow_mut();
+        match slots.get(idx) {
+            Some(TaskSlot::Occupied(g, _)) if *g == generation => {}
+            _ => return Err(()),
+        }
+
+        let next_gen = (generation + 1) & (usize::MAX >> INDEX_BITS);
+        let vacant = TaskSlot::Vacant(next_gen, self.free_head.get());
+        let TaskSlot::Occupied(_, t) = std::mem::replace(&mut slots[idx], vacant) else {
+            unreachable!();
+        };
+        self.free_head.set(Some(idx));
+        self.live.set(self.live.get() - 1);
+        Ok(t)
     }
-}
\ No newline at end of file
+
+    pub(crate) fn len(&self) -> usize {
+        self.live.get()
+    }
+}
@@ -8,7 +8,7 @@ use crate::executor::Executor;
 use crate::executor::runtime::Runtime;
 use crate::executor::sched_msg::{AsyncTaskFnBox, SchedMsg};
 use crate::network::ethernet::{EthKey, MacAddr};
-use crate::network::ipv4::IPv4Addr;
+use crate::network::ipv4::IPv4Cidr;
 use crate::network::module_traits::AsyncNetIOModule;
 use crate::network::packet::NetworkPacket;
 use crate::network::stack::NetworkStack;
@@ -38,14 +38,17 @@ fn main() {
     let test_func: AsyncTaskFnBox = Box::new(move |name: String| {
         Box::pin(async move {
             let start = Instant::now();
-            let mut pkt1 = NetworkPacket::new();
+            if let Err(e) = cloned_stk.start_timers() {
+                println!("starting stack timers failed: {}", e)
+            }
+            let frame = [0u8; 60];
             let mut pkt2 = NetworkPacket::new();
-            (pkt1, _) = cloned_stk.clone().rx(pkt1).await;
+            _ = cloned_stk.clone().rx_frame(&frame).await;
             (pkt2, _) = cloned_stk.clone().tx(pkt2).await;
             for i in 1..3 {
                 // Self::sleep(Duration::new(1, 0)).await;
                 println!("======== Example::async task {} Hello, {}, time: {}", i, name, start.elapsed().as_millis());
-                (pkt1, _) = cloned_stk.clone().rx(pkt1).await;
+                _ = cloned_stk.clone().rx_frame(&frame).await;
                 (pkt2, _) = cloned_stk.clone().tx(pkt2).await;
                 Runtime::sleep(Duration::new(1, 0)).await;
             }
@@ -53,22 +56,28 @@ fn main() {
             let mac = MacAddr::from_str("00-10-00-00-aa-bb").unwrap();
             let mac_res = cloned_stk.add_mac(&mac);
             if let Ok(_) = mac_res {
-                let ip = IPv4Addr::from_str("1.1.1.1").unwrap();
+                let ip = IPv4Cidr::from_str("1.1.1.1/24").unwrap();
                 let ip_res = cloned_stk.add_ipv4(ip, Some(&mac));
-                if let Ok(_) = ip_res {
-                    println!("adding IPv4 ok")
-                } else {
-                    println!("adding IPv4 failed")
+                match ip_res {
+                    Err(e) => println!("adding IPv4 failed: {}", e),
+                    Ok(events) => {
+                        println!("adding IPv4 ok");
+                        if let Some(ev) = events.recv().await {
+                            println!("IPv4 address event: {:?}", ev)
+                        }
+                    }
                 }
-            } else {
-                println!("adding MAC failed")
+            } else if let Err(e) = mac_res {
+                println!("adding MAC failed: {}", e)
             }
 
             println!("======@ example end at {}", start.elapsed().as_millis());
         })
     });
     let msg = SchedMsg::new(String::from("new_task"), Some(test_func));
-    _ = e.try_send(thread_id, msg);
+    if let Err(err) = e.send_blocking(thread_id, msg) {
+        println!("failed to send task: {:?}", err);
+    }
 
     while running.load(Ordering::SeqCst)  {
         // wait
@@ -4,22 +4,35 @@ use std::sync::Arc;
 use crate::network::module_traits::AsyncNetIOModule;
 use crate::network::packet::NetworkPacket;
 
+pub mod error;
 pub mod module_traits;
 pub mod packet;
+pub mod packet_meta;
+pub mod packet_pool;
 mod socket;
-mod protocol;
+pub mod protocol;
+pub mod protocol_graph;
 mod driver;
 pub mod stack;
 mod arp;
 pub(crate) mod ipv4;
-mod ipv6;
+pub(crate) mod fib_trie;
+pub(crate) mod ipv4_fib;
+mod ipv4_frag;
+pub(crate) mod ipv6;
+pub(crate) mod ipv6_fib;
 mod icmpv4;
 mod icmpv6;
+mod ndp;
+mod router_adv;
 pub(crate) mod ethernet;
 mod user_app;
 mod udp;
 mod tcp;
 mod subres;
+pub(crate) mod checksum;
+mod timer;
+pub mod tx_queue;
 //
 // pub struct NetworkStack {}
 //
@@ -1,55 +1,575 @@
-use crate::network::ethernet::EthernetProtocol;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use flume::Sender;
+use std::sync::atomic::Ordering::Relaxed;
+use crate::executor::runtime::Runtime;
+use crate::network::error::{ArpError, NetError};
+use crate::network::ethernet::{EthEntry, MacAddr, ETH_P_IPV4};
+use crate::network::ipv4::{IPv4Addr, IPv4AddrEvent, IPv4AddrEvents, IPv4AddrState, IPv4Entry, IPv4Protocol};
 use crate::network::module_traits::AsyncProtocolModule;
 use crate::network::packet::NetworkPacket;
 use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData};
+use crate::network::timer::jitter;
+use crate::network::tx_queue::TxOutbox;
+
+/// ARP for Ethernet/IPv4, the only kind we speak.
+pub const ARP_HDR_LEN: usize = 28;
+const ARP_HTYPE_ETHERNET: u16 = 1;
+
+pub const ARP_OP_REQUEST: u16 = 1;
+pub const ARP_OP_REPLY: u16 = 2;
+
+/// Packets held per unresolved address.
+const ARP_QUEUE_LEN: usize = 3;
+/// Requests sent before giving up on an address.
+const ARP_MAX_PROBES: u32 = 3;
+const ARP_RETRANS_USEC: u64 = 1_000_000;
+const ARP_REACHABLE_USEC: u64 = 30_000_000;
+const ARP_GC_USEC: u64 = 60_000_000;
+
+// RFC 5227 address conflict detection timing
+const PROBE_WAIT_USEC: u64 = 1_000_000;
+const PROBE_NUM: u8 = 3;
+const PROBE_MIN_USEC: u64 = 1_000_000;
+const PROBE_MAX_USEC: u64 = 2_000_000;
+const ANNOUNCE_WAIT_USEC: u64 = 2_000_000;
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL_USEC: u64 = 2_000_000;
+const DEFEND_INTERVAL_USEC: u64 = 10_000_000;
+
+static ARP_INSTANCE_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // packets waiting for resolution, keyed by (ArpProtocol id, address)
+    static ARP_PENDING: RefCell<HashMap<(u64, ArpKey), VecDeque<NetworkPacket>>> = RefCell::new(HashMap::new());
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) struct ArpKey {
     pub ip_addr: [u8; 4],   // IPv4 address
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpState {
+    /// request sent, no answer yet
+    Incomplete,
+    /// confirmed by a reply to us within `ARP_REACHABLE_USEC`
+    Reachable,
+    /// learned or confirmed a while ago, still used
+    Stale,
+}
+
 #[derive(Debug)]
 pub(crate) struct ArpEntry {
     pub mac_addr: [u8; 6],  // MAC address
     pub ttl: u64,           // time-to-live or expiration
+    state: ArpState,
+    // last state change, in usec
+    updated_usec: u64,
+    probes: u32,
+    // where requests for it go out, and from which of our addresses
+    iface: Arc<EthEntry>,
+    src_ip: IPv4Addr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcdPhase {
+    /// asking whether anybody has the address
+    Probing,
+    /// telling everybody we have it now
+    Announcing,
+    Bound,
+}
+
+/// Conflict detection of one of our addresses.
+struct AcdEntry {
+    addr: Arc<IPv4Entry>,
+    phase: AcdPhase,
+    // probes or announcements sent in this phase
+    sent: u8,
+    next_usec: u64,
+    last_defend_usec: Option<u64>,
+    events: Sender<IPv4AddrEvent>,
+}
+
+/// Decoded ARP packet.
+#[derive(Debug, Clone)]
+pub struct ArpPacket {
+    pub oper: u16,
+    pub sha: MacAddr,
+    pub spa: IPv4Addr,
+    pub tha: MacAddr,
+    pub tpa: IPv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(b: &[u8]) -> Result<ArpPacket, ArpError> {
+        if b.len() < ARP_HDR_LEN {
+            return Err(ArpError::Truncated { offset: 0, need: ARP_HDR_LEN });
+        }
+        let rd16 = |i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+        if rd16(0) != ARP_HTYPE_ETHERNET {
+            return Err(ArpError::UnsupportedFormat { offset: 0 });
+        }
+        if rd16(2) != ETH_P_IPV4 {
+            return Err(ArpError::UnsupportedFormat { offset: 2 });
+        }
+        if b[4] != 6 || b[5] != 4 {
+            return Err(ArpError::UnsupportedFormat { offset: 4 });
+        }
+        let oper = rd16(6);
+        if oper != ARP_OP_REQUEST && oper != ARP_OP_REPLY {
+            return Err(ArpError::Malformed { offset: 6 });
+        }
+
+        let mac = |i: usize| MacAddr::new(b[i..i + 6].try_into().unwrap());
+        let ip = |i: usize| IPv4Addr::new(b[i..i + 4].try_into().unwrap());
+        Ok(ArpPacket { oper, sha: mac(8), spa: ip(14), tha: mac(18), tpa: ip(24) })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARP_HDR_LEN] {
+        let mut b = [0u8; ARP_HDR_LEN];
+        b[0..2].copy_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+        b[2..4].copy_from_slice(&ETH_P_IPV4.to_be_bytes());
+        b[4] = 6;
+        b[5] = 4;
+        b[6..8].copy_from_slice(&self.oper.to_be_bytes());
+        b[8..14].copy_from_slice(&self.sha.mac);
+        b[14..18].copy_from_slice(&self.spa.val);
+        b[18..24].copy_from_slice(&self.tha.mac);
+        b[24..28].copy_from_slice(&self.tpa.val);
+        b
+    }
+}
+
+/// What happened to a packet handed to `ArpProtocol::resolve`.
+pub(crate) enum ArpResolve {
+    /// `l2_dst` is filled in, send it
+    Ready,
+    /// a copy is held until the address resolves, the packet handed back
+    /// is the original
+    Queued,
 }
 
 pub(crate) struct ArpProtocol {
+    id: u64,
     common: NetworkProtocolMng<ArpKey, ArpEntry>,
     // ARP-specific fields
+    // stale entries are removed after this long, in usec
     cache_timeout: u64,
+    ipv4: Arc<IPv4Protocol>,
+    acd: Mutex<HashMap<ArpKey, AcdEntry>>,
+    acd_enabled: AtomicBool,
+    outbox: TxOutbox,
 }
 
 impl ArpProtocol {
-    pub(crate) fn new() -> ArpProtocol {
+    pub(crate) fn new(ipv4: Arc<IPv4Protocol>, outbox: TxOutbox) -> ArpProtocol {
         ArpProtocol {
+            id: ARP_INSTANCE_ID.fetch_add(1, Relaxed),
             common: NetworkProtocolMng::new(ProtocolHeaderType::ARP),
-            cache_timeout: 0,
+            cache_timeout: ARP_GC_USEC,
+            ipv4,
+            acd: Mutex::new(HashMap::new()),
+            acd_enabled: AtomicBool::new(true),
+            outbox,
+        }
+    }
+
+    /// Probe before using new addresses. When off, addresses are usable
+    /// right away and only announced.
+    pub(crate) fn set_conflict_detection(&self, on: bool) {
+        self.acd_enabled.store(on, Relaxed);
+    }
+
+    /// Start conflict detection of a tentative address, the address becomes
+    /// usable when probing is done.
+    pub(crate) fn start_acd(&self, addr: Arc<IPv4Entry>, now: u64) -> IPv4AddrEvents {
+        let (tx, rx) = flume::unbounded();
+        let mut ent = AcdEntry {
+            addr: addr.clone(),
+            phase: AcdPhase::Probing,
+            sent: 0,
+            next_usec: now + jitter(PROBE_WAIT_USEC),
+            last_defend_usec: None,
+            events: tx,
+        };
+        if !self.acd_enabled.load(Relaxed) {
+            addr.set_state(IPv4AddrState::Preferred);
+            let _ = ent.events.send(IPv4AddrEvent::Bound(addr.get_addr().clone()));
+            ent.phase = AcdPhase::Announcing;
+            ent.next_usec = now;
+        }
+        let key = ArpKey { ip_addr: addr.get_addr().val };
+        self.acd.lock().unwrap().insert(key, ent);
+        IPv4AddrEvents::new(rx)
+    }
+
+    /// Forget a removed address; its event receiver sees the end.
+    pub(crate) fn stop_acd(&self, addr: &IPv4Addr) {
+        self.acd.lock().unwrap().remove(&ArpKey { ip_addr: addr.val });
+    }
+
+    /// Announce again the addresses on `ifaces`, e.g. after their MAC
+    /// changed.
+    pub(crate) fn announce_on(&self, ifaces: &[Arc<EthEntry>], now: u64) {
+        let mut acd = self.acd.lock().unwrap();
+        for ent in acd.values_mut() {
+            let Some(iface) = ent.addr.eth_entry() else { continue };
+            if ent.phase != AcdPhase::Probing && ifaces.iter().any(|i| Arc::ptr_eq(i, &iface)) {
+                ent.phase = AcdPhase::Announcing;
+                ent.sent = 0;
+                ent.next_usec = now;
+            }
         }
     }
+
+    /// Look for another host using one of our addresses (RFC 5227 2.1.1 and
+    /// 2.4). Returns whether the packet was such a claim, those are not
+    /// learned from.
+    fn check_conflict(&self, arp: &ArpPacket, now: u64) -> bool {
+        let mut acd = self.acd.lock().unwrap();
+        let key = if !arp.spa.is_unspecified() {
+            ArpKey { ip_addr: arp.spa.val }
+        } else {
+            // somebody else probing for an address we probe for too
+            let key = ArpKey { ip_addr: arp.tpa.val };
+            if acd.get(&key).is_none_or(|e| e.phase != AcdPhase::Probing) {
+                return false;
+            }
+            key
+        };
+        let Some(ent) = acd.get_mut(&key) else {
+            return false;
+        };
+        let Some(iface) = ent.addr.eth_entry() else {
+            return false;
+        };
+        if iface.get_mac() == arp.sha {
+            return false;
+        }
+
+        let addr = ent.addr.get_addr().clone();
+        let can_defend = ent.phase != AcdPhase::Probing
+            && ent.last_defend_usec.is_none_or(|t| now.saturating_sub(t) >= DEFEND_INTERVAL_USEC);
+        println!("arp: {} is used by {} too", addr, arp.sha);
+        let _ = ent.events.send(IPv4AddrEvent::Conflict { addr: addr.clone(), mac: arp.sha.clone(), defended: can_defend });
+        if can_defend {
+            ent.last_defend_usec = Some(now);
+            self.send_request(&iface, &addr, &addr, &MacAddr::BROADCAST);
+        } else {
+            if let Some(ent) = acd.remove(&key) {
+                ent.addr.set_state(IPv4AddrState::Conflict);
+            }
+            self.ipv4.remove_ipv4(&addr);
+        }
+        true
+    }
+
+    /// Send due probes and announcements.
+    fn acd_tick(&self, now: u64) {
+        let mut acd = self.acd.lock().unwrap();
+        for ent in acd.values_mut() {
+            if ent.phase == AcdPhase::Bound || now < ent.next_usec {
+                continue;
+            }
+            let Some(iface) = ent.addr.eth_entry() else { continue };
+            let addr = ent.addr.get_addr().clone();
+            if ent.phase == AcdPhase::Probing {
+                if ent.sent < PROBE_NUM {
+                    self.send_request(&iface, &IPv4Addr::UNSPECIFIED, &addr, &MacAddr::BROADCAST);
+                    ent.sent += 1;
+                    ent.next_usec = now + if ent.sent < PROBE_NUM {
+                        PROBE_MIN_USEC + jitter(PROBE_MAX_USEC - PROBE_MIN_USEC)
+                    } else {
+                        ANNOUNCE_WAIT_USEC
+                    };
+                    continue;
+                }
+                println!("arp: {} is ours", addr);
+                ent.addr.set_state(IPv4AddrState::Preferred);
+                let _ = ent.events.send(IPv4AddrEvent::Bound(addr.clone()));
+                ent.phase = AcdPhase::Announcing;
+                ent.sent = 0;
+            }
+            // gratuitous ARP: a request for our own address
+            self.send_request(&iface, &addr, &addr, &MacAddr::BROADCAST);
+            ent.sent += 1;
+            if ent.sent >= ANNOUNCE_NUM {
+                ent.phase = AcdPhase::Bound;
+            } else {
+                ent.next_usec = now + ANNOUNCE_INTERVAL_USEC;
+            }
+        }
+    }
+
+    /// Neighbor cache contents: address, MAC (zero while incomplete), state.
+    pub(crate) fn entries(&self) -> Vec<(IPv4Addr, MacAddr, ArpState)> {
+        self.common.res_read_borrow()
+            .iter()
+            .map(|(k, e)| (IPv4Addr::new(k.ip_addr), MacAddr::new(e.mac_addr), e.state))
+            .collect()
+    }
+
+    fn receive(&self, p: &NetworkPacket) -> Result<ProtocolMetaData, ArpError> {
+        let bytes = p.peek_front(ARP_HDR_LEN)
+            .map_err(|_| ArpError::Truncated { offset: 0, need: ARP_HDR_LEN })?;
+        let arp = ArpPacket::parse(&bytes)?;
+        p.pull_header(ProtocolHeaderType::ARP, ARP_HDR_LEN)
+            .map_err(|_| ArpError::Truncated { offset: 0, need: ARP_HDR_LEN })?;
+        {
+            let mut m = p.meta_mut();
+            m.l3_src = Some(IpAddr::V4(arp.spa.clone().into()));
+            m.l3_dst = Some(IpAddr::V4(arp.tpa.clone().into()));
+        }
+
+        let now = Runtime::get_time_usec();
+        if self.check_conflict(&arp, now) {
+            return Ok(ProtocolMetaData::new());
+        }
+        let local = self.ipv4.lookup_local(&arp.tpa);
+        // RFC 826: update the sender if we know it, add it if the packet is
+        // for us. A zero sender address is a probe, nothing to learn.
+        if !arp.spa.is_unspecified() {
+            let confirmed = arp.oper == ARP_OP_REPLY && local.is_some();
+            let merged = self.update(&arp.spa, &arp.sha, confirmed, now);
+            if !merged && let Some(iface) = local.as_ref().and_then(|l| l.eth_entry()) {
+                self.insert(&arp.spa, &arp.sha, iface, &arp.tpa, now);
+            }
+        }
+
+        if arp.oper == ARP_OP_REQUEST && let Some(local) = local {
+            self.send_reply(p, &arp, &local)?;
+        }
+        Ok(ProtocolMetaData::new())
+    }
+
+    /// Refresh an existing entry, returns whether there was one.
+    fn update(&self, ip: &IPv4Addr, mac: &MacAddr, confirmed: bool, now: u64) -> bool {
+        let key = ArpKey { ip_addr: ip.val };
+        let released = {
+            let mut w = self.common.res_write_borrow();
+            let Some(ent) = w.get_mut(&key) else {
+                return false;
+            };
+            let was_incomplete = ent.state == ArpState::Incomplete;
+            if confirmed {
+                ent.state = ArpState::Reachable;
+                ent.updated_usec = now;
+            } else if was_incomplete || ent.mac_addr != mac.mac {
+                ent.state = ArpState::Stale;
+                ent.updated_usec = now;
+            }
+            ent.mac_addr = mac.mac;
+            ent.probes = 0;
+            was_incomplete
+        };
+        if released {
+            self.release(&key, mac);
+        }
+        true
+    }
+
+    fn insert(&self, ip: &IPv4Addr, mac: &MacAddr, iface: Arc<EthEntry>, src_ip: &IPv4Addr, now: u64) {
+        let ent = ArpEntry {
+            mac_addr: mac.mac,
+            ttl: self.cache_timeout,
+            state: ArpState::Stale,
+            updated_usec: now,
+            probes: 0,
+            iface,
+            src_ip: src_ip.clone(),
+        };
+        self.common.res_write_borrow().insert(ArpKey { ip_addr: ip.val }, ent);
+    }
+
+    /// Send the packets that waited for `key`.
+    fn release(&self, key: &ArpKey, mac: &MacAddr) {
+        let queued = ARP_PENDING.with(|q| q.borrow_mut().remove(&(self.id, *key)));
+        for p in queued.into_iter().flatten() {
+            p.meta_mut().l2_dst = Some(mac.mac);
+            self.outbox.push(p);
+        }
+    }
+
+    /// Drop the packets that waited for `key`, returns how many.
+    fn discard(&self, key: &ArpKey) -> usize {
+        ARP_PENDING.with(|q| q.borrow_mut().remove(&(self.id, *key)).map_or(0, |q| q.len()))
+    }
+
+    /// Fill in the destination MAC of an IPv4 packet going to `next_hop`
+    /// through `iface`, or hold a copy of it and send a request from
+    /// `src_ip`.
+    pub(crate) fn resolve(&self, p: NetworkPacket, next_hop: &IPv4Addr, iface: &Arc<EthEntry>, src_ip: &IPv4Addr) -> (NetworkPacket, Result<ArpResolve, ArpError>) {
+        let key = ArpKey { ip_addr: next_hop.val };
+        let now = Runtime::get_time_usec();
+        let send_request = {
+            let mut w = self.common.res_write_borrow();
+            match w.get(&key) {
+                Some(ent) if ent.state != ArpState::Incomplete => {
+                    p.meta_mut().l2_dst = Some(ent.mac_addr);
+                    return (p, Ok(ArpResolve::Ready));
+                }
+                Some(_) => false,
+                None => {
+                    w.insert(key, ArpEntry {
+                        mac_addr: [0; 6],
+                        ttl: self.cache_timeout,
+                        state: ArpState::Incomplete,
+                        updated_usec: now,
+                        probes: 1,
+                        iface: iface.clone(),
+                        src_ip: src_ip.clone(),
+                    });
+                    true
+                }
+            }
+        };
+
+        let queued = ARP_PENDING.with(|q| {
+            let mut q = q.borrow_mut();
+            let queue = q.entry((self.id, key)).or_default();
+            if queue.len() >= ARP_QUEUE_LEN {
+                return false;
+            }
+            queue.push_back(p.clone_shared());
+            true
+        });
+        if send_request {
+            self.send_request(iface, src_ip, next_hop, &MacAddr::BROADCAST);
+        }
+        if !queued {
+            return (p, Err(ArpError::QueueFull(next_hop.clone())));
+        }
+        (p, Ok(ArpResolve::Queued))
+    }
+
+    fn send_request(&self, iface: &EthEntry, src_ip: &IPv4Addr, target: &IPv4Addr, l2_dst: &MacAddr) {
+        let arp = ArpPacket {
+            oper: ARP_OP_REQUEST,
+            sha: iface.get_mac(),
+            spa: src_ip.clone(),
+            tha: MacAddr::new([0; 6]),
+            tpa: target.clone(),
+        };
+        self.send(&arp, l2_dst, iface.get_vlan(), iface.get_svlan());
+    }
+
+    fn send_reply(&self, req_pkt: &NetworkPacket, req: &ArpPacket, local: &IPv4Entry) -> Result<(), ArpError> {
+        let iface = local.eth_entry().ok_or(ArpError::NoInterface)?;
+        let arp = ArpPacket {
+            oper: ARP_OP_REPLY,
+            sha: iface.get_mac(),
+            spa: req.tpa.clone(),
+            tha: req.sha.clone(),
+            tpa: req.spa.clone(),
+        };
+        // answer on the VLAN the request came in on
+        let (vlan, svlan) = {
+            let m = req_pkt.meta();
+            (m.vlan, m.svlan)
+        };
+        self.send(&arp, &req.sha, vlan, svlan);
+        Ok(())
+    }
+
+    fn send(&self, arp: &ArpPacket, l2_dst: &MacAddr, vlan: Option<u16>, svlan: Option<u16>) {
+        let p = NetworkPacket::new();
+        if p.push_header(ProtocolHeaderType::ARP, &arp.to_bytes()).is_err() {
+            return;
+        }
+        {
+            let mut m = p.meta_mut();
+            m.l2_src = Some(arp.sha.mac);
+            m.l2_dst = Some(l2_dst.mac);
+            m.l3_proto = ProtocolHeaderType::ARP;
+            m.vlan = vlan;
+            m.svlan = svlan;
+        }
+        self.outbox.push(p);
+    }
+
+    /// Drop the cache entries of an interface that goes away, and what
+    /// waits on them. Returns how many packets were dropped.
+    pub(crate) fn forget_iface(&self, iface: &Arc<EthEntry>) -> usize {
+        let mut gone = Vec::new();
+        self.common.res_write_borrow().retain(|key, ent| {
+            let keep = !Arc::ptr_eq(&ent.iface, iface);
+            if !keep {
+                gone.push(*key);
+            }
+            keep
+        });
+        gone.iter().map(|key| self.discard(key)).sum()
+    }
+
+    /// Run conflict detection, then age the cache: retransmit or give up on
+    /// incomplete entries, let reachable ones go stale and remove old stale
+    /// ones. Returns how many queued packets were dropped.
+    pub(crate) fn tick(&self, now: u64) -> usize {
+        self.acd_tick(now);
+        let mut retransmit = Vec::new();
+        let mut failed = Vec::new();
+        {
+            let mut w = self.common.res_write_borrow();
+            w.retain(|key, ent| {
+                let age = now.saturating_sub(ent.updated_usec);
+                match ent.state {
+                    ArpState::Incomplete if age >= ARP_RETRANS_USEC => {
+                        if ent.probes >= ARP_MAX_PROBES {
+                            failed.push(*key);
+                            return false;
+                        }
+                        ent.probes += 1;
+                        ent.updated_usec = now;
+                        retransmit.push((*key, ent.iface.clone(), ent.src_ip.clone()));
+                    }
+                    ArpState::Reachable if age >= ARP_REACHABLE_USEC => {
+                        ent.state = ArpState::Stale;
+                        ent.updated_usec = now;
+                    }
+                    ArpState::Stale if age >= ent.ttl => return false,
+                    _ => {}
+                }
+                true
+            });
+        }
+
+        for (key, iface, src_ip) in retransmit {
+            self.send_request(&iface, &src_ip, &IPv4Addr::new(key.ip_addr), &MacAddr::BROADCAST);
+        }
+        failed.iter().map(|key| {
+            println!("arp: {} unresolved", IPv4Addr::new(key.ip_addr));
+            self.discard(key)
+        }).sum()
+    }
 }
 
 impl AsyncProtocolModule<NetworkPacket> for ArpProtocol {
-    type EncodeResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
-    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
 
     async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
-        println!("----- encode arp -----");
-        (p, Ok(ProtocolMetaData::new()))
+        self.sync_encode(p)
     }
 
     async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
-        println!("----- decode arp -----");
-        (p, Ok(ProtocolMetaData::new()))
+        self.sync_decode(p)
     }
 
     fn sync_encode(&self, p: NetworkPacket) -> Self::EncodeResult {
         println!("----- encode arp -----");
-        (p, Ok(ProtocolMetaData::new()))
+        // the ARP header is built together with the packet, see `send`
+        (p, Ok(()))
     }
 
     fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode arp -----");
-        (p, Ok(ProtocolMetaData::new()))
+        let res = self.receive(&p).map_err(NetError::from);
+        (p, res)
     }
 }
+
@@ -0,0 +1,129 @@
+// Internet checksum (RFC 1071) helpers, shared by IPv4, ICMP, UDP and TCP.
+
+use std::net::Ipv6Addr;
+
+/// Add `data` to a running 32-bit one's complement sum. Odd lengths are
+/// padded with a zero byte, so only the last chunk may be odd.
+pub fn sum(initial: u32, data: &[u8]) -> u32 {
+    let mut acc = initial as u64;
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        acc += u16::from_be_bytes([c[0], c[1]]) as u64;
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u64) << 8;
+    }
+    fold64(acc) as u32
+}
+
+fn fold64(mut acc: u64) -> u16 {
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+/// Fold a running sum into the final checksum field value.
+pub fn finish(sum: u32) -> u16 {
+    !fold64(sum as u64)
+}
+
+/// Checksum of `data`, e.g. a header with its checksum field zeroed.
+pub fn checksum(data: &[u8]) -> u16 {
+    finish(sum(0, data))
+}
+
+/// Patch `csum` for one 16-bit word of the data changing from `old` to
+/// `new`, without summing everything again (RFC 1624).
+pub fn update(csum: u16, old: u16, new: u16) -> u16 {
+    let acc = (!csum) as u64 + (!old) as u64 + new as u64;
+    !fold64(acc)
+}
+
+/// Running sum of the IPv6 pseudo-header (RFC 8200 8.1) of an upper layer
+/// message of `len` bytes.
+pub fn pseudo_header_v6(src: &Ipv6Addr, dst: &Ipv6Addr, len: u32, next_header: u8) -> u32 {
+    let mut acc = sum(0, &src.octets());
+    acc = sum(acc, &dst.octets());
+    acc = sum(acc, &len.to_be_bytes());
+    sum(acc, &[0, 0, 0, next_header])
+}
+
+/// Whether `data`, checksum field included, sums up correctly.
+pub fn verify(data: &[u8]) -> bool {
+    checksum(data) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 1071 4.1: sums to 0xddf2.
+    const RFC1071: [u8; 8] = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+
+    #[test]
+    fn rfc1071_vector() {
+        assert_eq!(sum(0, &RFC1071), 0xddf2);
+        assert_eq!(checksum(&RFC1071), !0xddf2);
+    }
+
+    #[test]
+    fn sum_in_pieces() {
+        // even-length chunks in sequence give the sum of the whole
+        let acc = sum(0, &RFC1071[..2]);
+        let acc = sum(acc, &RFC1071[2..6]);
+        assert_eq!(sum(acc, &RFC1071[6..]), sum(0, &RFC1071));
+    }
+
+    #[test]
+    fn odd_length_pads_with_zero() {
+        assert_eq!(checksum(&[0x12, 0x34, 0x56]), checksum(&[0x12, 0x34, 0x56, 0x00]));
+        assert_eq!(sum(0, &[0xab]), 0xab00);
+    }
+
+    #[test]
+    fn carries_fold() {
+        assert_eq!(sum(0, &[0xff, 0xff, 0x00, 0x01]), 0x0001);
+        assert_eq!(checksum(&[0xff, 0xff, 0xff, 0xff]), 0x0000);
+        assert_eq!(checksum(&[]), 0xffff);
+    }
+
+    #[test]
+    fn verify_with_checksum_field() {
+        let mut b = RFC1071.to_vec();
+        b.extend_from_slice(&checksum(&RFC1071).to_be_bytes());
+        assert!(verify(&b));
+        b[3] ^= 1;
+        assert!(!verify(&b));
+    }
+
+    #[test]
+    fn update_matches_recompute() {
+        // IPv4 header with its checksum at 10..12, TTL decremented
+        let mut b = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        let csum = checksum(&b);
+        assert_eq!(csum, 0xb861);
+        let old = u16::from_be_bytes([b[8], b[9]]);
+        b[8] -= 1;
+        let new = u16::from_be_bytes([b[8], b[9]]);
+        assert_eq!(update(csum, old, new), checksum(&b));
+        // a word that doesn't change leaves it alone
+        assert_eq!(update(csum, old, old), csum);
+    }
+
+    #[test]
+    fn pseudo_header_v6_sum() {
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let dst: Ipv6Addr = "ff02::1".parse().unwrap();
+        let mut b = Vec::new();
+        b.extend_from_slice(&src.octets());
+        b.extend_from_slice(&dst.octets());
+        b.extend_from_slice(&8u32.to_be_bytes());
+        b.extend_from_slice(&[0, 0, 0, 58]);
+        assert_eq!(pseudo_header_v6(&src, &dst, 8, 58), sum(0, &b));
+        assert_eq!(pseudo_header_v6(&src, &dst, 8, 58), 0xfe80 + 0x0001 + 0xff02 + 0x0001 + 8 + 58 - 0xffff);
+    }
+}
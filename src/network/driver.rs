@@ -1,24 +1,49 @@
 use std::pin::Pin;
 use std::sync::Arc;
+use crate::executor::runtime::Runtime;
+use crate::network::error::{DriverError, NetError};
 use crate::network::module_traits::AsyncNetIOModule;
 use crate::network::packet::NetworkPacket;
+use crate::network::packet_pool::{PacketBufKind, PacketPool};
 use crate::network::protocol::ProtocolMetaData;
 
 pub(crate) struct NetworkDriver {
     
 }
 
+impl NetworkDriver {
+    /// Packet to receive a frame of `frame_len` bytes into. `Err` means the
+    /// pool is exhausted and the frame should be dropped.
+    pub(crate) fn alloc_rx_packet(&self, frame_len: usize) -> Result<NetworkPacket, DriverError> {
+        let kind = if PacketPool::headroom() + frame_len <= PacketPool::buf_size(PacketBufKind::Standard) {
+            PacketBufKind::Standard
+        } else {
+            PacketBufKind::Jumbo
+        };
+        NetworkPacket::alloc_rx(kind).map_err(|_| DriverError::NoBuffer)
+    }
+
+    /// Copy a frame taken off the wire into a pool packet.
+    pub(crate) fn receive_frame(&self, frame: &[u8]) -> Result<NetworkPacket, DriverError> {
+        let p = self.alloc_rx_packet(frame.len())?;
+        p.push_data(frame).map_err(|_| DriverError::NoBuffer)?;
+        Ok(p)
+    }
+}
+
 impl AsyncNetIOModule<NetworkPacket> for NetworkDriver {
-    type RxResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
-    type TxResult = (NetworkPacket, Result<(), ()>);
+    type RxResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
+    type TxResult = (NetworkPacket, Result<(), NetError>);
 
     async fn rx(self: Arc<Self>, p: NetworkPacket) -> Self::RxResult {
         // let res;
         // (p, res) = self.driver_layer.rx(p).await;
+        p.meta_mut().timestamp_usec = Runtime::get_time_usec();
         println!("!!!!!!!!!driver rx test, {:?}", p);
         (p, Ok(crate::network::protocol::ProtocolMetaData::new()))
     }
     async fn tx(self: Arc<Self>, p: NetworkPacket) -> Self::TxResult {
+        p.meta_mut().timestamp_usec = Runtime::get_time_usec();
         println!("!!!!!!!!!driver tx test. {:?}", p);
         (p, Ok(()))
     }
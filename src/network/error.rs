@@ -0,0 +1,438 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use crate::network::ethernet::{MacAddr, VlanIf};
+use std::net::Ipv6Addr;
+use crate::network::ipv4::{IPv4Addr, IPv4Cidr};
+use crate::network::ipv6::Ipv6Cidr;
+use crate::network::protocol::ProtocolHeaderType;
+
+/// Why a packet was dropped, what the stack counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// shorter than its headers claim
+    Truncated,
+    /// a header field is invalid
+    Malformed,
+    BadChecksum,
+    /// not addressed to any of our interfaces
+    NotForUs,
+    UnknownProtocol,
+    UnknownVlan,
+    VlanDisabled,
+    NoHandler,
+    NoRoute,
+    /// fragment dropped: overlapping, over the limits or timed out
+    Reassembly,
+    /// too big for the MTU and not to be fragmented
+    TooBig,
+    /// TTL ran out while forwarding
+    TtlExceeded,
+    /// the next hop's link address could not be resolved
+    Unresolved,
+    /// the layers above left out something the header needs
+    MissingInfo,
+    /// out of buffers or headroom
+    NoBuffer,
+    Driver,
+    Socket,
+    Other,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 18] = [
+        DropReason::Truncated,
+        DropReason::Malformed,
+        DropReason::BadChecksum,
+        DropReason::NotForUs,
+        DropReason::UnknownProtocol,
+        DropReason::UnknownVlan,
+        DropReason::VlanDisabled,
+        DropReason::NoHandler,
+        DropReason::NoRoute,
+        DropReason::Reassembly,
+        DropReason::TooBig,
+        DropReason::TtlExceeded,
+        DropReason::Unresolved,
+        DropReason::MissingInfo,
+        DropReason::NoBuffer,
+        DropReason::Driver,
+        DropReason::Socket,
+        DropReason::Other,
+    ];
+
+    fn index(&self) -> usize {
+        DropReason::ALL.iter().position(|r| r == self).unwrap()
+    }
+}
+
+/// Ethernet layer errors. Offsets are relative to the start of the frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EthError {
+    Truncated { offset: usize, need: usize },
+    /// more than two VLAN tags
+    TooManyTags { offset: usize },
+    UnknownEtherType { offset: usize, ethertype: u16 },
+    NotForUs(MacAddr),
+    UnknownVlan { vlan: Option<u16>, svlan: Option<u16> },
+    VlanDisabled,
+    InvalidVlan(u16),
+    /// TX frame without source or destination MAC
+    MissingAddress,
+    /// TX frame whose L3 protocol has no EtherType
+    NoEtherType(ProtocolHeaderType),
+    NoHeadroom,
+    DuplicateMac(MacAddr),
+    DuplicateVlan(VlanIf),
+    MacNotFound(MacAddr),
+    VlanNotFound(VlanIf),
+}
+
+/// ARP errors. Offsets are relative to the start of the ARP header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArpError {
+    Truncated { offset: usize, need: usize },
+    /// hardware or protocol type we don't speak
+    UnsupportedFormat { offset: usize },
+    Malformed { offset: usize },
+    /// no IPv4 address bound to an interface to send from
+    NoInterface,
+    /// too many packets already waiting for this address
+    QueueFull(IPv4Addr),
+    Unresolved(IPv4Addr),
+}
+
+/// IPv4 layer errors. Offsets are relative to the start of the IPv4 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ipv4Error {
+    Truncated { offset: usize, need: usize },
+    BadVersion(u8),
+    BadHeaderLength { offset: usize, ihl: u8 },
+    BadTotalLength { offset: usize, len: u16 },
+    BadChecksum,
+    /// source that no host can have, e.g. broadcast or multicast
+    BadSource(IPv4Addr),
+    NotForUs(IPv4Addr),
+    UnknownProtocol(u8),
+    /// fragment with a bad length or offset, or not matching the others
+    BadFragment,
+    OverlappingFragment,
+    /// too many fragments or datagrams being reassembled
+    ReassemblyLimit,
+    /// too big for the MTU with DF set
+    FragmentationNeeded { mtu: u16 },
+    BadMtu(u16),
+    /// forwarded packet whose TTL ran out
+    TtlExceeded,
+    /// TX packet without a destination, or no source to pick
+    MissingAddress,
+    /// TX packet whose L4 protocol has no IP protocol number
+    NoProtocol(ProtocolHeaderType),
+    TooLong(usize),
+    NoHeadroom,
+    NoRoute(IPv4Addr),
+    DuplicateAddress(IPv4Addr),
+    AddressNotFound(IPv4Addr),
+    /// route with neither gateway nor interface
+    NoInterface,
+    /// the gateway is on none of our subnets
+    GatewayUnreachable(IPv4Addr),
+    RouteExists(IPv4Cidr),
+    RouteNotFound(IPv4Cidr),
+}
+
+/// IPv6 layer errors. Offsets are relative to the start of the IPv6 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ipv6Error {
+    Truncated { offset: usize, need: usize },
+    BadVersion(u8),
+    /// multicast or loopback source
+    BadSource(Ipv6Addr),
+    NotForUs(Ipv6Addr),
+    UnknownProtocol(u8),
+    /// hop-by-hop options anywhere but right after the fixed header
+    MisplacedHopByHop { offset: usize },
+    TooManyExtHeaders,
+    /// option running past the end of its header
+    BadOption { offset: usize },
+    /// unknown option whose type says to discard the packet
+    UnknownOption { offset: usize, opt_type: u8 },
+    /// routing header with segments left, we don't route
+    UnsupportedRouting { offset: usize, routing_type: u8 },
+    /// fragments are not reassembled
+    Fragmented,
+    /// TX packet without a destination, or no source to pick
+    MissingAddress,
+    /// TX packet whose L4 protocol has no next header number
+    NoProtocol(ProtocolHeaderType),
+    TooLong(usize),
+    /// bigger than the MTU, we don't fragment
+    PacketTooBig { mtu: u32 },
+    NoHeadroom,
+    NoRoute(Ipv6Addr),
+    BadMtu(u32),
+    BadPrefixLength(u8),
+    /// multicast or unspecified address given as ours
+    BadAddress(Ipv6Addr),
+    DuplicateAddress(Ipv6Addr),
+    AddressNotFound(Ipv6Addr),
+    /// route with neither gateway nor interface
+    NoInterface,
+    /// the gateway is on none of our prefixes
+    GatewayUnreachable(Ipv6Addr),
+    RouteExists(Ipv6Cidr),
+    RouteNotFound(Ipv6Cidr),
+}
+
+/// ICMPv6 and neighbor discovery errors. Offsets are relative to the start
+/// of the ICMPv6 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Icmpv6Error {
+    Truncated { offset: usize, need: usize },
+    BadChecksum,
+    Malformed { offset: usize },
+    /// ND messages must not have crossed a router (RFC 4861 7.1.1)
+    BadHopLimit(u8),
+    /// ND target that no neighbor can have, e.g. multicast
+    BadTarget(Ipv6Addr),
+    /// router advertisement not from a link-local address
+    BadSource(Ipv6Addr),
+    /// the interface needs a link-local address to send from
+    NoLinkLocal,
+    /// no IPv6 address bound to an interface to send from
+    NoInterface,
+    /// too many packets already waiting for this address
+    QueueFull(Ipv6Addr),
+    Unresolved(Ipv6Addr),
+}
+
+/// UDP and TCP errors. Offsets are relative to the start of the L4 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportError {
+    Truncated { offset: usize, need: usize },
+}
+
+/// Errors of the protocol graph and the protocol number registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    NoHandler(ProtocolHeaderType),
+    TooManyLayers(ProtocolHeaderType),
+    /// the number is registered for another protocol
+    NumberInUse { number: u16, owner: ProtocolHeaderType },
+    NotRegistered(ProtocolHeaderType),
+}
+
+/// Configuration call that names something the stack can't bind to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// no sub-interface given
+    MissingInterface,
+    /// sub-interface of a type the stack doesn't know
+    InterfaceType,
+    /// needs to run on a scheduler thread
+    NoScheduler,
+    /// operation not supported by this stack type
+    StackType(ProtocolHeaderType),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverError {
+    NoBuffer,
+}
+
+/// Error of any network stack layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetError {
+    Eth(EthError),
+    Arp(ArpError),
+    Ipv4(Ipv4Error),
+    Ipv6(Ipv6Error),
+    Icmpv6(Icmpv6Error),
+    Transport(TransportError),
+    Graph(GraphError),
+    Config(ConfigError),
+    Driver(DriverError),
+}
+
+impl NetError {
+    /// How the drop counters file this error.
+    pub fn drop_reason(&self) -> DropReason {
+        match self {
+            NetError::Eth(e) => match e {
+                EthError::Truncated { .. } => DropReason::Truncated,
+                EthError::TooManyTags { .. } | EthError::InvalidVlan(_) => DropReason::Malformed,
+                EthError::UnknownEtherType { .. } | EthError::NoEtherType(_) => DropReason::UnknownProtocol,
+                EthError::NotForUs(_) => DropReason::NotForUs,
+                EthError::UnknownVlan { .. } | EthError::VlanNotFound(_) => DropReason::UnknownVlan,
+                EthError::VlanDisabled => DropReason::VlanDisabled,
+                EthError::MissingAddress => DropReason::MissingInfo,
+                EthError::NoHeadroom => DropReason::NoBuffer,
+                _ => DropReason::Other,
+            },
+            NetError::Arp(e) => match e {
+                ArpError::Truncated { .. } => DropReason::Truncated,
+                ArpError::UnsupportedFormat { .. } => DropReason::UnknownProtocol,
+                ArpError::Malformed { .. } => DropReason::Malformed,
+                ArpError::NoInterface => DropReason::NoRoute,
+                ArpError::QueueFull(_) => DropReason::NoBuffer,
+                ArpError::Unresolved(_) => DropReason::Unresolved,
+            },
+            NetError::Ipv4(e) => match e {
+                Ipv4Error::Truncated { .. } => DropReason::Truncated,
+                Ipv4Error::BadVersion(_)
+                | Ipv4Error::BadHeaderLength { .. }
+                | Ipv4Error::BadTotalLength { .. }
+                | Ipv4Error::BadSource(_)
+                | Ipv4Error::BadFragment => DropReason::Malformed,
+                Ipv4Error::OverlappingFragment | Ipv4Error::ReassemblyLimit => DropReason::Reassembly,
+                Ipv4Error::FragmentationNeeded { .. } => DropReason::TooBig,
+                Ipv4Error::TtlExceeded => DropReason::TtlExceeded,
+                Ipv4Error::BadChecksum => DropReason::BadChecksum,
+                Ipv4Error::NotForUs(_) => DropReason::NotForUs,
+                Ipv4Error::UnknownProtocol(_) | Ipv4Error::NoProtocol(_) => DropReason::UnknownProtocol,
+                Ipv4Error::MissingAddress => DropReason::MissingInfo,
+                Ipv4Error::NoHeadroom => DropReason::NoBuffer,
+                Ipv4Error::NoRoute(_) => DropReason::NoRoute,
+                _ => DropReason::Other,
+            },
+            NetError::Ipv6(e) => match e {
+                Ipv6Error::Truncated { .. } => DropReason::Truncated,
+                Ipv6Error::BadVersion(_)
+                | Ipv6Error::BadSource(_)
+                | Ipv6Error::MisplacedHopByHop { .. }
+                | Ipv6Error::TooManyExtHeaders
+                | Ipv6Error::BadOption { .. }
+                | Ipv6Error::UnknownOption { .. }
+                | Ipv6Error::UnsupportedRouting { .. } => DropReason::Malformed,
+                Ipv6Error::Fragmented => DropReason::Reassembly,
+                Ipv6Error::NotForUs(_) => DropReason::NotForUs,
+                Ipv6Error::UnknownProtocol(_) | Ipv6Error::NoProtocol(_) => DropReason::UnknownProtocol,
+                Ipv6Error::MissingAddress => DropReason::MissingInfo,
+                Ipv6Error::NoHeadroom => DropReason::NoBuffer,
+                Ipv6Error::NoRoute(_) => DropReason::NoRoute,
+                Ipv6Error::PacketTooBig { .. } => DropReason::TooBig,
+                _ => DropReason::Other,
+            },
+            NetError::Icmpv6(e) => match e {
+                Icmpv6Error::Truncated { .. } => DropReason::Truncated,
+                Icmpv6Error::BadChecksum => DropReason::BadChecksum,
+                Icmpv6Error::Malformed { .. }
+                | Icmpv6Error::BadHopLimit(_)
+                | Icmpv6Error::BadTarget(_)
+                | Icmpv6Error::BadSource(_) => DropReason::Malformed,
+                Icmpv6Error::NoInterface | Icmpv6Error::NoLinkLocal => DropReason::NoRoute,
+                Icmpv6Error::QueueFull(_) => DropReason::NoBuffer,
+                Icmpv6Error::Unresolved(_) => DropReason::Unresolved,
+            },
+            NetError::Transport(TransportError::Truncated { .. }) => DropReason::Truncated,
+            NetError::Graph(GraphError::NoHandler(_)) => DropReason::NoHandler,
+            NetError::Graph(GraphError::TooManyLayers(_)) => DropReason::Malformed,
+            NetError::Driver(DriverError::NoBuffer) => DropReason::NoBuffer,
+            _ => DropReason::Other,
+        }
+    }
+}
+
+impl Display for NetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetError::Eth(e) => write!(f, "ethernet: {:?}", e),
+            NetError::Arp(e) => write!(f, "arp: {:?}", e),
+            NetError::Ipv4(e) => write!(f, "ipv4: {:?}", e),
+            NetError::Ipv6(e) => write!(f, "ipv6: {:?}", e),
+            NetError::Icmpv6(e) => write!(f, "icmpv6: {:?}", e),
+            NetError::Transport(e) => write!(f, "transport: {:?}", e),
+            NetError::Graph(e) => write!(f, "protocol graph: {:?}", e),
+            NetError::Config(e) => write!(f, "config: {:?}", e),
+            NetError::Driver(e) => write!(f, "driver: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+impl From<EthError> for NetError {
+    fn from(e: EthError) -> Self {
+        NetError::Eth(e)
+    }
+}
+
+impl From<ArpError> for NetError {
+    fn from(e: ArpError) -> Self {
+        NetError::Arp(e)
+    }
+}
+
+impl From<Ipv4Error> for NetError {
+    fn from(e: Ipv4Error) -> Self {
+        NetError::Ipv4(e)
+    }
+}
+
+impl From<Ipv6Error> for NetError {
+    fn from(e: Ipv6Error) -> Self {
+        NetError::Ipv6(e)
+    }
+}
+
+impl From<Icmpv6Error> for NetError {
+    fn from(e: Icmpv6Error) -> Self {
+        NetError::Icmpv6(e)
+    }
+}
+
+impl From<TransportError> for NetError {
+    fn from(e: TransportError) -> Self {
+        NetError::Transport(e)
+    }
+}
+
+impl From<GraphError> for NetError {
+    fn from(e: GraphError) -> Self {
+        NetError::Graph(e)
+    }
+}
+
+impl From<ConfigError> for NetError {
+    fn from(e: ConfigError) -> Self {
+        NetError::Config(e)
+    }
+}
+
+impl From<DriverError> for NetError {
+    fn from(e: DriverError) -> Self {
+        NetError::Driver(e)
+    }
+}
+
+/// Drop counters, one per reason.
+pub struct DropStats {
+    counts: [AtomicU64; DropReason::ALL.len()],
+}
+
+impl DropStats {
+    pub fn new() -> DropStats {
+        DropStats { counts: std::array::from_fn(|_| AtomicU64::new(0)) }
+    }
+
+    pub fn count(&self, reason: DropReason) {
+        self.count_n(reason, 1);
+    }
+
+    pub fn count_n(&self, reason: DropReason, n: u64) {
+        self.counts[reason.index()].fetch_add(n, Relaxed);
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason.index()].load(Relaxed)
+    }
+
+    /// Reasons with a non-zero count.
+    pub fn snapshot(&self) -> Vec<(DropReason, u64)> {
+        DropReason::ALL
+            .iter()
+            .map(|r| (*r, self.get(*r)))
+            .filter(|(_, n)| *n > 0)
+            .collect()
+    }
+}
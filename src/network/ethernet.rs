@@ -4,17 +4,53 @@ use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::hash::Hash;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::atomic::Ordering::Relaxed;
+use crate::network::error::{EthError, NetError};
 use crate::network::module_traits::AsyncProtocolModule;
 use crate::network::packet::NetworkPacket;
-use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData};
+use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData, ProtocolRegistry};
+use crate::network::subres::SubInfo;
 
 
+pub const ETH_ADDR_LEN: usize = 6;
+pub const ETH_HDR_LEN: usize = 14;
+
+pub const ETH_P_IPV4: u16 = 0x0800;
+pub const ETH_P_ARP: u16 = 0x0806;
+pub const ETH_P_IPV6: u16 = 0x86DD;
+pub const ETH_P_8021Q: u16 = 0x8100;
+pub const ETH_P_8021AD: u16 = 0x88A8;
+
+pub const VLAN_TAG_LEN: usize = 4;
+pub const VLAN_VID_MAX: u16 = 4094;
+
+// interface indexes start at 1, 0 is never handed out
+static ETH_IFINDEX: AtomicU32 = AtomicU32::new(1);
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct MacAddr {
     pub mac: [u8; 6],
 }
 
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr { mac: [0xff; 6] };
+
+    pub fn new(mac: [u8; 6]) -> MacAddr {
+        MacAddr { mac }
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.mac == [0xff; 6]
+    }
+
+    /// Group bit set, broadcast included.
+    pub fn is_multicast(&self) -> bool {
+        self.mac[0] & 0x01 != 0
+    }
+}
+
 // Implement FromStr for safe parsing
 impl FromStr for MacAddr {
     type Err = String;
@@ -55,18 +91,43 @@ impl Display for MacAddr {
 pub struct EthKey {
     pub key: MacAddr,
     // pub ethertype: u16,
-    // pub vlan: Option<u16>,
+    pub vlan: Option<u16>,
+    // outer 802.1ad tag for QinQ
+    pub svlan: Option<u16>,
 }
 
 impl EthKey {
     pub fn new(key_ref: &MacAddr) -> EthKey {
-        EthKey { key: key_ref.clone() }
+        EthKey { key: key_ref.clone(), vlan: None, svlan: None }
+    }
+
+    pub fn with_vlan(key_ref: &MacAddr, vlan: Option<u16>, svlan: Option<u16>) -> EthKey {
+        EthKey { key: key_ref.clone(), vlan, svlan }
     }
 }
 
 impl Hash for EthKey {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.key.mac.hash(state);
+        self.vlan.hash(state);
+        self.svlan.hash(state);
+    }
+}
+
+/// A VLAN sub-interface of a MAC added with `NetworkStack::add_mac`.
+/// Pass it as the sub address of `NetworkStack::add_ipv4` to bind an
+/// address to the VLAN instead of the untagged interface.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VlanIf {
+    pub mac: MacAddr,
+    pub vlan: u16,
+    /// outer service tag, `Some` for QinQ
+    pub svlan: Option<u16>,
+}
+
+impl VlanIf {
+    pub fn new(mac: MacAddr, vlan: u16, svlan: Option<u16>) -> VlanIf {
+        VlanIf { mac, vlan, svlan }
     }
 }
 
@@ -78,16 +139,43 @@ impl Hash for EthKey {
 // }
 
 /// Ethernet resource: handler metadata or next-hop/port
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub(crate) struct EthEntry {
-    mac: MacAddr,        // destination or source MAC
+    mac: RwLock<MacAddr>,        // destination or source MAC, can be changed
     vlan: Option<u16>,   // to keep per-VLAN separation
+    svlan: Option<u16>,
     sub: Option<Arc<dyn Any + Send + Sync>>,
+    // stays the same for the life of the entry, even across MAC changes
+    index: u32,
 }
 
 impl EthEntry {
     pub fn new(mac: MacAddr, vlan: Option<u16>, sub: Option<Arc<dyn Any + Send + Sync>>) -> EthEntry {
-        EthEntry { mac, vlan, sub: None }
+        EthEntry { mac: RwLock::new(mac), vlan, svlan: None, sub, index: ETH_IFINDEX.fetch_add(1, Relaxed) }
+    }
+
+    /// Interface index, what `PacketMeta::ingress_if` refers to.
+    pub fn get_index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn get_mac(&self) -> MacAddr {
+        self.mac.read().unwrap().clone()
+    }
+
+    pub fn get_vlan(&self) -> Option<u16> {
+        self.vlan
+    }
+
+    pub fn get_svlan(&self) -> Option<u16> {
+        self.svlan
+    }
+
+    pub(crate) fn sub_info(&self) -> SubInfo {
+        match self.vlan {
+            Some(vid) => SubInfo::Vlan(vid),
+            None => SubInfo::Mac(self.get_mac()),
+        }
     }
 }
 
@@ -97,29 +185,270 @@ pub(crate) struct EthernetProtocol {
     // pub mac_table: Mutex<HashMap<MacKey, EthEntry>>,
 
     // Ethernet-specific knobs
-    pub default_vlan: Option<u16>,
-    pub enable_vlan: bool,
+    // untagged frames belong to this VLAN, frames sent on it go out untagged
+    pub default_vlan: Mutex<Option<u16>>,
+    // tagged frames are dropped unless set
+    pub enable_vlan: AtomicBool,
+    // accept frames for any destination MAC
+    promiscuous: AtomicBool,
+    // (vlan, svlan) of every interface, with how many MACs have it
+    vlans: RwLock<HashMap<VlanTags, usize>>,
+    registry: Arc<ProtocolRegistry>,
+}
+
+/// (vlan, svlan) of an interface, `None` for untagged.
+type VlanTags = (Option<u16>, Option<u16>);
+
+/// One 802.1Q / 802.1ad tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VlanTag {
+    pub tpid: u16,
+    pub pcp: u8,
+    pub dei: bool,
+    pub vid: u16,
+}
+
+impl VlanTag {
+    fn from_tci(tpid: u16, tci: u16) -> VlanTag {
+        VlanTag { tpid, pcp: (tci >> 13) as u8, dei: tci & 0x1000 != 0, vid: tci & 0x0fff }
+    }
+
+    fn tci(&self) -> u16 {
+        ((self.pcp as u16 & 0x7) << 13) | if self.dei { 0x1000 } else { 0 } | (self.vid & 0x0fff)
+    }
+}
+
+/// Decoded Ethernet II header, with up to two VLAN tags.
+#[derive(Debug, Clone)]
+pub struct EthHeader {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    /// 802.1ad service tag of a QinQ frame
+    pub outer: Option<VlanTag>,
+    pub inner: Option<VlanTag>,
+    pub ethertype: u16,
+}
+
+impl EthHeader {
+    /// Parse from the start of `b`, which holds at least `ETH_HDR_LEN` bytes
+    /// plus room for the tags, if any.
+    pub fn parse(b: &[u8]) -> Result<EthHeader, EthError> {
+        if b.len() < ETH_HDR_LEN {
+            return Err(EthError::Truncated { offset: 0, need: ETH_HDR_LEN });
+        }
+        let mut dst = [0u8; ETH_ADDR_LEN];
+        let mut src = [0u8; ETH_ADDR_LEN];
+        dst.copy_from_slice(&b[0..6]);
+        src.copy_from_slice(&b[6..12]);
+
+        let mut pos = 12;
+        let mut tags = Vec::new();
+        let mut ethertype = u16::from_be_bytes([b[pos], b[pos + 1]]);
+        while ethertype == ETH_P_8021AD || ethertype == ETH_P_8021Q {
+            // no triple tagging
+            if tags.len() == 2 {
+                return Err(EthError::TooManyTags { offset: pos });
+            }
+            if b.len() < pos + VLAN_TAG_LEN + 2 {
+                return Err(EthError::Truncated { offset: pos, need: VLAN_TAG_LEN + 2 });
+            }
+            let tci = u16::from_be_bytes([b[pos + 2], b[pos + 3]]);
+            tags.push(VlanTag::from_tci(ethertype, tci));
+            pos += VLAN_TAG_LEN;
+            ethertype = u16::from_be_bytes([b[pos], b[pos + 1]]);
+        }
+
+        let (outer, inner) = match tags.len() {
+            0 => (None, None),
+            1 if tags[0].tpid == ETH_P_8021AD => (Some(tags[0]), None),
+            1 => (None, Some(tags[0])),
+            _ => (Some(tags[0]), Some(tags[1])),
+        };
+        Ok(EthHeader { dst: MacAddr::new(dst), src: MacAddr::new(src), outer, inner, ethertype })
+    }
+
+    pub fn len(&self) -> usize {
+        ETH_HDR_LEN + VLAN_TAG_LEN * (self.outer.is_some() as usize + self.inner.is_some() as usize)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(self.len());
+        b.extend_from_slice(&self.dst.mac);
+        b.extend_from_slice(&self.src.mac);
+        for tag in [self.outer, self.inner].into_iter().flatten() {
+            b.extend_from_slice(&tag.tpid.to_be_bytes());
+            b.extend_from_slice(&tag.tci().to_be_bytes());
+        }
+        b.extend_from_slice(&self.ethertype.to_be_bytes());
+        b
+    }
 }
 
 impl EthernetProtocol {
-    pub(crate) fn new() -> EthernetProtocol {
+    pub(crate) fn new(registry: Arc<ProtocolRegistry>) -> EthernetProtocol {
         EthernetProtocol {
             common: NetworkProtocolMng::<EthKey, Arc<EthEntry>>::new(ProtocolHeaderType::Ethernet),
             // mac_table: Mutex::new(Default::default()),
-            default_vlan: None,
-            enable_vlan: false,
+            default_vlan: Mutex::new(None),
+            enable_vlan: AtomicBool::new(false),
+            promiscuous: AtomicBool::new(false),
+            vlans: RwLock::new(HashMap::new()),
+            registry,
+        }
+    }
+
+    pub(crate) fn set_promiscuous(&self, on: bool) {
+        self.promiscuous.store(on, Relaxed);
+    }
+
+    pub(crate) fn is_promiscuous(&self) -> bool {
+        self.promiscuous.load(Relaxed)
+    }
+
+    /// Turn VLAN handling on or off. `default_vlan` is the VLAN untagged
+    /// frames belong to.
+    pub(crate) fn set_vlan_mode(&self, enable: bool, default_vlan: Option<u16>) -> Result<(), EthError> {
+        if let Some(vid) = default_vlan.filter(|v| *v == 0 || *v > VLAN_VID_MAX) {
+            return Err(EthError::InvalidVlan(vid));
+        }
+        self.enable_vlan.store(enable, Relaxed);
+        *self.default_vlan.lock().unwrap() = default_vlan;
+        Ok(())
+    }
+
+    fn get_default_vlan(&self) -> Option<u16> {
+        *self.default_vlan.lock().unwrap()
+    }
+
+    /// Whether a frame sent to `dst` on the given VLAN is for us. Frames on a
+    /// VLAN without a sub-interface are dropped, promiscuous mode or not.
+    fn accept_dst(&self, dst: &MacAddr, vlan: Option<u16>, svlan: Option<u16>) -> Result<(), EthError> {
+        if !self.has_vlan(vlan, svlan) {
+            return Err(EthError::UnknownVlan { vlan, svlan });
+        }
+        if dst.is_multicast() || self.is_promiscuous() {
+            return Ok(());
+        }
+        if self.common.res_read_borrow().contains_key(&EthKey::with_vlan(dst, vlan, svlan)) {
+            Ok(())
+        } else {
+            Err(EthError::NotForUs(dst.clone()))
+        }
+    }
+
+    /// The interface a frame to `dst` on the given VLAN came in on: the
+    /// entry owning `dst`, or for multicast and promiscuous frames any entry
+    /// on that VLAN.
+    fn ingress_entry(&self, dst: &MacAddr, vlan: Option<u16>, svlan: Option<u16>) -> Option<Arc<EthEntry>> {
+        let r = self.common.res_read_borrow();
+        r.get(&EthKey::with_vlan(dst, vlan, svlan))
+            .or_else(|| r.iter().find(|(k, _)| k.vlan == vlan && k.svlan == svlan).map(|(_, ent)| ent))
+            .map(Arc::clone)
+    }
+
+    fn has_vlan(&self, vlan: Option<u16>, svlan: Option<u16>) -> bool {
+        self.vlans.read().unwrap().contains_key(&(vlan, svlan))
+    }
+
+    fn count_vlan(&self, vlan: Option<u16>, svlan: Option<u16>, added: bool) {
+        let mut vlans = self.vlans.write().unwrap();
+        let n = vlans.entry((vlan, svlan)).or_insert(0);
+        if added {
+            *n += 1;
+        } else {
+            *n = n.saturating_sub(1);
+            if *n == 0 {
+                vlans.remove(&(vlan, svlan));
+            }
         }
     }
 
-    pub(crate) fn add_mac(&self, mac: &MacAddr, sub: Option<Arc<dyn Any + Send + Sync>>) -> Result<(), ()> {
+    fn decode_frame(&self, p: &NetworkPacket) -> Result<ProtocolMetaData, EthError> {
+        let hdr = EthHeader::parse(&p.peek_front(ETH_HDR_LEN + 2 * VLAN_TAG_LEN)
+            .or_else(|_| p.peek_front(ETH_HDR_LEN))
+            .map_err(|_| EthError::Truncated { offset: 0, need: ETH_HDR_LEN })?)?;
+
+        let tagged = hdr.outer.is_some() || hdr.inner.is_some();
+        if tagged && !self.enable_vlan.load(Relaxed) {
+            return Err(EthError::VlanDisabled);
+        }
+        let svlan = hdr.outer.map(|tag| tag.vid);
+        // VID 0 is a priority tag only. Untagged frames go to the native
+        // VLAN's sub-interface if there is one, else to the plain interface.
+        let vlan = match hdr.inner {
+            Some(tag) if tag.vid != 0 => Some(tag.vid),
+            _ => self.get_default_vlan().filter(|vid| self.has_vlan(Some(*vid), svlan)),
+        };
+        self.accept_dst(&hdr.dst, vlan, svlan)?;
+        let Some(pt) = self.registry.by_ethertype(hdr.ethertype) else {
+            return Err(EthError::UnknownEtherType { offset: hdr.len() - 2, ethertype: hdr.ethertype });
+        };
+
+        let ingress_if = self.ingress_entry(&hdr.dst, vlan, svlan).map(|ent| ent.get_index());
+        let l2_offset = p.data_offset();
+        p.pull_header(ProtocolHeaderType::Ethernet, hdr.len())
+            .map_err(|_| EthError::Truncated { offset: 0, need: hdr.len() })?;
+        {
+            let mut m = p.meta_mut();
+            m.ingress_if = ingress_if;
+            m.l2_offset = Some(l2_offset);
+            m.l2_dst = Some(hdr.dst.mac);
+            m.l2_src = Some(hdr.src.mac);
+            m.vlan = vlan;
+            m.svlan = svlan;
+            m.vlan_pcp = hdr.inner.or(hdr.outer).map_or(0, |tag| tag.pcp);
+            m.l3_offset = Some(p.data_offset());
+            m.l3_proto = pt;
+        }
+
+        let mut meta = ProtocolMetaData::new();
+        meta.set_pt(pt);
+        Ok(meta)
+    }
+
+    /// Build the header from the packet metadata: `l2_dst`, `l2_src` and
+    /// `l3_proto` have to be set by the layers above, `vlan`/`svlan` pick the
+    /// tags.
+    fn encode_frame(&self, p: &NetworkPacket) -> Result<(), EthError> {
+        let default_vlan = self.get_default_vlan();
+        let hdr = {
+            let m = p.meta();
+            let (Some(dst), Some(src)) = (m.l2_dst, m.l2_src) else {
+                return Err(EthError::MissingAddress);
+            };
+            let Some(ethertype) = self.registry.ethertype(m.l3_proto) else {
+                return Err(EthError::NoEtherType(m.l3_proto));
+            };
+            let pcp = m.vlan_pcp;
+            let inner = match m.vlan {
+                // the native VLAN goes out untagged, unless it is inside QinQ
+                Some(vid) if m.svlan.is_some() || Some(vid) != default_vlan => {
+                    Some(VlanTag { tpid: ETH_P_8021Q, pcp, dei: false, vid })
+                }
+                _ => None,
+            };
+            let outer = m.svlan.map(|vid| VlanTag { tpid: ETH_P_8021AD, pcp, dei: false, vid });
+            if (inner.is_some() || outer.is_some()) && !self.enable_vlan.load(Relaxed) {
+                return Err(EthError::VlanDisabled);
+            }
+            EthHeader { dst: MacAddr::new(dst), src: MacAddr::new(src), outer, inner, ethertype }
+        };
+        p.push_header(ProtocolHeaderType::Ethernet, &hdr.to_bytes())
+            .map_err(|_| EthError::NoHeadroom)?;
+        p.meta_mut().l2_offset = Some(p.data_offset());
+        Ok(())
+    }
+
+    pub(crate) fn add_mac(&self, mac: &MacAddr, sub: Option<Arc<dyn Any + Send + Sync>>) -> Result<(), EthError> {
         let ky = EthKey::new(&mac);
         let ent = Arc::new(EthEntry::new(mac.clone(), None, sub));
-        let mut ret = Err(());
+        let mut ret = Err(EthError::DuplicateMac(mac.clone()));
         {
             let mut w = self.common.res_write_borrow();
             match (*w).entry(ky) {
                 Entry::Vacant(v) => {
                     v.insert(ent);
+                    self.count_vlan(None, None, true);
                     ret = Ok(())
                 }
                 Entry::Occupied(_) => {}
@@ -128,44 +457,144 @@ impl EthernetProtocol {
         ret
     }
     
-    pub(crate) fn search_mac(&self, mac: &MacAddr) -> Result<Arc<EthEntry>, ()> {
+    /// Add a VLAN sub-interface on top of an added MAC.
+    pub(crate) fn add_vlan(&self, vlan_if: &VlanIf) -> Result<Arc<EthEntry>, EthError> {
+        let invalid = |vid: u16| vid == 0 || vid > VLAN_VID_MAX;
+        if let Some(vid) = [Some(vlan_if.vlan), vlan_if.svlan].into_iter().flatten().find(|v| invalid(*v)) {
+            return Err(EthError::InvalidVlan(vid));
+        }
+        self.search_mac(&vlan_if.mac)?;
+
+        let ky = EthKey::with_vlan(&vlan_if.mac, Some(vlan_if.vlan), vlan_if.svlan);
+        let ent = Arc::new(EthEntry {
+            mac: RwLock::new(vlan_if.mac.clone()),
+            vlan: Some(vlan_if.vlan),
+            svlan: vlan_if.svlan,
+            sub: None,
+            index: ETH_IFINDEX.fetch_add(1, Relaxed),
+        });
+        let mut w = self.common.res_write_borrow();
+        match (*w).entry(ky) {
+            Entry::Vacant(v) => {
+                v.insert(ent.clone());
+                self.count_vlan(Some(vlan_if.vlan), vlan_if.svlan, true);
+                Ok(ent)
+            }
+            Entry::Occupied(_) => Err(EthError::DuplicateVlan(vlan_if.clone())),
+        }
+    }
+
+    pub(crate) fn remove_vlan(&self, vlan_if: &VlanIf) -> Result<(), EthError> {
+        let ky = EthKey::with_vlan(&vlan_if.mac, Some(vlan_if.vlan), vlan_if.svlan);
+        let mut w = self.common.res_write_borrow();
+        if w.remove(&ky).is_none() {
+            return Err(EthError::VlanNotFound(vlan_if.clone()));
+        }
+        self.count_vlan(Some(vlan_if.vlan), vlan_if.svlan, false);
+        Ok(())
+    }
+
+    pub(crate) fn search_vlan(&self, vlan_if: &VlanIf) -> Result<Arc<EthEntry>, EthError> {
+        let key = EthKey::with_vlan(&vlan_if.mac, Some(vlan_if.vlan), vlan_if.svlan);
+        let r = self.common.res_read_borrow();
+        match r.get(&key).map(Arc::clone) {
+            Some(ent) => Ok(ent),
+            None => Err(EthError::VlanNotFound(vlan_if.clone())),
+        }
+    }
+
+    /// Move an interface and its VLAN sub-interfaces to a new MAC. Returns
+    /// the moved entries; whatever is bound to them keeps working.
+    pub(crate) fn change_mac(&self, old: &MacAddr, new: &MacAddr) -> Result<Vec<Arc<EthEntry>>, EthError> {
+        let mut w = self.common.res_write_borrow();
+        if !w.contains_key(&EthKey::new(old)) {
+            return Err(EthError::MacNotFound(old.clone()));
+        }
+        if w.contains_key(&EthKey::new(new)) {
+            return Err(EthError::DuplicateMac(new.clone()));
+        }
+        let keys: Vec<EthKey> = w.keys().filter(|k| k.key == *old).cloned().collect();
+        let mut moved = Vec::with_capacity(keys.len());
+        for k in keys {
+            let Some(ent) = w.remove(&k) else { continue };
+            *ent.mac.write().unwrap() = new.clone();
+            w.insert(EthKey::with_vlan(new, k.vlan, k.svlan), ent.clone());
+            moved.push(ent);
+        }
+        Ok(moved)
+    }
+
+    pub(crate) fn search_mac(&self, mac: &MacAddr) -> Result<Arc<EthEntry>, EthError> {
         let key = EthKey::new(&mac);
         let mut r = self.common.res_read_borrow();
         match r.get(&key).map(Arc::clone) {
             Some(ent) => Ok(ent),
-            None => {
-                println!("No entry found for {:?}", mac);
-                Err(())
-            },
+            None => Err(EthError::MacNotFound(mac.clone())),
         }
     }
 }
 
 impl AsyncProtocolModule<NetworkPacket> for EthernetProtocol {
-    type EncodeResult = (NetworkPacket, Result<(), ()>);
-    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
 
     async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
-        println!("----- encode ethernet -----");
-        (p, Ok(()))
+        self.sync_encode(p)
     }
 
     async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
-        println!("----- decode ethernet -----");
-        let mut meta = ProtocolMetaData::new();
-        meta.set_pt(ProtocolHeaderType::IPv4);
-        (p, Ok(meta))
+        self.sync_decode(p)
     }
 
     fn sync_encode(&self, p: NetworkPacket) -> Self::EncodeResult {
         println!("----- encode ethernet -----");
-        (p, Ok(()))
+        let res = self.encode_frame(&p).map_err(NetError::from);
+        (p, res)
     }
 
     fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode ethernet -----");
-        let mut meta = ProtocolMetaData::new();
-        meta.set_pt(ProtocolHeaderType::IPv4);
-        (p, Ok(meta))
+        let res = self.decode_frame(&p).map_err(NetError::from);
+        (p, res)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OURS: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+
+    fn frame(dst: [u8; 6]) -> NetworkPacket {
+        let p = NetworkPacket::new();
+        let mut bytes = dst.to_vec();
+        bytes.extend_from_slice(&[0x02, 0, 0, 0, 0, 2]);
+        bytes.extend_from_slice(&ETH_P_IPV4.to_be_bytes());
+        bytes.extend_from_slice(&[0; 20]);
+        p.push_data(&bytes).unwrap();
+        p
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn decode_sets_ingress_if() {
+        let eth = EthernetProtocol::new(Arc::new(ProtocolRegistry::new()));
+        eth.add_mac(&MacAddr::new(OURS), None).unwrap();
+        let index = eth.search_mac(&MacAddr::new(OURS)).unwrap().get_index();
+
+        let p = frame(OURS);
+        eth.decode_frame(&p).unwrap();
+        assert_eq!(p.meta().ingress_if, Some(index));
+
+        // multicast is not addressed to an entry, it still has an interface
+        let p = frame([0x33, 0x33, 0, 0, 0, 1]);
+        eth.decode_frame(&p).unwrap();
+        assert_eq!(p.meta().ingress_if, Some(index));
+    }
+
+    #[test]
+    fn interface_indexes_are_unique() {
+        let a = EthEntry::new(MacAddr::new(OURS), None, None);
+        let b = EthEntry::new(MacAddr::new(OURS), None, None);
+        assert_ne!(a.get_index(), 0);
+        assert_ne!(a.get_index(), b.get_index());
+    }
+}
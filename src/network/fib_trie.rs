@@ -0,0 +1,305 @@
+// Binary prefix trie behind the IPv4 and IPv6 forwarding tables.
+
+/// Where a route came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteOrigin {
+    /// the subnet of one of our addresses
+    Connected,
+    /// added with `NetworkStack::add_route`
+    Static,
+    /// learned from a router advertisement, expires with it
+    RouterAdvert,
+}
+
+/// A route the trie can hold. Prefixes are left aligned in a `u128`, an
+/// IPv4 prefix in the top 32 bits.
+pub(crate) trait TrieRoute: Clone {
+    /// (prefix bits, prefix length)
+    fn key(&self) -> (u128, u8);
+    /// lower wins among routes of the same prefix
+    fn metric(&self) -> u32;
+    /// same next hop, interface and origin, so not a route of its own;
+    /// routes of different origins are withdrawn separately
+    fn same_path(&self, other: &Self) -> bool;
+}
+
+/// Index of the trie root in `Trie::nodes`.
+const ROOT: usize = 0;
+/// Child link of a leaf.
+const NIL: u32 = 0;
+
+/// Trie node, one per prefix bit. The root (/0) holds the default routes.
+struct TrieNode<R> {
+    child: [u32; 2],
+    /// routes of exactly this prefix, best (lowest metric) first
+    routes: Vec<R>,
+}
+
+impl<R> Default for TrieNode<R> {
+    fn default() -> Self {
+        TrieNode { child: [NIL; 2], routes: Vec::new() }
+    }
+}
+
+/// Nodes live in one vector and link by index; removed nodes are reused.
+pub(crate) struct Trie<R> {
+    nodes: Vec<TrieNode<R>>,
+    free: Vec<u32>,
+    count: usize,
+}
+
+impl<R: TrieRoute> Trie<R> {
+    pub(crate) fn new() -> Trie<R> {
+        Trie { nodes: vec![TrieNode::default()], free: Vec::new(), count: 0 }
+    }
+
+    fn bit(addr: u128, depth: u8) -> usize {
+        (addr >> (127 - depth)) as usize & 1
+    }
+
+    fn alloc(&mut self) -> u32 {
+        match self.free.pop() {
+            Some(idx) => idx,
+            None => {
+                self.nodes.push(TrieNode::default());
+                (self.nodes.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Node of a prefix, created with its path if `create`.
+    fn find(&mut self, (addr, len): (u128, u8), create: bool) -> Option<usize> {
+        let mut idx = ROOT;
+        for depth in 0..len {
+            let b = Self::bit(addr, depth);
+            if self.nodes[idx].child[b] == NIL {
+                if !create {
+                    return None;
+                }
+                let new = self.alloc();
+                self.nodes[idx].child[b] = new;
+            }
+            idx = self.nodes[idx].child[b] as usize;
+        }
+        Some(idx)
+    }
+
+    /// Add a route, false if its prefix has one with the same path.
+    pub(crate) fn insert(&mut self, route: R) -> bool {
+        let idx = self.find(route.key(), true).unwrap();
+        let routes = &mut self.nodes[idx].routes;
+        if routes.iter().any(|r| r.same_path(&route)) {
+            return false;
+        }
+        let at = routes.partition_point(|r| r.metric() <= route.metric());
+        routes.insert(at, route);
+        self.count += 1;
+        true
+    }
+
+    /// Remove the routes of a prefix that `pred` picks, returns how many.
+    pub(crate) fn remove(&mut self, key: (u128, u8), pred: impl Fn(&R) -> bool) -> usize {
+        let Some(idx) = self.find(key, false) else {
+            return 0;
+        };
+        let routes = &mut self.nodes[idx].routes;
+        let before = routes.len();
+        routes.retain(|r| !pred(r));
+        let removed = before - routes.len();
+        self.count -= removed;
+        if removed > 0 && self.nodes[idx].routes.is_empty() {
+            self.prune(key);
+        }
+        removed
+    }
+
+    /// Remove the routes of any prefix that `pred` picks, returns how many.
+    pub(crate) fn remove_all(&mut self, pred: impl Fn(&R) -> bool) -> usize {
+        let keys: Vec<(u128, u8)> = self.routes().iter().filter(|r| pred(r)).map(|r| r.key()).collect();
+        keys.into_iter().map(|key| self.remove(key, &pred)).sum()
+    }
+
+    /// Unlink the nodes on the path to a prefix that hold nothing anymore.
+    fn prune(&mut self, (addr, len): (u128, u8)) {
+        let mut path = Vec::with_capacity(len as usize + 1);
+        let mut idx = ROOT;
+        path.push(idx);
+        for depth in 0..len {
+            idx = self.nodes[idx].child[Self::bit(addr, depth)] as usize;
+            path.push(idx);
+        }
+        for depth in (0..len).rev() {
+            let idx = path[depth as usize + 1];
+            let node = &self.nodes[idx];
+            if !node.routes.is_empty() || node.child != [NIL; 2] {
+                break;
+            }
+            self.nodes[path[depth as usize]].child[Self::bit(addr, depth)] = NIL;
+            self.free.push(idx as u32);
+        }
+    }
+
+    /// Best route of the longest prefix covering `addr`.
+    pub(crate) fn lookup(&self, addr: u128) -> Option<&R> {
+        let mut idx = ROOT;
+        let mut best = self.nodes[ROOT].routes.first();
+        for depth in 0..128 {
+            let next = self.nodes[idx].child[Self::bit(addr, depth)];
+            if next == NIL {
+                break;
+            }
+            idx = next as usize;
+            if let Some(r) = self.nodes[idx].routes.first() {
+                best = Some(r);
+            }
+        }
+        best
+    }
+
+    /// All routes, shorter prefixes first along each branch.
+    pub(crate) fn routes(&self) -> Vec<R> {
+        let mut out = Vec::with_capacity(self.count);
+        let mut stack = vec![ROOT];
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            out.extend(node.routes.iter().cloned());
+            stack.extend(node.child.iter().rev().filter(|c| **c != NIL).map(|c| *c as usize));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct TestRoute {
+        key: (u128, u8),
+        metric: u32,
+        via: u8,
+    }
+
+    impl TrieRoute for TestRoute {
+        fn key(&self) -> (u128, u8) {
+            self.key
+        }
+        fn metric(&self) -> u32 {
+            self.metric
+        }
+        fn same_path(&self, other: &Self) -> bool {
+            self.via == other.via
+        }
+    }
+
+    fn addr(a: [u8; 4]) -> u128 {
+        (u32::from_be_bytes(a) as u128) << 96
+    }
+
+    fn route(a: [u8; 4], len: u8, metric: u32, via: u8) -> TestRoute {
+        TestRoute { key: (addr(a), len), metric, via }
+    }
+
+    fn via(t: &Trie<TestRoute>, a: [u8; 4]) -> Option<u8> {
+        t.lookup(addr(a)).map(|r| r.via)
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut t = Trie::new();
+        assert!(t.insert(route([10, 0, 0, 0], 8, 0, 1)));
+        assert!(t.insert(route([10, 1, 0, 0], 16, 0, 2)));
+        assert!(t.insert(route([10, 1, 2, 0], 24, 0, 3)));
+        assert!(t.insert(route([10, 1, 2, 3], 32, 0, 4)));
+        assert_eq!(via(&t, [10, 9, 9, 9]), Some(1));
+        assert_eq!(via(&t, [10, 1, 9, 9]), Some(2));
+        assert_eq!(via(&t, [10, 1, 2, 9]), Some(3));
+        assert_eq!(via(&t, [10, 1, 2, 3]), Some(4));
+        assert_eq!(via(&t, [11, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn default_route() {
+        let mut t = Trie::new();
+        assert_eq!(via(&t, [1, 2, 3, 4]), None);
+        assert!(t.insert(route([0, 0, 0, 0], 0, 0, 1)));
+        assert!(t.insert(route([192, 168, 0, 0], 16, 0, 2)));
+        assert_eq!(via(&t, [1, 2, 3, 4]), Some(1));
+        assert_eq!(via(&t, [192, 168, 1, 1]), Some(2));
+        // a branch that runs out below a longer prefix falls back to it
+        assert_eq!(via(&t, [192, 169, 0, 0]), Some(1));
+    }
+
+    #[test]
+    fn lowest_metric_first() {
+        let mut t = Trie::new();
+        assert!(t.insert(route([10, 0, 0, 0], 8, 20, 1)));
+        assert!(t.insert(route([10, 0, 0, 0], 8, 10, 2)));
+        assert!(t.insert(route([10, 0, 0, 0], 8, 30, 3)));
+        assert_eq!(via(&t, [10, 0, 0, 1]), Some(2));
+        // equal metrics keep insertion order
+        assert!(t.insert(route([10, 0, 0, 0], 8, 10, 4)));
+        let order: Vec<u8> = t.routes().iter().map(|r| r.via).collect();
+        assert_eq!(order, [2, 4, 1, 3]);
+        // a longer prefix wins over a better metric
+        assert!(t.insert(route([10, 0, 0, 0], 24, 100, 5)));
+        assert_eq!(via(&t, [10, 0, 0, 1]), Some(5));
+    }
+
+    #[test]
+    fn same_path_rejected() {
+        let mut t = Trie::new();
+        assert!(t.insert(route([10, 0, 0, 0], 8, 10, 1)));
+        assert!(!t.insert(route([10, 0, 0, 0], 8, 5, 1)));
+        assert_eq!(t.routes().len(), 1);
+        assert_eq!(t.lookup(addr([10, 0, 0, 1])).unwrap().metric, 10);
+        // the same path for another prefix is a route of its own
+        assert!(t.insert(route([10, 0, 0, 0], 16, 10, 1)));
+        assert_eq!(t.routes().len(), 2);
+    }
+
+    #[test]
+    fn remove_and_prune() {
+        let mut t = Trie::new();
+        assert!(t.insert(route([10, 0, 0, 0], 8, 0, 1)));
+        assert!(t.insert(route([10, 1, 2, 0], 24, 0, 2)));
+        assert!(t.insert(route([10, 1, 2, 0], 24, 5, 3)));
+        assert_eq!(t.remove((addr([10, 1, 2, 0]), 24), |r| r.via == 9), 0);
+        assert_eq!(t.remove((addr([10, 9, 0, 0]), 16), |_| true), 0);
+        assert_eq!(t.remove((addr([10, 1, 2, 0]), 24), |r| r.via == 2), 1);
+        assert_eq!(via(&t, [10, 1, 2, 3]), Some(3));
+        assert!(t.free.is_empty());
+
+        // the /24 node and the ones between it and the /8 go
+        assert_eq!(t.remove((addr([10, 1, 2, 0]), 24), |_| true), 1);
+        assert_eq!(via(&t, [10, 1, 2, 3]), Some(1));
+        assert_eq!(t.free.len(), 16);
+        let eight = t.find((addr([10, 0, 0, 0]), 8), false).unwrap();
+        assert_eq!(t.nodes[eight].child, [NIL; 2]);
+
+        // and are reused
+        let nodes = t.nodes.len();
+        assert!(t.insert(route([10, 128, 0, 0], 9, 0, 4)));
+        assert_eq!(t.nodes.len(), nodes);
+        assert_eq!(t.free.len(), 15);
+
+        assert_eq!(t.remove((addr([10, 0, 0, 0]), 8), |_| true), 1);
+        assert_eq!(t.remove((addr([10, 128, 0, 0]), 9), |_| true), 1);
+        assert!(t.routes().is_empty());
+        assert_eq!(t.nodes[ROOT].child, [NIL; 2]);
+        assert_eq!(t.free.len(), t.nodes.len() - 1);
+    }
+
+    #[test]
+    fn remove_all_matching() {
+        let mut t = Trie::new();
+        assert!(t.insert(route([0, 0, 0, 0], 0, 0, 1)));
+        assert!(t.insert(route([10, 0, 0, 0], 8, 0, 1)));
+        assert!(t.insert(route([10, 0, 0, 0], 8, 5, 2)));
+        assert!(t.insert(route([172, 16, 0, 0], 12, 0, 1)));
+        assert_eq!(t.remove_all(|r| r.via == 1), 3);
+        assert_eq!(t.routes(), [route([10, 0, 0, 0], 8, 5, 2)]);
+        assert_eq!(via(&t, [172, 16, 0, 1]), None);
+        assert_eq!(t.remove_all(|r| r.via == 1), 0);
+    }
+}
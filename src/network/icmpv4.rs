@@ -1,5 +1,74 @@
-use std::net::Ipv4Addr;
-use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType};
+use std::net::{IpAddr, Ipv4Addr};
+use crate::network::checksum;
+use crate::network::error::NetError;
+use crate::network::ipv4::Ipv4Header;
+use crate::network::module_traits::AsyncProtocolModule;
+use crate::network::packet::NetworkPacket;
+use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData};
+
+pub const ICMP_HDR_LEN: usize = 8;
+/// IP protocol number of ICMP.
+pub const IPPROTO_ICMP: u8 = 1;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DEST_UNREACH: u8 = 3;
+pub const ICMP_REDIRECT: u8 = 5;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_PARAM_PROBLEM: u8 = 12;
+
+/// `ICMP_DEST_UNREACH` codes
+pub const ICMP_CODE_NET_UNREACH: u8 = 0;
+/// DF set and the packet is too big
+pub const ICMP_CODE_FRAG_NEEDED: u8 = 4;
+/// `ICMP_TIME_EXCEEDED` codes
+pub const ICMP_CODE_TTL_EXCEEDED: u8 = 0;
+pub const ICMP_CODE_REASM_TIMEOUT: u8 = 1;
+
+/// Bytes of the offending datagram's payload quoted in an error.
+const ICMP_ERROR_QUOTE: usize = 8;
+
+fn is_error_type(icmp_type: u8) -> bool {
+    matches!(icmp_type, ICMP_DEST_UNREACH | ICMP_REDIRECT | ICMP_TIME_EXCEEDED | ICMP_PARAM_PROBLEM)
+}
+
+/// Build an ICMP error about `orig`, the offending datagram from its IPv4
+/// header on, addressed to its source. `rest` is the type specific second
+/// word, e.g. the next-hop MTU of frag-needed.
+///
+/// `None` where RFC 1122 3.2.2 forbids an error: about another ICMP error,
+/// a non-first fragment, or a datagram from or to a broadcast or multicast
+/// address.
+pub(crate) fn error_packet(orig: &[u8], icmp_type: u8, code: u8, rest: [u8; 4]) -> Option<NetworkPacket> {
+    let hdr = Ipv4Header::parse(orig).ok()?;
+    if hdr.frag_offset != 0
+        || hdr.src.is_unspecified() || hdr.src.is_broadcast() || hdr.src.is_multicast()
+        || hdr.dst.is_broadcast() || hdr.dst.is_multicast() {
+        return None;
+    }
+    if hdr.protocol == IPPROTO_ICMP
+        && orig.get(hdr.len()).is_none_or(|t| is_error_type(*t)) {
+        return None;
+    }
+
+    let quote = &orig[..orig.len().min(hdr.len() + ICMP_ERROR_QUOTE)];
+    let mut msg = Vec::with_capacity(ICMP_HDR_LEN + quote.len());
+    msg.extend_from_slice(&[icmp_type, code, 0, 0]);
+    msg.extend_from_slice(&rest);
+    msg.extend_from_slice(quote);
+    let csum = checksum::checksum(&msg);
+    msg[2..4].copy_from_slice(&csum.to_be_bytes());
+
+    let p = NetworkPacket::new();
+    p.push_data(&msg).ok()?;
+    {
+        let mut m = p.meta_mut();
+        m.l3_proto = ProtocolHeaderType::IPv4;
+        m.l4_proto = ProtocolHeaderType::ICMPv4;
+        m.l3_dst = Some(IpAddr::V4(hdr.src.into()));
+    }
+    Some(p)
+}
 
 /// ICMPv4 key: destination IPv4 + type + code
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -32,3 +101,28 @@ impl ICMPv4Protocol {
         }
     }
 }
+
+impl AsyncProtocolModule<NetworkPacket> for ICMPv4Protocol {
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
+
+    async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
+        self.sync_encode(p)
+    }
+
+    async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
+        self.sync_decode(p)
+    }
+
+    fn sync_encode(&self, p: NetworkPacket) -> Self::EncodeResult {
+        println!("----- encode icmpv4 -----");
+        // messages are built whole, see `error_packet`
+        (p, Ok(()))
+    }
+
+    fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
+        println!("----- decode icmpv4 -----");
+        // consumed here, nothing above
+        (p, Ok(ProtocolMetaData::new()))
+    }
+}
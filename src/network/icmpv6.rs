@@ -1,5 +1,19 @@
-use std::net::Ipv6Addr;
-use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use crate::network::checksum;
+use crate::network::error::{Icmpv6Error, NetError};
+use crate::network::ipv6::IPv6Protocol;
+use crate::network::module_traits::AsyncProtocolModule;
+use crate::network::packet::NetworkPacket;
+use crate::network::packet_meta::ChecksumStatus;
+use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData};
+use crate::network::ndp::{Ndp, ICMPV6_NEIGHBOR_ADVERT, ICMPV6_NEIGHBOR_SOLICIT};
+use crate::network::tx_queue::TxOutbox;
+use crate::network::router_adv::{RouterAdv, ICMPV6_ROUTER_ADVERT, ICMPV6_ROUTER_SOLICIT};
+
+pub const ICMPV6_HDR_LEN: usize = 4;
+/// Next header value of ICMPv6, as the checksum pseudo-header carries it.
+pub const IPPROTO_ICMPV6: u8 = 58;
 
 /// ICMPv6 key: destination IPv6 + type + code
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -21,13 +35,72 @@ pub struct Icmpv6Entry {
 pub struct ICMPv6Protocol {
     pub common: NetworkProtocolMng<Icmpv6Key, Icmpv6Entry>,
     pub default_hop_limit: u8,
+    pub(crate) ndp: Ndp,
+    pub(crate) router_adv: RouterAdv,
 }
 
 impl ICMPv6Protocol {
-    pub(crate) fn new() -> ICMPv6Protocol {
+    pub(crate) fn new(ipv6: Arc<IPv6Protocol>, outbox: TxOutbox) -> ICMPv6Protocol {
         ICMPv6Protocol {
             common: NetworkProtocolMng::new(ProtocolHeaderType::ICMPv6),
             default_hop_limit: 0,
+            ndp: Ndp::new(ipv6.clone(), outbox),
+            router_adv: RouterAdv::new(ipv6, outbox),
+        }
+    }
+
+    /// Verify the checksum over the pseudo-header and hand neighbor and
+    /// router discovery messages on; everything else ends here.
+    fn receive(&self, p: &NetworkPacket) -> Result<ProtocolMetaData, Icmpv6Error> {
+        let (src, dst) = match (p.meta().l3_src, p.meta().l3_dst) {
+            (Some(IpAddr::V6(src)), Some(IpAddr::V6(dst))) => (src, dst),
+            _ => return Err(Icmpv6Error::Malformed { offset: 0 }),
+        };
+        let len = p.data_len();
+        let msg = p.peek_front(len)
+            .map_err(|_| Icmpv6Error::Truncated { offset: 0, need: len })?;
+        if msg.len() < ICMPV6_HDR_LEN {
+            return Err(Icmpv6Error::Truncated { offset: 0, need: ICMPV6_HDR_LEN });
         }
+        if p.meta().l4_csum != ChecksumStatus::Verified {
+            let pseudo = checksum::pseudo_header_v6(&src, &dst, len as u32, IPPROTO_ICMPV6);
+            if checksum::finish(checksum::sum(pseudo, &msg)) != 0 {
+                p.meta_mut().l4_csum = ChecksumStatus::Bad;
+                return Err(Icmpv6Error::BadChecksum);
+            }
+            p.meta_mut().l4_csum = ChecksumStatus::Verified;
+        }
+        match msg[0] {
+            ICMPV6_NEIGHBOR_SOLICIT | ICMPV6_NEIGHBOR_ADVERT => self.ndp.receive(p, &msg)?,
+            ICMPV6_ROUTER_SOLICIT | ICMPV6_ROUTER_ADVERT => self.router_adv.receive(p, &msg, &self.ndp)?,
+            _ => {}
+        }
+        Ok(ProtocolMetaData::new())
+    }
+}
+
+impl AsyncProtocolModule<NetworkPacket> for ICMPv6Protocol {
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
+
+    async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
+        self.sync_encode(p)
+    }
+
+    async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
+        self.sync_decode(p)
+    }
+
+    fn sync_encode(&self, p: NetworkPacket) -> Self::EncodeResult {
+        println!("----- encode icmpv6 -----");
+        // messages are built whole, see `ndp::send_icmpv6`
+        (p, Ok(()))
+    }
+
+    fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
+        println!("----- decode icmpv6 -----");
+        // consumed here, nothing above
+        let res = self.receive(&p).map_err(NetError::from);
+        (p, res)
     }
 }
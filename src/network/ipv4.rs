@@ -2,21 +2,149 @@ use std::any::Any;
 use std::collections::hash_map::Entry;
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::{Hash, Hasher};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU16};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use flume::{Receiver, TryRecvError};
+use crate::executor::runtime::Runtime;
 use crate::network::arp::ArpProtocol;
+use crate::network::checksum;
+use crate::network::icmpv4;
+use crate::network::icmpv4::{ICMP_CODE_FRAG_NEEDED, ICMP_CODE_NET_UNREACH, ICMP_CODE_REASM_TIMEOUT, ICMP_CODE_TTL_EXCEEDED, ICMP_DEST_UNREACH, ICMP_TIME_EXCEEDED};
+use crate::network::ipv4_frag::{copied_options, Ipv4Reassembly, ReasmResult};
+use crate::network::fib_trie::RouteOrigin;
+use crate::network::ipv4_fib::{Ipv4Fib, Ipv4Route};
 use crate::network::ethernet::{EthEntry, EthKey, MacAddr};
+use crate::network::error::{Ipv4Error, NetError};
 use crate::network::module_traits::AsyncProtocolModule;
 use crate::network::packet::NetworkPacket;
-use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData, ProtocolResValue};
+use crate::network::packet_meta::{ChecksumStatus, PacketMeta};
+use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData, ProtocolRegistry, ProtocolResValue};
 use crate::network::subres::SubInfo;
+use crate::network::tx_queue::TxOutbox;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IPv4Addr {
     pub val: [u8; 4],
 }
 
+impl IPv4Addr {
+    pub const UNSPECIFIED: IPv4Addr = IPv4Addr { val: [0; 4] };
+    pub const BROADCAST: IPv4Addr = IPv4Addr { val: [255; 4] };
+
+    pub fn new(val: [u8; 4]) -> IPv4Addr {
+        IPv4Addr { val }
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        self.val == [0; 4]
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.val == [255; 4]
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.val[0] & 0xf0 == 0xe0
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.val[0] == 127
+    }
+
+    pub fn to_u32(&self) -> u32 {
+        u32::from_be_bytes(self.val)
+    }
+
+    pub fn from_u32(v: u32) -> IPv4Addr {
+        IPv4Addr { val: v.to_be_bytes() }
+    }
+}
+
+/// Address with a prefix length, "10.0.0.1/24". Without the "/len" part
+/// it is a host address (/32).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IPv4Cidr {
+    pub addr: IPv4Addr,
+    pub prefix_len: u8,
+}
+
+impl IPv4Cidr {
+    pub fn new(addr: IPv4Addr, prefix_len: u8) -> Result<IPv4Cidr, String> {
+        if prefix_len > 32 {
+            return Err(format!("Invalid prefix length: {}", prefix_len));
+        }
+        Ok(IPv4Cidr { addr, prefix_len })
+    }
+
+    /// 0.0.0.0/0
+    pub fn default_route() -> IPv4Cidr {
+        IPv4Cidr { addr: IPv4Addr::UNSPECIFIED, prefix_len: 0 }
+    }
+
+    pub fn netmask(&self) -> IPv4Addr {
+        IPv4Addr::from_u32(self.mask_u32())
+    }
+
+    fn mask_u32(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0)
+    }
+
+    /// The same prefix with the host bits cleared.
+    pub fn network(&self) -> IPv4Cidr {
+        IPv4Cidr { addr: IPv4Addr::from_u32(self.addr.to_u32() & self.mask_u32()), prefix_len: self.prefix_len }
+    }
+
+    /// Subnet broadcast address, none for /31 and /32 (RFC 3021).
+    pub fn broadcast(&self) -> Option<IPv4Addr> {
+        (self.prefix_len < 31).then(|| IPv4Addr::from_u32(self.addr.to_u32() | !self.mask_u32()))
+    }
+
+    pub fn contains(&self, addr: &IPv4Addr) -> bool {
+        (self.addr.to_u32() ^ addr.to_u32()) & self.mask_u32() == 0
+    }
+}
+
+impl From<IPv4Addr> for IPv4Cidr {
+    fn from(addr: IPv4Addr) -> Self {
+        IPv4Cidr { addr, prefix_len: 32 }
+    }
+}
+
+impl FromStr for IPv4Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((addr, len)) = s.split_once('/') else {
+            return Ok(IPv4Addr::from_str(s)?.into());
+        };
+        let prefix_len = len.parse::<u8>()
+            .map_err(|_| format!("Invalid prefix length: {}", len))?;
+        IPv4Cidr::new(IPv4Addr::from_str(addr)?, prefix_len)
+    }
+}
+
+impl Display for IPv4Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl From<Ipv4Addr> for IPv4Addr {
+    fn from(a: Ipv4Addr) -> Self {
+        IPv4Addr { val: a.octets() }
+    }
+}
+
+impl From<IPv4Addr> for Ipv4Addr {
+    fn from(a: IPv4Addr) -> Self {
+        Ipv4Addr::from(a.val)
+    }
+}
+
 impl FromStr for IPv4Addr {
     type Err = String;
 
@@ -44,10 +172,10 @@ impl Display for IPv4Addr {
     }
 }
 
-/// CIDR-aware key: network address + prefix length
+/// Key of our addresses: the address, unique across interfaces
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IPv4Key {
-    pub addr: IPv4Addr, // network address (masked)
+    pub addr: IPv4Addr, // host address, the prefix is in the entry
     pub sub: ProtocolResValue,
 }
 
@@ -64,87 +192,832 @@ impl Hash for IPv4Key {
     }
 }
 
-/// IPv4 resource entry: next hop + outbound interface + optional TTL
+pub const IPV4_HDR_LEN: usize = 20;
+pub const IPV4_MAX_HDR_LEN: usize = 60;
+pub const IPV4_DEFAULT_MTU: u16 = 1500;
+/// Every host has to take datagrams this big unfragmented (RFC 791).
+pub const IPV4_MIN_MTU: u16 = 68;
+const IPV4_FLAG_DF: u16 = 0x4000;
+const IPV4_FLAG_MF: u16 = 0x2000;
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+
+/// Decoded IPv4 header.
 #[derive(Debug, Clone)]
+pub struct Ipv4Header {
+    pub ihl: u8,
+    pub tos: u8,
+    pub total_len: u16,
+    pub ident: u16,
+    pub dont_frag: bool,
+    pub more_frags: bool,
+    /// in 8 byte units
+    pub frag_offset: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub checksum: u16,
+    pub src: IPv4Addr,
+    pub dst: IPv4Addr,
+    pub options: Vec<u8>,
+}
+
+impl Ipv4Header {
+    /// Parse and validate a header; `b` holds at least the whole header,
+    /// the checksum is not checked here.
+    pub fn parse(b: &[u8]) -> Result<Ipv4Header, Ipv4Error> {
+        if b.len() < IPV4_HDR_LEN {
+            return Err(Ipv4Error::Truncated { offset: 0, need: IPV4_HDR_LEN });
+        }
+        let version = b[0] >> 4;
+        if version != 4 {
+            return Err(Ipv4Error::BadVersion(version));
+        }
+        let ihl = b[0] & 0x0f;
+        let hdr_len = ihl as usize * 4;
+        if hdr_len < IPV4_HDR_LEN {
+            return Err(Ipv4Error::BadHeaderLength { offset: 0, ihl });
+        }
+        if b.len() < hdr_len {
+            return Err(Ipv4Error::Truncated { offset: IPV4_HDR_LEN, need: hdr_len });
+        }
+        let total_len = u16::from_be_bytes([b[2], b[3]]);
+        if (total_len as usize) < hdr_len {
+            return Err(Ipv4Error::BadTotalLength { offset: 2, len: total_len });
+        }
+        let frag = u16::from_be_bytes([b[6], b[7]]);
+        Ok(Ipv4Header {
+            ihl,
+            tos: b[1],
+            total_len,
+            ident: u16::from_be_bytes([b[4], b[5]]),
+            dont_frag: frag & IPV4_FLAG_DF != 0,
+            more_frags: frag & IPV4_FLAG_MF != 0,
+            frag_offset: frag & IPV4_FRAG_OFFSET_MASK,
+            ttl: b[8],
+            protocol: b[9],
+            checksum: u16::from_be_bytes([b[10], b[11]]),
+            src: IPv4Addr::new([b[12], b[13], b[14], b[15]]),
+            dst: IPv4Addr::new([b[16], b[17], b[18], b[19]]),
+            options: b[IPV4_HDR_LEN..hdr_len].to_vec(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ihl as usize * 4
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_frags || self.frag_offset != 0
+    }
+
+    /// Serialize with a freshly computed checksum. Options are padded to
+    /// a multiple of 4 bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let opt_len = self.options.len().div_ceil(4) * 4;
+        let mut b = vec![0u8; IPV4_HDR_LEN + opt_len];
+        b[0] = 0x40 | ((IPV4_HDR_LEN + opt_len) / 4) as u8;
+        b[1] = self.tos;
+        b[2..4].copy_from_slice(&self.total_len.to_be_bytes());
+        b[4..6].copy_from_slice(&self.ident.to_be_bytes());
+        let mut frag = self.frag_offset & IPV4_FRAG_OFFSET_MASK;
+        if self.dont_frag {
+            frag |= IPV4_FLAG_DF;
+        }
+        if self.more_frags {
+            frag |= IPV4_FLAG_MF;
+        }
+        b[6..8].copy_from_slice(&frag.to_be_bytes());
+        b[8] = self.ttl;
+        b[9] = self.protocol;
+        b[12..16].copy_from_slice(&self.src.val);
+        b[16..20].copy_from_slice(&self.dst.val);
+        b[IPV4_HDR_LEN..IPV4_HDR_LEN + self.options.len()].copy_from_slice(&self.options);
+        let csum = checksum::checksum(&b);
+        b[10..12].copy_from_slice(&csum.to_be_bytes());
+        b
+    }
+}
+
+/// How often `IPv4AddrEvents::recv` looks for a new event.
+const ADDR_EVENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where an address is in conflict detection (RFC 5227).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IPv4AddrState {
+    /// being probed, not used for sending or answered for yet
+    Tentative,
+    /// in use
+    Preferred,
+    /// another host has it, the address has been removed
+    Conflict,
+}
+
+/// What happened to an address added with `NetworkStack::add_ipv4`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IPv4AddrEvent {
+    /// probing found nobody else using it, the address is usable now
+    Bound(IPv4Addr),
+    /// `mac` uses the address too. If `defended` we announced ours and keep
+    /// it, otherwise the address is gone.
+    Conflict { addr: IPv4Addr, mac: MacAddr, defended: bool },
+}
+
+/// Receiving end of the events of one address.
+pub struct IPv4AddrEvents {
+    rx: Receiver<IPv4AddrEvent>,
+}
+
+impl IPv4AddrEvents {
+    pub(crate) fn new(rx: Receiver<IPv4AddrEvent>) -> IPv4AddrEvents {
+        IPv4AddrEvents { rx }
+    }
+
+    pub fn try_recv(&self) -> Option<IPv4AddrEvent> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Wait for the next event, `None` once the address is gone and all
+    /// events have been read. Must be awaited from a scheduler task.
+    pub async fn recv(&self) -> Option<IPv4AddrEvent> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(ev) => return Some(ev),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => Runtime::sleep(ADDR_EVENT_POLL_INTERVAL).await,
+            }
+        }
+    }
+}
+
+/// IPv4 resource entry: one of our addresses + its interface
+#[derive(Debug)]
 pub(crate) struct IPv4Entry {
-    addr: IPv4Addr, // network address (masked)
-    mask: u8,
+    addr: IPv4Addr, // host address
+    prefix_len: u8,
     mtu: u16,
     sub: Option<Arc<dyn Any + Send + Sync>>,
+    state: Mutex<IPv4AddrState>,
 }
 
 impl IPv4Entry {
-    pub fn new(addr: IPv4Addr, mtu: u16, sub: Option<Arc<dyn Any + Send + Sync>>) -> Self {
+    pub fn new(cidr: IPv4Cidr, mtu: u16, sub: Option<Arc<dyn Any + Send + Sync>>) -> Self {
         IPv4Entry {
-            addr,
-            mask: 0,
+            addr: cidr.addr,
+            prefix_len: cidr.prefix_len,
             mtu,
             sub,
+            state: Mutex::new(IPv4AddrState::Tentative),
         }
     }
+
+    pub fn get_addr(&self) -> &IPv4Addr {
+        &self.addr
+    }
+
+    pub fn get_cidr(&self) -> IPv4Cidr {
+        IPv4Cidr { addr: self.addr.clone(), prefix_len: self.prefix_len }
+    }
+
+    pub fn get_mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    pub(crate) fn get_state(&self) -> IPv4AddrState {
+        *self.state.lock().unwrap()
+    }
+
+    pub(crate) fn set_state(&self, state: IPv4AddrState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Interface the address is bound to.
+    pub(crate) fn eth_entry(&self) -> Option<Arc<EthEntry>> {
+        self.sub.clone()?.downcast::<EthEntry>().ok()
+    }
 }
 
 /// IPv4 protocol that embeds the shared manager and adds IPv4-specific knobs
 pub(crate) struct IPv4Protocol {
     pub common: NetworkProtocolMng<IPv4Key, Arc<IPv4Entry>>,
     pub ttl_default: u8,
-    pub mtu: u16,
-    pub allow_fragmentation: bool,
+    mtu: AtomicU16,
+    /// fragment our own packets that exceed the MTU, else send them with DF
+    allow_fragmentation: AtomicBool,
+    next_ident: AtomicU16,
+    fib: Ipv4Fib,
+    reasm: Mutex<Ipv4Reassembly>,
+    /// interfaces that route packets not addressed to us
+    forwarding: RwLock<Vec<Arc<EthEntry>>>,
+    registry: Arc<ProtocolRegistry>,
+    /// where ICMP errors are queued
+    outbox: TxOutbox,
 }
 
 impl IPv4Protocol {
-    pub(crate) fn new() -> IPv4Protocol {
+    pub(crate) fn new(registry: Arc<ProtocolRegistry>, outbox: TxOutbox) -> IPv4Protocol {
         IPv4Protocol {
             common: NetworkProtocolMng::new(ProtocolHeaderType::IPv4),
             ttl_default: 64,
-            mtu: 1500,
-            allow_fragmentation: false,
+            mtu: AtomicU16::new(IPV4_DEFAULT_MTU),
+            allow_fragmentation: AtomicBool::new(true),
+            next_ident: AtomicU16::new(0),
+            fib: Ipv4Fib::new(),
+            reasm: Mutex::new(Ipv4Reassembly::new()),
+            forwarding: RwLock::new(Vec::new()),
+            registry,
+            outbox,
         }
     }
 
-    pub(crate) fn add_ipv4(&self, addr: IPv4Addr, sub: Option<Arc<dyn Any + Send + Sync>>,) -> Result<(), ()> {
-        let key = IPv4Key::new(addr.clone(), ProtocolResValue::default());
-        let ent = Arc::new(IPv4Entry::new(addr.clone(), 1000, sub));
-        let mut ret = Err(());
+    pub(crate) fn get_mtu(&self) -> u16 {
+        self.mtu.load(Relaxed)
+    }
+
+    pub(crate) fn set_mtu(&self, mtu: u16) -> Result<(), Ipv4Error> {
+        if mtu < IPV4_MIN_MTU {
+            return Err(Ipv4Error::BadMtu(mtu));
+        }
+        self.mtu.store(mtu, Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn set_fragmentation(&self, on: bool) {
+        self.allow_fragmentation.store(on, Relaxed);
+    }
+
+    /// Route packets received on `iface` that are not for us, off by
+    /// default.
+    pub(crate) fn set_forwarding(&self, iface: Arc<EthEntry>, on: bool) {
+        let mut w = self.forwarding.write().unwrap();
+        w.retain(|e| !Arc::ptr_eq(e, &iface));
+        if on {
+            w.push(iface);
+        }
+    }
+
+    /// Forget an interface that goes away: its routes and forwarding flag.
+    /// Its addresses are removed one by one before.
+    pub(crate) fn forget_iface(&self, iface: &Arc<EthEntry>) {
+        self.set_forwarding(iface.clone(), false);
+        self.fib.remove_iface(iface);
+    }
+
+    /// Whether the interface a packet came in on forwards. Only frames sent
+    /// to the interface's own MAC count, not broadcasts or promiscuous ones.
+    fn forwards(&self, m: &PacketMeta) -> bool {
+        let Some(l2_dst) = m.l2_dst else {
+            return false;
+        };
+        self.forwarding.read().unwrap().iter().any(|e| {
+            e.get_mac().mac == l2_dst && e.get_vlan() == m.vlan && e.get_svlan() == m.svlan
+        })
+    }
+
+    /// Add a tentative address, usable once conflict detection is done,
+    /// and the route of its subnet.
+    pub(crate) fn add_ipv4(&self, cidr: IPv4Cidr, sub: Option<Arc<dyn Any + Send + Sync>>,) -> Result<Arc<IPv4Entry>, Ipv4Error> {
+        let key = IPv4Key::new(cidr.addr.clone(), ProtocolResValue::default());
+        let ent = Arc::new(IPv4Entry::new(cidr.clone(), self.get_mtu(), sub));
         {
             let mut w = self.common.res_write_borrow();
             match (*w).entry(key) {
                 Entry::Vacant(v) => {
-                    v.insert(ent);
-                    ret = Ok(())
+                    v.insert(ent.clone());
                 }
-                Entry::Occupied(_) => {}
+                Entry::Occupied(_) => return Err(Ipv4Error::DuplicateAddress(cidr.addr)),
             }
         }
-        ret
+        if let Some(iface) = ent.eth_entry() {
+            self.fib.add_connected(cidr.clone(), iface, cidr.addr);
+        }
+        Ok(ent)
+    }
+
+    /// Remove an address. Its subnet route goes too, unless another address
+    /// of the interface is in the same subnet.
+    pub(crate) fn remove_ipv4(&self, addr: &IPv4Addr) -> Option<Arc<IPv4Entry>> {
+        let key = IPv4Key::new(addr.clone(), ProtocolResValue::default());
+        let ent = self.common.res_write_borrow().remove(&key)?;
+        let Some(iface) = ent.eth_entry() else {
+            return Some(ent);
+        };
+        let subnet = ent.get_cidr().network();
+        self.fib.remove_connected(&subnet, &iface);
+        let other = self.common.res_read_borrow().values()
+            .find(|e| e.get_cidr().network() == subnet && e.eth_entry().is_some_and(|i| Arc::ptr_eq(&i, &iface)))
+            .cloned();
+        if let Some(other) = other {
+            self.fib.add_connected(subnet, iface, other.get_addr().clone());
+        }
+        Some(ent)
+    }
+
+    /// All our addresses.
+    pub(crate) fn entries(&self) -> Vec<Arc<IPv4Entry>> {
+        self.common.res_read_borrow().values().cloned().collect()
+    }
+
+    /// Route to `dst`, with the source address filled in.
+    pub(crate) fn route(&self, dst: &IPv4Addr) -> Option<Ipv4Route> {
+        let mut route = self.fib.lookup(dst)?;
+        if route.src.is_none() {
+            route.src = self.select_src(&route.iface, &route.next_hop(dst));
+        }
+        Some(route)
+    }
+
+    /// Source address for packets leaving through `iface` towards
+    /// `next_hop`: one in the next hop's subnet, else any of the interface,
+    /// else any at all.
+    fn select_src(&self, iface: &Arc<EthEntry>, next_hop: &IPv4Addr) -> Option<IPv4Addr> {
+        let r = self.common.res_read_borrow();
+        let on_iface: Vec<&Arc<IPv4Entry>> = r.values()
+            .filter(|e| e.get_state() == IPv4AddrState::Preferred)
+            .filter(|e| e.eth_entry().is_some_and(|i| Arc::ptr_eq(&i, iface)))
+            .collect();
+        on_iface.iter()
+            .find(|e| e.get_cidr().contains(next_hop))
+            .or(on_iface.first())
+            .map(|e| e.get_addr().clone())
+            .or_else(|| {
+                drop(r);
+                self.any_local().map(|e| e.get_addr().clone())
+            })
+    }
+
+    /// Add a static route. Without `iface` the gateway has to be on one of
+    /// our subnets, whose interface is used.
+    pub(crate) fn add_route(&self, prefix: IPv4Cidr, gateway: Option<IPv4Addr>, iface: Option<Arc<EthEntry>>, metric: u32) -> Result<(), Ipv4Error> {
+        let iface = match (iface, &gateway) {
+            (Some(iface), _) => iface,
+            (None, Some(gw)) => self.fib.lookup(gw)
+                .filter(|r| r.origin == RouteOrigin::Connected)
+                .map(|r| r.iface)
+                .ok_or_else(|| Ipv4Error::GatewayUnreachable(gw.clone()))?,
+            (None, None) => return Err(Ipv4Error::NoInterface),
+        };
+        let route = Ipv4Route {
+            prefix: prefix.network(),
+            gateway,
+            iface,
+            src: None,
+            metric,
+            origin: RouteOrigin::Static,
+        };
+        if !self.fib.add(route) {
+            return Err(Ipv4Error::RouteExists(prefix.network()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove_route(&self, prefix: &IPv4Cidr, gateway: Option<&IPv4Addr>) -> Result<(), Ipv4Error> {
+        match self.fib.remove_static(prefix, gateway) {
+            0 => Err(Ipv4Error::RouteNotFound(prefix.network())),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn routes(&self) -> Vec<Ipv4Route> {
+        self.fib.routes()
+    }
+
+    /// Our entry for `addr`, if it is one of the configured addresses and
+    /// in use.
+    pub(crate) fn lookup_local(&self, addr: &IPv4Addr) -> Option<Arc<IPv4Entry>> {
+        let key = IPv4Key::new(addr.clone(), ProtocolResValue::default());
+        self.common.res_read_borrow().get(&key)
+            .filter(|e| e.get_state() == IPv4AddrState::Preferred)
+            .cloned()
+    }
+
+    /// Whether a packet to `dst` is delivered locally: one of our addresses,
+    /// the broadcast of one of our subnets, the limited broadcast or a
+    /// multicast group.
+    pub(crate) fn is_local_dst(&self, dst: &IPv4Addr) -> bool {
+        dst.is_broadcast() || dst.is_multicast() || self.lookup_local(dst).is_some()
+            || self.common.res_read_borrow().values().any(|e| e.get_cidr().broadcast().as_ref() == Some(dst))
+    }
+
+    /// Validate the header and strip it. Fragments are held until their
+    /// datagram is complete, which then continues in place of `p`.
+    fn decode_packet(&self, p: NetworkPacket) -> (NetworkPacket, Result<ProtocolMetaData, Ipv4Error>) {
+        let (hdr, bytes) = match self.check_header(&p) {
+            Ok(hdr) => hdr,
+            Err(e) => return (p, Err(e)),
+        };
+        if !self.is_local_dst(&hdr.dst) {
+            let res = if self.forwards(&p.meta()) {
+                self.forward(&p, &hdr)
+            } else {
+                Err(Ipv4Error::NotForUs(hdr.dst))
+            };
+            return (p, res);
+        }
+        if hdr.is_fragment() {
+            return match self.reassemble(&p, &hdr, &bytes) {
+                Ok(Some(whole)) => self.decode_packet(whole),
+                // kept for now
+                Ok(None) => (p, Ok(ProtocolMetaData::new())),
+                Err(e) => (p, Err(e)),
+            };
+        }
+        let res = self.strip_header(&p, &hdr);
+        (p, res)
+    }
+
+    fn check_header(&self, p: &NetworkPacket) -> Result<(Ipv4Header, Vec<u8>), Ipv4Error> {
+        let base = p.peek_front(IPV4_HDR_LEN)
+            .map_err(|_| Ipv4Error::Truncated { offset: 0, need: IPV4_HDR_LEN })?;
+        let hdr_len = (base[0] & 0x0f) as usize * 4;
+        let bytes = if hdr_len > IPV4_HDR_LEN {
+            p.peek_front(hdr_len)
+                .map_err(|_| Ipv4Error::Truncated { offset: IPV4_HDR_LEN, need: hdr_len })?
+        } else {
+            base
+        };
+        let hdr = Ipv4Header::parse(&bytes)?;
+
+        let verified = p.meta().l3_csum == ChecksumStatus::Verified;
+        if !verified && !checksum::verify(&bytes[..hdr.len()]) {
+            p.meta_mut().l3_csum = ChecksumStatus::Bad;
+            return Err(Ipv4Error::BadChecksum);
+        }
+        let total_len = hdr.total_len as usize;
+        if p.data_len() < total_len {
+            return Err(Ipv4Error::Truncated { offset: 0, need: total_len });
+        }
+        if hdr.src.is_broadcast() || hdr.src.is_multicast() {
+            return Err(Ipv4Error::BadSource(hdr.src));
+        }
+        // the link layer may have padded the frame
+        p.trim(total_len).map_err(|_| Ipv4Error::Truncated { offset: 0, need: total_len })?;
+        Ok((hdr, bytes))
+    }
+
+    fn strip_header(&self, p: &NetworkPacket, hdr: &Ipv4Header) -> Result<ProtocolMetaData, Ipv4Error> {
+        let Some(pt) = self.registry.by_ip_proto(hdr.protocol) else {
+            return Err(Ipv4Error::UnknownProtocol(hdr.protocol));
+        };
+
+        let l3_offset = p.data_offset();
+        p.pull_header(ProtocolHeaderType::IPv4, hdr.len())
+            .map_err(|_| Ipv4Error::Truncated { offset: 0, need: hdr.len() })?;
+        {
+            let mut m = p.meta_mut();
+            m.l3_offset = Some(l3_offset);
+            m.l3_proto = ProtocolHeaderType::IPv4;
+            m.l3_src = Some(IpAddr::V4(hdr.src.clone().into()));
+            m.l3_dst = Some(IpAddr::V4(hdr.dst.clone().into()));
+            m.l3_csum = ChecksumStatus::Verified;
+            m.hop_limit = Some(hdr.ttl);
+            m.l4_offset = Some(p.data_offset());
+            m.l4_proto = pt;
+        }
+
+        let mut meta = ProtocolMetaData::new();
+        meta.set_pt(pt);
+        Ok(meta)
+    }
+
+    /// Decrement the TTL of a packet to be routed on and hand it back for
+    /// the stack to send. Routers send the ICMP errors of RFC 1812 when the
+    /// TTL runs out or there is no route.
+    fn forward(&self, p: &NetworkPacket, hdr: &Ipv4Header) -> Result<ProtocolMetaData, Ipv4Error> {
+        if hdr.src.is_unspecified() || hdr.src.is_loopback() || hdr.dst.is_loopback() {
+            return Err(Ipv4Error::BadSource(hdr.src.clone()));
+        }
+        if hdr.ttl <= 1 {
+            self.send_icmp_error(p, ICMP_TIME_EXCEEDED, ICMP_CODE_TTL_EXCEEDED, [0; 4]);
+            return Err(Ipv4Error::TtlExceeded);
+        }
+        if self.fib.lookup(&hdr.dst).is_none() {
+            self.send_icmp_error(p, ICMP_DEST_UNREACH, ICMP_CODE_NET_UNREACH, [0; 4]);
+            return Err(Ipv4Error::NoRoute(hdr.dst.clone()));
+        }
+
+        // TTL shares its word with the protocol
+        let old = u16::from_be_bytes([hdr.ttl, hdr.protocol]);
+        let new = u16::from_be_bytes([hdr.ttl - 1, hdr.protocol]);
+        let csum = checksum::update(hdr.checksum, old, new);
+        p.write_front(8, &[hdr.ttl - 1]).map_err(|_| Ipv4Error::NoHeadroom)?;
+        p.write_front(10, &csum.to_be_bytes()).map_err(|_| Ipv4Error::NoHeadroom)?;
+        {
+            let mut m = p.meta_mut();
+            m.l3_offset = Some(p.data_offset());
+            m.l3_proto = ProtocolHeaderType::IPv4;
+            m.l3_src = Some(IpAddr::V4(hdr.src.clone().into()));
+            m.l3_dst = Some(IpAddr::V4(hdr.dst.clone().into()));
+            m.l4_proto = ProtocolHeaderType::None;
+            m.l2_src = None;
+            m.l2_dst = None;
+            m.forwarded = true;
+        }
+        let mut meta = ProtocolMetaData::new();
+        meta.set_pt(ProtocolHeaderType::Forward);
+        Ok(meta)
+    }
+
+    /// Add a fragment to its datagram; the complete datagram as a new
+    /// packet once all fragments are in.
+    fn reassemble(&self, p: &NetworkPacket, hdr: &Ipv4Header, hdr_bytes: &[u8]) -> Result<Option<NetworkPacket>, Ipv4Error> {
+        let mut data = vec![0; p.data_len() - hdr.len()];
+        p.copy_out(hdr.len(), &mut data)
+            .map_err(|_| Ipv4Error::Truncated { offset: 0, need: hdr.total_len as usize })?;
+        let res = self.reasm.lock().unwrap().add(hdr, &hdr_bytes[..hdr.len()], data, Runtime::get_time_usec())?;
+        let ReasmResult::Complete(whole) = res else {
+            return Ok(None);
+        };
+        let q = NetworkPacket::new();
+        q.push_data(&whole).map_err(|_| Ipv4Error::NoHeadroom)?;
+        {
+            let mut m = q.meta_mut();
+            *m = p.meta().clone();
+            m.l2_offset = None;
+            m.l3_csum = ChecksumStatus::Unknown;
+        }
+        Ok(Some(q))
+    }
+
+    /// Split an encoded datagram that exceeds the MTU. Empty if it fits.
+    /// With DF set the datagram is dropped instead; if it isn't ours the
+    /// source gets an ICMP frag-needed.
+    pub(crate) fn fragment(&self, p: &NetworkPacket) -> Result<Vec<NetworkPacket>, Ipv4Error> {
+        let mtu = self.get_mtu() as usize;
+        if p.data_len() <= mtu {
+            return Ok(Vec::new());
+        }
+        let base = p.peek_front(IPV4_HDR_LEN)
+            .map_err(|_| Ipv4Error::Truncated { offset: 0, need: IPV4_HDR_LEN })?;
+        let hdr_len = (base[0] & 0x0f) as usize * 4;
+        let hdr = Ipv4Header::parse(&p.peek_front(hdr_len)
+            .map_err(|_| Ipv4Error::Truncated { offset: IPV4_HDR_LEN, need: hdr_len })?)?;
+        if hdr.dont_frag {
+            if self.lookup_local(&hdr.src).is_none() {
+                let rest = [0, 0, (mtu >> 8) as u8, mtu as u8];
+                self.send_icmp_error(p, ICMP_DEST_UNREACH, ICMP_CODE_FRAG_NEEDED, rest);
+            }
+            return Err(Ipv4Error::FragmentationNeeded { mtu: mtu as u16 });
+        }
+
+        let mut payload = vec![0; p.data_len() - hdr.len()];
+        p.copy_out(hdr.len(), &mut payload)
+            .map_err(|_| Ipv4Error::Truncated { offset: 0, need: hdr.total_len as usize })?;
+        let later_opts = copied_options(&hdr.options);
+        let mut frags = Vec::new();
+        let mut off = 0;
+        while off < payload.len() {
+            let options = if off == 0 { hdr.options.clone() } else { later_opts.clone() };
+            let frag_hdr_len = IPV4_HDR_LEN + options.len().div_ceil(4) * 4;
+            // all but the last fragment carry a multiple of 8 bytes
+            let max = mtu.saturating_sub(frag_hdr_len) & !7;
+            if max == 0 {
+                return Err(Ipv4Error::FragmentationNeeded { mtu: mtu as u16 });
+            }
+            let len = max.min(payload.len() - off);
+            let last = off + len == payload.len();
+            let frag_hdr = Ipv4Header {
+                ihl: (frag_hdr_len / 4) as u8,
+                total_len: (frag_hdr_len + len) as u16,
+                more_frags: !last || hdr.more_frags,
+                frag_offset: hdr.frag_offset + (off / 8) as u16,
+                options,
+                ..hdr.clone()
+            };
+            let f = NetworkPacket::new();
+            f.push_data(&payload[off..off + len]).map_err(|_| Ipv4Error::NoHeadroom)?;
+            f.push_header(ProtocolHeaderType::IPv4, &frag_hdr.to_bytes())
+                .map_err(|_| Ipv4Error::NoHeadroom)?;
+            {
+                let mut m = f.meta_mut();
+                *m = p.meta().clone();
+                m.l3_offset = Some(f.data_offset());
+            }
+            frags.push(f);
+            off += len;
+        }
+        Ok(frags)
+    }
+
+    /// Queue an ICMP error about `p`, which starts with its IPv4 header.
+    fn send_icmp_error(&self, p: &NetworkPacket, icmp_type: u8, code: u8, rest: [u8; 4]) {
+        let quote = p.peek_front(IPV4_MAX_HDR_LEN + 8)
+            .or_else(|_| p.peek_front(p.data_len()));
+        if let Some(err) = quote.ok().and_then(|q| icmpv4::error_packet(&q, icmp_type, code, rest)) {
+            self.outbox.push(err);
+        }
+    }
+
+    /// Drop timed out reassemblies and tell their sources. Returns how many
+    /// were dropped.
+    pub(crate) fn tick(&self, now: u64) -> usize {
+        let (expired, quotes) = self.reasm.lock().unwrap().expire(now);
+        for q in quotes {
+            if let Some(err) = icmpv4::error_packet(&q, ICMP_TIME_EXCEEDED, ICMP_CODE_REASM_TIMEOUT, [0; 4]) {
+                self.outbox.push(err);
+            }
+        }
+        expired
+    }
+
+    /// Build the header from the packet metadata: `l3_dst` and `l4_proto`
+    /// have to be set by the layers above, a missing `l3_src` is filled
+    /// with one of our addresses.
+    fn encode_packet(&self, p: &NetworkPacket) -> Result<(), Ipv4Error> {
+        let (src, dst, pt) = {
+            let m = p.meta();
+            let Some(IpAddr::V4(dst)) = m.l3_dst else {
+                return Err(Ipv4Error::MissingAddress);
+            };
+            let src = match m.l3_src {
+                Some(IpAddr::V4(src)) => IPv4Addr::from(src),
+                _ => self.any_local().ok_or(Ipv4Error::MissingAddress)?.get_addr().clone(),
+            };
+            (src, IPv4Addr::from(dst), m.l4_proto)
+        };
+        let Some(protocol) = self.registry.ip_proto(pt) else {
+            return Err(Ipv4Error::NoProtocol(pt));
+        };
+        let total_len = IPV4_HDR_LEN + p.data_len();
+        if total_len > u16::MAX as usize {
+            return Err(Ipv4Error::TooLong(total_len));
+        }
+
+        let hdr = Ipv4Header {
+            ihl: (IPV4_HDR_LEN / 4) as u8,
+            tos: 0,
+            total_len: total_len as u16,
+            ident: self.next_ident.fetch_add(1, Relaxed),
+            dont_frag: !self.allow_fragmentation.load(Relaxed),
+            more_frags: false,
+            frag_offset: 0,
+            ttl: p.meta().hop_limit.unwrap_or(self.ttl_default),
+            protocol,
+            checksum: 0,
+            src: src.clone(),
+            dst,
+            options: Vec::new(),
+        };
+        p.push_header(ProtocolHeaderType::IPv4, &hdr.to_bytes())
+            .map_err(|_| Ipv4Error::NoHeadroom)?;
+        let mut m = p.meta_mut();
+        m.l3_offset = Some(p.data_offset());
+        m.l3_proto = ProtocolHeaderType::IPv4;
+        m.l3_src = Some(IpAddr::V4(src.into()));
+        Ok(())
+    }
+
+    /// Some address in use, to source packets from when nothing better is
+    /// known.
+    pub(crate) fn any_local(&self) -> Option<Arc<IPv4Entry>> {
+        self.common.res_read_borrow().values()
+            .find(|e| e.get_state() == IPv4AddrState::Preferred)
+            .cloned()
     }
 }
 
+
 impl AsyncProtocolModule<NetworkPacket> for IPv4Protocol {
-    type EncodeResult = (NetworkPacket, Result<(), ()>);
-    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
 
     async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
-        println!("----- encode ipv4 -----");
-        (p, Ok(()))
+        self.sync_encode(p)
     }
 
     async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
-        println!("----- decode ipv4 -----");
-        let mut meta = ProtocolMetaData::new();
-        meta.set_pt(ProtocolHeaderType::UDP);
-        (p, Ok(meta))
+        self.sync_decode(p)
     }
 
     fn sync_encode(&self, p: NetworkPacket) -> Self::EncodeResult {
         println!("----- encode ipv4 -----");
-        (p, Ok(()))
+        let res = self.encode_packet(&p).map_err(NetError::from);
+        (p, res)
     }
 
     fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode ipv4 -----");
-        let mut meta = ProtocolMetaData::new();
-        meta.set_pt(ProtocolHeaderType::UDP);
-        (p, Ok(meta))
+        let (p, res) = self.decode_packet(p);
+        (p, res.map_err(NetError::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 192.168.0.1 -> 192.168.0.199, UDP, DF, checksum 0xb861.
+    const SAMPLE: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+        0xb8, 0x61, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+
+    fn header(options: Vec<u8>) -> Ipv4Header {
+        Ipv4Header {
+            // to_bytes works it out from the options
+            ihl: 5,
+            tos: 0x10,
+            total_len: 100,
+            ident: 0x1234,
+            dont_frag: false,
+            more_frags: true,
+            frag_offset: 0x0abc,
+            ttl: 17,
+            protocol: 6,
+            checksum: 0,
+            src: IPv4Addr::new([10, 0, 0, 1]),
+            dst: IPv4Addr::new([10, 1, 2, 3]),
+            options,
+        }
+    }
+
+    #[test]
+    fn parse_sample() {
+        let h = Ipv4Header::parse(&SAMPLE).unwrap();
+        assert_eq!(h.ihl, 5);
+        assert_eq!(h.len(), IPV4_HDR_LEN);
+        assert_eq!(h.total_len, 0x73);
+        assert!(h.dont_frag);
+        assert!(!h.is_fragment());
+        assert_eq!(h.ttl, 64);
+        assert_eq!(h.protocol, 17);
+        assert_eq!(h.checksum, 0xb861);
+        assert_eq!(h.src, IPv4Addr::new([192, 168, 0, 1]));
+        assert_eq!(h.dst, IPv4Addr::new([192, 168, 0, 199]));
+        assert!(h.options.is_empty());
+        assert!(checksum::verify(&SAMPLE));
+    }
+
+    #[test]
+    fn to_bytes_recomputes_checksum() {
+        let mut h = Ipv4Header::parse(&SAMPLE).unwrap();
+        assert_eq!(h.to_bytes(), SAMPLE);
+        h.checksum = 0;
+        assert_eq!(h.to_bytes(), SAMPLE);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn round_trip() {
+        let b = header(Vec::new()).to_bytes();
+        assert!(checksum::verify(&b));
+        let h = Ipv4Header::parse(&b).unwrap();
+        assert_eq!(h.ihl, 5);
+        assert_eq!(h.tos, 0x10);
+        assert_eq!(h.total_len, 100);
+        assert_eq!(h.ident, 0x1234);
+        assert!(!h.dont_frag);
+        assert!(h.more_frags);
+        assert_eq!(h.frag_offset, 0x0abc);
+        assert!(h.is_fragment());
+        assert_eq!(h.ttl, 17);
+        assert_eq!(h.protocol, 6);
+        assert_eq!(h.src, IPv4Addr::new([10, 0, 0, 1]));
+        assert_eq!(h.dst, IPv4Addr::new([10, 1, 2, 3]));
+        assert_eq!(h.to_bytes(), b);
+    }
+
+    #[test]
+    fn options_padded() {
+        // router alert, 4 bytes, then a lone NOP padded out to 8
+        let b = header(vec![0x94, 0x04, 0x00, 0x00, 0x01]).to_bytes();
+        assert_eq!(b.len(), IPV4_HDR_LEN + 8);
+        assert_eq!(b[0], 0x47);
+        assert!(checksum::verify(&b));
+        let h = Ipv4Header::parse(&b).unwrap();
+        assert_eq!(h.ihl, 7);
+        assert_eq!(h.len(), IPV4_HDR_LEN + 8);
+        assert_eq!(h.options, [0x94, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(Ipv4Header::parse(&[]).unwrap_err(), Ipv4Error::Truncated { offset: 0, need: IPV4_HDR_LEN });
+        assert_eq!(Ipv4Header::parse(&SAMPLE[..19]).unwrap_err(), Ipv4Error::Truncated { offset: 0, need: IPV4_HDR_LEN });
+        // IHL says 24 bytes, only the fixed part is there
+        let mut b = SAMPLE;
+        b[0] = 0x46;
+        assert_eq!(Ipv4Header::parse(&b).unwrap_err(), Ipv4Error::Truncated { offset: IPV4_HDR_LEN, need: 24 });
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut b = SAMPLE;
+        b[0] = 0x65;
+        assert_eq!(Ipv4Header::parse(&b).unwrap_err(), Ipv4Error::BadVersion(6));
+    }
+
+    #[test]
+    fn bad_header_length() {
+        let mut b = SAMPLE;
+        b[0] = 0x44;
+        assert_eq!(Ipv4Header::parse(&b).unwrap_err(), Ipv4Error::BadHeaderLength { offset: 0, ihl: 4 });
+    }
+
+    #[test]
+    fn bad_total_length() {
+        let mut b = SAMPLE;
+        b[2..4].copy_from_slice(&19u16.to_be_bytes());
+        assert_eq!(Ipv4Header::parse(&b).unwrap_err(), Ipv4Error::BadTotalLength { offset: 2, len: 19 });
+        // exactly the header is fine
+        b[2..4].copy_from_slice(&20u16.to_be_bytes());
+        assert!(Ipv4Header::parse(&b).is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::{Arc, RwLock};
+use crate::network::ethernet::{EthEntry, MacAddr};
+use crate::network::fib_trie::{RouteOrigin, Trie, TrieRoute};
+use crate::network::ipv4::{IPv4Addr, IPv4Cidr};
+
+/// IPv4 route: destination prefix, next hop and outgoing interface.
+#[derive(Debug, Clone)]
+pub(crate) struct Ipv4Route {
+    /// network form, host bits zero
+    pub prefix: IPv4Cidr,
+    /// `None` for on-link destinations
+    pub gateway: Option<IPv4Addr>,
+    pub iface: Arc<EthEntry>,
+    /// preferred source address
+    pub src: Option<IPv4Addr>,
+    /// lower wins among routes of the same prefix
+    pub metric: u32,
+    pub origin: RouteOrigin,
+}
+
+impl Ipv4Route {
+    /// The next hop for `dst`: the gateway, or `dst` itself when on-link.
+    pub(crate) fn next_hop(&self, dst: &IPv4Addr) -> IPv4Addr {
+        self.gateway.clone().unwrap_or_else(|| dst.clone())
+    }
+
+    pub(crate) fn info(&self) -> Ipv4RouteInfo {
+        Ipv4RouteInfo {
+            prefix: self.prefix.clone(),
+            gateway: self.gateway.clone(),
+            mac: self.iface.get_mac(),
+            vlan: self.iface.get_vlan(),
+            svlan: self.iface.get_svlan(),
+            src: self.src.clone(),
+            metric: self.metric,
+            origin: self.origin,
+        }
+    }
+}
+
+impl TrieRoute for Ipv4Route {
+    fn key(&self) -> (u128, u8) {
+        (prefix_key(&self.prefix.addr), self.prefix.prefix_len)
+    }
+
+    fn metric(&self) -> u32 {
+        self.metric
+    }
+
+    fn same_path(&self, other: &Ipv4Route) -> bool {
+        self.gateway == other.gateway && Arc::ptr_eq(&self.iface, &other.iface) && self.origin == other.origin
+    }
+}
+
+/// An address as trie key, in the top 32 bits.
+fn prefix_key(addr: &IPv4Addr) -> u128 {
+    (addr.to_u32() as u128) << 96
+}
+
+/// A route as `NetworkStack::ipv4_routes` lists it; the interface is given
+/// by its MAC and VLAN tags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv4RouteInfo {
+    pub prefix: IPv4Cidr,
+    pub gateway: Option<IPv4Addr>,
+    pub mac: MacAddr,
+    pub vlan: Option<u16>,
+    pub svlan: Option<u16>,
+    pub src: Option<IPv4Addr>,
+    pub metric: u32,
+    pub origin: RouteOrigin,
+}
+
+/// IPv4 forwarding table, longest prefix match over a binary trie.
+pub(crate) struct Ipv4Fib {
+    trie: RwLock<Trie<Ipv4Route>>,
+}
+
+impl Ipv4Fib {
+    pub(crate) fn new() -> Ipv4Fib {
+        Ipv4Fib { trie: RwLock::new(Trie::new()) }
+    }
+
+    /// Add the route of a connected subnet, unless the interface has it.
+    pub(crate) fn add_connected(&self, prefix: IPv4Cidr, iface: Arc<EthEntry>, src: IPv4Addr) {
+        let route = Ipv4Route {
+            prefix: prefix.network(),
+            gateway: None,
+            iface,
+            src: Some(src),
+            metric: 0,
+            origin: RouteOrigin::Connected,
+        };
+        self.trie.write().unwrap().insert(route);
+    }
+
+    pub(crate) fn remove_connected(&self, prefix: &IPv4Cidr, iface: &Arc<EthEntry>) {
+        let prefix = prefix.network();
+        self.trie.write().unwrap().remove((prefix_key(&prefix.addr), prefix.prefix_len), |r| {
+            r.origin == RouteOrigin::Connected && Arc::ptr_eq(&r.iface, iface)
+        });
+    }
+
+    /// Add a route, false if the prefix has one with the same gateway,
+    /// interface and origin already.
+    pub(crate) fn add(&self, route: Ipv4Route) -> bool {
+        self.trie.write().unwrap().insert(route)
+    }
+
+    /// Remove the static routes of `prefix`, only the one via `gateway` if
+    /// given. Returns how many went.
+    pub(crate) fn remove_static(&self, prefix: &IPv4Cidr, gateway: Option<&IPv4Addr>) -> usize {
+        let prefix = prefix.network();
+        self.trie.write().unwrap().remove((prefix_key(&prefix.addr), prefix.prefix_len), |r| {
+            r.origin == RouteOrigin::Static && gateway.is_none_or(|gw| r.gateway.as_ref() == Some(gw))
+        })
+    }
+
+    /// Remove every route out of `iface`, of any origin.
+    pub(crate) fn remove_iface(&self, iface: &Arc<EthEntry>) -> usize {
+        self.trie.write().unwrap().remove_all(|r| Arc::ptr_eq(&r.iface, iface))
+    }
+
+    /// Longest prefix match, the lowest metric among routes of that prefix.
+    pub(crate) fn lookup(&self, dst: &IPv4Addr) -> Option<Ipv4Route> {
+        self.trie.read().unwrap().lookup(prefix_key(dst)).cloned()
+    }
+
+    /// All routes, shorter prefixes first along each branch.
+    pub(crate) fn routes(&self) -> Vec<Ipv4Route> {
+        self.trie.read().unwrap().routes()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(a: [u8; 4]) -> IPv4Addr {
+        IPv4Addr::new(a)
+    }
+
+    fn cidr(a: [u8; 4], len: u8) -> IPv4Cidr {
+        IPv4Cidr::new(ip(a), len).unwrap()
+    }
+
+    fn iface(last: u8) -> Arc<EthEntry> {
+        Arc::new(EthEntry::new(MacAddr::new([0x02, 0, 0, 0, 0, last]), None, None))
+    }
+
+    fn static_route(prefix: IPv4Cidr, gateway: [u8; 4], iface: &Arc<EthEntry>, metric: u32) -> Ipv4Route {
+        Ipv4Route {
+            prefix: prefix.network(),
+            gateway: Some(ip(gateway)),
+            iface: iface.clone(),
+            src: None,
+            metric,
+            origin: RouteOrigin::Static,
+        }
+    }
+
+    fn gateway(fib: &Ipv4Fib, dst: [u8; 4]) -> Option<IPv4Addr> {
+        fib.lookup(&ip(dst)).and_then(|r| r.gateway)
+    }
+
+    #[test]
+    fn longest_prefix_match() {
+        let fib = Ipv4Fib::new();
+        let eth0 = iface(1);
+        fib.add_connected(cidr([10, 0, 0, 7], 24), eth0.clone(), ip([10, 0, 0, 7]));
+        assert!(fib.add(static_route(IPv4Cidr::default_route(), [10, 0, 0, 254], &eth0, 0)));
+        assert!(fib.add(static_route(cidr([10, 1, 0, 0], 16), [10, 0, 0, 1], &eth0, 0)));
+        assert!(fib.add(static_route(cidr([10, 1, 2, 0], 24), [10, 0, 0, 2], &eth0, 0)));
+
+        let r = fib.lookup(&ip([10, 0, 0, 9])).unwrap();
+        assert_eq!(r.origin, RouteOrigin::Connected);
+        assert_eq!(r.prefix, cidr([10, 0, 0, 0], 24));
+        assert_eq!(r.src, Some(ip([10, 0, 0, 7])));
+        assert_eq!(r.next_hop(&ip([10, 0, 0, 9])), ip([10, 0, 0, 9]));
+
+        assert_eq!(gateway(&fib, [10, 1, 9, 9]), Some(ip([10, 0, 0, 1])));
+        assert_eq!(gateway(&fib, [10, 1, 2, 9]), Some(ip([10, 0, 0, 2])));
+        assert_eq!(gateway(&fib, [8, 8, 8, 8]), Some(ip([10, 0, 0, 254])));
+    }
+
+    #[test]
+    fn no_default_route() {
+        let fib = Ipv4Fib::new();
+        fib.add_connected(cidr([192, 168, 1, 1], 24), iface(1), ip([192, 168, 1, 1]));
+        assert!(fib.lookup(&ip([192, 168, 2, 1])).is_none());
+        assert!(fib.lookup(&ip([192, 168, 1, 255])).is_some());
+    }
+
+    #[test]
+    fn metric_and_same_path() {
+        let fib = Ipv4Fib::new();
+        let (eth0, eth1) = (iface(1), iface(2));
+        let net = cidr([172, 16, 0, 0], 12);
+        assert!(fib.add(static_route(net.clone(), [10, 0, 0, 1], &eth0, 20)));
+        assert!(fib.add(static_route(net.clone(), [10, 0, 0, 2], &eth0, 10)));
+        assert_eq!(gateway(&fib, [172, 16, 1, 1]), Some(ip([10, 0, 0, 2])));
+        // same gateway and interface: not another route, whatever the metric
+        assert!(!fib.add(static_route(net.clone(), [10, 0, 0, 2], &eth0, 5)));
+        // same gateway through another interface is
+        assert!(fib.add(static_route(net.clone(), [10, 0, 0, 2], &eth1, 5)));
+        assert!(Arc::ptr_eq(&fib.lookup(&ip([172, 16, 1, 1])).unwrap().iface, &eth1));
+        assert_eq!(fib.routes().len(), 3);
+    }
+
+    #[test]
+    fn remove_routes() {
+        let fib = Ipv4Fib::new();
+        let (eth0, eth1) = (iface(1), iface(2));
+        let net = cidr([10, 2, 0, 0], 16);
+        assert!(fib.add(static_route(net.clone(), [10, 0, 0, 1], &eth0, 0)));
+        assert!(fib.add(static_route(net.clone(), [10, 0, 0, 2], &eth0, 10)));
+        assert_eq!(fib.remove_static(&net, Some(&ip([10, 0, 0, 3]))), 0);
+        assert_eq!(fib.remove_static(&net, Some(&ip([10, 0, 0, 1]))), 1);
+        assert_eq!(gateway(&fib, [10, 2, 0, 1]), Some(ip([10, 0, 0, 2])));
+        assert_eq!(fib.remove_static(&net, None), 1);
+        assert!(fib.lookup(&ip([10, 2, 0, 1])).is_none());
+
+        // connected routes only go with their own interface
+        fib.add_connected(cidr([10, 0, 0, 1], 24), eth0.clone(), ip([10, 0, 0, 1]));
+        fib.add_connected(cidr([10, 0, 0, 2], 24), eth1.clone(), ip([10, 0, 0, 2]));
+        assert_eq!(fib.remove_static(&cidr([10, 0, 0, 0], 24), None), 0);
+        fib.remove_connected(&cidr([10, 0, 0, 1], 24), &eth0);
+        let left = fib.routes();
+        assert_eq!(left.len(), 1);
+        assert!(Arc::ptr_eq(&left[0].iface, &eth1));
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use crate::network::error::Ipv4Error;
+use crate::network::ipv4::{Ipv4Header, IPV4_HDR_LEN};
+
+/// Datagrams not complete after this long are dropped (RFC 1122 3.3.2).
+const REASM_TIMEOUT_USEC: u64 = 30_000_000;
+/// Payload bytes held over all datagrams.
+const REASM_MAX_BYTES: usize = 4 << 20;
+/// Datagrams reassembled at the same time.
+const REASM_MAX_QUEUES: usize = 1024;
+/// Fragments per datagram, against floods of tiny fragments.
+const REASM_MAX_FRAGS: usize = 64;
+/// Largest payload a datagram can carry.
+const IPV4_MAX_PAYLOAD: usize = u16::MAX as usize - IPV4_HDR_LEN;
+
+const IPOPT_END: u8 = 0;
+const IPOPT_NOOP: u8 = 1;
+/// Option type bit: repeat the option in every fragment.
+const IPOPT_COPIED: u8 = 0x80;
+
+/// The options of a first fragment that go into the other fragments too.
+pub(crate) fn copied_options(options: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            IPOPT_END => break,
+            IPOPT_NOOP => i += 1,
+            opt => {
+                let Some(len) = options.get(i + 1).map(|l| *l as usize) else { break };
+                if len < 2 || i + len > options.len() {
+                    break;
+                }
+                if opt & IPOPT_COPIED != 0 {
+                    out.extend_from_slice(&options[i..i + len]);
+                }
+                i += len;
+            }
+        }
+    }
+    out
+}
+
+/// Fragments of one datagram: source, destination, protocol and ident.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ReasmKey {
+    src: [u8; 4],
+    dst: [u8; 4],
+    protocol: u8,
+    ident: u16,
+}
+
+struct ReasmQueue {
+    /// header of the offset 0 fragment, once it is in
+    first_hdr: Option<Vec<u8>>,
+    /// (offset, data), sorted by offset and not overlapping
+    frags: Vec<(usize, Vec<u8>)>,
+    /// payload length, known once the last fragment is in
+    total: Option<usize>,
+    received: usize,
+    created_usec: u64,
+}
+
+impl ReasmQueue {
+    /// First bytes of the datagram as an ICMP error quotes them.
+    fn quote(&self) -> Option<Vec<u8>> {
+        let mut q = self.first_hdr.clone()?;
+        let (_, data) = self.frags.first()?;
+        q.extend_from_slice(&data[..data.len().min(8)]);
+        Some(q)
+    }
+}
+
+/// What became of a fragment.
+pub(crate) enum ReasmResult {
+    /// kept, the datagram is not complete yet
+    Held,
+    /// the whole datagram, header included
+    Complete(Vec<u8>),
+}
+
+/// Reassembly buffers of all datagrams in progress.
+pub(crate) struct Ipv4Reassembly {
+    queues: HashMap<ReasmKey, ReasmQueue>,
+    /// payload bytes in all queues
+    bytes: usize,
+}
+
+impl Ipv4Reassembly {
+    pub(crate) fn new() -> Ipv4Reassembly {
+        Ipv4Reassembly { queues: HashMap::new(), bytes: 0 }
+    }
+
+    fn drop_queue(&mut self, key: &ReasmKey) {
+        if let Some(q) = self.queues.remove(key) {
+            self.bytes -= q.received;
+        }
+    }
+
+    /// Add a fragment. Overlapping fragments other than exact duplicates
+    /// drop the whole datagram, as do inconsistent lengths.
+    pub(crate) fn add(&mut self, hdr: &Ipv4Header, hdr_bytes: &[u8], data: Vec<u8>, now: u64) -> Result<ReasmResult, Ipv4Error> {
+        let offset = hdr.frag_offset as usize * 8;
+        let end = offset + data.len();
+        if data.is_empty() || (hdr.more_frags && !data.len().is_multiple_of(8)) || end > IPV4_MAX_PAYLOAD {
+            return Err(Ipv4Error::BadFragment);
+        }
+        let key = ReasmKey { src: hdr.src.val, dst: hdr.dst.val, protocol: hdr.protocol, ident: hdr.ident };
+
+        if !self.queues.contains_key(&key) {
+            if self.queues.len() >= REASM_MAX_QUEUES || self.bytes + data.len() > REASM_MAX_BYTES {
+                return Err(Ipv4Error::ReassemblyLimit);
+            }
+            self.queues.insert(key.clone(), ReasmQueue {
+                first_hdr: None,
+                frags: Vec::new(),
+                total: None,
+                received: 0,
+                created_usec: now,
+            });
+        }
+        if self.bytes + data.len() > REASM_MAX_BYTES {
+            self.drop_queue(&key);
+            return Err(Ipv4Error::ReassemblyLimit);
+        }
+
+        let q = self.queues.get_mut(&key).unwrap();
+        let inconsistent = match (hdr.more_frags, q.total) {
+            // the last fragment, nothing may lie past it
+            (false, Some(total)) => total != end,
+            (false, None) => q.frags.last().is_some_and(|(o, d)| o + d.len() > end),
+            (true, Some(total)) => end > total,
+            (true, None) => false,
+        };
+        if inconsistent {
+            self.drop_queue(&key);
+            return Err(Ipv4Error::BadFragment);
+        }
+        let at = q.frags.partition_point(|(o, _)| *o < offset);
+        let overlaps = |(o, d): &(usize, Vec<u8>)| *o < end && offset < o + d.len();
+        if let Some(dup) = q.frags.get(at).filter(|f| overlaps(f))
+            && dup.0 == offset
+            && dup.1 == data
+        {
+            return Ok(ReasmResult::Held);
+        }
+        let hits_prev = at > 0 && overlaps(&q.frags[at - 1]);
+        let hits_next = q.frags.get(at).is_some_and(overlaps);
+        if hits_prev || hits_next {
+            self.drop_queue(&key);
+            return Err(Ipv4Error::OverlappingFragment);
+        }
+        if q.frags.len() >= REASM_MAX_FRAGS {
+            self.drop_queue(&key);
+            return Err(Ipv4Error::ReassemblyLimit);
+        }
+
+        if !hdr.more_frags {
+            q.total = Some(end);
+        }
+        if offset == 0 {
+            q.first_hdr = Some(hdr_bytes.to_vec());
+        }
+        q.received += data.len();
+        self.bytes += data.len();
+        q.frags.insert(at, (offset, data));
+
+        if q.first_hdr.is_none() || q.total != Some(q.received) {
+            return Ok(ReasmResult::Held);
+        }
+        // no overlaps and all bytes in: the fragments are contiguous
+        let q = self.queues.remove(&key).unwrap();
+        self.bytes -= q.received;
+        let mut first = Ipv4Header::parse(q.first_hdr.as_ref().unwrap())?;
+        if first.len() + q.received > u16::MAX as usize {
+            return Err(Ipv4Error::BadFragment);
+        }
+        first.more_frags = false;
+        first.frag_offset = 0;
+        first.total_len = (first.len() + q.received) as u16;
+        let mut out = first.to_bytes();
+        for (_, d) in q.frags {
+            out.extend_from_slice(&d);
+        }
+        Ok(ReasmResult::Complete(out))
+    }
+
+    /// Drop the datagrams that timed out. Returns how many, and the quotes
+    /// of those whose first fragment came in, for ICMP errors.
+    pub(crate) fn expire(&mut self, now: u64) -> (usize, Vec<Vec<u8>>) {
+        let expired: Vec<ReasmKey> = self.queues.iter()
+            .filter(|(_, q)| now.saturating_sub(q.created_usec) >= REASM_TIMEOUT_USEC)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut quotes = Vec::new();
+        for key in &expired {
+            if let Some(q) = self.queues.remove(key) {
+                self.bytes -= q.received;
+                quotes.extend(q.quote());
+            }
+        }
+        (expired.len(), quotes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ipv4::IPv4Addr;
+
+    const NOW: u64 = 1_000_000;
+
+    fn frag_hdr(ident: u16, offset: usize, len: usize, more_frags: bool) -> Ipv4Header {
+        Ipv4Header {
+            ihl: 5,
+            tos: 0,
+            total_len: (IPV4_HDR_LEN + len) as u16,
+            ident,
+            dont_frag: false,
+            more_frags,
+            frag_offset: (offset / 8) as u16,
+            ttl: 64,
+            protocol: 17,
+            checksum: 0,
+            src: IPv4Addr::new([10, 0, 0, 1]),
+            dst: IPv4Addr::new([10, 0, 0, 2]),
+            options: Vec::new(),
+        }
+    }
+
+    fn add(r: &mut Ipv4Reassembly, ident: u16, offset: usize, data: &[u8], more_frags: bool) -> Result<ReasmResult, Ipv4Error> {
+        let hdr = frag_hdr(ident, offset, data.len(), more_frags);
+        r.add(&hdr, &hdr.to_bytes(), data.to_vec(), NOW)
+    }
+
+    fn payload() -> Vec<u8> {
+        (0..40).collect()
+    }
+
+    fn check_complete(res: Result<ReasmResult, Ipv4Error>) {
+        let out = match res {
+            Ok(ReasmResult::Complete(out)) => out,
+            Ok(ReasmResult::Held) => panic!("datagram not complete"),
+            Err(e) => panic!("reassembly failed: {:?}", e),
+        };
+        let hdr = Ipv4Header::parse(&out).unwrap();
+        assert!(!hdr.is_fragment());
+        assert_eq!(hdr.total_len as usize, IPV4_HDR_LEN + 40);
+        assert_eq!(&out[IPV4_HDR_LEN..], &payload()[..]);
+    }
+
+    #[test]
+    fn in_order() {
+        let mut r = Ipv4Reassembly::new();
+        let data = payload();
+        assert!(matches!(add(&mut r, 1, 0, &data[..16], true), Ok(ReasmResult::Held)));
+        assert!(matches!(add(&mut r, 1, 16, &data[16..32], true), Ok(ReasmResult::Held)));
+        check_complete(add(&mut r, 1, 32, &data[32..], false));
+        assert_eq!(r.bytes, 0);
+        assert!(r.queues.is_empty());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut r = Ipv4Reassembly::new();
+        let data = payload();
+        assert!(matches!(add(&mut r, 1, 32, &data[32..], false), Ok(ReasmResult::Held)));
+        assert!(matches!(add(&mut r, 1, 16, &data[16..32], true), Ok(ReasmResult::Held)));
+        check_complete(add(&mut r, 1, 0, &data[..16], true));
+        assert_eq!(r.bytes, 0);
+    }
+
+    #[test]
+    fn duplicate_is_ignored() {
+        let mut r = Ipv4Reassembly::new();
+        let data = payload();
+        assert!(matches!(add(&mut r, 1, 0, &data[..16], true), Ok(ReasmResult::Held)));
+        assert!(matches!(add(&mut r, 1, 0, &data[..16], true), Ok(ReasmResult::Held)));
+        assert_eq!(r.bytes, 16);
+        assert!(matches!(add(&mut r, 1, 16, &data[16..32], true), Ok(ReasmResult::Held)));
+        check_complete(add(&mut r, 1, 32, &data[32..], false));
+    }
+
+    #[test]
+    fn overlap_drops_datagram() {
+        let mut r = Ipv4Reassembly::new();
+        let data = payload();
+        assert!(matches!(add(&mut r, 1, 0, &data[..16], true), Ok(ReasmResult::Held)));
+        assert_eq!(add(&mut r, 1, 8, &data[8..24], true).err(), Some(Ipv4Error::OverlappingFragment));
+        assert!(r.queues.is_empty());
+        assert_eq!(r.bytes, 0);
+        // same offset, different bytes
+        assert!(matches!(add(&mut r, 2, 0, &data[..16], true), Ok(ReasmResult::Held)));
+        assert_eq!(add(&mut r, 2, 0, &[0xff; 16], true).err(), Some(Ipv4Error::OverlappingFragment));
+        assert!(r.queues.is_empty());
+    }
+
+    #[test]
+    fn inconsistent_lengths() {
+        let mut r = Ipv4Reassembly::new();
+        let data = payload();
+        // MF fragments must be multiples of 8
+        assert_eq!(add(&mut r, 1, 0, &data[..12], true).err(), Some(Ipv4Error::BadFragment));
+        assert_eq!(add(&mut r, 1, 0, &[], true).err(), Some(Ipv4Error::BadFragment));
+        assert_eq!(add(&mut r, 1, IPV4_MAX_PAYLOAD & !7, &data[..16], false).err(), Some(Ipv4Error::BadFragment));
+        // data past the last fragment
+        assert!(matches!(add(&mut r, 2, 0, &data[..16], false), Ok(ReasmResult::Complete(_))));
+        assert!(matches!(add(&mut r, 3, 16, &data[16..32], true), Ok(ReasmResult::Held)));
+        assert_eq!(add(&mut r, 3, 0, &data[..8], false).err(), Some(Ipv4Error::BadFragment));
+        assert!(r.queues.is_empty());
+    }
+
+    #[test]
+    fn fragment_limit() {
+        let mut r = Ipv4Reassembly::new();
+        for i in 0..REASM_MAX_FRAGS {
+            assert!(matches!(add(&mut r, 1, i * 16, &[0; 8], true), Ok(ReasmResult::Held)));
+        }
+        let res = add(&mut r, 1, REASM_MAX_FRAGS * 16, &[0; 8], true);
+        assert_eq!(res.err(), Some(Ipv4Error::ReassemblyLimit));
+        assert!(r.queues.is_empty());
+        assert_eq!(r.bytes, 0);
+    }
+
+    #[test]
+    fn queue_limit() {
+        let mut r = Ipv4Reassembly::new();
+        for ident in 0..REASM_MAX_QUEUES {
+            assert!(matches!(add(&mut r, ident as u16, 0, &[0; 8], true), Ok(ReasmResult::Held)));
+        }
+        let res = add(&mut r, REASM_MAX_QUEUES as u16, 0, &[0; 8], true);
+        assert_eq!(res.err(), Some(Ipv4Error::ReassemblyLimit));
+        // datagrams in progress still take fragments
+        assert!(matches!(add(&mut r, 0, 8, &[0; 8], true), Ok(ReasmResult::Held)));
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut r = Ipv4Reassembly::new();
+        let big = vec![0; IPV4_MAX_PAYLOAD & !7];
+        let mut ident = 0;
+        let err = loop {
+            match add(&mut r, ident, 0, &big, true) {
+                Ok(_) => ident += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, Ipv4Error::ReassemblyLimit);
+        assert_eq!(ident as usize, REASM_MAX_BYTES / big.len());
+        assert!(r.bytes <= REASM_MAX_BYTES);
+    }
+
+    #[test]
+    fn expire_quotes_first_fragment() {
+        let mut r = Ipv4Reassembly::new();
+        let data = payload();
+        assert!(matches!(add(&mut r, 1, 0, &data[..16], true), Ok(ReasmResult::Held)));
+        assert!(matches!(add(&mut r, 2, 16, &data[16..32], true), Ok(ReasmResult::Held)));
+        assert_eq!(r.expire(NOW + REASM_TIMEOUT_USEC - 1).0, 0);
+        let (expired, quotes) = r.expire(NOW + REASM_TIMEOUT_USEC);
+        assert_eq!(expired, 2);
+        assert_eq!(quotes.len(), 1);
+        assert_eq!(quotes[0].len(), IPV4_HDR_LEN + 8);
+        assert_eq!(&quotes[0][IPV4_HDR_LEN..], &data[..8]);
+        assert_eq!(r.bytes, 0);
+    }
+}
@@ -1,74 +1,1043 @@
-use std::hash::{Hash, Hasher};
-use std::net::Ipv6Addr;
-use crate::network::ipv4::IPv4Protocol;
+use std::any::Any;
+use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, Entry, RandomState};
+use std::fmt::{Display, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU8};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use flume::{Receiver, TryRecvError};
+use crate::executor::runtime::Runtime;
+use crate::network::error::{Ipv6Error, NetError};
+use crate::network::ethernet::{EthEntry, MacAddr};
+use crate::network::fib_trie::RouteOrigin;
+use crate::network::ipv6_fib::{Ipv6Fib, Ipv6Route};
 use crate::network::module_traits::AsyncProtocolModule;
 use crate::network::packet::NetworkPacket;
-use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData};
+use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData, ProtocolRegistry};
 
-/// CIDR-aware IPv6 key: network address + prefix length
+pub const IPV6_HDR_LEN: usize = 40;
+/// Every link has to carry packets this big (RFC 8200 5).
+pub const IPV6_MIN_MTU: u32 = 1280;
+pub const IPV6_DEFAULT_MTU: u32 = 1500;
+
+/// Next header numbers of the extension headers (RFC 8200 4).
+pub const IPV6_NH_HOPOPTS: u8 = 0;
+pub const IPV6_NH_ROUTING: u8 = 43;
+pub const IPV6_NH_FRAGMENT: u8 = 44;
+/// nothing follows
+pub const IPV6_NH_NONE: u8 = 59;
+pub const IPV6_NH_DSTOPTS: u8 = 60;
+
+const IPV6_FRAG_HDR_LEN: usize = 8;
+/// Extension headers walked before the packet is given up on.
+const IPV6_MAX_EXT_HDRS: usize = 8;
+/// Hop limit of multicast packets unless the sender picks one (RFC 3493).
+const IPV6_MCAST_HOPS: u8 = 1;
+
+const IPV6_OPT_PAD1: u8 = 0;
+const IPV6_OPT_PADN: u8 = 1;
+const IPV6_OPT_ROUTER_ALERT: u8 = 5;
+
+/// Decoded fixed IPv6 header.
+#[derive(Debug, Clone)]
+pub struct Ipv6Header {
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub payload_len: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+}
+
+impl Ipv6Header {
+    pub fn parse(b: &[u8]) -> Result<Ipv6Header, Ipv6Error> {
+        if b.len() < IPV6_HDR_LEN {
+            return Err(Ipv6Error::Truncated { offset: 0, need: IPV6_HDR_LEN });
+        }
+        let version = b[0] >> 4;
+        if version != 6 {
+            return Err(Ipv6Error::BadVersion(version));
+        }
+        let word = u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+        let addr = |at: usize| Ipv6Addr::from(<[u8; 16]>::try_from(&b[at..at + 16]).unwrap());
+        Ok(Ipv6Header {
+            traffic_class: (word >> 20) as u8,
+            flow_label: word & 0x000f_ffff,
+            payload_len: u16::from_be_bytes([b[4], b[5]]),
+            next_header: b[6],
+            hop_limit: b[7],
+            src: addr(8),
+            dst: addr(24),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = vec![0u8; IPV6_HDR_LEN];
+        let word = 6 << 28 | (self.traffic_class as u32) << 20 | (self.flow_label & 0x000f_ffff);
+        b[0..4].copy_from_slice(&word.to_be_bytes());
+        b[4..6].copy_from_slice(&self.payload_len.to_be_bytes());
+        b[6] = self.next_header;
+        b[7] = self.hop_limit;
+        b[8..24].copy_from_slice(&self.src.octets());
+        b[24..40].copy_from_slice(&self.dst.octets());
+        b
+    }
+}
+
+/// Kinds of extension headers we walk through.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Ipv6Key {
-    pub network: [u8; 16], // masked network address
-    pub prefix: u8,        // 0..=128
+pub enum Ipv6ExtKind {
+    HopByHop,
+    Routing,
+    Fragment,
+    DestOptions,
 }
 
-impl Hash for Ipv6Key {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.network.hash(state);
-        self.prefix.hash(state);
+impl Ipv6ExtKind {
+    fn from_next_header(nh: u8) -> Option<Ipv6ExtKind> {
+        match nh {
+            IPV6_NH_HOPOPTS => Some(Ipv6ExtKind::HopByHop),
+            IPV6_NH_ROUTING => Some(Ipv6ExtKind::Routing),
+            IPV6_NH_FRAGMENT => Some(Ipv6ExtKind::Fragment),
+            IPV6_NH_DSTOPTS => Some(Ipv6ExtKind::DestOptions),
+            _ => None,
+        }
     }
 }
 
-/// IPv6 resource entry: next hop + outbound interface + optional hop limit
+/// The extension headers of a packet and what follows them.
 #[derive(Debug, Clone)]
-pub struct Ipv6Entry {
-    pub next_hop: Ipv6Addr,
-    pub iface: String,
-    pub hop_limit: u8,
+pub struct Ipv6ExtChain {
+    /// (kind, offset, length), offsets from the start of the IPv6 header
+    pub headers: Vec<(Ipv6ExtKind, usize, usize)>,
+    /// upper layer protocol number, `IPV6_NH_NONE` if there is none
+    pub upper: u8,
+    /// bytes of all extension headers
+    pub len: usize,
+}
+
+/// Walk the extension headers after the fixed header of `p`, which starts
+/// at the IPv6 header and is trimmed to its payload length.
+fn walk_ext_headers(p: &NetworkPacket, hdr: &Ipv6Header) -> Result<Ipv6ExtChain, Ipv6Error> {
+    let end = IPV6_HDR_LEN + hdr.payload_len as usize;
+    let mut headers = Vec::new();
+    let mut nh = hdr.next_header;
+    let mut off = IPV6_HDR_LEN;
+    while let Some(kind) = Ipv6ExtKind::from_next_header(nh) {
+        // hop-by-hop options only right after the fixed header
+        if kind == Ipv6ExtKind::HopByHop && off != IPV6_HDR_LEN {
+            return Err(Ipv6Error::MisplacedHopByHop { offset: off });
+        }
+        if headers.len() == IPV6_MAX_EXT_HDRS {
+            return Err(Ipv6Error::TooManyExtHeaders);
+        }
+        let mut fixed = [0u8; 2];
+        if off + fixed.len() > end {
+            return Err(Ipv6Error::Truncated { offset: off, need: fixed.len() });
+        }
+        p.copy_out(off, &mut fixed)
+            .map_err(|_| Ipv6Error::Truncated { offset: off, need: fixed.len() })?;
+        let len = match kind {
+            Ipv6ExtKind::Fragment => IPV6_FRAG_HDR_LEN,
+            _ => (fixed[1] as usize + 1) * 8,
+        };
+        if off + len > end {
+            return Err(Ipv6Error::Truncated { offset: off, need: len });
+        }
+        let mut b = vec![0u8; len];
+        p.copy_out(off, &mut b)
+            .map_err(|_| Ipv6Error::Truncated { offset: off, need: len })?;
+
+        match kind {
+            Ipv6ExtKind::HopByHop | Ipv6ExtKind::DestOptions => check_options(&b, off)?,
+            Ipv6ExtKind::Routing => {
+                // we are the final destination only when no segments are left
+                if b[3] != 0 {
+                    return Err(Ipv6Error::UnsupportedRouting { offset: off, routing_type: b[2] });
+                }
+            }
+            Ipv6ExtKind::Fragment => {
+                // atomic fragments are whole packets (RFC 6946)
+                let frag = u16::from_be_bytes([b[2], b[3]]);
+                if frag & 0xfff9 != 0 {
+                    return Err(Ipv6Error::Fragmented);
+                }
+            }
+        }
+        headers.push((kind, off, len));
+        nh = b[0];
+        off += len;
+    }
+    Ok(Ipv6ExtChain { headers, upper: nh, len: off - IPV6_HDR_LEN })
+}
+
+/// Go through the TLV options of a hop-by-hop or destination options
+/// header. Unknown options are skipped only if their type says so.
+fn check_options(b: &[u8], base: usize) -> Result<(), Ipv6Error> {
+    let mut i = 2;
+    while i < b.len() {
+        let opt = b[i];
+        if opt == IPV6_OPT_PAD1 {
+            i += 1;
+            continue;
+        }
+        let Some(len) = b.get(i + 1).map(|l| *l as usize) else {
+            return Err(Ipv6Error::BadOption { offset: base + i });
+        };
+        if i + 2 + len > b.len() {
+            return Err(Ipv6Error::BadOption { offset: base + i });
+        }
+        let known = matches!(opt, IPV6_OPT_PADN | IPV6_OPT_ROUTER_ALERT);
+        // top two bits 00: skip over it, anything else: discard the packet
+        if !known && opt >> 6 != 0 {
+            return Err(Ipv6Error::UnknownOption { offset: base + i, opt_type: opt });
+        }
+        i += 2 + len;
+    }
+    Ok(())
+}
+
+/// Solicited-node multicast group of `addr` (RFC 4291 2.7.1).
+pub(crate) fn solicited_node(addr: &Ipv6Addr) -> Ipv6Addr {
+    let o = addr.octets();
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 1, 0xff00 | o[13] as u16, u16::from_be_bytes([o[14], o[15]]))
+}
+
+/// Ethernet group address of an IPv6 multicast group (RFC 2464 7).
+pub(crate) fn multicast_mac(group: &Ipv6Addr) -> MacAddr {
+    let o = group.octets();
+    MacAddr::new([0x33, 0x33, o[12], o[13], o[14], o[15]])
+}
+
+/// Address with a prefix length, "2001:db8::1/64". Without the "/len" part
+/// it is a host address (/128).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Cidr {
+    pub addr: Ipv6Addr,
+    pub prefix_len: u8,
 }
 
+impl Ipv6Cidr {
+    pub fn new(addr: Ipv6Addr, prefix_len: u8) -> Result<Ipv6Cidr, String> {
+        if prefix_len > 128 {
+            return Err(format!("Invalid prefix length: {}", prefix_len));
+        }
+        Ok(Ipv6Cidr { addr, prefix_len })
+    }
+
+    /// ::/0
+    pub fn default_route() -> Ipv6Cidr {
+        Ipv6Cidr { addr: Ipv6Addr::UNSPECIFIED, prefix_len: 0 }
+    }
+
+    /// fe80::/64
+    pub fn link_local() -> Ipv6Cidr {
+        Ipv6Cidr { addr: Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), prefix_len: 64 }
+    }
+
+    fn mask(&self) -> u128 {
+        u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0)
+    }
+
+    /// The same prefix with the host bits cleared.
+    pub fn network(&self) -> Ipv6Cidr {
+        Ipv6Cidr { addr: Ipv6Addr::from_bits(self.addr.to_bits() & self.mask()), prefix_len: self.prefix_len }
+    }
+
+    pub fn contains(&self, addr: &Ipv6Addr) -> bool {
+        (self.addr.to_bits() ^ addr.to_bits()) & self.mask() == 0
+    }
+}
+
+impl From<Ipv6Addr> for Ipv6Cidr {
+    fn from(addr: Ipv6Addr) -> Self {
+        Ipv6Cidr { addr, prefix_len: 128 }
+    }
+}
+
+impl FromStr for Ipv6Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s.split_once('/').unwrap_or((s, "128"));
+        let addr = Ipv6Addr::from_str(addr).map_err(|_| format!("Invalid IPv6 address: {}", addr))?;
+        let prefix_len = len.parse::<u8>()
+            .map_err(|_| format!("Invalid prefix length: {}", len))?;
+        Ipv6Cidr::new(addr, prefix_len)
+    }
+}
+
+impl Display for Ipv6Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Link-local unicast, fe80::/10.
+pub(crate) fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// How the interface identifier of an address made up by the stack is
+/// picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6IidMode {
+    /// modified EUI-64 of the MAC (RFC 4291 appendix A), the same on every
+    /// network
+    Eui64,
+    /// stable per prefix and interface, without exposing the MAC (RFC 7217)
+    StablePrivacy,
+}
+
+/// Interface identifiers that are anycast addresses (RFC 5453).
+fn is_reserved_iid(iid: u64) -> bool {
+    iid == 0 || (0xfdff_ffff_ffff_ff80..=0xfdff_ffff_ffff_ffff).contains(&iid)
+}
+
+fn eui64_iid(mac: &MacAddr) -> u64 {
+    let m = mac.mac;
+    // flip the universal/local bit
+    u64::from_be_bytes([m[0] ^ 0x02, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5]])
+}
+
+/// RFC 7217 F(): hash of the secret, the prefix, the interface and the DAD
+/// counter. SipHash keyed through the secret rather than a cryptographic
+/// PRF, which is plenty for emulated hosts.
+fn stable_iid(secret: &[u8; 16], prefix: &Ipv6Cidr, iface: &EthEntry, dad_counter: u8) -> u64 {
+    let mut counter = dad_counter;
+    loop {
+        let mut h = DefaultHasher::new();
+        h.write(secret);
+        h.write(&prefix.network().addr.octets()[..8]);
+        h.write(&iface.get_mac().mac);
+        h.write_u16(iface.get_vlan().unwrap_or(0));
+        h.write_u8(counter);
+        let iid = h.finish();
+        if !is_reserved_iid(iid) {
+            return iid;
+        }
+        counter = counter.wrapping_add(1);
+    }
+}
+
+/// Key of our addresses: the address, unique across interfaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv6Key {
+    pub addr: Ipv6Addr,
+}
+
+/// How often `Ipv6AddrEvents::recv` looks for a new event.
+const ADDR_EVENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where an address is in duplicate address detection and its lifetime
+/// (RFC 4862).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6AddrState {
+    /// DAD running, not used for sending or receiving yet
+    Tentative,
+    /// in use
+    Preferred,
+    /// preferred lifetime over: still valid, but only a source when
+    /// nothing else fits
+    Deprecated,
+    /// another node has it, the address has been removed
+    Duplicate,
+}
+
+/// What happened to an address added with `NetworkStack::add_ipv6`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ipv6AddrEvent {
+    /// DAD found nobody else using it, the address is usable now
+    Bound(Ipv6Addr),
+    /// `mac` has the address too, ours is gone
+    Duplicate { addr: Ipv6Addr, mac: MacAddr },
+}
+
+/// Receiving end of the events of one address.
+pub struct Ipv6AddrEvents {
+    rx: Receiver<Ipv6AddrEvent>,
+}
+
+impl Ipv6AddrEvents {
+    pub(crate) fn new(rx: Receiver<Ipv6AddrEvent>) -> Ipv6AddrEvents {
+        Ipv6AddrEvents { rx }
+    }
+
+    pub fn try_recv(&self) -> Option<Ipv6AddrEvent> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Wait for the next event, `None` once the address is gone and all
+    /// events have been read. Must be awaited from a scheduler task.
+    pub async fn recv(&self) -> Option<Ipv6AddrEvent> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(ev) => return Some(ev),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => Runtime::sleep(ADDR_EVENT_POLL_INTERVAL).await,
+            }
+        }
+    }
+}
+
+/// IPv6 resource entry: one of our addresses + its interface
+#[derive(Debug)]
+pub(crate) struct Ipv6Entry {
+    addr: Ipv6Addr,
+    prefix_len: u8,
+    sub: Option<Arc<dyn Any + Send + Sync>>,
+    state: Mutex<Ipv6AddrState>,
+}
+
+impl Ipv6Entry {
+    pub fn new(addr: Ipv6Addr, prefix_len: u8, sub: Option<Arc<dyn Any + Send + Sync>>) -> Self {
+        Ipv6Entry { addr, prefix_len, sub, state: Mutex::new(Ipv6AddrState::Tentative) }
+    }
+
+    pub(crate) fn get_state(&self) -> Ipv6AddrState {
+        *self.state.lock().unwrap()
+    }
+
+    pub(crate) fn set_state(&self, state: Ipv6AddrState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Whether the address can be sent from and received on.
+    pub(crate) fn is_usable(&self) -> bool {
+        matches!(self.get_state(), Ipv6AddrState::Preferred | Ipv6AddrState::Deprecated)
+    }
+
+    pub fn get_addr(&self) -> &Ipv6Addr {
+        &self.addr
+    }
+
+    pub fn get_cidr(&self) -> Ipv6Cidr {
+        Ipv6Cidr { addr: self.addr, prefix_len: self.prefix_len }
+    }
+
+    /// Interface the address is bound to.
+    pub(crate) fn eth_entry(&self) -> Option<Arc<EthEntry>> {
+        self.sub.clone()?.downcast::<EthEntry>().ok()
+    }
+}
 
 pub(crate) struct IPv6Protocol {
-    pub common: NetworkProtocolMng<Ipv6Key, Ipv6Entry>,
-    pub hop_limit_default: u8,
-    pub mtu: u32,
+    pub common: NetworkProtocolMng<Ipv6Key, Arc<Ipv6Entry>>,
+    hop_limit_default: AtomicU8,
+    mtu: AtomicU32,
+    fib: Ipv6Fib,
+    /// RFC 7217 secret key of stable-privacy addresses
+    stable_secret: Mutex<[u8; 16]>,
+    /// multicast groups we listen to, with how many addresses need each
+    groups: Mutex<HashMap<Ipv6Addr, usize>>,
+    registry: Arc<ProtocolRegistry>,
 }
 
 impl IPv6Protocol {
-    pub(crate) fn new() -> IPv6Protocol {
+    pub(crate) fn new(registry: Arc<ProtocolRegistry>) -> IPv6Protocol {
         IPv6Protocol {
             common: NetworkProtocolMng::new(ProtocolHeaderType::IPv6),
-            mtu: 1500,
-            hop_limit_default: 64,
+            mtu: AtomicU32::new(IPV6_DEFAULT_MTU),
+            hop_limit_default: AtomicU8::new(64),
+            fib: Ipv6Fib::new(),
+            stable_secret: Mutex::new(Self::random_secret()),
+            groups: Mutex::new(HashMap::new()),
+            registry,
+        }
+    }
+
+    pub(crate) fn get_mtu(&self) -> u32 {
+        self.mtu.load(Relaxed)
+    }
+
+    /// Link MTU, at least 1280 (RFC 8200 5). We don't fragment, bigger
+    /// packets are dropped.
+    pub(crate) fn set_mtu(&self, mtu: u32) -> Result<(), Ipv6Error> {
+        if mtu < IPV6_MIN_MTU {
+            return Err(Ipv6Error::BadMtu(mtu));
+        }
+        self.mtu.store(mtu, Relaxed);
+        Ok(())
+    }
+
+    /// Hop limit of unicast packets the layers above don't set one for.
+    pub(crate) fn set_hop_limit(&self, hop_limit: u8) {
+        self.hop_limit_default.store(hop_limit, Relaxed);
+    }
+
+    fn random_secret() -> [u8; 16] {
+        let s = RandomState::new();
+        let hi = s.hash_one(0u8);
+        let lo = s.hash_one(1u8);
+        ((hi as u128) << 64 | lo as u128).to_be_bytes()
+    }
+
+    /// Set the secret of stable-privacy addresses. A host keeps its
+    /// addresses across restarts only with the same secret.
+    pub(crate) fn set_stable_secret(&self, secret: [u8; 16]) {
+        *self.stable_secret.lock().unwrap() = secret;
+    }
+
+    /// Our address in the /64 `prefix` on `iface`. `dad_counter` picks
+    /// another stable-privacy address after a duplicate was found.
+    pub(crate) fn make_addr(&self, prefix: &Ipv6Cidr, iface: &EthEntry, mode: Ipv6IidMode, dad_counter: u8) -> Result<Ipv6Addr, Ipv6Error> {
+        if prefix.prefix_len != 64 {
+            return Err(Ipv6Error::BadPrefixLength(prefix.prefix_len));
+        }
+        let iid = match mode {
+            Ipv6IidMode::Eui64 => eui64_iid(&iface.get_mac()),
+            Ipv6IidMode::StablePrivacy => {
+                stable_iid(&self.stable_secret.lock().unwrap(), prefix, iface, dad_counter)
+            }
+        };
+        Ok(Ipv6Addr::from_bits(prefix.network().addr.to_bits() | iid as u128))
+    }
+
+    pub(crate) fn join_group(&self, group: Ipv6Addr) {
+        *self.groups.lock().unwrap().entry(group).or_insert(0) += 1;
+    }
+
+    pub(crate) fn leave_group(&self, group: &Ipv6Addr) {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some(n) = groups.get_mut(group) {
+            *n -= 1;
+            if *n == 0 {
+                groups.remove(group);
+            }
+        }
+    }
+
+    /// Add a tentative address, usable once DAD is done, and the route of
+    /// its on-link prefix. Its solicited-node group is joined right away so
+    /// that DAD hears the others.
+    pub(crate) fn add_ipv6(&self, cidr: Ipv6Cidr, sub: Option<Arc<dyn Any + Send + Sync>>) -> Result<Arc<Ipv6Entry>, Ipv6Error> {
+        if cidr.addr.is_multicast() || cidr.addr.is_unspecified() {
+            return Err(Ipv6Error::BadAddress(cidr.addr));
+        }
+        let ent = Arc::new(Ipv6Entry::new(cidr.addr, cidr.prefix_len, sub));
+        match self.common.res_write_borrow().entry(Ipv6Key { addr: cidr.addr }) {
+            Entry::Vacant(v) => {
+                v.insert(ent.clone());
+            }
+            Entry::Occupied(_) => return Err(Ipv6Error::DuplicateAddress(cidr.addr)),
+        }
+        self.join_group(solicited_node(&cidr.addr));
+        if let Some(iface) = ent.eth_entry().filter(|_| cidr.prefix_len < 128) {
+            self.fib.add_connected(cidr, iface);
+        }
+        Ok(ent)
+    }
+
+    /// Remove an address. Its prefix route goes too, unless another address
+    /// of the interface is in the same prefix.
+    pub(crate) fn remove_ipv6(&self, addr: &Ipv6Addr) -> Option<Arc<Ipv6Entry>> {
+        let ent = self.common.res_write_borrow().remove(&Ipv6Key { addr: *addr })?;
+        self.leave_group(&solicited_node(addr));
+        let Some(iface) = ent.eth_entry() else {
+            return Some(ent);
+        };
+        let prefix = ent.get_cidr().network();
+        self.fib.remove_connected(&prefix, &iface);
+        let other = self.common.res_read_borrow().values()
+            .any(|e| e.get_cidr().network() == prefix && e.eth_entry().is_some_and(|i| Arc::ptr_eq(&i, &iface)));
+        if other && prefix.prefix_len < 128 {
+            self.fib.add_connected(prefix, iface);
+        }
+        Some(ent)
+    }
+
+    /// Route to `dst`, with the source address filled in.
+    pub(crate) fn route(&self, dst: &Ipv6Addr) -> Option<Ipv6Route> {
+        let mut route = self.fib.lookup(dst)?;
+        if route.src.is_none() {
+            route.src = self.select_src(&route.iface, dst);
+        }
+        Some(route)
+    }
+
+    /// Source address for packets to `dst` leaving through `iface`: one of
+    /// the interface of the same scope, in `dst`'s prefix if there is one.
+    fn select_src(&self, iface: &Arc<EthEntry>, dst: &Ipv6Addr) -> Option<Ipv6Addr> {
+        let r = self.common.res_read_borrow();
+        let link_scope = is_link_local(dst) || (dst.is_multicast() && dst.segments()[0] & 0x000f <= 2);
+        let mut same_scope: Vec<&Arc<Ipv6Entry>> = r.values()
+            .filter(|e| e.is_usable())
+            .filter(|e| e.eth_entry().is_some_and(|i| Arc::ptr_eq(&i, iface)))
+            .filter(|e| is_link_local(e.get_addr()) == link_scope)
+            .collect();
+        // RFC 6724 rule 3: avoid deprecated addresses
+        same_scope.sort_by_key(|e| e.get_state() == Ipv6AddrState::Deprecated);
+        same_scope.iter()
+            .find(|e| e.get_cidr().contains(dst))
+            .or(same_scope.first())
+            .map(|e| *e.get_addr())
+    }
+
+    /// The link-local address of `iface` in use, if any.
+    pub(crate) fn link_local_of(&self, iface: &Arc<EthEntry>) -> Option<Ipv6Addr> {
+        self.common.res_read_borrow().values()
+            .filter(|e| e.is_usable() && is_link_local(e.get_addr()))
+            .find(|e| e.eth_entry().is_some_and(|i| Arc::ptr_eq(&i, iface)))
+            .map(|e| *e.get_addr())
+    }
+
+    /// Add a static route. Without `iface` the gateway has to be on one of
+    /// our prefixes, whose interface is used.
+    pub(crate) fn add_route(&self, prefix: Ipv6Cidr, gateway: Option<Ipv6Addr>, iface: Option<Arc<EthEntry>>, metric: u32) -> Result<(), Ipv6Error> {
+        let iface = match (iface, &gateway) {
+            (Some(iface), _) => iface,
+            (None, Some(gw)) => self.fib.lookup(gw)
+                .filter(|r| r.origin == RouteOrigin::Connected)
+                .map(|r| r.iface)
+                .ok_or(Ipv6Error::GatewayUnreachable(*gw))?,
+            (None, None) => return Err(Ipv6Error::NoInterface),
+        };
+        let route = Ipv6Route {
+            prefix: prefix.network(),
+            gateway,
+            iface,
+            src: None,
+            metric,
+            origin: RouteOrigin::Static,
+        };
+        if !self.fib.add(route) {
+            return Err(Ipv6Error::RouteExists(prefix.network()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove_route(&self, prefix: &Ipv6Cidr, gateway: Option<&Ipv6Addr>) -> Result<(), Ipv6Error> {
+        match self.fib.remove_static(prefix, gateway) {
+            0 => Err(Ipv6Error::RouteNotFound(prefix.network())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Add a route a router advertisement announced, `gateway` is the
+    /// router or `None` for an on-link prefix. False if it is there already.
+    pub(crate) fn add_advertised_route(&self, prefix: Ipv6Cidr, gateway: Option<Ipv6Addr>, iface: Arc<EthEntry>, metric: u32) -> bool {
+        self.fib.add(Ipv6Route {
+            prefix: prefix.network(),
+            gateway,
+            iface,
+            src: None,
+            metric,
+            origin: RouteOrigin::RouterAdvert,
+        })
+    }
+
+    pub(crate) fn remove_advertised_route(&self, prefix: &Ipv6Cidr, gateway: Option<&Ipv6Addr>, iface: &Arc<EthEntry>) {
+        self.fib.remove_advertised(prefix, gateway, iface);
+    }
+
+    /// Forget the routes of an interface that goes away. Its addresses are
+    /// removed one by one before.
+    pub(crate) fn forget_iface(&self, iface: &Arc<EthEntry>) {
+        self.fib.remove_iface(iface);
+    }
+
+    pub(crate) fn routes(&self) -> Vec<Ipv6Route> {
+        self.fib.routes()
+    }
+
+    pub(crate) fn entries(&self) -> Vec<Arc<Ipv6Entry>> {
+        self.common.res_read_borrow().values().cloned().collect()
+    }
+
+    /// Our entry for `addr`, tentative or not.
+    pub(crate) fn entry(&self, addr: &Ipv6Addr) -> Option<Arc<Ipv6Entry>> {
+        self.common.res_read_borrow().get(&Ipv6Key { addr: *addr }).cloned()
+    }
+
+    /// Our entry for `addr`, if the address is in use.
+    pub(crate) fn lookup_local(&self, addr: &Ipv6Addr) -> Option<Arc<Ipv6Entry>> {
+        self.entry(addr).filter(|e| e.is_usable())
+    }
+
+    /// Whether a packet to `dst` is delivered locally: one of our addresses
+    /// in use, all-nodes or a group we joined. Tentative addresses get
+    /// nothing but their solicited-node traffic (RFC 4862 5.4).
+    pub(crate) fn is_local_dst(&self, dst: &Ipv6Addr) -> bool {
+        if !dst.is_multicast() {
+            return self.lookup_local(dst).is_some();
         }
+        let all_nodes = dst.segments()[1..] == [0, 0, 0, 0, 0, 0, 1]
+            && matches!(dst.segments()[0], 0xff01 | 0xff02);
+        all_nodes || self.groups.lock().unwrap().contains_key(dst)
+    }
+
+    /// Some address to source packets from when nothing better is known.
+    fn any_local(&self) -> Option<Arc<Ipv6Entry>> {
+        self.common.res_read_borrow().values().find(|e| e.is_usable()).cloned()
+    }
+
+    /// Validate the fixed header, walk the extension headers and strip them
+    /// all; `header_bytes(IPv6)` keeps the fixed header with its extensions.
+    fn decode_packet(&self, p: &NetworkPacket) -> Result<ProtocolMetaData, Ipv6Error> {
+        let hdr = Ipv6Header::parse(&p.peek_front(IPV6_HDR_LEN)
+            .map_err(|_| Ipv6Error::Truncated { offset: 0, need: IPV6_HDR_LEN })?)?;
+        let total_len = IPV6_HDR_LEN + hdr.payload_len as usize;
+        if p.data_len() < total_len {
+            return Err(Ipv6Error::Truncated { offset: 0, need: total_len });
+        }
+        if hdr.src.is_multicast() || hdr.src.is_loopback() {
+            return Err(Ipv6Error::BadSource(hdr.src));
+        }
+        if !self.is_local_dst(&hdr.dst) {
+            return Err(Ipv6Error::NotForUs(hdr.dst));
+        }
+        // the link layer may have padded the frame
+        p.trim(total_len).map_err(|_| Ipv6Error::Truncated { offset: 0, need: total_len })?;
+
+        let chain = walk_ext_headers(p, &hdr)?;
+        if chain.upper == IPV6_NH_NONE {
+            return Ok(ProtocolMetaData::new());
+        }
+        let Some(pt) = self.registry.by_ip_proto(chain.upper) else {
+            return Err(Ipv6Error::UnknownProtocol(chain.upper));
+        };
+
+        let l3_offset = p.data_offset();
+        let hdr_len = IPV6_HDR_LEN + chain.len;
+        p.pull_header(ProtocolHeaderType::IPv6, hdr_len)
+            .map_err(|_| Ipv6Error::Truncated { offset: 0, need: hdr_len })?;
+        {
+            let mut m = p.meta_mut();
+            m.l3_offset = Some(l3_offset);
+            m.l3_proto = ProtocolHeaderType::IPv6;
+            m.l3_src = Some(IpAddr::V6(hdr.src));
+            m.l3_dst = Some(IpAddr::V6(hdr.dst));
+            m.hop_limit = Some(hdr.hop_limit);
+            m.l4_offset = Some(p.data_offset());
+            m.l4_proto = pt;
+        }
+
+        let mut meta = ProtocolMetaData::new();
+        meta.set_pt(pt);
+        Ok(meta)
+    }
+
+    /// Build the header from the packet metadata: `l3_dst` and `l4_proto`
+    /// have to be set by the layers above, a missing `l3_src` is filled
+    /// with one of our addresses and `hop_limit` overrides the default.
+    fn encode_packet(&self, p: &NetworkPacket) -> Result<(), Ipv6Error> {
+        let (src, dst, pt, hop_limit) = {
+            let m = p.meta();
+            let Some(IpAddr::V6(dst)) = m.l3_dst else {
+                return Err(Ipv6Error::MissingAddress);
+            };
+            let src = match m.l3_src {
+                Some(IpAddr::V6(src)) => src,
+                _ => self.route(&dst).and_then(|r| r.src)
+                    .or_else(|| self.any_local().map(|e| *e.get_addr()))
+                    .ok_or(Ipv6Error::MissingAddress)?,
+            };
+            let default = if dst.is_multicast() { IPV6_MCAST_HOPS } else { self.hop_limit_default.load(Relaxed) };
+            (src, dst, m.l4_proto, m.hop_limit.unwrap_or(default))
+        };
+        let Some(next_header) = self.registry.ip_proto(pt) else {
+            return Err(Ipv6Error::NoProtocol(pt));
+        };
+        let payload_len = p.data_len();
+        if payload_len > u16::MAX as usize {
+            return Err(Ipv6Error::TooLong(payload_len));
+        }
+        let mtu = self.get_mtu();
+        if IPV6_HDR_LEN + payload_len > mtu as usize {
+            return Err(Ipv6Error::PacketTooBig { mtu });
+        }
+
+        let hdr = Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_len: payload_len as u16,
+            next_header,
+            hop_limit,
+            src,
+            dst,
+        };
+        p.push_header(ProtocolHeaderType::IPv6, &hdr.to_bytes())
+            .map_err(|_| Ipv6Error::NoHeadroom)?;
+        let mut m = p.meta_mut();
+        m.l3_offset = Some(p.data_offset());
+        m.l3_proto = ProtocolHeaderType::IPv6;
+        m.l3_src = Some(IpAddr::V6(src));
+        Ok(())
     }
 }
 
 impl AsyncProtocolModule<NetworkPacket> for IPv6Protocol {
-    type EncodeResult = (NetworkPacket, Result<(), ()>);
-    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
 
     async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
-        println!("----- encode ipv6 -----");
-        (p, Ok(()))
+        self.sync_encode(p)
     }
 
     async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
-        println!("----- decode ipv6 -----");
-        let mut meta = ProtocolMetaData::new();
-        meta.set_pt(ProtocolHeaderType::UDP);
-        (p, Ok(meta))
+        self.sync_decode(p)
     }
 
     fn sync_encode(&self, p: NetworkPacket) -> Self::EncodeResult {
         println!("----- encode ipv6 -----");
-        (p, Ok(()))
+        let res = self.encode_packet(&p).map_err(NetError::from);
+        (p, res)
     }
 
     fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode ipv6 -----");
-        let mut meta = ProtocolMetaData::new();
-        meta.set_pt(ProtocolHeaderType::UDP);
-        (p, Ok(meta))
+        let res = self.decode_packet(&p).map_err(NetError::from);
+        (p, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NH_UDP: u8 = 17;
+    const NH_ICMPV6: u8 = 58;
+
+    /// Fixed header followed by `ext`, with the payload length covering
+    /// `payload_len` bytes of it.
+    fn walk_len(next_header: u8, ext: &[u8], payload_len: usize) -> Result<Ipv6ExtChain, Ipv6Error> {
+        let hdr = Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_len: payload_len as u16,
+            next_header,
+            hop_limit: 64,
+            src: Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1),
+            dst: Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2),
+        };
+        let p = NetworkPacket::new();
+        p.push_data(&hdr.to_bytes()).unwrap();
+        p.push_data(ext).unwrap();
+        walk_ext_headers(&p, &hdr)
+    }
+
+    fn walk(next_header: u8, ext: &[u8]) -> Result<Ipv6ExtChain, Ipv6Error> {
+        walk_len(next_header, ext, ext.len())
+    }
+
+    /// 8 byte options header, a PadN filling it out.
+    fn options(nh: u8) -> [u8; 8] {
+        [nh, 0, IPV6_OPT_PADN, 4, 0, 0, 0, 0]
+    }
+
+    fn routing(nh: u8, segments_left: u8) -> [u8; 8] {
+        [nh, 0, 4, segments_left, 0, 0, 0, 0]
+    }
+
+    /// `frag` is the offset in 8 byte units shifted left by 3, M flag in
+    /// bit 0.
+    fn fragment(nh: u8, frag: u16) -> [u8; 8] {
+        let f = frag.to_be_bytes();
+        [nh, 0, f[0], f[1], 0, 0, 0x12, 0x34]
+    }
+
+    #[test]
+    fn no_ext_headers() {
+        let chain = walk(NH_ICMPV6, &[0; 8]).unwrap();
+        assert!(chain.headers.is_empty());
+        assert_eq!(chain.upper, NH_ICMPV6);
+        assert_eq!(chain.len, 0);
+    }
+
+    #[test]
+    fn full_chain() {
+        let mut ext = Vec::new();
+        ext.extend_from_slice(&options(IPV6_NH_DSTOPTS));
+        // 16 byte destination options: Pad1 and a router alert in there
+        ext.extend_from_slice(&[IPV6_NH_ROUTING, 1, 0, IPV6_OPT_ROUTER_ALERT, 2, 0, 0, IPV6_OPT_PADN, 6, 0, 0, 0, 0, 0, 0, 0]);
+        ext.extend_from_slice(&routing(IPV6_NH_FRAGMENT, 0));
+        ext.extend_from_slice(&fragment(NH_UDP, 0));
+        ext.extend_from_slice(&[0xaa; 12]);
+        let chain = walk(IPV6_NH_HOPOPTS, &ext).unwrap();
+        assert_eq!(chain.headers, [
+            (Ipv6ExtKind::HopByHop, 40, 8),
+            (Ipv6ExtKind::DestOptions, 48, 16),
+            (Ipv6ExtKind::Routing, 64, 8),
+            (Ipv6ExtKind::Fragment, 72, 8),
+        ]);
+        assert_eq!(chain.upper, NH_UDP);
+        assert_eq!(chain.len, 40);
+    }
+
+    #[test]
+    fn no_next_header() {
+        let chain = walk(IPV6_NH_DSTOPTS, &options(IPV6_NH_NONE)).unwrap();
+        assert_eq!(chain.headers.len(), 1);
+        assert_eq!(chain.upper, IPV6_NH_NONE);
+    }
+
+    #[test]
+    fn hop_by_hop_only_first() {
+        let mut ext = options(IPV6_NH_HOPOPTS).to_vec();
+        ext.extend_from_slice(&options(NH_UDP));
+        assert_eq!(walk(IPV6_NH_DSTOPTS, &ext).unwrap_err(), Ipv6Error::MisplacedHopByHop { offset: 48 });
+    }
+
+    #[test]
+    fn too_many_headers() {
+        let mut ext = Vec::new();
+        for _ in 0..IPV6_MAX_EXT_HDRS - 1 {
+            ext.extend_from_slice(&options(IPV6_NH_DSTOPTS));
+        }
+        ext.extend_from_slice(&options(NH_UDP));
+        assert_eq!(walk(IPV6_NH_DSTOPTS, &ext).unwrap().headers.len(), IPV6_MAX_EXT_HDRS);
+
+        let last = ext.len() - 8;
+        ext[last] = IPV6_NH_DSTOPTS;
+        ext.extend_from_slice(&options(NH_UDP));
+        assert_eq!(walk(IPV6_NH_DSTOPTS, &ext).unwrap_err(), Ipv6Error::TooManyExtHeaders);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn truncated_by_payload_length() {
+        // not even next header and length
+        assert_eq!(walk_len(IPV6_NH_DSTOPTS, &options(NH_UDP), 1).unwrap_err(),
+                   Ipv6Error::Truncated { offset: IPV6_HDR_LEN, need: 2 });
+        // header length past the payload, though the bytes are in the packet
+        let mut ext = vec![NH_UDP, 1, IPV6_OPT_PADN, 12];
+        ext.resize(16, 0);
+        assert_eq!(walk_len(IPV6_NH_DSTOPTS, &ext, 8).unwrap_err(),
+                   Ipv6Error::Truncated { offset: IPV6_HDR_LEN, need: 16 });
+        assert!(walk_len(IPV6_NH_DSTOPTS, &ext, 16).is_ok());
+        // second header cut short
+        let mut ext = options(IPV6_NH_FRAGMENT).to_vec();
+        ext.extend_from_slice(&fragment(NH_UDP, 0)[..4]);
+        assert_eq!(walk(IPV6_NH_HOPOPTS, &ext).unwrap_err(),
+                   Ipv6Error::Truncated { offset: 48, need: IPV6_FRAG_HDR_LEN });
+    }
+
+    #[test]
+    fn bad_options() {
+        // PadN running past the end of the header
+        let ext = [NH_UDP, 0, IPV6_OPT_PADN, 5, 0, 0, 0, 0];
+        assert_eq!(walk(IPV6_NH_DSTOPTS, &ext).unwrap_err(), Ipv6Error::BadOption { offset: 42 });
+        // type without its length byte
+        let ext = [NH_UDP, 0, IPV6_OPT_PADN, 3, 0, 0, 0, 0x1e];
+        assert_eq!(walk(IPV6_NH_DSTOPTS, &ext).unwrap_err(), Ipv6Error::BadOption { offset: 47 });
+        // unknown, top bits 00: skipped
+        let ext = [NH_UDP, 0, 0x1e, 4, 0, 0, 0, 0];
+        assert!(walk(IPV6_NH_DSTOPTS, &ext).is_ok());
+        // unknown, top bits 01: discard
+        let ext = [NH_UDP, 0, IPV6_OPT_PAD1, 0x5e, 3, 0, 0, 0];
+        assert_eq!(walk(IPV6_NH_HOPOPTS, &ext).unwrap_err(), Ipv6Error::UnknownOption { offset: 43, opt_type: 0x5e });
+    }
+
+    #[test]
+    fn routing_with_segments_left() {
+        assert_eq!(walk(IPV6_NH_ROUTING, &routing(NH_UDP, 2)).unwrap_err(),
+                   Ipv6Error::UnsupportedRouting { offset: IPV6_HDR_LEN, routing_type: 4 });
+    }
+
+    #[test]
+    fn fragments() {
+        // atomic fragment
+        assert_eq!(walk(IPV6_NH_FRAGMENT, &fragment(NH_UDP, 0)).unwrap().upper, NH_UDP);
+        // reserved bits don't matter
+        assert!(walk(IPV6_NH_FRAGMENT, &fragment(NH_UDP, 0x0006)).is_ok());
+        // more fragments, or not the first
+        assert_eq!(walk(IPV6_NH_FRAGMENT, &fragment(NH_UDP, 0x0001)).unwrap_err(), Ipv6Error::Fragmented);
+        assert_eq!(walk(IPV6_NH_FRAGMENT, &fragment(NH_UDP, 0x0008)).unwrap_err(), Ipv6Error::Fragmented);
+    }
+
+    fn eth(mac: [u8; 6]) -> Arc<EthEntry> {
+        Arc::new(EthEntry::new(MacAddr::new(mac), None, None))
+    }
+
+    fn cidr(s: &str) -> Ipv6Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn eui64_rfc4291_vector() {
+        // RFC 4291 appendix A: 34-56-78-9A-BC-DE -> 3656:78FF:FE9A:BCDE
+        let mac = MacAddr::new([0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde]);
+        assert_eq!(eui64_iid(&mac), 0x3656_78ff_fe9a_bcde);
+    }
+
+    #[test]
+    fn stable_iid_per_secret_and_counter() {
+        let iface = eth([0x02, 0, 0, 0, 0, 1]);
+        let prefix = cidr("2001:db8:1::/64");
+        let secret = [7; 16];
+        let iid = stable_iid(&secret, &prefix, &iface, 0);
+        assert_eq!(iid, stable_iid(&secret, &prefix, &iface, 0));
+        // host bits of the prefix don't matter
+        assert_eq!(iid, stable_iid(&secret, &cidr("2001:db8:1::5/64"), &iface, 0));
+        assert_ne!(iid, stable_iid(&secret, &prefix, &iface, 1));
+        assert_ne!(iid, stable_iid(&[8; 16], &prefix, &iface, 0));
+        assert_ne!(iid, stable_iid(&secret, &cidr("2001:db8:2::/64"), &iface, 0));
+        assert!(!is_reserved_iid(iid));
+    }
+
+    #[test]
+    fn make_addr_modes() {
+        let ipv6 = IPv6Protocol::new(Arc::new(ProtocolRegistry::new()));
+        let iface = eth([0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde]);
+        let prefix = cidr("2001:db8::99/64");
+        assert_eq!(ipv6.make_addr(&prefix, &iface, Ipv6IidMode::Eui64, 0).unwrap(),
+                   "2001:db8::3656:78ff:fe9a:bcde".parse::<Ipv6Addr>().unwrap());
+
+        ipv6.set_stable_secret([1; 16]);
+        let a = ipv6.make_addr(&prefix, &iface, Ipv6IidMode::StablePrivacy, 0).unwrap();
+        assert!(cidr("2001:db8::/64").contains(&a));
+        assert_eq!(a.to_bits() as u64, stable_iid(&[1; 16], &prefix, &iface, 0));
+        assert_ne!(a, ipv6.make_addr(&prefix, &iface, Ipv6IidMode::StablePrivacy, 1).unwrap());
+
+        assert_eq!(ipv6.make_addr(&cidr("2001:db8::/48"), &iface, Ipv6IidMode::Eui64, 0).unwrap_err(),
+                   Ipv6Error::BadPrefixLength(48));
+    }
+
+    #[test]
+    fn cidr_parse_contains_network() {
+        let c = cidr("2001:db8::1/64");
+        assert_eq!(c.network(), cidr("2001:db8::/64"));
+        assert!(c.contains(&"2001:db8::ffff".parse().unwrap()));
+        assert!(!c.contains(&"2001:db9::1".parse().unwrap()));
+
+        let all = cidr("::/0");
+        assert_eq!(all, Ipv6Cidr::default_route());
+        assert!(all.contains(&"2001:db8::1".parse().unwrap()));
+        assert_eq!(cidr("2001:db8::1/0").network(), all);
+
+        // no length is a host address
+        let host = cidr("2001:db8::1");
+        assert_eq!(host.prefix_len, 128);
+        assert_eq!(host.network(), host);
+        assert!(host.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!host.contains(&"2001:db8::2".parse().unwrap()));
+
+        assert!("2001:db8::/129".parse::<Ipv6Cidr>().is_err());
+        assert!("2001:db8::/x".parse::<Ipv6Cidr>().is_err());
+        assert!("2001:db8:::1/64".parse::<Ipv6Cidr>().is_err());
+    }
+
+    #[test]
+    fn connected_route_skips_tentative_source() {
+        let ipv6 = IPv6Protocol::new(Arc::new(ProtocolRegistry::new()));
+        let iface = eth([0x02, 0, 0, 0, 0, 1]);
+        let dst = "2001:db8::2".parse().unwrap();
+        let ent = ipv6.add_ipv6(cidr("2001:db8::1/64"), Some(iface.clone())).unwrap();
+        let route = ipv6.route(&dst).unwrap();
+        assert!(Arc::ptr_eq(&route.iface, &iface));
+        assert_eq!(route.src, None);
+
+        ent.set_state(Ipv6AddrState::Preferred);
+        assert_eq!(ipv6.route(&dst).unwrap().src, Some(*ent.get_addr()));
+
+        // a second tentative address doesn't take over when the first goes
+        ipv6.add_ipv6(cidr("2001:db8::3/64"), Some(iface.clone())).unwrap();
+        ipv6.remove_ipv6(ent.get_addr());
+        let route = ipv6.route(&dst).unwrap();
+        assert_eq!(route.src, None);
+    }
+}
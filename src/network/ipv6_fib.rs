@@ -0,0 +1,141 @@
+use std::net::Ipv6Addr;
+use std::sync::{Arc, RwLock};
+use crate::network::ethernet::{EthEntry, MacAddr};
+use crate::network::fib_trie::{Trie, TrieRoute};
+use crate::network::fib_trie::RouteOrigin;
+use crate::network::ipv6::Ipv6Cidr;
+
+/// IPv6 route: destination prefix, next hop and outgoing interface.
+#[derive(Debug, Clone)]
+pub(crate) struct Ipv6Route {
+    /// network form, host bits zero
+    pub prefix: Ipv6Cidr,
+    /// `None` for on-link destinations
+    pub gateway: Option<Ipv6Addr>,
+    pub iface: Arc<EthEntry>,
+    /// preferred source address
+    pub src: Option<Ipv6Addr>,
+    /// lower wins among routes of the same prefix
+    pub metric: u32,
+    pub origin: RouteOrigin,
+}
+
+impl Ipv6Route {
+    /// The next hop for `dst`: the gateway, or `dst` itself when on-link.
+    pub(crate) fn next_hop(&self, dst: &Ipv6Addr) -> Ipv6Addr {
+        self.gateway.unwrap_or(*dst)
+    }
+
+    pub(crate) fn info(&self) -> Ipv6RouteInfo {
+        Ipv6RouteInfo {
+            prefix: self.prefix,
+            gateway: self.gateway,
+            mac: self.iface.get_mac(),
+            vlan: self.iface.get_vlan(),
+            svlan: self.iface.get_svlan(),
+            src: self.src,
+            metric: self.metric,
+            origin: self.origin,
+        }
+    }
+}
+
+impl TrieRoute for Ipv6Route {
+    fn key(&self) -> (u128, u8) {
+        (self.prefix.addr.to_bits(), self.prefix.prefix_len)
+    }
+
+    fn metric(&self) -> u32 {
+        self.metric
+    }
+
+    fn same_path(&self, other: &Ipv6Route) -> bool {
+        self.gateway == other.gateway && Arc::ptr_eq(&self.iface, &other.iface) && self.origin == other.origin
+    }
+}
+
+/// A route as `NetworkStack::ipv6_routes` lists it; the interface is given
+/// by its MAC and VLAN tags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6RouteInfo {
+    pub prefix: Ipv6Cidr,
+    pub gateway: Option<Ipv6Addr>,
+    pub mac: MacAddr,
+    pub vlan: Option<u16>,
+    pub svlan: Option<u16>,
+    pub src: Option<Ipv6Addr>,
+    pub metric: u32,
+    pub origin: RouteOrigin,
+}
+
+/// IPv6 forwarding table, longest prefix match over a binary trie.
+pub(crate) struct Ipv6Fib {
+    trie: RwLock<Trie<Ipv6Route>>,
+}
+
+impl Ipv6Fib {
+    pub(crate) fn new() -> Ipv6Fib {
+        Ipv6Fib { trie: RwLock::new(Trie::new()) }
+    }
+
+    /// Add the route of an on-link prefix, unless the interface has it.
+    /// No source is pinned: the address may still be tentative, the stack
+    /// picks a usable one per packet.
+    pub(crate) fn add_connected(&self, prefix: Ipv6Cidr, iface: Arc<EthEntry>) {
+        let route = Ipv6Route {
+            prefix: prefix.network(),
+            gateway: None,
+            iface,
+            src: None,
+            metric: 0,
+            origin: RouteOrigin::Connected,
+        };
+        self.trie.write().unwrap().insert(route);
+    }
+
+    pub(crate) fn remove_connected(&self, prefix: &Ipv6Cidr, iface: &Arc<EthEntry>) {
+        let prefix = prefix.network();
+        self.trie.write().unwrap().remove((prefix.addr.to_bits(), prefix.prefix_len), |r| {
+            r.origin == RouteOrigin::Connected && Arc::ptr_eq(&r.iface, iface)
+        });
+    }
+
+    /// Add a route, false if the prefix has one with the same gateway,
+    /// interface and origin already.
+    pub(crate) fn add(&self, route: Ipv6Route) -> bool {
+        self.trie.write().unwrap().insert(route)
+    }
+
+    /// Remove the static routes of `prefix`, only the one via `gateway` if
+    /// given. Returns how many went.
+    pub(crate) fn remove_static(&self, prefix: &Ipv6Cidr, gateway: Option<&Ipv6Addr>) -> usize {
+        let prefix = prefix.network();
+        self.trie.write().unwrap().remove((prefix.addr.to_bits(), prefix.prefix_len), |r| {
+            r.origin == RouteOrigin::Static && gateway.is_none_or(|gw| r.gateway.as_ref() == Some(gw))
+        })
+    }
+
+    /// Remove the route of `prefix` via `gateway` on `iface` that a router
+    /// advertisement installed.
+    pub(crate) fn remove_advertised(&self, prefix: &Ipv6Cidr, gateway: Option<&Ipv6Addr>, iface: &Arc<EthEntry>) -> usize {
+        let prefix = prefix.network();
+        self.trie.write().unwrap().remove((prefix.addr.to_bits(), prefix.prefix_len), |r| {
+            r.origin == RouteOrigin::RouterAdvert && r.gateway.as_ref() == gateway && Arc::ptr_eq(&r.iface, iface)
+        })
+    }
+
+    /// Remove every route out of `iface`, of any origin.
+    pub(crate) fn remove_iface(&self, iface: &Arc<EthEntry>) -> usize {
+        self.trie.write().unwrap().remove_all(|r| Arc::ptr_eq(&r.iface, iface))
+    }
+
+    /// Longest prefix match, the lowest metric among routes of that prefix.
+    pub(crate) fn lookup(&self, dst: &Ipv6Addr) -> Option<Ipv6Route> {
+        self.trie.read().unwrap().lookup(dst.to_bits()).cloned()
+    }
+
+    /// All routes, shorter prefixes first along each branch.
+    pub(crate) fn routes(&self) -> Vec<Ipv6Route> {
+        self.trie.read().unwrap().routes()
+    }
+}
@@ -1,6 +1,9 @@
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::Arc;
+use crate::network::error::NetError;
+use crate::network::packet::NetworkPacket;
+use crate::network::protocol::ProtocolMetaData;
 
 // for stack block
 pub trait AsyncNetIOModule<Pkt> {
@@ -55,4 +58,31 @@ pub trait AsyncSocketModule<Pkt> {
     async fn rx(&self, p: Pkt) -> Self::RxResult;
 
     async fn tx(&self, p: Pkt) -> Self::TxResult;
-}
\ No newline at end of file
+}
+
+/// Object-safe view of a protocol module, what the stack's protocol graph
+/// dispatches to. `decode` pulls the layer's header and names the next layer
+/// in the returned metadata, `encode` pushes the header.
+///
+/// Every `AsyncProtocolModule` with the usual result types gets it for free.
+pub trait ProtocolLayer: Send + Sync {
+    fn decode_layer(&self, p: NetworkPacket) -> (NetworkPacket, Result<ProtocolMetaData, NetError>);
+    fn encode_layer(&self, p: NetworkPacket) -> (NetworkPacket, Result<(), NetError>);
+}
+
+impl<T> ProtocolLayer for T
+where
+    T: AsyncProtocolModule<
+        NetworkPacket,
+        EncodeResult = (NetworkPacket, Result<(), NetError>),
+        DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>),
+    > + Send + Sync,
+{
+    fn decode_layer(&self, p: NetworkPacket) -> (NetworkPacket, Result<ProtocolMetaData, NetError>) {
+        self.sync_decode(p)
+    }
+
+    fn encode_layer(&self, p: NetworkPacket) -> (NetworkPacket, Result<(), NetError>) {
+        self.sync_encode(p)
+    }
+}
@@ -0,0 +1,852 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::Ordering::Relaxed;
+use flume::Sender;
+use crate::executor::runtime::Runtime;
+use crate::network::checksum;
+use crate::network::error::Icmpv6Error;
+use crate::network::ethernet::{EthEntry, MacAddr};
+use crate::network::icmpv6::IPPROTO_ICMPV6;
+use crate::network::ipv6::{multicast_mac, solicited_node, IPv6Protocol, Ipv6AddrEvent, Ipv6AddrEvents, Ipv6AddrState, Ipv6Entry};
+use crate::network::packet::NetworkPacket;
+use crate::network::protocol::ProtocolHeaderType;
+use crate::network::timer::jitter;
+use crate::network::tx_queue::TxOutbox;
+
+pub const ICMPV6_NEIGHBOR_SOLICIT: u8 = 135;
+pub const ICMPV6_NEIGHBOR_ADVERT: u8 = 136;
+
+/// NS and NA up to the end of the target address.
+const ND_MSG_LEN: usize = 24;
+pub(crate) const ND_OPT_SOURCE_LLADDR: u8 = 1;
+const ND_OPT_TARGET_LLADDR: u8 = 2;
+/// Link-layer address option for Ethernet, in 8 byte units.
+pub(crate) const ND_OPT_LLADDR_UNITS: u8 = 1;
+
+const NA_FLAG_ROUTER: u8 = 0x80;
+const NA_FLAG_SOLICITED: u8 = 0x40;
+const NA_FLAG_OVERRIDE: u8 = 0x20;
+
+/// Hop limit of every ND message; anything less crossed a router.
+pub(crate) const ND_HOP_LIMIT: u8 = 255;
+
+// RFC 4861 10 protocol constants, routers may advertise other
+// reachable and retransmit times
+const REACHABLE_TIME_USEC: u64 = 30_000_000;
+const RETRANS_TIMER_USEC: u64 = 1_000_000;
+const DELAY_FIRST_PROBE_USEC: u64 = 5_000_000;
+const MAX_MULTICAST_SOLICIT: u32 = 3;
+const MAX_UNICAST_SOLICIT: u32 = 3;
+/// Stale entries are removed after this long.
+const NEIGHBOR_GC_USEC: u64 = 60_000_000;
+/// Packets held per unresolved address.
+const NDP_QUEUE_LEN: usize = 3;
+
+// RFC 4862 5.4 duplicate address detection
+const DUP_ADDR_DETECT_TRANSMITS: u8 = 1;
+/// Random delay before the first solicitation.
+const MAX_DAD_DELAY_USEC: u64 = 1_000_000;
+
+static NDP_INSTANCE_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // packets waiting for resolution, keyed by (Ndp id, address)
+    static NDP_PENDING: RefCell<HashMap<(u64, Ipv6Addr), VecDeque<NetworkPacket>>> = RefCell::new(HashMap::new());
+}
+
+/// Neighbor cache entry states (RFC 4861 7.3.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborState {
+    /// solicitation sent, no answer yet
+    Incomplete,
+    /// confirmed within the reachable time
+    Reachable,
+    /// not confirmed lately, still used
+    Stale,
+    /// used while stale, waiting for upper layers to confirm it
+    Delay,
+    /// unicast solicitations sent to confirm it
+    Probe,
+}
+
+#[derive(Debug)]
+struct Neighbor {
+    mac: MacAddr,
+    state: NeighborState,
+    // last state change, in usec
+    updated_usec: u64,
+    probes: u32,
+    // where solicitations for it go out, and from which of our addresses
+    iface: Arc<EthEntry>,
+    src: Ipv6Addr,
+}
+
+/// Duplicate address detection of one of our addresses.
+struct DadEntry {
+    addr: Arc<Ipv6Entry>,
+    sent: u8,
+    next_usec: u64,
+    done: bool,
+    events: Sender<Ipv6AddrEvent>,
+}
+
+/// The options of an ND message from `start` on, as (type, whole option).
+pub(crate) fn nd_options(b: &[u8], start: usize) -> Result<Vec<(u8, &[u8])>, Icmpv6Error> {
+    let mut opts = Vec::new();
+    let mut i = start;
+    while i < b.len() {
+        let units = *b.get(i + 1).ok_or(Icmpv6Error::Truncated { offset: i, need: 2 })?;
+        let len = units as usize * 8;
+        if len == 0 {
+            return Err(Icmpv6Error::Malformed { offset: i + 1 });
+        }
+        if i + len > b.len() {
+            return Err(Icmpv6Error::Truncated { offset: i, need: len });
+        }
+        opts.push((b[i], &b[i..i + len]));
+        i += len;
+    }
+    Ok(opts)
+}
+
+/// The Ethernet address in a link-layer address option.
+pub(crate) fn lladdr_option(opt: &[u8]) -> Option<MacAddr> {
+    if opt.len() != ND_OPT_LLADDR_UNITS as usize * 8 {
+        return None;
+    }
+    Some(MacAddr::new(opt[2..8].try_into().unwrap()))
+}
+
+/// Fill in the checksum of an ICMPv6 message and queue it with complete
+/// metadata, so that only the IPv6 and Ethernet headers are added.
+pub(crate) fn send_icmpv6(outbox: &TxOutbox, iface: &EthEntry, mut msg: Vec<u8>, src: &Ipv6Addr, dst: &Ipv6Addr, l2_dst: &MacAddr) {
+    let pseudo = checksum::pseudo_header_v6(src, dst, msg.len() as u32, IPPROTO_ICMPV6);
+    let csum = checksum::finish(checksum::sum(pseudo, &msg));
+    msg[2..4].copy_from_slice(&csum.to_be_bytes());
+
+    let p = NetworkPacket::new();
+    if p.push_data(&msg).is_err() {
+        return;
+    }
+    {
+        let mut m = p.meta_mut();
+        m.l2_src = Some(iface.get_mac().mac);
+        m.l2_dst = Some(l2_dst.mac);
+        m.vlan = iface.get_vlan();
+        m.svlan = iface.get_svlan();
+        m.l3_proto = ProtocolHeaderType::IPv6;
+        m.l4_proto = ProtocolHeaderType::ICMPv6;
+        m.l3_src = Some(IpAddr::V6(*src));
+        m.l3_dst = Some(IpAddr::V6(*dst));
+        m.hop_limit = Some(ND_HOP_LIMIT);
+    }
+    outbox.push(p);
+}
+
+/// Decoded Neighbor Solicitation or Advertisement.
+#[derive(Debug, Clone)]
+pub struct NdMessage {
+    pub icmp_type: u8,
+    /// `NA_FLAG_*`, zero for solicitations
+    pub flags: u8,
+    pub target: Ipv6Addr,
+    /// source link-layer address of an NS, target link-layer address of an NA
+    pub lladdr: Option<MacAddr>,
+}
+
+impl NdMessage {
+    /// Parse and validate an NS or NA, checksum already verified.
+    pub fn parse(b: &[u8]) -> Result<NdMessage, Icmpv6Error> {
+        if b.len() < ND_MSG_LEN {
+            return Err(Icmpv6Error::Truncated { offset: 0, need: ND_MSG_LEN });
+        }
+        if b[1] != 0 {
+            return Err(Icmpv6Error::Malformed { offset: 1 });
+        }
+        let icmp_type = b[0];
+        let target = Ipv6Addr::from(<[u8; 16]>::try_from(&b[8..24]).unwrap());
+        if target.is_multicast() {
+            return Err(Icmpv6Error::BadTarget(target));
+        }
+        let want = if icmp_type == ICMPV6_NEIGHBOR_SOLICIT { ND_OPT_SOURCE_LLADDR } else { ND_OPT_TARGET_LLADDR };
+        let lladdr = nd_options(b, ND_MSG_LEN)?
+            .into_iter()
+            .filter(|(t, _)| *t == want)
+            .find_map(|(_, opt)| lladdr_option(opt));
+        let flags = if icmp_type == ICMPV6_NEIGHBOR_ADVERT { b[4] } else { 0 };
+        Ok(NdMessage { icmp_type, flags, target, lladdr })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = vec![0u8; ND_MSG_LEN];
+        b[0] = self.icmp_type;
+        b[4] = self.flags;
+        b[8..24].copy_from_slice(&self.target.octets());
+        if let Some(mac) = &self.lladdr {
+            let opt = if self.icmp_type == ICMPV6_NEIGHBOR_SOLICIT { ND_OPT_SOURCE_LLADDR } else { ND_OPT_TARGET_LLADDR };
+            b.extend_from_slice(&[opt, ND_OPT_LLADDR_UNITS]);
+            b.extend_from_slice(&mac.mac);
+        }
+        b
+    }
+}
+
+/// What happened to a packet handed to `Ndp::resolve`.
+pub(crate) enum NdpResolve {
+    /// `l2_dst` is filled in, send it
+    Ready,
+    /// a copy is held until the address resolves, the packet handed back
+    /// is the original
+    Queued,
+}
+
+/// Neighbor discovery (RFC 4861) and duplicate address detection
+/// (RFC 4862), over ICMPv6.
+pub(crate) struct Ndp {
+    id: u64,
+    ipv6: Arc<IPv6Protocol>,
+    cache: Mutex<HashMap<Ipv6Addr, Neighbor>>,
+    dad: Mutex<HashMap<Ipv6Addr, DadEntry>>,
+    dad_enabled: AtomicBool,
+    reachable_usec: AtomicU64,
+    retrans_usec: AtomicU64,
+    /// interfaces that advertise themselves as routers
+    routers: RwLock<Vec<Arc<EthEntry>>>,
+    outbox: TxOutbox,
+}
+
+impl Ndp {
+    pub(crate) fn new(ipv6: Arc<IPv6Protocol>, outbox: TxOutbox) -> Ndp {
+        Ndp {
+            id: NDP_INSTANCE_ID.fetch_add(1, Relaxed),
+            ipv6,
+            cache: Mutex::new(HashMap::new()),
+            dad: Mutex::new(HashMap::new()),
+            dad_enabled: AtomicBool::new(true),
+            reachable_usec: AtomicU64::new(REACHABLE_TIME_USEC),
+            retrans_usec: AtomicU64::new(RETRANS_TIMER_USEC),
+            routers: RwLock::new(Vec::new()),
+            outbox,
+        }
+    }
+
+    /// Take the reachable and retransmit times a router advertised, zero
+    /// leaves one as it is (RFC 4861 6.3.4).
+    pub(crate) fn set_timers(&self, reachable_msec: u32, retrans_msec: u32) {
+        if reachable_msec != 0 {
+            self.reachable_usec.store(reachable_msec as u64 * 1000, Relaxed);
+        }
+        if retrans_msec != 0 {
+            self.retrans_usec.store(retrans_msec as u64 * 1000, Relaxed);
+        }
+    }
+
+    /// Set the router flag in advertisements sent from `iface`.
+    pub(crate) fn set_router(&self, iface: &Arc<EthEntry>, on: bool) {
+        let mut routers = self.routers.write().unwrap();
+        routers.retain(|i| !Arc::ptr_eq(i, iface));
+        if on {
+            routers.push(iface.clone());
+        }
+    }
+
+    fn is_router(&self, iface: &EthEntry) -> bool {
+        self.routers.read().unwrap().iter().any(|i| std::ptr::eq(i.as_ref(), iface))
+    }
+
+    /// Check new addresses for duplicates before using them. When off,
+    /// addresses are usable right away.
+    pub(crate) fn set_dad(&self, on: bool) {
+        self.dad_enabled.store(on, Relaxed);
+    }
+
+    /// Start duplicate address detection of a tentative address, the
+    /// address becomes usable when nobody answered.
+    pub(crate) fn start_dad(&self, addr: Arc<Ipv6Entry>, now: u64) -> Ipv6AddrEvents {
+        let (tx, rx) = flume::unbounded();
+        let mut ent = DadEntry {
+            addr: addr.clone(),
+            sent: 0,
+            next_usec: now + jitter(MAX_DAD_DELAY_USEC),
+            done: false,
+            events: tx,
+        };
+        if !self.dad_enabled.load(Relaxed) {
+            addr.set_state(Ipv6AddrState::Preferred);
+            let _ = ent.events.send(Ipv6AddrEvent::Bound(*addr.get_addr()));
+            ent.done = true;
+        }
+        self.dad.lock().unwrap().insert(*addr.get_addr(), ent);
+        Ipv6AddrEvents::new(rx)
+    }
+
+    /// Forget a removed address; its event receiver sees the end.
+    pub(crate) fn stop_dad(&self, addr: &Ipv6Addr) {
+        self.dad.lock().unwrap().remove(addr);
+    }
+
+    /// Another node has our tentative address: give it up.
+    fn dad_failed(&self, addr: &Ipv6Addr, mac: MacAddr) {
+        let Some(ent) = self.dad.lock().unwrap().remove(addr) else {
+            return;
+        };
+        println!("ndp: {} is used by {} too", addr, mac);
+        ent.addr.set_state(Ipv6AddrState::Duplicate);
+        let _ = ent.events.send(Ipv6AddrEvent::Duplicate { addr: *addr, mac });
+        self.ipv6.remove_ipv6(addr);
+    }
+
+    /// Send due solicitations and bind the addresses nobody answered for.
+    fn dad_tick(&self, now: u64) {
+        let mut dad = self.dad.lock().unwrap();
+        for ent in dad.values_mut() {
+            if ent.done || now < ent.next_usec {
+                continue;
+            }
+            let Some(iface) = ent.addr.eth_entry() else { continue };
+            let addr = *ent.addr.get_addr();
+            if ent.sent < DUP_ADDR_DETECT_TRANSMITS {
+                self.send_solicit(&iface, &Ipv6Addr::UNSPECIFIED, &addr, None);
+                ent.sent += 1;
+                ent.next_usec = now + self.retrans_usec.load(Relaxed);
+                continue;
+            }
+            println!("ndp: {} is ours", addr);
+            ent.addr.set_state(Ipv6AddrState::Preferred);
+            let _ = ent.events.send(Ipv6AddrEvent::Bound(addr));
+            ent.done = true;
+        }
+    }
+
+    /// Neighbor cache contents: address, MAC (zero while incomplete), state.
+    pub(crate) fn entries(&self) -> Vec<(Ipv6Addr, MacAddr, NeighborState)> {
+        self.cache.lock().unwrap()
+            .iter()
+            .map(|(addr, n)| (*addr, n.mac.clone(), n.state))
+            .collect()
+    }
+
+    /// Handle an NS or NA, the packet starting at the ICMPv6 header.
+    pub(crate) fn receive(&self, p: &NetworkPacket, msg: &[u8]) -> Result<(), Icmpv6Error> {
+        let (src, dst, hop_limit, l2_src) = {
+            let m = p.meta();
+            let (Some(IpAddr::V6(src)), Some(IpAddr::V6(dst))) = (m.l3_src, m.l3_dst) else {
+                return Err(Icmpv6Error::Malformed { offset: 0 });
+            };
+            (src, dst, m.hop_limit.unwrap_or(0), MacAddr::new(m.l2_src.unwrap_or([0; 6])))
+        };
+        // RFC 4861 7.1.1 and 7.1.2
+        if hop_limit != ND_HOP_LIMIT {
+            return Err(Icmpv6Error::BadHopLimit(hop_limit));
+        }
+        let nd = NdMessage::parse(msg)?;
+        let now = Runtime::get_time_usec();
+        if nd.icmp_type == ICMPV6_NEIGHBOR_SOLICIT {
+            if src.is_unspecified() && (dst != solicited_node(&nd.target) || nd.lladdr.is_some()) {
+                return Err(Icmpv6Error::Malformed { offset: 0 });
+            }
+            self.receive_solicit(&nd, &src, &l2_src, now)
+        } else {
+            if dst.is_multicast() && nd.flags & NA_FLAG_SOLICITED != 0 {
+                return Err(Icmpv6Error::Malformed { offset: 4 });
+            }
+            self.receive_advert(&nd, &l2_src, now);
+            Ok(())
+        }
+    }
+
+    fn receive_solicit(&self, ns: &NdMessage, src: &Ipv6Addr, l2_src: &MacAddr, now: u64) -> Result<(), Icmpv6Error> {
+        let Some(local) = self.ipv6.entry(&ns.target) else {
+            return Ok(());
+        };
+        let iface = local.eth_entry().ok_or(Icmpv6Error::NoInterface)?;
+        if local.get_state() == Ipv6AddrState::Tentative {
+            // RFC 4862 5.4.3: only another node's DAD makes ours fail
+            if src.is_unspecified() && *l2_src != iface.get_mac() {
+                self.dad_failed(&ns.target, l2_src.clone());
+            }
+            return Ok(());
+        }
+        if !local.is_usable() {
+            return Ok(());
+        }
+
+        if src.is_unspecified() {
+            // somebody checks for our address, tell everybody it is taken
+            let all_nodes = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+            self.send_advert(&iface, &ns.target, &all_nodes, &multicast_mac(&all_nodes), NA_FLAG_OVERRIDE);
+            return Ok(());
+        }
+        if let Some(mac) = &ns.lladdr {
+            self.learn(src, mac, &iface, &ns.target, now);
+        }
+        let l2_dst = ns.lladdr.clone().unwrap_or_else(|| l2_src.clone());
+        self.send_advert(&iface, &ns.target, src, &l2_dst, NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE);
+        Ok(())
+    }
+
+    /// Take the link address from a solicitation or router advertisement
+    /// (RFC 4861 7.2.3, 6.3.4); `src` is our address to probe it from.
+    pub(crate) fn learn(&self, addr: &Ipv6Addr, mac: &MacAddr, iface: &Arc<EthEntry>, src: &Ipv6Addr, now: u64) {
+        let release = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get_mut(addr) {
+                Some(n) => {
+                    let was_incomplete = n.state == NeighborState::Incomplete;
+                    if was_incomplete || n.mac != *mac {
+                        n.mac = mac.clone();
+                        n.state = NeighborState::Stale;
+                        n.updated_usec = now;
+                        n.probes = 0;
+                    }
+                    was_incomplete
+                }
+                None => {
+                    cache.insert(*addr, Neighbor {
+                        mac: mac.clone(),
+                        state: NeighborState::Stale,
+                        updated_usec: now,
+                        probes: 0,
+                        iface: iface.clone(),
+                        src: *src,
+                    });
+                    false
+                }
+            }
+        };
+        if release {
+            self.release(addr, mac);
+        }
+    }
+
+    /// Apply an advertisement to the cache (RFC 4861 7.2.5).
+    fn receive_advert(&self, na: &NdMessage, l2_src: &MacAddr, now: u64) {
+        if let Some(local) = self.ipv6.entry(&na.target) {
+            let mac = na.lladdr.clone().unwrap_or_else(|| l2_src.clone());
+            if local.get_state() == Ipv6AddrState::Tentative {
+                self.dad_failed(&na.target, mac);
+            } else if local.eth_entry().is_some_and(|i| i.get_mac() != mac) {
+                println!("ndp: {} is used by {} too", na.target, mac);
+            }
+            return;
+        }
+
+        let solicited = na.flags & NA_FLAG_SOLICITED != 0;
+        let override_ = na.flags & NA_FLAG_OVERRIDE != 0;
+        let release = {
+            let mut cache = self.cache.lock().unwrap();
+            let Some(n) = cache.get_mut(&na.target) else {
+                return;
+            };
+            if n.state == NeighborState::Incomplete {
+                let Some(mac) = &na.lladdr else {
+                    return;
+                };
+                n.mac = mac.clone();
+                n.state = if solicited { NeighborState::Reachable } else { NeighborState::Stale };
+                n.updated_usec = now;
+                n.probes = 0;
+                Some(mac.clone())
+            } else {
+                let changed = na.lladdr.as_ref().is_some_and(|mac| *mac != n.mac);
+                if !override_ && changed {
+                    // keep the address we have, but doubt it
+                    if n.state == NeighborState::Reachable {
+                        n.state = NeighborState::Stale;
+                        n.updated_usec = now;
+                    }
+                } else {
+                    if let Some(mac) = &na.lladdr {
+                        n.mac = mac.clone();
+                    }
+                    if solicited {
+                        n.state = NeighborState::Reachable;
+                        n.updated_usec = now;
+                        n.probes = 0;
+                    } else if changed {
+                        n.state = NeighborState::Stale;
+                        n.updated_usec = now;
+                    }
+                }
+                None
+            }
+        };
+        if let Some(mac) = release {
+            self.release(&na.target, &mac);
+        }
+    }
+
+    /// Send the packets that waited for `addr`.
+    fn release(&self, addr: &Ipv6Addr, mac: &MacAddr) {
+        let queued = NDP_PENDING.with(|q| q.borrow_mut().remove(&(self.id, *addr)));
+        for p in queued.into_iter().flatten() {
+            p.meta_mut().l2_dst = Some(mac.mac);
+            self.outbox.push(p);
+        }
+    }
+
+    /// Drop the packets that waited for `addr`, returns how many.
+    fn discard(&self, addr: &Ipv6Addr) -> usize {
+        NDP_PENDING.with(|q| q.borrow_mut().remove(&(self.id, *addr)).map_or(0, |q| q.len()))
+    }
+
+    /// Fill in the destination MAC of an IPv6 packet going to `next_hop`
+    /// through `iface`, or hold a copy of it and solicit from `src`.
+    pub(crate) fn resolve(&self, p: NetworkPacket, next_hop: &Ipv6Addr, iface: &Arc<EthEntry>, src: &Ipv6Addr) -> (NetworkPacket, Result<NdpResolve, Icmpv6Error>) {
+        let now = Runtime::get_time_usec();
+        let solicit = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get_mut(next_hop) {
+                Some(n) if n.state != NeighborState::Incomplete => {
+                    if n.state == NeighborState::Stale {
+                        // RFC 4861 7.3.3: give upper layers a chance to confirm it
+                        n.state = NeighborState::Delay;
+                        n.updated_usec = now;
+                    }
+                    p.meta_mut().l2_dst = Some(n.mac.mac);
+                    return (p, Ok(NdpResolve::Ready));
+                }
+                Some(_) => false,
+                None => {
+                    cache.insert(*next_hop, Neighbor {
+                        mac: MacAddr::new([0; 6]),
+                        state: NeighborState::Incomplete,
+                        updated_usec: now,
+                        probes: 1,
+                        iface: iface.clone(),
+                        src: *src,
+                    });
+                    true
+                }
+            }
+        };
+
+        let queued = NDP_PENDING.with(|q| {
+            let mut q = q.borrow_mut();
+            let queue = q.entry((self.id, *next_hop)).or_default();
+            if queue.len() >= NDP_QUEUE_LEN {
+                return false;
+            }
+            queue.push_back(p.clone_shared());
+            true
+        });
+        if solicit {
+            self.send_solicit(iface, src, next_hop, None);
+        }
+        if !queued {
+            return (p, Err(Icmpv6Error::QueueFull(*next_hop)));
+        }
+        (p, Ok(NdpResolve::Queued))
+    }
+
+    /// Solicit `target`, to its solicited-node group unless `unicast` gives
+    /// its MAC. From `::` (DAD) the source link-layer option is left out.
+    fn send_solicit(&self, iface: &EthEntry, src: &Ipv6Addr, target: &Ipv6Addr, unicast: Option<&MacAddr>) {
+        let (dst, l2_dst) = match unicast {
+            Some(mac) => (*target, mac.clone()),
+            None => {
+                let group = solicited_node(target);
+                (group, multicast_mac(&group))
+            }
+        };
+        let ns = NdMessage {
+            icmp_type: ICMPV6_NEIGHBOR_SOLICIT,
+            flags: 0,
+            target: *target,
+            lladdr: (!src.is_unspecified()).then(|| iface.get_mac()),
+        };
+        send_icmpv6(&self.outbox, iface, ns.to_bytes(), src, &dst, &l2_dst);
+    }
+
+    fn send_advert(&self, iface: &EthEntry, target: &Ipv6Addr, dst: &Ipv6Addr, l2_dst: &MacAddr, flags: u8) {
+        let router = if self.is_router(iface) { NA_FLAG_ROUTER } else { 0 };
+        let na = NdMessage {
+            icmp_type: ICMPV6_NEIGHBOR_ADVERT,
+            flags: flags | router,
+            target: *target,
+            lladdr: Some(iface.get_mac()),
+        };
+        send_icmpv6(&self.outbox, iface, na.to_bytes(), target, dst, l2_dst);
+    }
+
+    /// Forget an interface that goes away: its router flag, its neighbors
+    /// and what waits on them. Returns how many packets were dropped.
+    pub(crate) fn forget_iface(&self, iface: &Arc<EthEntry>) -> usize {
+        self.set_router(iface, false);
+        let mut gone = Vec::new();
+        self.cache.lock().unwrap().retain(|addr, n| {
+            let keep = !Arc::ptr_eq(&n.iface, iface);
+            if !keep {
+                gone.push(*addr);
+            }
+            keep
+        });
+        gone.iter().map(|addr| self.discard(addr)).sum()
+    }
+
+    /// Run DAD, then age the cache (RFC 4861 7.3.3): retransmit or give up
+    /// on incomplete entries, let reachable ones go stale, probe delayed
+    /// ones and remove old stale ones. Returns how many queued packets were
+    /// dropped.
+    pub(crate) fn tick(&self, now: u64) -> usize {
+        self.dad_tick(now);
+        let reachable_usec = self.reachable_usec.load(Relaxed);
+        let retrans_usec = self.retrans_usec.load(Relaxed);
+        let mut multicast = Vec::new();
+        let mut unicast = Vec::new();
+        let mut failed = Vec::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|addr, n| {
+                let age = now.saturating_sub(n.updated_usec);
+                match n.state {
+                    NeighborState::Incomplete if age >= retrans_usec => {
+                        if n.probes >= MAX_MULTICAST_SOLICIT {
+                            failed.push(*addr);
+                            return false;
+                        }
+                        n.probes += 1;
+                        n.updated_usec = now;
+                        multicast.push((*addr, n.iface.clone(), n.src));
+                    }
+                    NeighborState::Reachable if age >= reachable_usec => {
+                        n.state = NeighborState::Stale;
+                        n.updated_usec = now;
+                    }
+                    NeighborState::Delay if age >= DELAY_FIRST_PROBE_USEC => {
+                        n.state = NeighborState::Probe;
+                        n.probes = 1;
+                        n.updated_usec = now;
+                        unicast.push((*addr, n.iface.clone(), n.src, n.mac.clone()));
+                    }
+                    NeighborState::Probe if age >= retrans_usec => {
+                        if n.probes >= MAX_UNICAST_SOLICIT {
+                            println!("ndp: {} unreachable", addr);
+                            return false;
+                        }
+                        n.probes += 1;
+                        n.updated_usec = now;
+                        unicast.push((*addr, n.iface.clone(), n.src, n.mac.clone()));
+                    }
+                    NeighborState::Stale if age >= NEIGHBOR_GC_USEC => return false,
+                    _ => {}
+                }
+                true
+            });
+        }
+
+        for (addr, iface, src) in multicast {
+            self.send_solicit(&iface, &src, &addr, None);
+        }
+        for (addr, iface, src, mac) in unicast {
+            self.send_solicit(&iface, &src, &addr, Some(&mac));
+        }
+        failed.iter().map(|addr| {
+            println!("ndp: {} unresolved", addr);
+            self.discard(addr)
+        }).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::protocol::ProtocolRegistry;
+
+    const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+    const OTHER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 3];
+
+    struct Host {
+        ndp: Ndp,
+        outbox: TxOutbox,
+        iface: Arc<EthEntry>,
+        src: Ipv6Addr,
+        peer: Ipv6Addr,
+    }
+
+    fn host() -> Host {
+        let ipv6 = Arc::new(IPv6Protocol::new(Arc::new(ProtocolRegistry::new())));
+        let outbox = TxOutbox::new();
+        Host {
+            ndp: Ndp::new(ipv6, outbox),
+            outbox,
+            iface: Arc::new(EthEntry::new(MacAddr::new([0x02, 0, 0, 0, 0, 1]), None, None)),
+            src: "fe80::1".parse().unwrap(),
+            peer: "fe80::2".parse().unwrap(),
+        }
+    }
+
+    fn packet() -> NetworkPacket {
+        let p = NetworkPacket::new();
+        p.push_data(&[0; 8]).unwrap();
+        p
+    }
+
+    fn state(h: &Host) -> Option<(MacAddr, NeighborState)> {
+        h.ndp.entries().into_iter().find(|(a, _, _)| *a == h.peer).map(|(_, mac, st)| (mac, st))
+    }
+
+    /// Resolve the peer at time 0, leaving it incomplete with a packet
+    /// queued.
+    fn incomplete(h: &Host) {
+        let (_, res) = h.ndp.resolve(packet(), &h.peer, &h.iface, &h.src);
+        assert!(matches!(res, Ok(NdpResolve::Queued)));
+        assert_eq!(state(h).unwrap().1, NeighborState::Incomplete);
+    }
+
+    fn advert(h: &Host, flags: u8, mac: Option<[u8; 6]>, now: u64) {
+        let na = NdMessage { icmp_type: ICMPV6_NEIGHBOR_ADVERT, flags, target: h.peer, lladdr: mac.map(MacAddr::new) };
+        h.ndp.receive_advert(&na, &MacAddr::new(PEER_MAC), now);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let msgs = [
+            NdMessage { icmp_type: ICMPV6_NEIGHBOR_SOLICIT, flags: 0, target: "2001:db8::1".parse().unwrap(), lladdr: Some(MacAddr::new(PEER_MAC)) },
+            NdMessage { icmp_type: ICMPV6_NEIGHBOR_SOLICIT, flags: 0, target: "fe80::1".parse().unwrap(), lladdr: None },
+            NdMessage { icmp_type: ICMPV6_NEIGHBOR_ADVERT, flags: NA_FLAG_ROUTER | NA_FLAG_SOLICITED, target: "fe80::2".parse().unwrap(), lladdr: Some(MacAddr::new(OTHER_MAC)) },
+        ];
+        for m in &msgs {
+            let back = NdMessage::parse(&m.to_bytes()).unwrap();
+            assert_eq!(back.icmp_type, m.icmp_type);
+            assert_eq!(back.flags, m.flags);
+            assert_eq!(back.target, m.target);
+            assert_eq!(back.lladdr, m.lladdr);
+        }
+        // a target link-layer option in an NS is not its source address
+        let mut b = msgs[2].to_bytes();
+        b[0] = ICMPV6_NEIGHBOR_SOLICIT;
+        let ns = NdMessage::parse(&b).unwrap();
+        assert_eq!((ns.flags, ns.lladdr), (0, None));
+    }
+
+    #[test]
+    fn message_bad_input() {
+        let ns = NdMessage { icmp_type: ICMPV6_NEIGHBOR_SOLICIT, flags: 0, target: "fe80::1".parse().unwrap(), lladdr: None };
+        let b = ns.to_bytes();
+        assert_eq!(NdMessage::parse(&b[..ND_MSG_LEN - 1]).unwrap_err(), Icmpv6Error::Truncated { offset: 0, need: ND_MSG_LEN });
+
+        let mut bad = b.clone();
+        bad[1] = 1;
+        assert_eq!(NdMessage::parse(&bad).unwrap_err(), Icmpv6Error::Malformed { offset: 1 });
+
+        let group: Ipv6Addr = "ff02::1".parse().unwrap();
+        let mut bad = b.clone();
+        bad[8..24].copy_from_slice(&group.octets());
+        assert_eq!(NdMessage::parse(&bad).unwrap_err(), Icmpv6Error::BadTarget(group));
+
+        let mut bad = b.clone();
+        bad.extend_from_slice(&[ND_OPT_SOURCE_LLADDR, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(NdMessage::parse(&bad).unwrap_err(), Icmpv6Error::Malformed { offset: ND_MSG_LEN + 1 });
+
+        let mut bad = b.clone();
+        bad.extend_from_slice(&[ND_OPT_SOURCE_LLADDR, 2, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(NdMessage::parse(&bad).unwrap_err(), Icmpv6Error::Truncated { offset: ND_MSG_LEN, need: 16 });
+    }
+
+    #[test]
+    fn advert_completes_incomplete() {
+        let h = host();
+        incomplete(&h);
+        assert_eq!(h.outbox.len(), 1);
+        // no link-layer address, nothing learned
+        advert(&h, NA_FLAG_SOLICITED, None, 0);
+        assert_eq!(state(&h).unwrap().1, NeighborState::Incomplete);
+
+        advert(&h, NA_FLAG_SOLICITED, Some(PEER_MAC), 0);
+        assert_eq!(state(&h), Some((MacAddr::new(PEER_MAC), NeighborState::Reachable)));
+        // the queued packet goes out after the solicitation
+        assert_eq!(h.outbox.len(), 2);
+        h.outbox.pop();
+        assert_eq!(h.outbox.pop().unwrap().meta().l2_dst, Some(PEER_MAC));
+
+        // unsolicited only makes it stale
+        let h = host();
+        incomplete(&h);
+        advert(&h, NA_FLAG_OVERRIDE, Some(PEER_MAC), 0);
+        assert_eq!(state(&h).unwrap().1, NeighborState::Stale);
+    }
+
+    #[test]
+    fn advert_override() {
+        let h = host();
+        incomplete(&h);
+        advert(&h, NA_FLAG_SOLICITED, Some(PEER_MAC), 0);
+
+        // another address without override: keep ours, but doubt it
+        advert(&h, NA_FLAG_SOLICITED, Some(OTHER_MAC), 0);
+        assert_eq!(state(&h), Some((MacAddr::new(PEER_MAC), NeighborState::Stale)));
+        // the same address confirms it
+        advert(&h, NA_FLAG_SOLICITED, Some(PEER_MAC), 0);
+        assert_eq!(state(&h), Some((MacAddr::new(PEER_MAC), NeighborState::Reachable)));
+
+        // override replaces it, stale unless solicited
+        advert(&h, NA_FLAG_OVERRIDE, Some(OTHER_MAC), 0);
+        assert_eq!(state(&h), Some((MacAddr::new(OTHER_MAC), NeighborState::Stale)));
+        advert(&h, NA_FLAG_OVERRIDE | NA_FLAG_SOLICITED, Some(PEER_MAC), 0);
+        assert_eq!(state(&h), Some((MacAddr::new(PEER_MAC), NeighborState::Reachable)));
+        // unsolicited with the same address changes nothing
+        advert(&h, NA_FLAG_OVERRIDE, Some(PEER_MAC), 0);
+        assert_eq!(state(&h).unwrap().1, NeighborState::Reachable);
+    }
+
+    #[test]
+    fn incomplete_gives_up() {
+        let h = host();
+        incomplete(&h);
+        assert_eq!(h.ndp.tick(RETRANS_TIMER_USEC - 1), 0);
+        assert_eq!(h.outbox.len(), 1);
+        // resent every retransmit time, up to MAX_MULTICAST_SOLICIT in all
+        for i in 1..MAX_MULTICAST_SOLICIT as u64 {
+            assert_eq!(h.ndp.tick(i * RETRANS_TIMER_USEC), 0);
+            assert_eq!(h.outbox.len(), i as usize + 1);
+        }
+        assert_eq!(h.ndp.tick(MAX_MULTICAST_SOLICIT as u64 * RETRANS_TIMER_USEC), 1);
+        assert_eq!(state(&h), None);
+        assert_eq!(h.outbox.len(), MAX_MULTICAST_SOLICIT as usize);
+    }
+
+    #[test]
+    fn reachable_goes_stale_and_away() {
+        let h = host();
+        incomplete(&h);
+        advert(&h, NA_FLAG_SOLICITED, Some(PEER_MAC), 0);
+        h.ndp.tick(REACHABLE_TIME_USEC - 1);
+        assert_eq!(state(&h).unwrap().1, NeighborState::Reachable);
+        h.ndp.tick(REACHABLE_TIME_USEC);
+        assert_eq!(state(&h).unwrap().1, NeighborState::Stale);
+        h.ndp.tick(REACHABLE_TIME_USEC + NEIGHBOR_GC_USEC);
+        assert_eq!(state(&h), None);
+    }
+
+    #[test]
+    fn delay_probes_then_unreachable() {
+        let h = host();
+        h.ndp.learn(&h.peer, &MacAddr::new(PEER_MAC), &h.iface, &h.src, 0);
+        assert_eq!(state(&h).unwrap().1, NeighborState::Stale);
+        // used while stale: sent right away, confirmed later
+        let (p, res) = h.ndp.resolve(packet(), &h.peer, &h.iface, &h.src);
+        assert!(matches!(res, Ok(NdpResolve::Ready)));
+        assert_eq!(p.meta().l2_dst, Some(PEER_MAC));
+        assert_eq!(state(&h).unwrap().1, NeighborState::Delay);
+
+        h.ndp.tick(DELAY_FIRST_PROBE_USEC);
+        assert_eq!(state(&h).unwrap().1, NeighborState::Probe);
+        let probe = h.outbox.pop().unwrap();
+        assert_eq!(probe.meta().l2_dst, Some(PEER_MAC));
+        assert_eq!(probe.meta().l3_dst, Some(IpAddr::V6(h.peer)));
+
+        let mut now = DELAY_FIRST_PROBE_USEC;
+        for _ in 1..MAX_UNICAST_SOLICIT {
+            now += RETRANS_TIMER_USEC;
+            h.ndp.tick(now);
+            assert_eq!(state(&h).unwrap().1, NeighborState::Probe);
+        }
+        h.ndp.tick(now + RETRANS_TIMER_USEC);
+        assert_eq!(state(&h), None);
+        assert_eq!(h.outbox.len(), MAX_UNICAST_SOLICIT as usize - 1);
+    }
+}
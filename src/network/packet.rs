@@ -1,61 +1,59 @@
-use std::cell::{Cell, Ref, RefCell};
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::fmt;
 use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+use crate::network::packet_meta::PacketMeta;
+use crate::network::packet_pool::{PacketBufKind, PacketPool};
 use crate::network::protocol::ProtocolHeaderType;
 
+/// Room kept in front of the payload so every layer can prepend its header
+/// without moving the data.
+pub const DEFAULT_HEADROOM: usize = 128;
+/// Largest standard Ethernet frame without FCS.
+pub const DEFAULT_FRAME_SIZE: usize = 1514;
+
+/// Packet storage. Several frags, possibly of different packets, may view
+/// the same buffer; it is copied before any of them writes to it.
+/// The storage comes from the thread's `PacketPool` and goes back on drop.
 struct FixedPacketBuffer {
-    w_idx: Cell<usize>,
-    // r_idx: Cell<usize>,
     buffer: RefCell<Vec<u8>>,
+    pooled: Option<PacketBufKind>,
 }
 
 impl FixedPacketBuffer {
     fn new(sz: usize) -> FixedPacketBuffer {
-        FixedPacketBuffer {
-            w_idx: Cell::new(0),
-            // r_idx: Cell::new(0),
-            buffer: RefCell::new(vec![0; sz]),
-        }
-    }
-
-    fn reset(&self) {
-        self.w_idx.set(0);
+        let (buffer, pooled) = PacketPool::alloc_at_least(sz);
+        Self::from_vec(buffer, pooled)
     }
 
-    fn free_space(&self) -> usize {
-        self.buffer.borrow().len() - self.w_idx.get()
+    fn from_vec(buffer: Vec<u8>, pooled: Option<PacketBufKind>) -> FixedPacketBuffer {
+        FixedPacketBuffer {
+            buffer: RefCell::new(buffer),
+            pooled,
+        }
     }
 
-    fn data_len(&self) -> usize {
-        self.w_idx.get()
+    fn len(&self) -> usize {
+        self.buffer.borrow().len()
     }
 
-    fn push_data(&self, data: &[u8]) -> Result<(), ()> {
-        let buffer_len = self.buffer.borrow().len();
-        let w_idx = self.w_idx.get();
-        let data_len = data.len();
-        if w_idx >= buffer_len || w_idx + data_len > buffer_len {
-            return Err(());
-        }
-        let free_space = buffer_len - w_idx;
-        let mut s = &mut self.buffer.borrow_mut()[w_idx..w_idx + data_len];
-        s.copy_from_slice(data);
-        Ok(())
+    /// Private copy of `[0, used)` in a buffer of at least `sz` bytes, same offsets.
+    fn copy_prefix(&self, used: usize, sz: usize) -> FixedPacketBuffer {
+        let copy = FixedPacketBuffer::new(sz.max(self.len()));
+        copy.buffer.borrow_mut()[..used].copy_from_slice(&self.buffer.borrow()[..used]);
+        copy
     }
+}
 
-    fn borrow_excerpt(&self, offset: usize, len: usize) -> Result<Ref<[u8]>, ()> {
-        let w_len = self.w_idx.get();
-        if offset + len > w_len {
-            return Err(());
+impl Drop for FixedPacketBuffer {
+    fn drop(&mut self) {
+        if let Some(kind) = self.pooled {
+            PacketPool::recycle(kind, std::mem::take(self.buffer.get_mut()));
         }
-
-        let slice_ref = Ref::map(self.buffer.borrow(),
-                                 |v| &v[offset..offset + len]);
-        // let slice_ref = &ref_s[offset..offset + len];
-        Ok(slice_ref)
     }
 }
 
+/// Where one layer's header sits in the frag buffer (absolute offset).
 struct PacketFragHeader {
     hdr_type: Cell<ProtocolHeaderType>,
     hdr_offset: Cell<u32>,
@@ -71,71 +69,228 @@ impl PacketFragHeader {
         }
     }
 
+    fn with_range(hdr_type: ProtocolHeaderType, offset: usize, len: usize) -> PacketFragHeader {
+        let hdr = PacketFragHeader::new(hdr_type);
+        hdr.hdr_offset.set(offset as u32);
+        hdr.hdr_len.set(len as u32);
+        hdr
+    }
 }
 
+/// A data window over a (possibly shared) buffer:
+/// `[0, head)` headroom, `[head, w_idx)` data, `[w_idx, len)` tailroom.
 struct PacketFrag {
     hdrs: RefCell<Vec<PacketFragHeader>>,
-    // w_offset: Cell<u32>,
-    // r_offset: Cell<u32>,
-    buffer: RefCell<FixedPacketBuffer>,
+    head: Cell<usize>,
+    w_idx: Cell<usize>,
+    buffer: RefCell<Rc<FixedPacketBuffer>>,
 }
 
 impl PacketFrag {
-    pub(crate) fn new(sz: usize) -> PacketFrag {
+    pub(crate) fn new(sz: usize, headroom: usize) -> PacketFrag {
+        Self::from_buffer(FixedPacketBuffer::new(sz), headroom)
+    }
+
+    fn from_buffer(buffer: FixedPacketBuffer, headroom: usize) -> PacketFrag {
+        let headroom = headroom.min(buffer.len());
         PacketFrag {
             hdrs: RefCell::new(Vec::new()),
-            // w_offset: Cell::new(0),
-            // r_offset: Cell::new(0),
-            buffer: RefCell::new(FixedPacketBuffer::new(sz)),
+            head: Cell::new(headroom),
+            w_idx: Cell::new(headroom),
+            buffer: RefCell::new(Rc::new(buffer)),
         }
     }
 
-    pub(crate) fn push_data(&self, data: &[u8]) -> Result<(), ()> {
-        self.buffer.borrow().push_data(data)
+    /// Zero-copy view of `len` bytes starting at data offset `offset`.
+    fn share(&self, offset: usize, len: usize) -> PacketFrag {
+        let head = self.head.get() + offset;
+        PacketFrag {
+            hdrs: RefCell::new(Vec::new()),
+            head: Cell::new(head),
+            w_idx: Cell::new(head + len),
+            buffer: RefCell::new(self.buffer.borrow().clone()),
+        }
     }
 
+    fn is_shared(&self) -> bool {
+        Rc::strong_count(&self.buffer.borrow()) > 1
+    }
+
+    /// Copy-on-write: make the buffer private before modifying it.
+    fn make_unique(&self) {
+        if self.is_shared() {
+            let copy = self.buffer.borrow().copy_prefix(self.w_idx.get(), 0);
+            self.buffer.replace(Rc::new(copy));
+        }
+    }
+
+    fn headroom(&self) -> usize {
+        self.head.get()
+    }
+
+    fn free_space(&self) -> usize {
+        self.buffer.borrow().len() - self.w_idx.get()
+    }
 
     pub(crate) fn data_len(&self) -> usize {
-        self.buffer.borrow().data_len()
+        self.w_idx.get() - self.head.get()
+    }
+
+    /// Append at the tail.
+    pub(crate) fn push_data(&self, data: &[u8]) -> Result<(), ()> {
+        let w_idx = self.w_idx.get();
+        if data.len() > self.free_space() {
+            return Err(());
+        }
+        self.make_unique();
+        self.buffer.borrow().buffer.borrow_mut()[w_idx..w_idx + data.len()].copy_from_slice(data);
+        self.w_idx.set(w_idx + data.len());
+        Ok(())
+    }
+
+    /// Prepend into the headroom, returns the buffer offset of the new bytes.
+    fn push_front(&self, data: &[u8]) -> Result<usize, ()> {
+        let head = self.head.get();
+        if data.len() > head {
+            return Err(());
+        }
+        self.make_unique();
+        let new_head = head - data.len();
+        self.buffer.borrow().buffer.borrow_mut()[new_head..head].copy_from_slice(data);
+        self.head.set(new_head);
+        Ok(new_head)
+    }
+
+    /// Strip `len` bytes from the front, returns the buffer offset they were at.
+    fn pull_front(&self, len: usize) -> Result<usize, ()> {
+        if len > self.data_len() {
+            return Err(());
+        }
+        let head = self.head.get();
+        self.head.set(head + len);
+        Ok(head)
+    }
+
+    /// Keep only the first `len` bytes of data.
+    fn trim(&self, len: usize) {
+        if len < self.data_len() {
+            self.w_idx.set(self.head.get() + len);
+        }
+    }
+
+    /// Copy data starting at `offset` (relative to the data start) into `out`.
+    fn copy_out(&self, offset: usize, out: &mut [u8]) -> Result<(), ()> {
+        if offset + out.len() > self.data_len() {
+            return Err(());
+        }
+        let start = self.head.get() + offset;
+        out.copy_from_slice(&self.buffer.borrow().buffer.borrow()[start..start + out.len()]);
+        Ok(())
+    }
+
+    /// Overwrite data starting at `offset` (relative to the data start).
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<(), ()> {
+        if offset + data.len() > self.data_len() {
+            return Err(());
+        }
+        self.make_unique();
+        let start = self.head.get() + offset;
+        self.buffer.borrow().buffer.borrow_mut()[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Raw bytes at an absolute buffer offset, also valid for pulled headers.
+    fn copy_abs(&self, abs_offset: usize, len: usize) -> Result<Vec<u8>, ()> {
+        let buffer = self.buffer.borrow();
+        let bytes = buffer.buffer.borrow();
+        if abs_offset + len > bytes.len() {
+            return Err(());
+        }
+        Ok(bytes[abs_offset..abs_offset + len].to_vec())
+    }
+
+    /// Record (or re-record) the position of a layer's header.
+    fn set_header(&self, hdr_type: ProtocolHeaderType, offset: usize, len: usize) {
+        let mut hdrs = self.hdrs.borrow_mut();
+        match hdrs.iter().find(|h| h.hdr_type.get() == hdr_type) {
+            Some(h) => {
+                h.hdr_offset.set(offset as u32);
+                h.hdr_len.set(len as u32);
+            }
+            None => hdrs.push(PacketFragHeader::with_range(hdr_type, offset, len)),
+        }
+    }
+
+    fn header_range(&self, hdr_type: ProtocolHeaderType) -> Option<(usize, usize)> {
+        self.hdrs
+            .borrow()
+            .iter()
+            .find(|h| h.hdr_type.get() == hdr_type)
+            .map(|h| (h.hdr_offset.get() as usize, h.hdr_len.get() as usize))
+    }
+
+    fn copy_headers_from(&self, other: &PacketFrag) {
+        for h in other.hdrs.borrow().iter() {
+            self.set_header(h.hdr_type.get(), h.hdr_offset.get() as usize, h.hdr_len.get() as usize);
+        }
     }
 }
 
 impl Debug for PacketFrag {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "PacketFrag")
+        write!(f, "PacketFrag(len:{}, shared:{})", self.data_len(), self.is_shared())
     }
 }
 
 pub struct NetworkPacket {
     // size: Cell<usize>,
-    app_id: Cell<usize>,
-    sock_id: Cell<usize>,
+    meta: RefCell<PacketMeta>,
+    headroom: usize,
     frags: RefCell<Vec<PacketFrag>>,
 }
 
 impl NetworkPacket {
     pub fn new() -> NetworkPacket {
+        Self::with_headroom(DEFAULT_HEADROOM)
+    }
+
+    /// Packet whose first frag keeps `headroom` bytes free for headers.
+    pub fn with_headroom(headroom: usize) -> NetworkPacket {
         NetworkPacket {
             // size: Cell::new(0),
-            app_id: Cell::new(0),
-            sock_id: Cell::new(0),
+            meta: RefCell::new(PacketMeta::new()),
+            headroom,
             frags: RefCell::new(Vec::new())
         }
     }
 
+    /// Empty packet on a pool buffer of the given kind, for driver RX.
+    /// Fails when the pool is exhausted, the caller should drop the frame.
+    pub fn alloc_rx(kind: PacketBufKind) -> Result<NetworkPacket, ()> {
+        let buffer = PacketPool::alloc(kind).ok_or(())?;
+        let headroom = PacketPool::headroom();
+        let pkt = Self::with_headroom(headroom);
+        let frag = PacketFrag::from_buffer(FixedPacketBuffer::from_vec(buffer, Some(kind)), headroom);
+        pkt.frags.borrow_mut().push(frag);
+        Ok(pkt)
+    }
+
     fn push_data_to_new_frag(&self, data: &[u8]) -> Result<(), ()> {
-        let frag = PacketFrag::new(1514);
+        let mut frags = self.frags.borrow_mut();
+        // only the first frag needs room for headers
+        let headroom = if frags.is_empty() { self.headroom } else { 0 };
+        let frag = PacketFrag::new(headroom + DEFAULT_FRAME_SIZE.max(data.len()), headroom);
         frag.push_data(data)?;
-        self.frags.borrow_mut().push(frag);
+        frags.push(frag);
         Ok(())
     }
-    
+
     pub fn push_data(&self, data: &[u8]) -> Result<(), ()> {
         let mut res = Err(());
         if let Some(frag) = self.frags.borrow().last() {
             res = frag.push_data(data);
         }
-        
+
         match res {
             Ok(()) => Ok(()),
             Err(()) => self.push_data_to_new_frag(data),
@@ -149,6 +304,226 @@ impl NetworkPacket {
         }
         sz
     }
+
+    pub fn frag_count(&self) -> usize {
+        self.frags.borrow().len()
+    }
+
+    pub fn meta(&self) -> Ref<'_, PacketMeta> {
+        self.meta.borrow()
+    }
+
+    pub fn meta_mut(&self) -> RefMut<'_, PacketMeta> {
+        self.meta.borrow_mut()
+    }
+
+    /// Absolute buffer offset where the data currently starts, i.e. where the
+    /// next header to be pulled sits. Used for the offsets in `PacketMeta`.
+    pub fn data_offset(&self) -> usize {
+        match self.frags.borrow().first() {
+            Some(frag) => frag.head.get(),
+            None => self.headroom,
+        }
+    }
+
+    /// Free bytes in front of the data.
+    pub fn headroom(&self) -> usize {
+        match self.frags.borrow().first() {
+            Some(frag) => frag.headroom(),
+            None => self.headroom,
+        }
+    }
+
+    /// Prepend a header for layer `hdr_type` and record where it went.
+    pub fn push_header(&self, hdr_type: ProtocolHeaderType, hdr: &[u8]) -> Result<(), ()> {
+        if self.frags.borrow().is_empty() {
+            let frag = PacketFrag::new(self.headroom + DEFAULT_FRAME_SIZE, self.headroom);
+            self.frags.borrow_mut().push(frag);
+        }
+
+        let frags = self.frags.borrow();
+        let first = &frags[0];
+        let offset = first.push_front(hdr)?;
+        first.set_header(hdr_type, offset, hdr.len());
+        Ok(())
+    }
+
+    /// Strip the `len` byte header of layer `hdr_type` from the front. Its
+    /// position stays recorded, so `header_bytes` can still return it.
+    pub fn pull_header(&self, hdr_type: ProtocolHeaderType, len: usize) -> Result<(), ()> {
+        self.linearize(len)?;
+        let frags = self.frags.borrow();
+        let first = frags.first().ok_or(())?;
+        let offset = first.pull_front(len)?;
+        first.set_header(hdr_type, offset, len);
+        Ok(())
+    }
+
+    /// Shorten the packet to `len` bytes, e.g. to drop Ethernet padding.
+    pub fn trim(&self, len: usize) -> Result<(), ()> {
+        if len > self.data_len() {
+            return Err(());
+        }
+        let mut frags = self.frags.borrow_mut();
+        let mut left = len;
+        let mut keep = 0;
+        for frag in frags.iter() {
+            if left == 0 {
+                break;
+            }
+            let frag_len = frag.data_len();
+            frag.trim(left);
+            left -= frag_len.min(left);
+            keep += 1;
+        }
+        // the first frag holds the header records, keep it even if empty
+        frags.truncate(keep.max(1));
+        if len == 0 && let Some(first) = frags.first() {
+            first.trim(0);
+        }
+        Ok(())
+    }
+
+    /// Make the first `len` bytes contiguous in the first frag, so a parser
+    /// can treat them as one header. Buffer offsets of recorded headers stay valid.
+    pub fn linearize(&self, len: usize) -> Result<(), ()> {
+        if len > self.data_len() {
+            return Err(());
+        }
+        let mut frags = self.frags.borrow_mut();
+        let Some(first) = frags.first() else {
+            return Ok(());
+        };
+        let first_len = first.data_len();
+        if first_len >= len {
+            return Ok(());
+        }
+
+        // gather into a private copy of the first buffer, grown if needed
+        let head = first.head.get();
+        let need = head + len;
+        let copy = first.buffer.borrow().copy_prefix(first.w_idx.get(), need);
+        let mut missing = len - first_len;
+        let mut consumed = 0;
+        for frag in frags.iter().skip(1) {
+            if missing == 0 {
+                break;
+            }
+            let take = frag.data_len().min(missing);
+            let at = need - missing;
+            frag.copy_out(0, &mut copy.buffer.borrow_mut()[at..at + take])?;
+            frag.pull_front(take)?;
+            missing -= take;
+            if frag.data_len() == 0 {
+                consumed += 1;
+            }
+        }
+        first.buffer.replace(Rc::new(copy));
+        first.w_idx.set(need);
+        frags.drain(1..1 + consumed);
+        Ok(())
+    }
+
+    /// Reader over the data of all frags.
+    pub fn cursor(&self) -> PacketCursor<'_> {
+        PacketCursor { pkt: self, pos: 0 }
+    }
+
+    /// Copy data at `offset` into `out`, across frag boundaries.
+    pub fn copy_out(&self, offset: usize, out: &mut [u8]) -> Result<(), ()> {
+        let mut cursor = self.cursor();
+        cursor.seek(offset)?;
+        cursor.read(out)
+    }
+
+    /// Copy the first `len` bytes of data, e.g. a header about to be parsed.
+    pub fn peek_front(&self, len: usize) -> Result<Vec<u8>, ()> {
+        let mut out = vec![0; len];
+        self.copy_out(0, &mut out)?;
+        Ok(out)
+    }
+
+    /// Overwrite bytes of the data at `offset`, e.g. to patch a checksum.
+    /// Shared buffers are copied first.
+    pub fn write_front(&self, offset: usize, data: &[u8]) -> Result<(), ()> {
+        if offset + data.len() > self.data_len() {
+            return Err(());
+        }
+        let frags = self.frags.borrow();
+        let mut offset = offset;
+        let mut data = data;
+        for frag in frags.iter() {
+            if data.is_empty() {
+                break;
+            }
+            let frag_len = frag.data_len();
+            if offset >= frag_len {
+                offset -= frag_len;
+                continue;
+            }
+            let n = (frag_len - offset).min(data.len());
+            frag.write_at(offset, &data[..n])?;
+            data = &data[n..];
+            offset = 0;
+        }
+        Ok(())
+    }
+
+    /// Zero-copy packet over `len` bytes at `offset`; the bytes are shared
+    /// until either packet writes to them.
+    pub fn slice(&self, offset: usize, len: usize) -> Result<NetworkPacket, ()> {
+        if offset + len > self.data_len() {
+            return Err(());
+        }
+        let pkt = NetworkPacket::with_headroom(self.headroom);
+        let mut offset = offset;
+        let mut left = len;
+        for frag in self.frags.borrow().iter() {
+            if left == 0 {
+                break;
+            }
+            let frag_len = frag.data_len();
+            if offset >= frag_len {
+                offset -= frag_len;
+                continue;
+            }
+            let n = (frag_len - offset).min(left);
+            pkt.frags.borrow_mut().push(frag.share(offset, n));
+            left -= n;
+            offset = 0;
+        }
+        Ok(pkt)
+    }
+
+    /// Zero-copy clone of the whole packet, header records included.
+    pub fn clone_shared(&self) -> NetworkPacket {
+        let pkt = NetworkPacket::with_headroom(self.headroom);
+        pkt.meta.replace(self.meta.borrow().clone());
+        for frag in self.frags.borrow().iter() {
+            let shared = frag.share(0, frag.data_len());
+            shared.copy_headers_from(frag);
+            pkt.frags.borrow_mut().push(shared);
+        }
+        pkt
+    }
+
+    /// Whether any frag still shares its buffer with another packet.
+    pub fn is_shared(&self) -> bool {
+        self.frags.borrow().iter().any(|f| f.is_shared())
+    }
+
+    /// `(absolute offset, len)` of the header recorded for `hdr_type`.
+    pub fn header_range(&self, hdr_type: ProtocolHeaderType) -> Option<(usize, usize)> {
+        self.frags.borrow().first()?.header_range(hdr_type)
+    }
+
+    /// Copy of the header recorded for `hdr_type`, pushed or pulled.
+    pub fn header_bytes(&self, hdr_type: ProtocolHeaderType) -> Option<Vec<u8>> {
+        let frags = self.frags.borrow();
+        let first = frags.first()?;
+        let (offset, len) = first.header_range(hdr_type)?;
+        first.copy_abs(offset, len).ok()
+    }
 }
 
 impl Debug for NetworkPacket {
@@ -156,8 +531,80 @@ impl Debug for NetworkPacket {
         f.debug_struct("Packet")
             // `Cell` gives us `get()`
             .field("size", &self.data_len())
+            .field("meta", &self.meta.borrow())
             // `RefCell` requires borrow; we pass a reference to the Vec
             .field("frags", &self.frags.borrow())
             .finish()
     }
-}
\ No newline at end of file
+}
+
+/// Sequential reader over all frags of a packet, big-endian helpers included.
+pub struct PacketCursor<'a> {
+    pkt: &'a NetworkPacket,
+    pos: usize,
+}
+
+impl<'a> PacketCursor<'a> {
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.pkt.data_len().saturating_sub(self.pos)
+    }
+
+    pub fn seek(&mut self, pos: usize) -> Result<(), ()> {
+        if pos > self.pkt.data_len() {
+            return Err(());
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    pub fn skip(&mut self, n: usize) -> Result<(), ()> {
+        self.seek(self.pos + n)
+    }
+
+    pub fn read(&mut self, out: &mut [u8]) -> Result<(), ()> {
+        if out.len() > self.remaining() {
+            return Err(());
+        }
+        let frags = self.pkt.frags.borrow();
+        let mut offset = self.pos;
+        let mut done = 0;
+        for frag in frags.iter() {
+            if done == out.len() {
+                break;
+            }
+            let frag_len = frag.data_len();
+            if offset >= frag_len {
+                offset -= frag_len;
+                continue;
+            }
+            let n = (frag_len - offset).min(out.len() - done);
+            frag.copy_out(offset, &mut out[done..done + n])?;
+            done += n;
+            offset = 0;
+        }
+        self.pos += out.len();
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ()> {
+        let mut b = [0u8; 1];
+        self.read(&mut b)?;
+        Ok(b[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ()> {
+        let mut b = [0u8; 2];
+        self.read(&mut b)?;
+        Ok(u16::from_be_bytes(b))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ()> {
+        let mut b = [0u8; 4];
+        self.read(&mut b)?;
+        Ok(u32::from_be_bytes(b))
+    }
+}
@@ -0,0 +1,115 @@
+use std::net::IpAddr;
+use crate::network::error::TransportError;
+use crate::network::packet::NetworkPacket;
+use crate::network::protocol::ProtocolHeaderType;
+
+/// What is known about a packet's checksums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumStatus {
+    /// nobody looked yet, software must verify
+    #[default]
+    Unknown,
+    /// verified by the driver or a decoder
+    Verified,
+    /// verification failed
+    Bad,
+    /// TX: the pseudo-header sum is in place, the rest is left to the driver
+    Partial,
+}
+
+/// Per-packet metadata. Decoders fill it in on the way up, later layers,
+/// forwarding and sockets read it instead of parsing headers again.
+///
+/// Offsets are absolute offsets into the packet's first buffer, the same
+/// space as `NetworkPacket::header_range`.
+#[derive(Debug, Clone, Default)]
+pub struct PacketMeta {
+    /// index of the interface the packet came in on, see `EthEntry::get_index`
+    pub ingress_if: Option<u32>,
+    /// free-form mark for filters and policy routing, kept when forwarded
+    pub mark: u32,
+
+    pub vlan: Option<u16>,
+    /// outer tag of a QinQ frame
+    pub svlan: Option<u16>,
+    pub vlan_pcp: u8,
+
+    pub l2_offset: Option<usize>,
+    pub l3_offset: Option<usize>,
+    pub l4_offset: Option<usize>,
+    pub l3_proto: ProtocolHeaderType,
+    pub l4_proto: ProtocolHeaderType,
+
+    pub l2_src: Option<[u8; 6]>,
+    pub l2_dst: Option<[u8; 6]>,
+    pub l3_src: Option<IpAddr>,
+    pub l3_dst: Option<IpAddr>,
+    pub l4_src_port: Option<u16>,
+    pub l4_dst_port: Option<u16>,
+    /// TTL / hop limit: RX as received, TX instead of the default
+    pub hop_limit: Option<u8>,
+
+    pub l3_csum: ChecksumStatus,
+    /// routed through us: the IP header and payload are final, TX only
+    /// redoes the link layer
+    pub forwarded: bool,
+    pub l4_csum: ChecksumStatus,
+
+    /// RX: when the driver received it, TX: when it was queued (usec)
+    pub timestamp_usec: u64,
+    pub flow_hash: Option<u32>,
+
+    pub app_id: usize,
+    pub sock_id: usize,
+}
+
+impl PacketMeta {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash of addresses, L4 protocol and ports, the same for both directions
+    /// of a flow. `None` until the L3 addresses are known.
+    pub fn compute_flow_hash(&self) -> Option<u32> {
+        let (src, dst) = (self.l3_src?, self.l3_dst?);
+        let fold = |addr: IpAddr| -> u32 {
+            match addr {
+                IpAddr::V4(a) => fnv1a(0x811c9dc5, &a.octets()),
+                IpAddr::V6(a) => fnv1a(0x811c9dc5, &a.octets()),
+            }
+        };
+        let ports = self.l4_src_port.unwrap_or(0) as u32 ^ self.l4_dst_port.unwrap_or(0) as u32;
+        let mut h = fold(src) ^ fold(dst);
+        h = fnv1a(h, &self.l4_proto.code().to_be_bytes());
+        h = fnv1a(h, &ports.to_be_bytes());
+        Some(h)
+    }
+
+    /// Compute and store the flow hash if it is not set yet.
+    pub fn fill_flow_hash(&mut self) -> Option<u32> {
+        if self.flow_hash.is_none() {
+            self.flow_hash = self.compute_flow_hash();
+        }
+        self.flow_hash
+    }
+}
+
+/// Record the ports of the UDP or TCP header at the front of `p`, which
+/// must have at least `hdr_len` bytes.
+pub(crate) fn read_l4_ports(p: &NetworkPacket, hdr_len: usize) -> Result<(), TransportError> {
+    let hdr = p.peek_front(hdr_len)
+        .map_err(|_| TransportError::Truncated { offset: 0, need: hdr_len })?;
+    let mut m = p.meta_mut();
+    m.l4_src_port = Some(u16::from_be_bytes([hdr[0], hdr[1]]));
+    m.l4_dst_port = Some(u16::from_be_bytes([hdr[2], hdr[3]]));
+    Ok(())
+}
+
+fn fnv1a(seed: u32, data: &[u8]) -> u32 {
+    let mut h = seed;
+    for b in data {
+        h ^= *b as u32;
+        h = h.wrapping_mul(0x01000193);
+    }
+    h
+}
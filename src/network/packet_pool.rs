@@ -0,0 +1,205 @@
+use std::cell::{Cell, RefCell};
+use crate::network::packet::{DEFAULT_FRAME_SIZE, DEFAULT_HEADROOM};
+
+/// Largest jumbo Ethernet frame we size buffers for, without FCS.
+pub const JUMBO_FRAME_SIZE: usize = 9018;
+
+thread_local! {
+    // one pool per scheduler thread, buffers never cross threads through it
+    static PACKET_POOL: PacketPool = PacketPool::new(PacketPoolConfig::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketBufKind {
+    Standard,
+    Jumbo,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PacketPoolConfig {
+    pub headroom: usize,
+    pub std_frame_size: usize,
+    /// most standard buffers handed out at once
+    pub std_count: usize,
+    pub jumbo_frame_size: usize,
+    /// most jumbo buffers handed out at once
+    pub jumbo_count: usize,
+}
+
+impl Default for PacketPoolConfig {
+    fn default() -> Self {
+        PacketPoolConfig {
+            headroom: DEFAULT_HEADROOM,
+            std_frame_size: DEFAULT_FRAME_SIZE,
+            std_count: 4096,
+            jumbo_frame_size: JUMBO_FRAME_SIZE,
+            jumbo_count: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PacketPoolStats {
+    pub buf_size: usize,
+    pub capacity: usize,
+    pub in_use: usize,
+    pub free: usize,
+    pub allocs: u64,
+    pub recycled: u64,
+    /// requests refused because `capacity` buffers were out
+    pub exhausted: u64,
+    /// exhausted requests served from the heap instead
+    pub fallback: u64,
+}
+
+struct PoolClass {
+    buf_size: Cell<usize>,
+    capacity: Cell<usize>,
+    free: RefCell<Vec<Vec<u8>>>,
+    stats: Cell<PacketPoolStats>,
+}
+
+impl PoolClass {
+    fn new(buf_size: usize, capacity: usize) -> PoolClass {
+        PoolClass {
+            buf_size: Cell::new(buf_size),
+            capacity: Cell::new(capacity),
+            free: RefCell::new(Vec::new()),
+            stats: Cell::new(PacketPoolStats::default()),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut PacketPoolStats)) {
+        let mut st = self.stats.get();
+        f(&mut st);
+        self.stats.set(st);
+    }
+
+    fn take(&self) -> Option<Vec<u8>> {
+        let st = self.stats.get();
+        if st.in_use >= self.capacity.get() {
+            self.update(|st| st.exhausted += 1);
+            return None;
+        }
+        let buf = self.free
+            .borrow_mut()
+            .pop()
+            .unwrap_or_else(|| vec![0; self.buf_size.get()]);
+        self.update(|st| {
+            st.allocs += 1;
+            st.in_use += 1;
+        });
+        Some(buf)
+    }
+
+    fn give_back(&self, mut buf: Vec<u8>) {
+        self.update(|st| st.in_use = st.in_use.saturating_sub(1));
+        // buffers from before a reconfigure are just freed
+        if buf.len() == self.buf_size.get() {
+            // don't leak the previous packet's bytes to the next user
+            buf.fill(0);
+            self.free.borrow_mut().push(buf);
+            self.update(|st| st.recycled += 1);
+        }
+    }
+
+    fn stats(&self) -> PacketPoolStats {
+        let mut st = self.stats.get();
+        st.buf_size = self.buf_size.get();
+        st.capacity = self.capacity.get();
+        st.free = self.free.borrow().len();
+        st
+    }
+}
+
+/// Fixed-size packet buffers recycled per thread. `FixedPacketBuffer` takes
+/// its storage from here and gives it back when the packet is dropped.
+pub struct PacketPool {
+    headroom: Cell<usize>,
+    std: PoolClass,
+    jumbo: PoolClass,
+}
+
+impl PacketPool {
+    fn new(config: PacketPoolConfig) -> PacketPool {
+        PacketPool {
+            headroom: Cell::new(config.headroom),
+            std: PoolClass::new(config.headroom + config.std_frame_size, config.std_count),
+            jumbo: PoolClass::new(config.headroom + config.jumbo_frame_size, config.jumbo_count),
+        }
+    }
+
+    fn class(&self, kind: PacketBufKind) -> &PoolClass {
+        match kind {
+            PacketBufKind::Standard => &self.std,
+            PacketBufKind::Jumbo => &self.jumbo,
+        }
+    }
+
+    fn kind_for(&self, sz: usize) -> Option<PacketBufKind> {
+        if sz <= self.std.buf_size.get() {
+            Some(PacketBufKind::Standard)
+        } else if sz <= self.jumbo.buf_size.get() {
+            Some(PacketBufKind::Jumbo)
+        } else {
+            None
+        }
+    }
+
+    /// Resize the current thread's pool. Free buffers are released, buffers
+    /// still in flight are dropped instead of recycled when they come back.
+    pub fn configure(config: PacketPoolConfig) {
+        PACKET_POOL.with(|pool| {
+            pool.headroom.set(config.headroom);
+            for (class, size, count) in [
+                (&pool.std, config.std_frame_size, config.std_count),
+                (&pool.jumbo, config.jumbo_frame_size, config.jumbo_count),
+            ] {
+                class.buf_size.set(config.headroom + size);
+                class.capacity.set(count);
+                class.free.borrow_mut().clear();
+            }
+        })
+    }
+
+    /// Headroom reserved in buffers allocated by drivers.
+    pub fn headroom() -> usize {
+        PACKET_POOL.with(|pool| pool.headroom.get())
+    }
+
+    pub fn buf_size(kind: PacketBufKind) -> usize {
+        PACKET_POOL.with(|pool| pool.class(kind).buf_size.get())
+    }
+
+    pub fn stats(kind: PacketBufKind) -> PacketPoolStats {
+        PACKET_POOL.with(|pool| pool.class(kind).stats())
+    }
+
+    /// Strict allocation for drivers: `None` when the class is exhausted.
+    pub(crate) fn alloc(kind: PacketBufKind) -> Option<Vec<u8>> {
+        PACKET_POOL.with(|pool| pool.class(kind).take())
+    }
+
+    /// Buffer of at least `sz` bytes. Falls back to the heap when the pool is
+    /// exhausted or `sz` is larger than any class; such buffers are not recycled.
+    pub(crate) fn alloc_at_least(sz: usize) -> (Vec<u8>, Option<PacketBufKind>) {
+        PACKET_POOL.with(|pool| {
+            let Some(kind) = pool.kind_for(sz) else {
+                return (vec![0; sz], None);
+            };
+            let class = pool.class(kind);
+            match class.take() {
+                Some(buf) => (buf, Some(kind)),
+                None => {
+                    class.update(|st| st.fallback += 1);
+                    (vec![0; sz], None)
+                }
+            }
+        })
+    }
+
+    pub(crate) fn recycle(kind: PacketBufKind, buf: Vec<u8>) {
+        // the pool may already be gone while the thread shuts down
+        _ = PACKET_POOL.try_with(|pool| pool.class(kind).give_back(buf));
+    }
+}
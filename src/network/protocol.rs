@@ -2,12 +2,16 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use crate::network::ethernet::EthKey;
+use crate::network::error::GraphError;
+use crate::network::ethernet::{EthKey, ETH_P_ARP, ETH_P_IPV4, ETH_P_IPV6};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub enum ProtocolHeaderType {
+    #[default]
     None,
     Socket,
+    /// not for us, to be routed on
+    Forward,
     Ethernet,
     ARP,
     IPv4,
@@ -16,24 +20,122 @@ pub enum ProtocolHeaderType {
     TCP,
     ICMPv4,
     ICMPv6,
+    /// protocols registered from outside the stack, e.g. GRE or a custom L2
+    Custom(u16),
+}
+
+/// Numbers every stack knows out of the box. More can be added at runtime
+/// with `ProtocolRegistry::register_ethertype` / `register_ip_proto`.
+const DEFAULT_ETHERTYPES: &[(u16, ProtocolHeaderType)] = &[
+    (ETH_P_IPV4, ProtocolHeaderType::IPv4),
+    (ETH_P_ARP, ProtocolHeaderType::ARP),
+    (ETH_P_IPV6, ProtocolHeaderType::IPv6),
+];
+
+const DEFAULT_IP_PROTOS: &[(u8, ProtocolHeaderType)] = &[
+    (1, ProtocolHeaderType::ICMPv4),
+    (6, ProtocolHeaderType::TCP),
+    (17, ProtocolHeaderType::UDP),
+    (58, ProtocolHeaderType::ICMPv6),
+];
+
+/// One number space in both directions. A type registered under several
+/// numbers decodes from all of them and encodes with the first one.
+struct NumberTable<N> {
+    by_num: HashMap<N, ProtocolHeaderType>,
+    by_type: HashMap<ProtocolHeaderType, N>,
+}
+
+impl<N: Hash + Eq + Copy + Into<u16>> NumberTable<N> {
+    fn new(defaults: &[(N, ProtocolHeaderType)]) -> Self {
+        let mut t = NumberTable { by_num: HashMap::new(), by_type: HashMap::new() };
+        for (n, pt) in defaults {
+            _ = t.register(*n, *pt);
+        }
+        t
+    }
+
+    fn register(&mut self, n: N, pt: ProtocolHeaderType) -> Result<(), GraphError> {
+        match self.by_num.get(&n) {
+            Some(old) if *old != pt => Err(GraphError::NumberInUse { number: n.into(), owner: *old }),
+            _ => {
+                self.by_num.insert(n, pt);
+                self.by_type.entry(pt).or_insert(n);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Two-way mapping between header types and wire numbers, one table for
+/// EtherTypes and one for IP protocol numbers. Each stack has its own,
+/// shared by its protocol graph and the layers that read or write numbers.
+pub struct ProtocolRegistry {
+    ethertypes: RwLock<NumberTable<u16>>,
+    ip_protos: RwLock<NumberTable<u8>>,
+}
+
+impl ProtocolRegistry {
+    pub(crate) fn new() -> ProtocolRegistry {
+        ProtocolRegistry {
+            ethertypes: RwLock::new(NumberTable::new(DEFAULT_ETHERTYPES)),
+            ip_protos: RwLock::new(NumberTable::new(DEFAULT_IP_PROTOS)),
+        }
+    }
+
+    /// Map `ethertype` to `pt`. `Err` if the number is taken by another type.
+    pub fn register_ethertype(&self, ethertype: u16, pt: ProtocolHeaderType) -> Result<(), GraphError> {
+        self.ethertypes.write().unwrap().register(ethertype, pt)
+    }
+
+    /// Map IP protocol number `proto` to `pt`. `Err` if the number is taken
+    /// by another type.
+    pub fn register_ip_proto(&self, proto: u8, pt: ProtocolHeaderType) -> Result<(), GraphError> {
+        self.ip_protos.write().unwrap().register(proto, pt)
+    }
+
+    /// EtherType carried in an Ethernet header for `pt`.
+    pub fn ethertype(&self, pt: ProtocolHeaderType) -> Option<u16> {
+        self.ethertypes.read().unwrap().by_type.get(&pt).copied()
+    }
+
+    /// IP protocol / IPv6 next header number for `pt`.
+    pub fn ip_proto(&self, pt: ProtocolHeaderType) -> Option<u8> {
+        self.ip_protos.read().unwrap().by_type.get(&pt).copied()
+    }
+
+    /// Protocol registered for an EtherType.
+    pub fn by_ethertype(&self, ethertype: u16) -> Option<ProtocolHeaderType> {
+        self.ethertypes.read().unwrap().by_num.get(&ethertype).copied()
+    }
+
+    /// Protocol registered for an IP protocol / IPv6 next header number.
+    pub fn by_ip_proto(&self, proto: u8) -> Option<ProtocolHeaderType> {
+        self.ip_protos.read().unwrap().by_num.get(&proto).copied()
+    }
 }
 
 impl ProtocolHeaderType {
-    pub fn code(&self) -> u16 {
+    /// Stable id of the type itself, not a wire number. The stack's
+    /// `ProtocolRegistry` has what goes into headers.
+    pub fn code(&self) -> u32 {
         match self {
-            ProtocolHeaderType::Ethernet => 1,
-            ProtocolHeaderType::ARP      => 0xaa,
-            ProtocolHeaderType::IPv4     => 0x0800, // typical EtherType for IPv4
-            ProtocolHeaderType::IPv6     => 0x86DD, // typical EtherType for IPv6
-            ProtocolHeaderType::UDP      => 17,     // IP protocol number
-            ProtocolHeaderType::TCP      => 6,      // IP protocol number
-            ProtocolHeaderType::ICMPv4    => 0xa4,
-            ProtocolHeaderType::ICMPv6    => 0xa6,
-            ProtocolHeaderType::None    => 0,
-            _ => 0
+            ProtocolHeaderType::None     => 0,
+            ProtocolHeaderType::Socket   => 1,
+            ProtocolHeaderType::Ethernet => 2,
+            ProtocolHeaderType::ARP      => 3,
+            ProtocolHeaderType::IPv4     => 4,
+            ProtocolHeaderType::IPv6     => 5,
+            ProtocolHeaderType::UDP      => 6,
+            ProtocolHeaderType::TCP      => 7,
+            ProtocolHeaderType::ICMPv4   => 8,
+            ProtocolHeaderType::ICMPv6   => 9,
+            ProtocolHeaderType::Forward  => 10,
+            ProtocolHeaderType::Custom(id) => 0x100 + *id as u32,
         }
     }
 }
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ProtocolResValue {
     pub t: ProtocolHeaderType,   // 0..=32
@@ -87,20 +189,23 @@ impl<ProtocolKey, ProtocolRes> NetworkProtocolMng<ProtocolKey, ProtocolRes> {
 
 pub(crate) trait NetworkProtocol {}
 
-pub(crate) struct ProtocolMetaData {
+/// What a decoder hands back: the type of the next layer. `None` when the
+/// packet was consumed, `Socket` when it goes up to the sockets, `Forward`
+/// when it is routed on.
+pub struct ProtocolMetaData {
     pt: ProtocolHeaderType,
 }
 
 impl ProtocolMetaData {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         ProtocolMetaData { pt: ProtocolHeaderType::None }
     }
 
-    pub(crate) fn set_pt(&mut self, p: ProtocolHeaderType) {
+    pub fn set_pt(&mut self, p: ProtocolHeaderType) {
         self.pt = p;
     }
 
-    pub(crate) fn get_pt(&self) -> ProtocolHeaderType {
+    pub fn get_pt(&self) -> ProtocolHeaderType {
         self.pt
     }
 }
\ No newline at end of file
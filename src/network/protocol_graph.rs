@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use crate::network::error::{GraphError, NetError};
+use crate::network::module_traits::ProtocolLayer;
+use crate::network::packet::NetworkPacket;
+use crate::network::protocol::{ProtocolHeaderType, ProtocolRegistry};
+
+/// Most layers a packet may go through, keeps a looping graph from spinning.
+const MAX_LAYER_DEPTH: usize = 8;
+
+/// Where a packet ended up after walking the graph on RX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolDispatch {
+    /// a layer kept it, e.g. ARP or ICMP
+    Consumed,
+    /// all headers are off, hand it to the sockets
+    Socket,
+    /// addressed elsewhere, route it on from its L3 header
+    Forward,
+}
+
+/// Protocol handlers keyed by header type. Decoders name the next layer by
+/// type; the EtherType / IP protocol number mapping lives in the stack's
+/// `ProtocolRegistry`, so a handler registered here with its number is
+/// reached without any other change to the stack.
+pub struct ProtocolGraph {
+    layers: RwLock<HashMap<ProtocolHeaderType, Arc<dyn ProtocolLayer>>>,
+    registry: Arc<ProtocolRegistry>,
+}
+
+impl ProtocolGraph {
+    pub fn new(registry: Arc<ProtocolRegistry>) -> ProtocolGraph {
+        ProtocolGraph { layers: RwLock::new(HashMap::new()), registry }
+    }
+
+    /// Register a handler for `pt`, replacing the previous one.
+    pub fn register(&self, pt: ProtocolHeaderType, layer: Arc<dyn ProtocolLayer>) {
+        self.layers.write().unwrap().insert(pt, layer);
+    }
+
+    /// Register a handler carried directly over Ethernet.
+    pub fn register_ethertype(&self, ethertype: u16, pt: ProtocolHeaderType, layer: Arc<dyn ProtocolLayer>) -> Result<(), GraphError> {
+        self.registry.register_ethertype(ethertype, pt)?;
+        self.register(pt, layer);
+        Ok(())
+    }
+
+    /// Register a handler carried over IPv4/IPv6.
+    pub fn register_ip_proto(&self, proto: u8, pt: ProtocolHeaderType, layer: Arc<dyn ProtocolLayer>) -> Result<(), GraphError> {
+        self.registry.register_ip_proto(proto, pt)?;
+        self.register(pt, layer);
+        Ok(())
+    }
+
+    pub fn unregister(&self, pt: ProtocolHeaderType) -> Result<(), GraphError> {
+        self.layers.write().unwrap().remove(&pt).map(|_| ()).ok_or(GraphError::NotRegistered(pt))
+    }
+
+    pub fn get(&self, pt: ProtocolHeaderType) -> Option<Arc<dyn ProtocolLayer>> {
+        self.layers.read().unwrap().get(&pt).cloned()
+    }
+
+    /// Decode from the `first` layer up, following what each decoder names
+    /// as the next one.
+    pub fn decode(&self, first: ProtocolHeaderType, p: NetworkPacket) -> (NetworkPacket, Result<ProtocolDispatch, NetError>) {
+        let mut p = p;
+        let mut pt = first;
+        for _ in 0..MAX_LAYER_DEPTH {
+            let Some(layer) = self.get(pt) else {
+                return (p, Err(GraphError::NoHandler(pt).into()));
+            };
+            let res;
+            (p, res) = layer.decode_layer(p);
+            let meta = match res {
+                Ok(meta) => meta,
+                Err(e) => return (p, Err(e)),
+            };
+            pt = match meta.get_pt() {
+                ProtocolHeaderType::None => return (p, Ok(ProtocolDispatch::Consumed)),
+                ProtocolHeaderType::Socket => return (p, Ok(ProtocolDispatch::Socket)),
+                ProtocolHeaderType::Forward => return (p, Ok(ProtocolDispatch::Forward)),
+                next => next,
+            };
+        }
+        (p, Err(GraphError::TooManyLayers(pt).into()))
+    }
+
+    /// Encode the given layers in order, innermost first.
+    pub fn encode(&self, chain: &[ProtocolHeaderType], p: NetworkPacket) -> (NetworkPacket, Result<(), NetError>) {
+        let mut p = p;
+        for pt in chain {
+            let Some(layer) = self.get(*pt) else {
+                return (p, Err(GraphError::NoHandler(*pt).into()));
+            };
+            let res;
+            (p, res) = layer.encode_layer(p);
+            if res.is_err() {
+                return (p, res);
+            }
+        }
+        (p, Ok(()))
+    }
+}
@@ -0,0 +1,897 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use crate::executor::runtime::Runtime;
+use crate::network::error::Icmpv6Error;
+use crate::network::ethernet::{EthEntry, MacAddr};
+use crate::network::ipv6::{is_link_local, multicast_mac, IPv6Protocol, Ipv6AddrEvent, Ipv6AddrEvents, Ipv6AddrState, Ipv6Cidr, Ipv6IidMode};
+use crate::network::timer::jitter;
+use crate::network::tx_queue::TxOutbox;
+use crate::network::ndp::{lladdr_option, nd_options, send_icmpv6, Ndp, ND_HOP_LIMIT, ND_OPT_LLADDR_UNITS, ND_OPT_SOURCE_LLADDR};
+use crate::network::packet::NetworkPacket;
+use crate::network::packet_meta::PacketMeta;
+
+pub const ICMPV6_ROUTER_SOLICIT: u8 = 133;
+pub const ICMPV6_ROUTER_ADVERT: u8 = 134;
+
+/// RS up to the options.
+const RS_MSG_LEN: usize = 8;
+/// RA up to the options.
+const RA_MSG_LEN: usize = 16;
+const ND_OPT_PREFIX_INFO: u8 = 3;
+const ND_OPT_MTU: u8 = 5;
+const PREFIX_INFO_LEN: usize = 32;
+const MTU_OPT_LEN: usize = 8;
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+/// Lifetime that never runs out.
+const INFINITE_LIFETIME: u32 = u32::MAX;
+
+// RFC 4861 10 host constants
+const RTR_SOLICITATION_INTERVAL_USEC: u64 = 4_000_000;
+const MAX_RTR_SOLICITATIONS: u8 = 3;
+const MAX_RTR_SOLICITATION_DELAY_USEC: u64 = 1_000_000;
+// RFC 4861 10 router constants
+const MAX_INITIAL_RTR_ADVERT_INTERVAL_USEC: u64 = 16_000_000;
+const MAX_INITIAL_RTR_ADVERTISEMENTS: u8 = 3;
+const MIN_DELAY_BETWEEN_RAS_USEC: u64 = 3_000_000;
+const MAX_RA_DELAY_TIME_USEC: u64 = 500_000;
+
+/// RFC 4862 5.5.3 e): advertised valid lifetimes shorter than this don't
+/// cut an address's remaining one, against spoofed RAs.
+const MIN_VALID_LIFETIME_USEC: u64 = 2 * 3600 * 1_000_000;
+/// Stable-privacy addresses tried after a duplicate (RFC 7217 6).
+const IDGEN_RETRIES: u8 = 3;
+/// Metric of routes learned from router advertisements.
+const RA_ROUTE_METRIC: u32 = 1024;
+
+/// When a lifetime in seconds runs out, counted from `now`.
+fn expiry(now: u64, secs: u32) -> u64 {
+    if secs == INFINITE_LIFETIME {
+        return u64::MAX;
+    }
+    now + secs as u64 * 1_000_000
+}
+
+/// What an interface advertises in router mode. The defaults are those of
+/// RFC 4861 6.2.1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6RaConfig {
+    /// on-link prefixes hosts autoconfigure addresses from, /64 for SLAAC
+    pub prefixes: Vec<Ipv6Cidr>,
+    /// seconds hosts use us as default router, 0 for none
+    pub router_lifetime: u16,
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+    /// hop limit for hosts, 0 leaves theirs
+    pub hop_limit: u8,
+    /// link MTU to advertise, if any
+    pub mtu: Option<u32>,
+    /// seconds between unsolicited advertisements at most, the least is a
+    /// third of it
+    pub max_interval: u32,
+}
+
+impl Default for Ipv6RaConfig {
+    fn default() -> Self {
+        Ipv6RaConfig {
+            prefixes: Vec::new(),
+            router_lifetime: 1800,
+            valid_lifetime: 2_592_000,
+            preferred_lifetime: 604_800,
+            hop_limit: 64,
+            mtu: None,
+            max_interval: 600,
+        }
+    }
+}
+
+/// Prefix Information option.
+#[derive(Debug, Clone)]
+struct PrefixInfo {
+    prefix: Ipv6Cidr,
+    on_link: bool,
+    autonomous: bool,
+    valid: u32,
+    preferred: u32,
+}
+
+impl PrefixInfo {
+    fn parse(opt: &[u8]) -> Option<PrefixInfo> {
+        if opt.len() != PREFIX_INFO_LEN || opt[2] > 128 {
+            return None;
+        }
+        let rd32 = |i: usize| u32::from_be_bytes(opt[i..i + 4].try_into().unwrap());
+        let addr = Ipv6Addr::from(<[u8; 16]>::try_from(&opt[16..32]).unwrap());
+        Some(PrefixInfo {
+            prefix: Ipv6Cidr { addr, prefix_len: opt[2] }.network(),
+            on_link: opt[3] & PREFIX_FLAG_ON_LINK != 0,
+            autonomous: opt[3] & PREFIX_FLAG_AUTONOMOUS != 0,
+            valid: rd32(4),
+            preferred: rd32(8),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; PREFIX_INFO_LEN] {
+        let mut b = [0u8; PREFIX_INFO_LEN];
+        b[0] = ND_OPT_PREFIX_INFO;
+        b[1] = (PREFIX_INFO_LEN / 8) as u8;
+        b[2] = self.prefix.prefix_len;
+        if self.on_link {
+            b[3] |= PREFIX_FLAG_ON_LINK;
+        }
+        if self.autonomous {
+            b[3] |= PREFIX_FLAG_AUTONOMOUS;
+        }
+        b[4..8].copy_from_slice(&self.valid.to_be_bytes());
+        b[8..12].copy_from_slice(&self.preferred.to_be_bytes());
+        b[16..32].copy_from_slice(&self.prefix.network().addr.octets());
+        b
+    }
+}
+
+/// Decoded Router Advertisement.
+#[derive(Debug, Clone)]
+struct RouterAdvert {
+    hop_limit: u8,
+    router_lifetime: u16,
+    reachable_msec: u32,
+    retrans_msec: u32,
+    lladdr: Option<MacAddr>,
+    mtu: Option<u32>,
+    prefixes: Vec<PrefixInfo>,
+}
+
+impl RouterAdvert {
+    fn parse(b: &[u8]) -> Result<RouterAdvert, Icmpv6Error> {
+        if b.len() < RA_MSG_LEN {
+            return Err(Icmpv6Error::Truncated { offset: 0, need: RA_MSG_LEN });
+        }
+        if b[1] != 0 {
+            return Err(Icmpv6Error::Malformed { offset: 1 });
+        }
+        let rd32 = |i: usize| u32::from_be_bytes(b[i..i + 4].try_into().unwrap());
+        let mut ra = RouterAdvert {
+            hop_limit: b[4],
+            router_lifetime: u16::from_be_bytes([b[6], b[7]]),
+            reachable_msec: rd32(8),
+            retrans_msec: rd32(12),
+            lladdr: None,
+            mtu: None,
+            prefixes: Vec::new(),
+        };
+        for (t, opt) in nd_options(b, RA_MSG_LEN)? {
+            match t {
+                ND_OPT_SOURCE_LLADDR => ra.lladdr = lladdr_option(opt),
+                ND_OPT_MTU if opt.len() == MTU_OPT_LEN => {
+                    ra.mtu = Some(u32::from_be_bytes(opt[4..8].try_into().unwrap()));
+                }
+                ND_OPT_PREFIX_INFO => ra.prefixes.extend(PrefixInfo::parse(opt)),
+                _ => {}
+            }
+        }
+        Ok(ra)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut b = vec![0u8; RA_MSG_LEN];
+        b[0] = ICMPV6_ROUTER_ADVERT;
+        b[4] = self.hop_limit;
+        b[6..8].copy_from_slice(&self.router_lifetime.to_be_bytes());
+        b[8..12].copy_from_slice(&self.reachable_msec.to_be_bytes());
+        b[12..16].copy_from_slice(&self.retrans_msec.to_be_bytes());
+        if let Some(mac) = &self.lladdr {
+            b.extend_from_slice(&[ND_OPT_SOURCE_LLADDR, ND_OPT_LLADDR_UNITS]);
+            b.extend_from_slice(&mac.mac);
+        }
+        if let Some(mtu) = self.mtu {
+            b.extend_from_slice(&[ND_OPT_MTU, (MTU_OPT_LEN / 8) as u8, 0, 0]);
+            b.extend_from_slice(&mtu.to_be_bytes());
+        }
+        for p in &self.prefixes {
+            b.extend_from_slice(&p.to_bytes());
+        }
+        b
+    }
+}
+
+/// An interface that autoconfigures from router advertisements.
+struct AutoconfIf {
+    iface: Arc<EthEntry>,
+    mode: Ipv6IidMode,
+    solicits_sent: u8,
+    /// next router solicitation, `None` once a router answered or we gave up
+    next_solicit_usec: Option<u64>,
+}
+
+/// A router advertisement installed as default route.
+struct DefaultRouter {
+    addr: Ipv6Addr,
+    iface: Arc<EthEntry>,
+    expires_usec: u64,
+}
+
+/// An on-link prefix route from a router advertisement.
+struct OnLinkPrefix {
+    prefix: Ipv6Cidr,
+    iface: Arc<EthEntry>,
+    expires_usec: u64,
+}
+
+/// An address made from an advertised prefix.
+struct AutoAddr {
+    addr: Ipv6Addr,
+    prefix: Ipv6Cidr,
+    iface: Arc<EthEntry>,
+    mode: Ipv6IidMode,
+    dad_counter: u8,
+    preferred_usec: u64,
+    valid_usec: u64,
+    events: Ipv6AddrEvents,
+}
+
+/// A prefix whose addresses all turned out duplicate, not tried again on
+/// its interface until its valid lifetime runs out.
+struct GivenUpPrefix {
+    prefix: Ipv6Cidr,
+    iface: Arc<EthEntry>,
+    expires_usec: u64,
+}
+
+/// An interface in router mode.
+struct AdvertIf {
+    iface: Arc<EthEntry>,
+    config: Ipv6RaConfig,
+    initial_sent: u8,
+    next_usec: u64,
+    last_usec: Option<u64>,
+}
+
+/// Router discovery and stateless address autoconfiguration (RFC 4861 6,
+/// RFC 4862): the host side learns default routers, on-link prefixes and
+/// addresses from RAs, the router side sends RAs. Lifetimes run out on the
+/// stack timer.
+pub(crate) struct RouterAdv {
+    ipv6: Arc<IPv6Protocol>,
+    autoconf: Mutex<Vec<AutoconfIf>>,
+    routers: Mutex<Vec<DefaultRouter>>,
+    prefixes: Mutex<Vec<OnLinkPrefix>>,
+    addrs: Mutex<Vec<AutoAddr>>,
+    given_up: Mutex<Vec<GivenUpPrefix>>,
+    advertising: Mutex<Vec<AdvertIf>>,
+    outbox: TxOutbox,
+}
+
+/// Whether a packet came in on `iface`: its VLAN tags, and its MAC unless
+/// sent to a group.
+fn arrived_on(iface: &EthEntry, m: &PacketMeta) -> bool {
+    let to_iface = m.l2_dst.is_none_or(|d| MacAddr::new(d).is_multicast() || d == iface.get_mac().mac);
+    to_iface && iface.get_vlan() == m.vlan && iface.get_svlan() == m.svlan
+}
+
+fn all_nodes() -> Ipv6Addr {
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1)
+}
+
+fn all_routers() -> Ipv6Addr {
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2)
+}
+
+impl RouterAdv {
+    pub(crate) fn new(ipv6: Arc<IPv6Protocol>, outbox: TxOutbox) -> RouterAdv {
+        RouterAdv {
+            ipv6,
+            autoconf: Mutex::new(Vec::new()),
+            routers: Mutex::new(Vec::new()),
+            prefixes: Mutex::new(Vec::new()),
+            addrs: Mutex::new(Vec::new()),
+            given_up: Mutex::new(Vec::new()),
+            advertising: Mutex::new(Vec::new()),
+            outbox,
+        }
+    }
+
+    /// Autoconfigure `iface` from router advertisements, interface
+    /// identifiers made as `mode` says, or stop with `None`. Stopping keeps
+    /// what was learned until it expires.
+    pub(crate) fn set_autoconf(&self, iface: &Arc<EthEntry>, mode: Option<Ipv6IidMode>, now: u64) {
+        let mut autoconf = self.autoconf.lock().unwrap();
+        autoconf.retain(|a| !Arc::ptr_eq(&a.iface, iface));
+        if let Some(mode) = mode {
+            autoconf.push(AutoconfIf {
+                iface: iface.clone(),
+                mode,
+                solicits_sent: 0,
+                next_solicit_usec: Some(now + jitter(MAX_RTR_SOLICITATION_DELAY_USEC)),
+            });
+        }
+    }
+
+    /// Send router advertisements from `iface` as `config` says, or stop
+    /// with `None`; the last one then has a zero router lifetime.
+    pub(crate) fn set_advertising(&self, iface: &Arc<EthEntry>, config: Option<Ipv6RaConfig>, ndp: &Ndp, now: u64) -> Result<(), Icmpv6Error> {
+        let mut adv = self.advertising.lock().unwrap();
+        let old = adv.iter().position(|a| Arc::ptr_eq(&a.iface, iface)).map(|i| adv.remove(i));
+        let Some(config) = config else {
+            if let Some(mut old) = old {
+                old.config.router_lifetime = 0;
+                self.send_advert(&old);
+                ndp.set_router(iface, false);
+                self.ipv6.leave_group(&all_routers());
+            }
+            return Ok(());
+        };
+        if self.ipv6.link_local_of(iface).is_none() {
+            return Err(Icmpv6Error::NoLinkLocal);
+        }
+        if old.is_none() {
+            ndp.set_router(iface, true);
+            self.ipv6.join_group(all_routers());
+        }
+        adv.push(AdvertIf { iface: iface.clone(), config, initial_sent: 0, next_usec: now, last_usec: None });
+        Ok(())
+    }
+
+    /// Forget an interface that goes away: stop advertising there, and drop
+    /// what was learned on it. Its routes and addresses are removed by the
+    /// caller.
+    pub(crate) fn forget_iface(&self, iface: &Arc<EthEntry>, ndp: &Ndp, now: u64) {
+        _ = self.set_advertising(iface, None, ndp, now);
+        self.autoconf.lock().unwrap().retain(|a| !Arc::ptr_eq(&a.iface, iface));
+        self.routers.lock().unwrap().retain(|r| !Arc::ptr_eq(&r.iface, iface));
+        self.prefixes.lock().unwrap().retain(|p| !Arc::ptr_eq(&p.iface, iface));
+        self.addrs.lock().unwrap().retain(|a| !Arc::ptr_eq(&a.iface, iface));
+        self.given_up.lock().unwrap().retain(|g| !Arc::ptr_eq(&g.iface, iface));
+    }
+
+    fn is_advertising(&self, iface: &Arc<EthEntry>) -> bool {
+        self.advertising.lock().unwrap().iter().any(|a| Arc::ptr_eq(&a.iface, iface))
+    }
+
+    /// Handle an RS or RA, the packet starting at the ICMPv6 header.
+    pub(crate) fn receive(&self, p: &NetworkPacket, msg: &[u8], ndp: &Ndp) -> Result<(), Icmpv6Error> {
+        let (src, hop_limit) = {
+            let m = p.meta();
+            let Some(IpAddr::V6(src)) = m.l3_src else {
+                return Err(Icmpv6Error::Malformed { offset: 0 });
+            };
+            (src, m.hop_limit.unwrap_or(0))
+        };
+        // RFC 4861 6.1.1 and 6.1.2
+        if hop_limit != ND_HOP_LIMIT {
+            return Err(Icmpv6Error::BadHopLimit(hop_limit));
+        }
+        let now = Runtime::get_time_usec();
+        if msg[0] == ICMPV6_ROUTER_SOLICIT {
+            return self.receive_solicit(p, msg, &src, ndp, now);
+        }
+        if !is_link_local(&src) {
+            return Err(Icmpv6Error::BadSource(src));
+        }
+        let ra = RouterAdvert::parse(msg)?;
+        let ifaces: Vec<(Arc<EthEntry>, Ipv6IidMode)> = self.autoconf.lock().unwrap()
+            .iter_mut()
+            .filter(|a| arrived_on(&a.iface, &p.meta()))
+            .map(|a| {
+                a.next_solicit_usec = None;
+                (a.iface.clone(), a.mode)
+            })
+            .collect();
+        for (iface, mode) in ifaces {
+            // routers don't take configuration from each other
+            if !self.is_advertising(&iface) {
+                self.apply(&ra, &src, &iface, mode, ndp, now);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_solicit(&self, p: &NetworkPacket, msg: &[u8], src: &Ipv6Addr, ndp: &Ndp, now: u64) -> Result<(), Icmpv6Error> {
+        if msg.len() < RS_MSG_LEN {
+            return Err(Icmpv6Error::Truncated { offset: 0, need: RS_MSG_LEN });
+        }
+        if msg[1] != 0 {
+            return Err(Icmpv6Error::Malformed { offset: 1 });
+        }
+        let lladdr = nd_options(msg, RS_MSG_LEN)?
+            .into_iter()
+            .filter(|(t, _)| *t == ND_OPT_SOURCE_LLADDR)
+            .find_map(|(_, opt)| lladdr_option(opt));
+        if src.is_unspecified() && lladdr.is_some() {
+            return Err(Icmpv6Error::Malformed { offset: RS_MSG_LEN });
+        }
+        let mut adv = self.advertising.lock().unwrap();
+        for a in adv.iter_mut().filter(|a| arrived_on(&a.iface, &p.meta())) {
+            if let (Some(mac), Some(ours)) = (&lladdr, self.ipv6.link_local_of(&a.iface)) {
+                ndp.learn(src, mac, &a.iface, &ours, now);
+            }
+            // RFC 4861 6.2.6: answer soon, but not more often than allowed
+            let mut at = now + jitter(MAX_RA_DELAY_TIME_USEC);
+            if let Some(last) = a.last_usec {
+                at = at.max(last + MIN_DELAY_BETWEEN_RAS_USEC);
+            }
+            a.next_usec = a.next_usec.min(at);
+        }
+        Ok(())
+    }
+
+    /// Take in an RA that came in on `iface` (RFC 4861 6.3.4, RFC 4862
+    /// 5.5.3).
+    fn apply(&self, ra: &RouterAdvert, router: &Ipv6Addr, iface: &Arc<EthEntry>, mode: Ipv6IidMode, ndp: &Ndp, now: u64) {
+        if ra.hop_limit != 0 {
+            self.ipv6.set_hop_limit(ra.hop_limit);
+        }
+        ndp.set_timers(ra.reachable_msec, ra.retrans_msec);
+        if let Some(mtu) = ra.mtu {
+            let _ = self.ipv6.set_mtu(mtu);
+        }
+        if let (Some(mac), Some(ours)) = (&ra.lladdr, self.ipv6.link_local_of(iface)) {
+            ndp.learn(router, mac, iface, &ours, now);
+        }
+        self.update_router(router, iface, ra.router_lifetime, now);
+        for pi in &ra.prefixes {
+            if is_link_local(&pi.prefix.addr) {
+                continue;
+            }
+            if pi.on_link {
+                self.update_prefix(pi, iface, now);
+            }
+            if pi.autonomous && pi.preferred <= pi.valid {
+                self.update_addr(pi, iface, mode, ndp, now);
+            }
+        }
+    }
+
+    fn update_router(&self, router: &Ipv6Addr, iface: &Arc<EthEntry>, lifetime: u16, now: u64) {
+        let mut routers = self.routers.lock().unwrap();
+        let at = routers.iter().position(|r| r.addr == *router && Arc::ptr_eq(&r.iface, iface));
+        match at {
+            Some(i) if lifetime == 0 => {
+                routers.remove(i);
+                self.ipv6.remove_advertised_route(&Ipv6Cidr::default_route(), Some(router), iface);
+            }
+            Some(i) => routers[i].expires_usec = expiry(now, lifetime as u32),
+            None if lifetime == 0 => {}
+            None => {
+                println!("ndp: default router {}", router);
+                self.ipv6.add_advertised_route(Ipv6Cidr::default_route(), Some(*router), iface.clone(), RA_ROUTE_METRIC);
+                routers.push(DefaultRouter {
+                    addr: *router,
+                    iface: iface.clone(),
+                    expires_usec: expiry(now, lifetime as u32),
+                });
+            }
+        }
+    }
+
+    fn update_prefix(&self, pi: &PrefixInfo, iface: &Arc<EthEntry>, now: u64) {
+        let mut prefixes = self.prefixes.lock().unwrap();
+        let at = prefixes.iter().position(|p| p.prefix == pi.prefix && Arc::ptr_eq(&p.iface, iface));
+        match at {
+            Some(i) if pi.valid == 0 => {
+                prefixes.remove(i);
+                self.ipv6.remove_advertised_route(&pi.prefix, None, iface);
+            }
+            Some(i) => prefixes[i].expires_usec = expiry(now, pi.valid),
+            None if pi.valid == 0 => {}
+            None => {
+                self.ipv6.add_advertised_route(pi.prefix, None, iface.clone(), RA_ROUTE_METRIC);
+                prefixes.push(OnLinkPrefix {
+                    prefix: pi.prefix,
+                    iface: iface.clone(),
+                    expires_usec: expiry(now, pi.valid),
+                });
+            }
+        }
+    }
+
+    /// Form an address in an advertised prefix, or refresh the lifetimes of
+    /// the one we have.
+    fn update_addr(&self, pi: &PrefixInfo, iface: &Arc<EthEntry>, mode: Ipv6IidMode, ndp: &Ndp, now: u64) {
+        self.retry_duplicates(ndp, now);
+        let mut addrs = self.addrs.lock().unwrap();
+        // removed by hand: configure it again
+        addrs.retain(|a| self.ipv6.entry(&a.addr).is_some());
+        if let Some(a) = addrs.iter_mut().find(|a| a.prefix == pi.prefix && Arc::ptr_eq(&a.iface, iface)) {
+            let valid = expiry(now, pi.valid);
+            let remaining = a.valid_usec.saturating_sub(now);
+            if valid > now + MIN_VALID_LIFETIME_USEC || valid > a.valid_usec {
+                a.valid_usec = valid;
+            } else if remaining > MIN_VALID_LIFETIME_USEC {
+                a.valid_usec = now + MIN_VALID_LIFETIME_USEC;
+            }
+            a.preferred_usec = expiry(now, pi.preferred).min(a.valid_usec);
+            let ent = self.ipv6.entry(&a.addr);
+            if let Some(ent) = ent.filter(|e| e.get_state() == Ipv6AddrState::Deprecated && pi.preferred > 0) {
+                ent.set_state(Ipv6AddrState::Preferred);
+            }
+            return;
+        }
+        if pi.valid == 0 {
+            return;
+        }
+        if pi.prefix.prefix_len != 64 {
+            println!("ndp: can't autoconfigure from {}, not a /64", pi.prefix);
+            return;
+        }
+        if let Some(g) = self.given_up.lock().unwrap().iter_mut().find(|g| g.prefix == pi.prefix && Arc::ptr_eq(&g.iface, iface)) {
+            // still advertised, keep staying away
+            g.expires_usec = expiry(now, pi.valid);
+            return;
+        }
+        if let Some(a) = self.form_addr(pi.prefix, iface, mode, 0, ndp, now) {
+            addrs.push(AutoAddr {
+                preferred_usec: expiry(now, pi.preferred),
+                valid_usec: expiry(now, pi.valid),
+                ..a
+            });
+        }
+    }
+
+    /// Add the address of `prefix` on `iface` and start its DAD; lifetimes
+    /// are left for the caller.
+    fn form_addr(&self, prefix: Ipv6Cidr, iface: &Arc<EthEntry>, mode: Ipv6IidMode, dad_counter: u8, ndp: &Ndp, now: u64) -> Option<AutoAddr> {
+        let addr = self.ipv6.make_addr(&prefix, iface, mode, dad_counter).ok()?;
+        let cidr = Ipv6Cidr { addr, prefix_len: prefix.prefix_len };
+        let ent = match self.ipv6.add_ipv6(cidr, Some(iface.clone())) {
+            Ok(ent) => ent,
+            Err(e) => {
+                println!("ndp: can't autoconfigure {}: {:?}", cidr, e);
+                return None;
+            }
+        };
+        println!("ndp: autoconfigured {} on {:?}", cidr, iface.sub_info());
+        Some(AutoAddr {
+            addr,
+            prefix,
+            iface: iface.clone(),
+            mode,
+            dad_counter,
+            preferred_usec: 0,
+            valid_usec: 0,
+            events: ndp.start_dad(ent, now),
+        })
+    }
+
+    /// Addresses where DAD found a duplicate: try another stable-privacy
+    /// one, or give the prefix up.
+    fn retry_duplicates(&self, ndp: &Ndp, now: u64) {
+        let mut addrs = self.addrs.lock().unwrap();
+        let mut given_up = self.given_up.lock().unwrap();
+        let mut retries = Vec::new();
+        addrs.retain(|a| {
+            let duplicate = std::iter::from_fn(|| a.events.try_recv())
+                .any(|ev| matches!(ev, Ipv6AddrEvent::Duplicate { .. }));
+            if !duplicate {
+                return true;
+            }
+            if a.mode == Ipv6IidMode::StablePrivacy && a.dad_counter < IDGEN_RETRIES {
+                retries.push((a.prefix, a.iface.clone(), a.dad_counter + 1, a.preferred_usec, a.valid_usec));
+            } else {
+                println!("ndp: giving up on {} on {:?}", a.prefix, a.iface.sub_info());
+                given_up.push(GivenUpPrefix { prefix: a.prefix, iface: a.iface.clone(), expires_usec: a.valid_usec });
+            }
+            false
+        });
+        drop(given_up);
+        for (prefix, iface, dad_counter, preferred_usec, valid_usec) in retries {
+            if let Some(a) = self.form_addr(prefix, &iface, Ipv6IidMode::StablePrivacy, dad_counter, ndp, now) {
+                addrs.push(AutoAddr { preferred_usec, valid_usec, ..a });
+            }
+        }
+    }
+
+    /// Deprecate and remove addresses whose lifetimes ran out, drop expired
+    /// routers and prefixes.
+    fn expire(&self, ndp: &Ndp, now: u64) {
+        self.addrs.lock().unwrap().retain(|a| {
+            if now >= a.valid_usec {
+                if self.ipv6.remove_ipv6(&a.addr).is_some() {
+                    println!("ndp: {} expired", a.addr);
+                    ndp.stop_dad(&a.addr);
+                }
+                return false;
+            }
+            let ent = self.ipv6.entry(&a.addr);
+            if let Some(ent) = ent.filter(|e| now >= a.preferred_usec && e.get_state() == Ipv6AddrState::Preferred) {
+                println!("ndp: {} deprecated", a.addr);
+                ent.set_state(Ipv6AddrState::Deprecated);
+            }
+            true
+        });
+        self.given_up.lock().unwrap().retain(|g| now < g.expires_usec);
+        self.routers.lock().unwrap().retain(|r| {
+            if now < r.expires_usec {
+                return true;
+            }
+            println!("ndp: default router {} expired", r.addr);
+            self.ipv6.remove_advertised_route(&Ipv6Cidr::default_route(), Some(&r.addr), &r.iface);
+            false
+        });
+        self.prefixes.lock().unwrap().retain(|p| {
+            if now < p.expires_usec {
+                return true;
+            }
+            self.ipv6.remove_advertised_route(&p.prefix, None, &p.iface);
+            false
+        });
+    }
+
+    /// Solicit routers on interfaces that just started autoconfiguring, from
+    /// their link-local address once it is usable.
+    fn solicit_tick(&self, now: u64) {
+        let mut autoconf = self.autoconf.lock().unwrap();
+        for a in autoconf.iter_mut() {
+            if a.next_solicit_usec.is_none_or(|t| now < t) {
+                continue;
+            }
+            if a.solicits_sent >= MAX_RTR_SOLICITATIONS {
+                a.next_solicit_usec = None;
+                continue;
+            }
+            let src = self.ipv6.link_local_of(&a.iface).unwrap_or(Ipv6Addr::UNSPECIFIED);
+            let mut msg = vec![0u8; RS_MSG_LEN];
+            msg[0] = ICMPV6_ROUTER_SOLICIT;
+            if !src.is_unspecified() {
+                msg.extend_from_slice(&[ND_OPT_SOURCE_LLADDR, ND_OPT_LLADDR_UNITS]);
+                msg.extend_from_slice(&a.iface.get_mac().mac);
+            }
+            send_icmpv6(&self.outbox, &a.iface, msg, &src, &all_routers(), &multicast_mac(&all_routers()));
+            a.solicits_sent += 1;
+            a.next_solicit_usec = Some(now + RTR_SOLICITATION_INTERVAL_USEC);
+        }
+    }
+
+    fn send_advert(&self, a: &AdvertIf) {
+        let Some(src) = self.ipv6.link_local_of(&a.iface) else {
+            println!("ndp: no link-local address to advertise from on {:?}", a.iface.sub_info());
+            return;
+        };
+        let c = &a.config;
+        let ra = RouterAdvert {
+            hop_limit: c.hop_limit,
+            router_lifetime: c.router_lifetime,
+            reachable_msec: 0,
+            retrans_msec: 0,
+            lladdr: Some(a.iface.get_mac()),
+            mtu: c.mtu,
+            prefixes: c.prefixes.iter().map(|p| PrefixInfo {
+                prefix: p.network(),
+                on_link: true,
+                autonomous: true,
+                valid: c.valid_lifetime,
+                preferred: c.preferred_lifetime,
+            }).collect(),
+        };
+        send_icmpv6(&self.outbox, &a.iface, ra.to_bytes(), &src, &all_nodes(), &multicast_mac(&all_nodes()));
+    }
+
+    /// Send due advertisements and pick the time of the next (RFC 4861
+    /// 6.2.4).
+    fn advert_tick(&self, now: u64) {
+        let mut adv = self.advertising.lock().unwrap();
+        for a in adv.iter_mut() {
+            if now < a.next_usec {
+                continue;
+            }
+            self.send_advert(a);
+            a.last_usec = Some(now);
+            let max = a.config.max_interval as u64 * 1_000_000;
+            let min = max / 3;
+            let mut interval = min + jitter(max - min);
+            if a.initial_sent < MAX_INITIAL_RTR_ADVERTISEMENTS {
+                a.initial_sent += 1;
+                interval = interval.min(MAX_INITIAL_RTR_ADVERT_INTERVAL_USEC);
+            }
+            a.next_usec = now + interval;
+        }
+    }
+
+    /// Run solicitations, advertisements and lifetimes.
+    pub(crate) fn tick(&self, now: u64, ndp: &Ndp) {
+        self.solicit_tick(now);
+        self.advert_tick(now);
+        self.retry_duplicates(ndp, now);
+        self.expire(ndp, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::fib_trie::RouteOrigin;
+    use crate::network::protocol::ProtocolRegistry;
+
+    const HOUR_USEC: u64 = 3600 * 1_000_000;
+
+    struct Host {
+        ipv6: Arc<IPv6Protocol>,
+        ndp: Ndp,
+        ra: RouterAdv,
+        iface: Arc<EthEntry>,
+    }
+
+    /// A host whose addresses are usable right away.
+    fn host() -> Host {
+        let ipv6 = Arc::new(IPv6Protocol::new(Arc::new(ProtocolRegistry::new())));
+        let outbox = TxOutbox::new();
+        let ndp = Ndp::new(ipv6.clone(), outbox);
+        ndp.set_dad(false);
+        let ra = RouterAdv::new(ipv6.clone(), outbox);
+        let iface = Arc::new(EthEntry::new(MacAddr::new([0x02, 0, 0, 0, 0, 1]), None, None));
+        Host { ipv6, ndp, ra, iface }
+    }
+
+    fn prefix_info(prefix: &str, valid: u32, preferred: u32) -> PrefixInfo {
+        PrefixInfo { prefix: prefix.parse().unwrap(), on_link: true, autonomous: true, valid, preferred }
+    }
+
+    fn advert(prefixes: Vec<PrefixInfo>) -> RouterAdvert {
+        RouterAdvert {
+            hop_limit: 64,
+            router_lifetime: 0,
+            reachable_msec: 0,
+            retrans_msec: 0,
+            lladdr: None,
+            mtu: None,
+            prefixes,
+        }
+    }
+
+    /// An address of `prefix` whose DAD just found a duplicate.
+    fn duplicate(h: &Host, prefix: &str, mode: Ipv6IidMode, dad_counter: u8, valid_usec: u64) {
+        let prefix: Ipv6Cidr = prefix.parse().unwrap();
+        let addr = h.ipv6.make_addr(&prefix, &h.iface, mode, dad_counter).unwrap();
+        let (tx, rx) = flume::unbounded();
+        tx.send(Ipv6AddrEvent::Duplicate { addr, mac: MacAddr::new([0x02, 0, 0, 0, 0, 9]) }).unwrap();
+        h.ra.addrs.lock().unwrap().push(AutoAddr {
+            addr,
+            prefix,
+            iface: h.iface.clone(),
+            mode,
+            dad_counter,
+            preferred_usec: valid_usec,
+            valid_usec,
+            events: Ipv6AddrEvents::new(rx),
+        });
+    }
+
+    #[test]
+    fn advert_round_trip() {
+        let mut ra = advert(vec![prefix_info("2001:db8:1::/64", 7200, 3600)]);
+        ra.router_lifetime = 1800;
+        ra.reachable_msec = 30_000;
+        ra.retrans_msec = 1000;
+        ra.lladdr = Some(MacAddr::new([0x02, 0, 0, 0, 0, 7]));
+        ra.mtu = Some(1400);
+        let back = RouterAdvert::parse(&ra.to_bytes()).unwrap();
+        assert_eq!(back.hop_limit, 64);
+        assert_eq!(back.router_lifetime, 1800);
+        assert_eq!(back.reachable_msec, 30_000);
+        assert_eq!(back.retrans_msec, 1000);
+        assert_eq!(back.lladdr, ra.lladdr);
+        assert_eq!(back.mtu, Some(1400));
+        assert_eq!(back.prefixes.len(), 1);
+        let pi = &back.prefixes[0];
+        assert_eq!(pi.prefix, "2001:db8:1::/64".parse().unwrap());
+        assert!(pi.on_link && pi.autonomous);
+        assert_eq!((pi.valid, pi.preferred), (7200, 3600));
+    }
+
+    #[test]
+    fn advert_bad_input() {
+        let b = advert(Vec::new()).to_bytes();
+        assert_eq!(RouterAdvert::parse(&b[..RA_MSG_LEN - 1]).unwrap_err(),
+                   Icmpv6Error::Truncated { offset: 0, need: RA_MSG_LEN });
+        let mut bad = b.clone();
+        bad[1] = 1;
+        assert_eq!(RouterAdvert::parse(&bad).unwrap_err(), Icmpv6Error::Malformed { offset: 1 });
+        // zero-length option
+        let mut bad = b.clone();
+        bad.extend_from_slice(&[ND_OPT_MTU, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(RouterAdvert::parse(&bad).is_err());
+    }
+
+    #[test]
+    fn prefix_info_parse() {
+        let mut pi = prefix_info("2001:db8:1::/48", 100, 50);
+        pi.on_link = false;
+        let mut b = pi.to_bytes();
+        // host bits are cleared
+        b[31] = 1;
+        let back = PrefixInfo::parse(&b).unwrap();
+        assert_eq!(back.prefix, "2001:db8:1::/48".parse().unwrap());
+        assert!(!back.on_link && back.autonomous);
+        assert_eq!((back.valid, back.preferred), (100, 50));
+
+        b[2] = 129;
+        assert!(PrefixInfo::parse(&b).is_none());
+        assert!(PrefixInfo::parse(&pi.to_bytes()[..PREFIX_INFO_LEN - 8]).is_none());
+    }
+
+    #[test]
+    fn valid_lifetime_two_hour_rule() {
+        let h = host();
+        let pi = |valid: u32| prefix_info("2001:db8:1::/64", valid, valid);
+        let valid_usec = || h.ra.addrs.lock().unwrap()[0].valid_usec;
+        h.ra.update_addr(&pi(10 * 3600), &h.iface, Ipv6IidMode::Eui64, &h.ndp, 0);
+        assert_eq!(valid_usec(), 10 * HOUR_USEC);
+
+        // longer than two hours: taken
+        h.ra.update_addr(&pi(3 * 3600), &h.iface, Ipv6IidMode::Eui64, &h.ndp, 0);
+        assert_eq!(valid_usec(), 3 * HOUR_USEC);
+        // shorter, with more than two hours left: cut to two hours
+        h.ra.update_addr(&pi(3600), &h.iface, Ipv6IidMode::Eui64, &h.ndp, 0);
+        assert_eq!(valid_usec(), 2 * HOUR_USEC);
+        // two hours or less left: ignored
+        h.ra.update_addr(&pi(0), &h.iface, Ipv6IidMode::Eui64, &h.ndp, HOUR_USEC);
+        assert_eq!(valid_usec(), 2 * HOUR_USEC);
+        // unless it is longer than what is left
+        h.ra.update_addr(&pi(5400), &h.iface, Ipv6IidMode::Eui64, &h.ndp, HOUR_USEC);
+        assert_eq!(valid_usec(), HOUR_USEC + 5400 * 1_000_000);
+    }
+
+    #[test]
+    fn prefix_withdrawn_keeps_connected_route() {
+        let h = host();
+        let dst = "2001:db8:1::99".parse().unwrap();
+        h.ra.apply(&advert(vec![prefix_info("2001:db8:1::/64", 10 * 3600, 3600)]),
+                   &"fe80::1".parse().unwrap(), &h.iface, Ipv6IidMode::Eui64, &h.ndp, 0);
+        let origins = || h.ipv6.routes().iter().map(|r| r.origin).collect::<Vec<_>>();
+        assert_eq!(origins().len(), 2);
+        assert!(origins().contains(&RouteOrigin::RouterAdvert));
+        assert!(origins().contains(&RouteOrigin::Connected));
+
+        // the address outlives the withdrawn prefix, and so does its route
+        h.ra.apply(&advert(vec![prefix_info("2001:db8:1::/64", 0, 0)]),
+                   &"fe80::1".parse().unwrap(), &h.iface, Ipv6IidMode::Eui64, &h.ndp, 0);
+        assert_eq!(origins(), vec![RouteOrigin::Connected]);
+        let route = h.ipv6.route(&dst).unwrap();
+        assert!(Arc::ptr_eq(&route.iface, &h.iface));
+        assert_eq!(route.src, Some(h.ra.addrs.lock().unwrap()[0].addr));
+    }
+
+    #[test]
+    fn duplicate_retries_stable_privacy() {
+        let h = host();
+        duplicate(&h, "2001:db8:1::/64", Ipv6IidMode::StablePrivacy, 0, 10 * HOUR_USEC);
+        h.ra.retry_duplicates(&h.ndp, 0);
+        let addrs = h.ra.addrs.lock().unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].dad_counter, 1);
+        assert_eq!(addrs[0].valid_usec, 10 * HOUR_USEC);
+        assert!(h.ipv6.entry(&addrs[0].addr).is_some());
+        assert!(h.ra.given_up.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn given_up_prefix_until_valid_lifetime_ends() {
+        let h = host();
+        let pi = prefix_info("2001:db8:1::/64", 3600, 3600);
+        duplicate(&h, "2001:db8:1::/64", Ipv6IidMode::StablePrivacy, IDGEN_RETRIES, HOUR_USEC);
+        // out of retries: the prefix is given up, not tried again while
+        // still advertised
+        h.ra.update_addr(&pi, &h.iface, Ipv6IidMode::StablePrivacy, &h.ndp, 0);
+        assert!(h.ra.addrs.lock().unwrap().is_empty());
+        assert!(h.ipv6.entries().is_empty());
+        // each advertisement pushes the end out
+        h.ra.update_addr(&pi, &h.iface, Ipv6IidMode::StablePrivacy, &h.ndp, HOUR_USEC / 2);
+        h.ra.expire(&h.ndp, HOUR_USEC);
+        assert_eq!(h.ra.given_up.lock().unwrap().len(), 1);
+        h.ra.update_addr(&pi, &h.iface, Ipv6IidMode::StablePrivacy, &h.ndp, HOUR_USEC);
+        assert!(h.ra.addrs.lock().unwrap().is_empty());
+
+        // EUI-64 addresses are given up after the first duplicate; another
+        // prefix is not affected
+        duplicate(&h, "2001:db8:2::/64", Ipv6IidMode::Eui64, 0, HOUR_USEC);
+        h.ra.retry_duplicates(&h.ndp, HOUR_USEC);
+        assert_eq!(h.ra.given_up.lock().unwrap().len(), 2);
+
+        // once the valid lifetime ran out, the prefix is tried afresh
+        h.ra.expire(&h.ndp, 3 * HOUR_USEC);
+        assert!(h.ra.given_up.lock().unwrap().is_empty());
+        h.ra.update_addr(&pi, &h.iface, Ipv6IidMode::StablePrivacy, &h.ndp, 3 * HOUR_USEC);
+        let addrs = h.ra.addrs.lock().unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].dad_counter, 0);
+    }
+}
@@ -1,6 +1,7 @@
 use std::pin::Pin;
 use std::sync::Arc;
 use crate::network::ipv4::{IPv4Entry, IPv4Key};
+use crate::network::error::NetError;
 use crate::network::module_traits::AsyncNetIOModule;
 use crate::network::packet::NetworkPacket;
 use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType};
@@ -24,8 +25,8 @@ impl NetworkSocket {
 }
 
 impl AsyncNetIOModule<NetworkPacket> for NetworkSocket {
-    type RxResult = (NetworkPacket, Result<(), ()>);
-    type TxResult = (NetworkPacket, Result<(), ()>);
+    type RxResult = (NetworkPacket, Result<(), NetError>);
+    type TxResult = (NetworkPacket, Result<(), NetError>);
 
     async fn rx(self: Arc<Self>, p: NetworkPacket) -> Self::RxResult {
         // let res;
@@ -1,20 +1,33 @@
 use std::any::Any;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+use crate::executor::runtime::Runtime;
 use std::sync::Arc;
-use crate::network::ipv4::IPv4Addr;
-use crate::network::arp::ArpProtocol;
-use crate::network::module_traits::{AsyncNetIOModule, AsyncProtocolModule};
+use crate::network::ipv4::{IPv4Addr, IPv4AddrEvents, IPv4AddrState, IPv4Cidr};
+use crate::network::arp::{ArpProtocol, ArpResolve, ArpState};
+use crate::network::module_traits::{AsyncNetIOModule, ProtocolLayer};
 use crate::network::driver::NetworkDriver;
-use crate::network::ethernet::{EthEntry, EthKey, EthernetProtocol, MacAddr};
+use crate::network::error::{ConfigError, DropReason, DropStats, Ipv4Error, Ipv6Error, NetError};
+use crate::network::ethernet::{EthEntry, EthKey, EthernetProtocol, MacAddr, VlanIf};
 use crate::network::icmpv4::ICMPv4Protocol;
 use crate::network::icmpv6::ICMPv6Protocol;
 use crate::network::ipv4::IPv4Protocol;
-use crate::network::ipv6::IPv6Protocol;
+use crate::network::ipv4_fib::Ipv4RouteInfo;
+use crate::network::ipv6::{multicast_mac, IPv6Protocol, Ipv6AddrEvents, Ipv6AddrState, Ipv6Cidr, Ipv6IidMode};
+use crate::network::ipv6_fib::Ipv6RouteInfo;
+use crate::network::ndp::{NdpResolve, NeighborState};
+use crate::network::router_adv::Ipv6RaConfig;
 use crate::network::packet::NetworkPacket;
-use crate::network::protocol::{ProtocolHeaderType, ProtocolMetaData};
+use crate::network::protocol::{ProtocolHeaderType, ProtocolRegistry};
+use crate::network::protocol_graph::{ProtocolDispatch, ProtocolGraph};
 use crate::network::socket::NetworkSocket;
 use crate::network::tcp::TCPProtocol;
+use crate::network::tx_queue::TxOutbox;
 use crate::network::udp::UDPProtocol;
 
+/// How often the stack's timers run.
+const TIMER_TICK_MSEC: u64 = 100;
+
 pub struct NetworkStack {
     stack_type: ProtocolHeaderType,
     socket_layer: Arc<NetworkSocket>,
@@ -27,137 +40,653 @@ pub struct NetworkStack {
     protocol_tcp: Arc<TCPProtocol>,
     protocol_eth: Arc<EthernetProtocol>,
     driver_layer: Arc<NetworkDriver>,
+    graph: ProtocolGraph,
+    drops: DropStats,
+    /// what the layers queue on their own, sent by `flush_outbox`
+    outbox: TxOutbox,
 }
 
 impl NetworkStack {
     pub fn new_eth_stack() -> NetworkStack {
-        NetworkStack{
+        let registry = Arc::new(ProtocolRegistry::new());
+        let outbox = TxOutbox::new();
+        let ipv4 = Arc::new(IPv4Protocol::new(registry.clone(), outbox));
+        let ipv6 = Arc::new(IPv6Protocol::new(registry.clone()));
+        let stk = NetworkStack{
             stack_type: ProtocolHeaderType::Ethernet,
             socket_layer: Arc::new(NetworkSocket::new()),
-            protocol_arp: Arc::new(ArpProtocol::new()),
-            protocol_ipv4: Arc::new(IPv4Protocol::new()),
-            protocol_ipv6: Arc::new(IPv6Protocol::new()),
+            protocol_arp: Arc::new(ArpProtocol::new(ipv4.clone(), outbox)),
+            protocol_ipv4: ipv4,
+            protocol_icmpv6: Arc::new(ICMPv6Protocol::new(ipv6.clone(), outbox)),
+            protocol_ipv6: ipv6,
             protocol_icmpv4: Arc::new(ICMPv4Protocol::new()),
-            protocol_icmpv6: Arc::new(ICMPv6Protocol::new()),
             protocol_udp: Arc::new(UDPProtocol::new()),
             protocol_tcp: Arc::new(TCPProtocol::new()),
-            protocol_eth: Arc::new(EthernetProtocol::new()),
-            driver_layer: Arc::new(NetworkDriver {})
+            protocol_eth: Arc::new(EthernetProtocol::new(registry.clone())),
+            driver_layer: Arc::new(NetworkDriver {}),
+            graph: ProtocolGraph::new(registry),
+            drops: DropStats::new(),
+            outbox,
+        };
+        stk.register_builtin_protocols();
+        stk
+    }
+
+    fn register_builtin_protocols(&self) {
+        let builtin: [(ProtocolHeaderType, Arc<dyn ProtocolLayer>); 8] = [
+            (ProtocolHeaderType::Ethernet, self.protocol_eth.clone()),
+            (ProtocolHeaderType::ARP, self.protocol_arp.clone()),
+            (ProtocolHeaderType::IPv4, self.protocol_ipv4.clone()),
+            (ProtocolHeaderType::IPv6, self.protocol_ipv6.clone()),
+            (ProtocolHeaderType::ICMPv4, self.protocol_icmpv4.clone()),
+            (ProtocolHeaderType::ICMPv6, self.protocol_icmpv6.clone()),
+            (ProtocolHeaderType::UDP, self.protocol_udp.clone()),
+            (ProtocolHeaderType::TCP, self.protocol_tcp.clone()),
+        ];
+        for (pt, layer) in builtin {
+            self.graph.register(pt, layer);
+        }
+    }
+
+    /// Add a protocol on top of Ethernet, or replace the handler of a known
+    /// one. Decoders reach it through the EtherType.
+    pub fn register_ethertype_protocol(&self, ethertype: u16, pt: ProtocolHeaderType, layer: Arc<dyn ProtocolLayer>) -> Result<(), NetError> {
+        Ok(self.graph.register_ethertype(ethertype, pt, layer)?)
+    }
+
+    /// Add a protocol on top of IPv4/IPv6, reached through the IP protocol
+    /// number.
+    pub fn register_ip_protocol(&self, proto: u8, pt: ProtocolHeaderType, layer: Arc<dyn ProtocolLayer>) -> Result<(), NetError> {
+        Ok(self.graph.register_ip_proto(proto, pt, layer)?)
+    }
+
+    pub fn unregister_protocol(&self, pt: ProtocolHeaderType) -> Result<(), NetError> {
+        Ok(self.graph.unregister(pt)?)
+    }
+
+    pub fn add_mac(&self, mac: &MacAddr) -> Result<(), NetError> {
+        Ok(self.protocol_eth.add_mac(mac, None)?)
+    }
+
+    /// Packets dropped so far, per reason.
+    pub fn drop_stats(&self) -> Vec<(DropReason, u64)> {
+        self.drops.snapshot()
+    }
+
+    /// The ARP cache: address, link address and entry state.
+    pub fn arp_entries(&self) -> Vec<(IPv4Addr, MacAddr, ArpState)> {
+        self.protocol_arp.entries()
+    }
+
+    /// The IPv6 neighbor cache: address, link address and entry state.
+    pub fn ipv6_neighbors(&self) -> Vec<(Ipv6Addr, MacAddr, NeighborState)> {
+        self.protocol_icmpv6.ndp.entries()
+    }
+
+    /// Start the stack's timers (ARP and ND retransmission and aging, router
+    /// advertisements and address lifetimes) as a task of
+    /// the calling scheduler thread.
+    pub fn start_timers(self: &Arc<Self>) -> Result<(), NetError> {
+        let Some(sched) = Runtime::get_scheduler() else {
+            return Err(ConfigError::NoScheduler.into());
+        };
+        let stk = self.clone();
+        let res = sched.spawn_task(String::from("net-timer"), async move {
+            loop {
+                Runtime::sleep(Duration::from_millis(TIMER_TICK_MSEC)).await;
+                stk.tick(Runtime::get_time_usec()).await;
+            }
+        });
+        res.map(|_| ()).map_err(|_| ConfigError::NoScheduler.into())
+    }
+
+    async fn tick(&self, now: u64) {
+        let icmpv6 = &self.protocol_icmpv6;
+        icmpv6.router_adv.tick(now, &icmpv6.ndp);
+        let dropped = self.protocol_arp.tick(now) + icmpv6.ndp.tick(now);
+        if dropped > 0 {
+            self.drops.count_n(DropReason::Unresolved, dropped as u64);
+        }
+        let expired = self.protocol_ipv4.tick(now);
+        if expired > 0 {
+            self.drops.count_n(DropReason::Reassembly, expired as u64);
+        }
+        self.flush_outbox().await;
+    }
+
+    /// Run a frame taken off the wire up the stack. It is copied into a
+    /// pool buffer first; when the pool is exhausted the frame is dropped.
+    pub async fn rx_frame(self: Arc<Self>, frame: &[u8]) -> Result<(), NetError> {
+        let p = match self.driver_layer.receive_frame(frame) {
+            Ok(p) => p,
+            Err(e) => {
+                let e = NetError::from(e);
+                self.count_drop(&e);
+                return Err(e);
+            }
+        };
+        self.rx(p).await.1
+    }
+
+    /// Send what the layers queued on their own while handling packets.
+    async fn flush_outbox(&self) {
+        while let Some(p) = self.outbox.pop() {
+            let _ = self.output(p).await;
+        }
+    }
+
+    /// Route and resolve a packet if it has no link destination yet, then
+    /// send it.
+    async fn output(&self, p: NetworkPacket) -> (NetworkPacket, Result<(), NetError>) {
+        let needs_l2 = {
+            let m = p.meta();
+            self.stack_type == ProtocolHeaderType::Ethernet
+                && matches!(m.l3_proto, ProtocolHeaderType::IPv4 | ProtocolHeaderType::IPv6)
+                && m.l2_dst.is_none()
+        };
+        if !needs_l2 {
+            return self.encode_and_send(p).await;
+        }
+        let (resolved, res) = self.resolve_l2(p.clone_shared());
+        if let Err(e) = res {
+            self.count_drop(&e);
+            return (p, Err(e));
+        }
+        let Some(resolved) = resolved else {
+            // a copy waits for ARP or ND and goes out from the outbox
+            return (p, Ok(()));
+        };
+        let (_, res) = self.encode_and_send(resolved).await;
+        (p, res)
+    }
+
+    /// Encode a packet whose metadata is complete and hand it to the driver,
+    /// in fragments if it exceeds the IPv4 MTU.
+    async fn encode_and_send(&self, p: NetworkPacket) -> (NetworkPacket, Result<(), NetError>) {
+        // innermost header first, the layers above said what they need;
+        // forwarded packets are done up to L3
+        let (chain, l3_proto): (Vec<ProtocolHeaderType>, _) = {
+            let m = p.meta();
+            let chain = [m.l4_proto, m.l3_proto]
+                .into_iter()
+                .filter(|pt| *pt != ProtocolHeaderType::None && !m.forwarded)
+                .collect();
+            (chain, m.l3_proto)
+        };
+        let (p, res) = self.graph.encode(&chain, p);
+        if let Err(e) = res {
+            self.count_drop(&e);
+            return (p, Err(e));
         }
+        if l3_proto == ProtocolHeaderType::IPv4 {
+            let frags = match self.protocol_ipv4.fragment(&p) {
+                Ok(frags) => frags,
+                Err(e) => {
+                    let e = e.into();
+                    self.count_drop(&e);
+                    return (p, Err(e));
+                }
+            };
+            if !frags.is_empty() {
+                for f in frags {
+                    let (_, res) = self.send_link(f).await;
+                    if res.is_err() {
+                        return (p, res);
+                    }
+                }
+                return (p, Ok(()));
+            }
+        }
+        self.send_link(p).await
     }
 
-    pub fn add_mac(&self, mac: &MacAddr) -> Result<(), ()> {
-        self.protocol_eth.add_mac(mac, None)
+    /// Add the link layer header and hand the packet to the driver.
+    async fn send_link(&self, p: NetworkPacket) -> (NetworkPacket, Result<(), NetError>) {
+        let (p, res) = self.graph.encode(&[self.stack_type], p);
+        if let Err(e) = res {
+            self.count_drop(&e);
+            return (p, Err(e));
+        }
+        let (p, res) = self.driver_layer.clone().tx(p).await;
+        if let Err(e) = &res {
+            self.count_drop(e);
+        }
+        (p, res)
     }
 
-    fn add_ipv4_on_ethernet(&self, ip: IPv4Addr, sub: Option<Arc<dyn Any + Send + Sync>>) -> Result<(), ()> {
+    /// Pick the interface of an IP packet and fill in its MACs, through
+    /// ARP or ND unless the destination maps to a MAC directly. `None` means
+    /// the packet waits for resolution and is sent from the outbox.
+    fn resolve_l2(&self, p: NetworkPacket) -> (Option<NetworkPacket>, Result<(), NetError>) {
+        let (dst, src) = {
+            let m = p.meta();
+            (m.l3_dst, m.l3_src)
+        };
+        let dst = match dst {
+            Some(IpAddr::V4(dst)) => dst,
+            Some(IpAddr::V6(dst)) => return self.resolve_l2_v6(p, dst, src),
+            None => return (Some(p), Ok(())),
+        };
+        let dst = IPv4Addr::from(dst);
+        let src = match src {
+            Some(IpAddr::V4(src)) => Some(IPv4Addr::from(src)),
+            _ => None,
+        };
+
+        if dst.is_broadcast() || dst.is_multicast() {
+            // no route, leave through the interface of the source address
+            let local = match &src {
+                Some(src) => self.protocol_ipv4.lookup_local(src),
+                None => self.protocol_ipv4.any_local(),
+            };
+            let Some((local, iface)) = local.and_then(|l| l.eth_entry().map(|i| (l, i))) else {
+                return (Some(p), Err(Ipv4Error::NoRoute(dst).into()));
+            };
+            Self::set_l2_src(&p, &iface, IpAddr::V4(local.get_addr().clone().into()));
+            let l2_dst = if dst.is_broadcast() {
+                MacAddr::BROADCAST.mac
+            } else {
+                // RFC 1112: low 23 bits of the group under 01:00:5e
+                [0x01, 0x00, 0x5e, dst.val[1] & 0x7f, dst.val[2], dst.val[3]]
+            };
+            p.meta_mut().l2_dst = Some(l2_dst);
+            return (Some(p), Ok(()));
+        }
+
+        let Some(route) = self.protocol_ipv4.route(&dst) else {
+            return (Some(p), Err(Ipv4Error::NoRoute(dst).into()));
+        };
+        // the ARP request goes out with an address of the egress interface,
+        // a forwarded packet's own source only belongs in its IP header
+        let Some(src) = route.src.clone() else {
+            return (Some(p), Err(Ipv4Error::MissingAddress.into()));
+        };
+        Self::set_l2_src(&p, &route.iface, IpAddr::V4(src.clone().into()));
+        if route.gateway.is_none() && route.prefix.broadcast().as_ref() == Some(&dst) {
+            p.meta_mut().l2_dst = Some(MacAddr::BROADCAST.mac);
+            return (Some(p), Ok(()));
+        }
+        match self.protocol_arp.resolve(p, &route.next_hop(&dst), &route.iface, &src) {
+            (p, Ok(ArpResolve::Ready)) => (Some(p), Ok(())),
+            (_, Ok(ArpResolve::Queued)) => (None, Ok(())),
+            (p, Err(e)) => (Some(p), Err(e.into())),
+        }
+    }
+
+    fn resolve_l2_v6(&self, p: NetworkPacket, dst: Ipv6Addr, src: Option<IpAddr>) -> (Option<NetworkPacket>, Result<(), NetError>) {
+        let src = match src {
+            Some(IpAddr::V6(src)) => Some(src),
+            _ => None,
+        };
+        if dst.is_multicast() {
+            // no route, leave through the interface of the source address
+            let local = match &src {
+                Some(src) => self.protocol_ipv6.entry(src),
+                None => self.protocol_ipv6.entries().into_iter().find(|e| e.is_usable()),
+            };
+            let Some((local, iface)) = local.and_then(|l| l.eth_entry().map(|i| (l, i))) else {
+                return (Some(p), Err(Ipv6Error::NoRoute(dst).into()));
+            };
+            Self::set_l2_src(&p, &iface, IpAddr::V6(*local.get_addr()));
+            p.meta_mut().l2_dst = Some(multicast_mac(&dst).mac);
+            return (Some(p), Ok(()));
+        }
+
+        let Some(route) = self.protocol_ipv6.route(&dst) else {
+            return (Some(p), Err(Ipv6Error::NoRoute(dst).into()));
+        };
+        // solicit from an address of the egress interface, as for ARP
+        let Some(src) = route.src else {
+            return (Some(p), Err(Ipv6Error::MissingAddress.into()));
+        };
+        Self::set_l2_src(&p, &route.iface, IpAddr::V6(src));
+        match self.protocol_icmpv6.ndp.resolve(p, &route.next_hop(&dst), &route.iface, &src) {
+            (p, Ok(NdpResolve::Ready)) => (Some(p), Ok(())),
+            (_, Ok(NdpResolve::Queued)) => (None, Ok(())),
+            (p, Err(e)) => (Some(p), Err(e.into())),
+        }
+    }
+
+    fn set_l2_src(p: &NetworkPacket, iface: &EthEntry, src: IpAddr) {
+        let mut m = p.meta_mut();
+        m.l2_src = Some(iface.get_mac().mac);
+        m.vlan = iface.get_vlan();
+        m.svlan = iface.get_svlan();
+        if m.l3_src.is_none() {
+            m.l3_src = Some(src);
+        }
+    }
+
+    fn count_drop(&self, err: &NetError) {
+        println!("stack drop: {}", err);
+        self.drops.count(err.drop_reason());
+    }
+
+    /// Accept frames for any destination MAC, not only the added ones.
+    pub fn set_promiscuous(&self, on: bool) {
+        self.protocol_eth.set_promiscuous(on)
+    }
+
+    fn add_ipv4_on_ethernet(&self, ip: IPv4Cidr, sub: Option<Arc<dyn Any + Send + Sync>>) -> Result<(), NetError> {
         let Some(sub_res) = sub else {
-            return Err(());
+            return Err(ConfigError::MissingInterface.into());
         };
 
         if let Ok(eth_res) = sub_res.downcast::<EthEntry>() {
-            return self.protocol_ipv4.add_ipv4(ip, Some(eth_res));
+            self.protocol_ipv4.add_ipv4(ip, Some(eth_res))?;
+            Ok(())
         } else {
-            return Err(());
+            Err(ConfigError::InterfaceType.into())
         }
-
-        Ok(())
     }
 
-    fn add_ipv4_internal(&self, ip: IPv4Addr, sub: Option<Arc<dyn Any + Send + Sync>>) -> Result<(), ()> {
+    fn add_ipv4_internal(&self, ip: IPv4Cidr, sub: Option<Arc<dyn Any + Send + Sync>>) -> Result<(), NetError> {
         match self.stack_type {
             ProtocolHeaderType::Ethernet => self.add_ipv4_on_ethernet(ip, sub),
-            _ => Err(())
+            t => Err(ConfigError::StackType(t).into())
         }
     }
 
-    pub fn add_ipv4<'a>(&self, ip: IPv4Addr, sub_addr: Option<&'a(dyn Any + Send + Sync)>) -> Result<(), ()> {
-        let Some(sub_addr_val) = sub_addr else {
-            println!("No sub addr for ipv4");
-            return Err(());
-        };
-
-        if let Some(eth) = sub_addr_val.downcast_ref::<MacAddr>(){
-            // search mac
-            let search_res = self.protocol_eth.search_mac(eth);
-            let ret = match search_res { 
-                Ok(mac_res) => {
-                    self.protocol_ipv4.add_ipv4(ip, Some(mac_res))
-                },
-                Err(_) => {
-                    println!("Error while searching MAC for IPv4");
-                    Err(())
-                }
-            };
-            ret
+    /// Find the interface an address is bound to: a `MacAddr` for the plain
+    /// interface or a `VlanIf` for a VLAN sub-interface.
+    fn resolve_sub_if(&self, sub_addr_val: &(dyn Any + Send + Sync)) -> Result<Arc<EthEntry>, NetError> {
+        if let Some(eth) = sub_addr_val.downcast_ref::<MacAddr>() {
+            Ok(self.protocol_eth.search_mac(eth)?)
+        } else if let Some(vlan_if) = sub_addr_val.downcast_ref::<VlanIf>() {
+            Ok(self.protocol_eth.search_vlan(vlan_if)?)
         } else {
-            println!("Sub for ipv4 type error");
-            Err(())
+            Err(ConfigError::InterfaceType.into())
         }
     }
+
+    /// Add an address with its prefix, e.g. "10.0.0.1/24", and the route of
+    /// its subnet. The address is probed for conflicts first (RFC 5227) and
+    /// usable once `IPv4AddrEvent::Bound` is reported; needs `start_timers`.
+    pub fn add_ipv4(&self, ip: IPv4Cidr, sub_addr: Option<&(dyn Any + Send + Sync)>) -> Result<IPv4AddrEvents, NetError> {
+        let Some(sub_addr_val) = sub_addr else {
+            return Err(ConfigError::MissingInterface.into());
+        };
+
+        let eth_res = self.resolve_sub_if(sub_addr_val)?;
+        println!("adding {} on {:?}", ip, eth_res.sub_info());
+        let ent = self.protocol_ipv4.add_ipv4(ip, Some(eth_res))?;
+        Ok(self.protocol_arp.start_acd(ent, Runtime::get_time_usec()))
+    }
+
+    /// Remove an address, and its subnet route if no other address of the
+    /// interface is in that subnet.
+    pub fn remove_ipv4(&self, ip: &IPv4Addr) -> Result<(), NetError> {
+        let Some(ent) = self.protocol_ipv4.remove_ipv4(ip) else {
+            return Err(Ipv4Error::AddressNotFound(ip.clone()).into());
+        };
+        self.protocol_arp.stop_acd(ip);
+        println!("removed {} from {:?}", ent.get_cidr(), ent.eth_entry().map(|e| e.sub_info()));
+        Ok(())
+    }
+
+    /// Add a static route, `0.0.0.0/0` for the default route. The interface
+    /// is `oif` (a `MacAddr` or `VlanIf`) if given, else the one of the
+    /// subnet the gateway is on.
+    pub fn add_route(&self, prefix: IPv4Cidr, gateway: Option<IPv4Addr>, oif: Option<&(dyn Any + Send + Sync)>, metric: u32) -> Result<(), NetError> {
+        let iface = match oif {
+            Some(oif) => Some(self.resolve_sub_if(oif)?),
+            None => None,
+        };
+        Ok(self.protocol_ipv4.add_route(prefix, gateway, iface, metric)?)
+    }
+
+    pub fn add_default_route(&self, gateway: IPv4Addr, metric: u32) -> Result<(), NetError> {
+        self.add_route(IPv4Cidr::default_route(), Some(gateway), None, metric)
+    }
+
+    /// Remove the static routes of `prefix`, or only the one via `gateway`.
+    pub fn remove_route(&self, prefix: &IPv4Cidr, gateway: Option<&IPv4Addr>) -> Result<(), NetError> {
+        Ok(self.protocol_ipv4.remove_route(prefix, gateway)?)
+    }
+
+    /// The IPv4 routing table, connected and static routes.
+    pub fn ipv4_routes(&self) -> Vec<Ipv4RouteInfo> {
+        self.protocol_ipv4.routes().iter().map(|r| r.info()).collect()
+    }
+
+    /// Our IPv4 addresses and where they are in conflict detection.
+    pub fn ipv4_addresses(&self) -> Vec<(IPv4Cidr, IPv4AddrState)> {
+        self.protocol_ipv4.entries()
+            .iter()
+            .map(|e| (e.get_cidr(), e.get_state()))
+            .collect()
+    }
+
+    /// Route IPv4 packets that come in on an interface (a `MacAddr` or
+    /// `VlanIf`) and are addressed elsewhere, instead of dropping them.
+    pub fn set_ipv4_forwarding(&self, sub_addr: &(dyn Any + Send + Sync), on: bool) -> Result<(), NetError> {
+        let iface = self.resolve_sub_if(sub_addr)?;
+        self.protocol_ipv4.set_forwarding(iface, on);
+        Ok(())
+    }
+
+    /// IPv4 MTU of all interfaces, at least 68. Bigger packets are
+    /// fragmented, or dropped if they have DF set.
+    pub fn set_ipv4_mtu(&self, mtu: u16) -> Result<(), NetError> {
+        Ok(self.protocol_ipv4.set_mtu(mtu)?)
+    }
+
+    /// Fragment our packets that exceed the MTU, on by default. When off
+    /// they are sent with DF set and dropped instead.
+    pub fn set_ipv4_fragmentation(&self, on: bool) {
+        self.protocol_ipv4.set_fragmentation(on)
+    }
+
+    /// Probe new addresses for conflicts before using them, on by default.
+    pub fn set_ipv4_conflict_detection(&self, on: bool) {
+        self.protocol_arp.set_conflict_detection(on)
+    }
+
+    /// Add an IPv6 address with its prefix, e.g. "2001:db8::1/64", and the
+    /// route of the prefix. The address is checked for duplicates first
+    /// (RFC 4862) and usable once `Ipv6AddrEvent::Bound` is reported; needs
+    /// `start_timers`.
+    pub fn add_ipv6(&self, ip: Ipv6Cidr, sub_addr: Option<&(dyn Any + Send + Sync)>) -> Result<Ipv6AddrEvents, NetError> {
+        let Some(sub_addr_val) = sub_addr else {
+            return Err(ConfigError::MissingInterface.into());
+        };
+        let eth_res = self.resolve_sub_if(sub_addr_val)?;
+        println!("adding {} on {:?}", ip, eth_res.sub_info());
+        let ent = self.protocol_ipv6.add_ipv6(ip, Some(eth_res))?;
+        Ok(self.protocol_icmpv6.ndp.start_dad(ent, Runtime::get_time_usec()))
+    }
+
+    /// Add the link-local address (fe80::/64) of an interface, its
+    /// interface identifier made from the MAC as `mode` says. Checked for
+    /// duplicates like `add_ipv6`.
+    pub fn add_ipv6_link_local(&self, sub_addr: &(dyn Any + Send + Sync), mode: Ipv6IidMode) -> Result<(Ipv6Addr, Ipv6AddrEvents), NetError> {
+        let eth_res = self.resolve_sub_if(sub_addr)?;
+        let addr = self.protocol_ipv6.make_addr(&Ipv6Cidr::link_local(), &eth_res, mode, 0)?;
+        let cidr = Ipv6Cidr { addr, prefix_len: Ipv6Cidr::link_local().prefix_len };
+        println!("adding {} on {:?}", cidr, eth_res.sub_info());
+        let ent = self.protocol_ipv6.add_ipv6(cidr, Some(eth_res))?;
+        Ok((addr, self.protocol_icmpv6.ndp.start_dad(ent, Runtime::get_time_usec())))
+    }
+
+    /// Autoconfigure an interface (a `MacAddr` or `VlanIf`) from router
+    /// advertisements (RFC 4862): default routers, on-link prefixes and
+    /// addresses in advertised /64s, interface identifiers made as `mode`
+    /// says. `None` stops taking new ones. Needs `start_timers`.
+    pub fn set_ipv6_autoconf(&self, sub_addr: &(dyn Any + Send + Sync), mode: Option<Ipv6IidMode>) -> Result<(), NetError> {
+        let iface = self.resolve_sub_if(sub_addr)?;
+        self.protocol_icmpv6.router_adv.set_autoconf(&iface, mode, Runtime::get_time_usec());
+        Ok(())
+    }
+
+    /// Act as a router on an interface and advertise `config` there, or
+    /// stop with `None`. The interface needs a link-local address.
+    pub fn set_ipv6_router(&self, sub_addr: &(dyn Any + Send + Sync), config: Option<Ipv6RaConfig>) -> Result<(), NetError> {
+        let iface = self.resolve_sub_if(sub_addr)?;
+        let icmpv6 = &self.protocol_icmpv6;
+        Ok(icmpv6.router_adv.set_advertising(&iface, config, &icmpv6.ndp, Runtime::get_time_usec())?)
+    }
+
+    /// IPv6 MTU of all interfaces, at least 1280. Bigger packets are
+    /// dropped, IPv6 is not fragmented on the way.
+    pub fn set_ipv6_mtu(&self, mtu: u32) -> Result<(), NetError> {
+        Ok(self.protocol_ipv6.set_mtu(mtu)?)
+    }
+
+    /// Check new IPv6 addresses for duplicates before using them, on by
+    /// default.
+    pub fn set_ipv6_dad(&self, on: bool) {
+        self.protocol_icmpv6.ndp.set_dad(on)
+    }
+
+    /// Secret of stable-privacy addresses (RFC 7217), random by default.
+    /// Set the same one on every start for addresses that persist.
+    pub fn set_ipv6_stable_secret(&self, secret: [u8; 16]) {
+        self.protocol_ipv6.set_stable_secret(secret)
+    }
+
+    /// Remove an IPv6 address, and its prefix route if no other address of
+    /// the interface is in that prefix.
+    pub fn remove_ipv6(&self, ip: &Ipv6Addr) -> Result<(), NetError> {
+        let Some(ent) = self.protocol_ipv6.remove_ipv6(ip) else {
+            return Err(Ipv6Error::AddressNotFound(*ip).into());
+        };
+        self.protocol_icmpv6.ndp.stop_dad(ip);
+        println!("removed {} from {:?}", ent.get_cidr(), ent.eth_entry().map(|e| e.sub_info()));
+        Ok(())
+    }
+
+    /// Our IPv6 addresses and where they are in duplicate address
+    /// detection.
+    pub fn ipv6_addresses(&self) -> Vec<(Ipv6Cidr, Ipv6AddrState)> {
+        self.protocol_ipv6.entries()
+            .iter()
+            .map(|e| (e.get_cidr(), e.get_state()))
+            .collect()
+    }
+
+    /// Add a static IPv6 route, `::/0` for the default route. The interface
+    /// is `oif` (a `MacAddr` or `VlanIf`) if given, else the one of the
+    /// prefix the gateway is on.
+    pub fn add_ipv6_route(&self, prefix: Ipv6Cidr, gateway: Option<Ipv6Addr>, oif: Option<&(dyn Any + Send + Sync)>, metric: u32) -> Result<(), NetError> {
+        let iface = match oif {
+            Some(oif) => Some(self.resolve_sub_if(oif)?),
+            None => None,
+        };
+        Ok(self.protocol_ipv6.add_route(prefix, gateway, iface, metric)?)
+    }
+
+    pub fn add_ipv6_default_route(&self, gateway: Ipv6Addr, metric: u32) -> Result<(), NetError> {
+        self.add_ipv6_route(Ipv6Cidr::default_route(), Some(gateway), None, metric)
+    }
+
+    /// Remove the static IPv6 routes of `prefix`, or only the one via
+    /// `gateway`.
+    pub fn remove_ipv6_route(&self, prefix: &Ipv6Cidr, gateway: Option<&Ipv6Addr>) -> Result<(), NetError> {
+        Ok(self.protocol_ipv6.remove_route(prefix, gateway)?)
+    }
+
+    /// The IPv6 routing table, connected and static routes.
+    pub fn ipv6_routes(&self) -> Vec<Ipv6RouteInfo> {
+        self.protocol_ipv6.routes().iter().map(|r| r.info()).collect()
+    }
+
+    /// Give an interface and its VLAN sub-interfaces a new MAC and announce
+    /// their addresses with gratuitous ARP.
+    pub fn change_mac(&self, old: &MacAddr, new: &MacAddr) -> Result<(), NetError> {
+        let moved = self.protocol_eth.change_mac(old, new)?;
+        self.protocol_arp.announce_on(&moved, Runtime::get_time_usec());
+        Ok(())
+    }
+
+    /// Enable or disable 802.1Q/802.1ad handling. Untagged frames belong to
+    /// `default_vlan`, and frames sent on it go out untagged.
+    pub fn set_vlan_mode(&self, enable: bool, default_vlan: Option<u16>) -> Result<(), NetError> {
+        Ok(self.protocol_eth.set_vlan_mode(enable, default_vlan)?)
+    }
+
+    /// Add a VLAN sub-interface on an added MAC. Frames for VLANs without a
+    /// sub-interface are dropped.
+    pub fn add_vlan(&self, vlan_if: &VlanIf) -> Result<(), NetError> {
+        self.protocol_eth.add_vlan(vlan_if)?;
+        Ok(())
+    }
+
+    /// Remove a VLAN sub-interface and everything bound to it: addresses
+    /// with their conflict and duplicate detection, routes, neighbors and
+    /// router discovery state.
+    pub fn remove_vlan(&self, vlan_if: &VlanIf) -> Result<(), NetError> {
+        let iface = self.protocol_eth.search_vlan(vlan_if)?;
+        let now = Runtime::get_time_usec();
+        let on_iface = |sub: Option<Arc<EthEntry>>| sub.is_some_and(|e| Arc::ptr_eq(&e, &iface));
+
+        let icmpv6 = &self.protocol_icmpv6;
+        icmpv6.router_adv.forget_iface(&iface, &icmpv6.ndp, now);
+        for ent in self.protocol_ipv6.entries().iter().filter(|e| on_iface(e.eth_entry())) {
+            self.remove_ipv6(ent.get_addr())?;
+        }
+        let mut dropped = icmpv6.ndp.forget_iface(&iface);
+        self.protocol_ipv6.forget_iface(&iface);
+
+        for ent in self.protocol_ipv4.entries().iter().filter(|e| on_iface(e.eth_entry())) {
+            self.remove_ipv4(&ent.get_cidr().addr)?;
+        }
+        dropped += self.protocol_arp.forget_iface(&iface);
+        self.protocol_ipv4.forget_iface(&iface);
+        self.drops.count_n(DropReason::Unresolved, dropped as u64);
+
+        Ok(self.protocol_eth.remove_vlan(vlan_if)?)
+    }
 }
 
 impl AsyncNetIOModule<NetworkPacket> for NetworkStack
 {
     // type OutputOK = ();
     // type OutputErr = ();
-    type RxResult = (NetworkPacket, Result<(), ()>);
-    type TxResult = (NetworkPacket, Result<(), ()>);
+    type RxResult = (NetworkPacket, Result<(), NetError>);
+    type TxResult = (NetworkPacket, Result<(), NetError>);
 
     // fn rx(self: Arc<Self>, p: NetworkPacket) -> Pin<Box<dyn Future<Output = Self::RxResult> >> {
     async fn rx(self: Arc<Self>, p: NetworkPacket) -> Self::RxResult {
         
         println!("!!!!!!!!!stack rx test, {:?}", p);
         
-        let (mut p, mut res) = self.driver_layer.clone().rx(p).await;
+        let (p, res) = self.driver_layer.clone().rx(p).await;
+        if let Err(e) = res {
+            self.count_drop(&e);
+            return (p, Err(e));
+        }
 
-        match self.stack_type {
-            ProtocolHeaderType::Ethernet => {
-                // L2
-                (p, res) = self.protocol_eth.sync_decode(p);
-                let l3_meta = match res {
-                    Ok(meta) => meta,
-                    _ => return (p, Err(())),
-                };
-                
-                // L3
-                (p, res) = match l3_meta.get_pt() {
-                    ProtocolHeaderType::ARP => self.protocol_arp.sync_decode(p),
-                    ProtocolHeaderType::IPv4 => self.protocol_ipv4.sync_decode(p),
-                    ProtocolHeaderType::IPv6 => self.protocol_ipv6.sync_decode(p),
-                    _ => return (p, Err(())),
-                };
-                let l4_meta = match res {
-                    Ok(meta) => meta,
-                    _ => return (p, Err(())),
-                };
-                
-                // l4
-                (p, res) = match l4_meta.get_pt() {
-                    ProtocolHeaderType::UDP => self.protocol_udp.sync_decode(p),
-                    ProtocolHeaderType::TCP => self.protocol_tcp.sync_decode(p),
-                    _ => return (p, Err(())),
-                };
-                let app_meta = match res {
-                    Ok(meta) => meta,
-                    _ => return (p, Err(())),
-                };
-                
-                // socket
+        let (p, dispatch) = self.graph.decode(self.stack_type, p);
+        let (p, res) = match dispatch {
+            Ok(ProtocolDispatch::Socket) => {
+                // addresses and ports are known now
+                p.meta_mut().fill_flow_hash();
                 self.socket_layer.clone().rx(p).await
-                // (p, Ok(()))
             }
-            _ => (p, Err(()))
+            Ok(ProtocolDispatch::Consumed) => (p, Ok(())),
+            Ok(ProtocolDispatch::Forward) => {
+                // drops on the way out are counted there
+                let (p, res) = self.output(p).await;
+                self.flush_outbox().await;
+                return (p, res);
+            }
+            Err(e) => (p, Err(e)),
+        };
+        if let Err(e) = &res {
+            self.count_drop(e);
         }
+        self.flush_outbox().await;
+        (p, res)
     }
     async fn tx(self: Arc<Self>, p: NetworkPacket) -> Self::TxResult {
         println!("!!!!!!!!!stack tx test. {:?}", p);
         let (p, res) = self.socket_layer.clone().tx(p).await;
-        let (p, res) = self.protocol_eth.sync_encode(p);
-        let (p, res) = self.protocol_arp.sync_encode(p);
-        let (p, res) = self.protocol_ipv4.sync_encode(p);
-        let (p, res) = self.protocol_ipv6.sync_encode(p);
-        let (p, res) = self.driver_layer.clone().tx(p).await;
-        (p, Ok(()))
+        if let Err(e) = res {
+            self.count_drop(&e);
+            return (p, Err(e));
+        }
+        let (p, res) = self.output(p).await;
+        self.flush_outbox().await;
+        (p, res)
     }
-}
\ No newline at end of file
+}
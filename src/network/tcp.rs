@@ -1,10 +1,15 @@
 use std::hash::{Hash, Hasher};
 use std::net::Ipv4Addr;
 use crate::network::arp::ArpProtocol;
+use crate::network::error::NetError;
 use crate::network::module_traits::AsyncProtocolModule;
 use crate::network::packet::NetworkPacket;
+use crate::network::packet_meta::read_l4_ports;
 use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData};
 
+/// Without options.
+pub const TCP_HDR_LEN: usize = 20;
+
 /// CIDR-aware key: network address + prefix length
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TCPKey {
@@ -38,7 +43,7 @@ pub(crate) struct TCPProtocol {
 impl TCPProtocol {
     pub(crate) fn new() -> TCPProtocol {
         TCPProtocol {
-            common: NetworkProtocolMng::new(ProtocolHeaderType::IPv4),
+            common: NetworkProtocolMng::new(ProtocolHeaderType::TCP),
             ttl_default: 64,
             mtu: 1500,
             allow_fragmentation: false,
@@ -47,8 +52,8 @@ impl TCPProtocol {
 }
 
 impl AsyncProtocolModule<NetworkPacket> for TCPProtocol {
-    type EncodeResult = (NetworkPacket, Result<(), ()>);
-    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
 
     async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
         println!("----- encode TCP -----");
@@ -57,6 +62,9 @@ impl AsyncProtocolModule<NetworkPacket> for TCPProtocol {
 
     async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode TCP -----");
+        if let Err(e) = read_l4_ports(&p, TCP_HDR_LEN) {
+            return (p, Err(e.into()));
+        }
         let mut meta = ProtocolMetaData::new();
         meta.set_pt(ProtocolHeaderType::Socket);
         (p, Ok(meta))
@@ -69,6 +77,9 @@ impl AsyncProtocolModule<NetworkPacket> for TCPProtocol {
 
     fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode TCP -----");
+        if let Err(e) = read_l4_ports(&p, TCP_HDR_LEN) {
+            return (p, Err(e.into()));
+        }
         let mut meta = ProtocolMetaData::new();
         meta.set_pt(ProtocolHeaderType::Socket);
         (p, Ok(meta))
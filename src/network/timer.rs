@@ -0,0 +1,12 @@
+// Timer helpers shared by ARP, NDP and router discovery.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use crate::executor::runtime::Runtime;
+
+/// Random delay in `0..max` usec, spreads out hosts that start together.
+pub(crate) fn jitter(max: u64) -> u64 {
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(Runtime::get_time_usec());
+    h.finish() % max.max(1)
+}
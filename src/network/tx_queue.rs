@@ -0,0 +1,51 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use crate::network::packet::NetworkPacket;
+
+static TX_OUTBOX_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // packets are bound to their thread, and so are the queues, keyed by
+    // TxOutbox id
+    static TX_OUTBOX: RefCell<BTreeMap<u64, VecDeque<NetworkPacket>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Packets a layer produced on its own while handling another one, e.g. an
+/// ARP reply or packets released by a resolved neighbor. The stack sends
+/// them after the packet it is working on.
+///
+/// Each stack has its own, the layers get a copy of the handle. The layers
+/// above have filled in the metadata; they go through the protocol graph's
+/// encoders like any other TX packet.
+#[derive(Debug, Clone, Copy)]
+pub struct TxOutbox {
+    id: u64,
+}
+
+impl TxOutbox {
+    pub(crate) fn new() -> TxOutbox {
+        TxOutbox { id: TX_OUTBOX_ID.fetch_add(1, Relaxed) }
+    }
+
+    pub(crate) fn push(&self, p: NetworkPacket) {
+        TX_OUTBOX.with(|q| q.borrow_mut().entry(self.id).or_default().push_back(p));
+    }
+
+    pub(crate) fn pop(&self) -> Option<NetworkPacket> {
+        TX_OUTBOX.with(|q| {
+            let mut q = q.borrow_mut();
+            let queue = q.get_mut(&self.id)?;
+            let p = queue.pop_front();
+            if queue.is_empty() {
+                q.remove(&self.id);
+            }
+            p
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        TX_OUTBOX.with(|q| q.borrow().get(&self.id).map_or(0, |q| q.len()))
+    }
+}
@@ -1,10 +1,14 @@
 use std::hash::{Hash, Hasher};
 use std::net::Ipv4Addr;
 use crate::network::arp::ArpProtocol;
+use crate::network::error::NetError;
 use crate::network::module_traits::AsyncProtocolModule;
 use crate::network::packet::NetworkPacket;
+use crate::network::packet_meta::read_l4_ports;
 use crate::network::protocol::{NetworkProtocolMng, ProtocolHeaderType, ProtocolMetaData};
 
+pub const UDP_HDR_LEN: usize = 8;
+
 /// CIDR-aware key: network address + prefix length
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct UDPKey {
@@ -38,7 +42,7 @@ pub(crate) struct UDPProtocol {
 impl UDPProtocol {
     pub(crate) fn new() -> UDPProtocol {
         UDPProtocol {
-            common: NetworkProtocolMng::new(ProtocolHeaderType::IPv4),
+            common: NetworkProtocolMng::new(ProtocolHeaderType::UDP),
             ttl_default: 64,
             mtu: 1500,
             allow_fragmentation: false,
@@ -47,8 +51,8 @@ impl UDPProtocol {
 }
 
 impl AsyncProtocolModule<NetworkPacket> for UDPProtocol {
-    type EncodeResult = (NetworkPacket, Result<(), ()>);
-    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, ()>);
+    type EncodeResult = (NetworkPacket, Result<(), NetError>);
+    type DecodeResult = (NetworkPacket, Result<ProtocolMetaData, NetError>);
 
     async fn encode(&self, p: NetworkPacket) -> Self::EncodeResult {
         println!("----- encode UDP -----");
@@ -57,6 +61,9 @@ impl AsyncProtocolModule<NetworkPacket> for UDPProtocol {
 
     async fn decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode UDP -----");
+        if let Err(e) = read_l4_ports(&p, UDP_HDR_LEN) {
+            return (p, Err(e.into()));
+        }
         let mut meta = ProtocolMetaData::new();
         meta.set_pt(ProtocolHeaderType::Socket);
         (p, Ok(meta))
@@ -69,6 +76,9 @@ impl AsyncProtocolModule<NetworkPacket> for UDPProtocol {
 
     fn sync_decode(&self, p: NetworkPacket) -> Self::DecodeResult {
         println!("----- decode UDP -----");
+        if let Err(e) = read_l4_ports(&p, UDP_HDR_LEN) {
+            return (p, Err(e.into()));
+        }
         let mut meta = ProtocolMetaData::new();
         meta.set_pt(ProtocolHeaderType::Socket);
         (p, Ok(meta))
@@ -1,5 +1,6 @@
 use std::pin::Pin;
 use std::sync::Arc;
+use crate::network::error::NetError;
 use crate::network::module_traits::AsyncNetIOModule;
 use crate::network::packet::NetworkPacket;
 use crate::network::stack::NetworkStack;
@@ -15,8 +16,8 @@ impl UsrApplication {
 }
 
 impl AsyncNetIOModule<NetworkPacket> for UsrApplication {
-    type RxResult = (NetworkPacket, Result<(), ()>);
-    type TxResult = (NetworkPacket, Result<(), ()>);
+    type RxResult = (NetworkPacket, Result<(), NetError>);
+    type TxResult = (NetworkPacket, Result<(), NetError>);
 
     async fn rx(self: Arc<Self>, p: NetworkPacket) -> Self::RxResult {
         // let res;